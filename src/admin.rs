@@ -0,0 +1,116 @@
+use std::time::Duration;
+
+use crate::config::parse_duration;
+
+/// Commands accepted from admins in a private message to the bot
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AdminCommand {
+    Freeze(String),
+    Unfreeze(String),
+    Set(String, i64),
+    Normalize(String),
+    Variants(String),
+    ExportCsv,
+    Reset(String),
+    Restore(String),
+    Purge,
+    /// Stop replying in a channel, until unmuted or for the duration
+    Mute(String, Option<Duration>),
+    Unmute(String),
+    /// Change every key starting with the prefix, given as `prefix*`
+    AdjustPrefix(String, i64),
+    /// Change the bot's nick
+    Nick(String),
+    /// Whether the bot is a channel operator in the channel
+    HaveOp(String),
+    /// Remove the keys closer to zero than the value which haven't changed
+    /// within the duration
+    Prune(i64, Duration),
+    /// Go ahead with a destructive command, given the token sent for it
+    Confirm(String),
+}
+use AdminCommand::*;
+
+impl AdminCommand {
+    /// The key the command changes, if it changes a single key
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Freeze(key) | Unfreeze(key) | Set(key, _) | Reset(key) | Restore(key) => Some(key),
+            _ => None,
+        }
+    }
+}
+
+pub fn parse_admin_command(line: &str) -> Option<AdminCommand> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        ["freeze", key] => Some(Freeze(key.to_string())),
+        ["unfreeze", key] => Some(Unfreeze(key.to_string())),
+        ["normalize", key] => Some(Normalize(key.to_string())),
+        ["variants", key] => Some(Variants(key.to_string())),
+        ["export", "csv"] => Some(ExportCsv),
+        ["reset", key] => Some(Reset(key.to_string())),
+        ["restore", key] => Some(Restore(key.to_string())),
+        ["purge"] => Some(Purge),
+        ["mute", channel] => Some(Mute(channel.to_string(), None)),
+        ["mute", channel, duration] => parse_duration(duration).ok()
+                                           .map(|duration| Mute(channel.to_string(), Some(duration))),
+        ["unmute", channel] => Some(Unmute(channel.to_string())),
+        ["nick", nick] => Some(Nick(nick.to_string())),
+        ["haveop", channel] => Some(HaveOp(channel.to_string())),
+        ["prune", min_abs, age] => {
+            let age = parse_duration(age).ok()?;
+            min_abs.parse::<i64>().ok().map(|min_abs| Prune(min_abs, age))
+        }
+        ["confirm", token] => Some(Confirm(token.to_string())),
+        ["addall", pattern, delta] => {
+            let prefix = pattern.strip_suffix('*')?;
+            delta.parse::<i64>().ok().map(|delta| AdjustPrefix(prefix.to_string(), delta))
+        }
+        ["set", key, value] => value.parse::<i64>().ok()
+                                    .map(|value| Set(key.to_string(), value)),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_admin_parser() {
+    assert_eq!(parse_admin_command(""), None);
+    assert_eq!(parse_admin_command("++foo"), None);
+    assert_eq!(parse_admin_command("freeze foo"), Some(Freeze("foo".to_string())));
+    assert_eq!(parse_admin_command("  freeze   Foo::Bar "), Some(Freeze("Foo::Bar".to_string())));
+    assert_eq!(parse_admin_command("freeze"), None);
+    assert_eq!(parse_admin_command("freeze foo bar"), None);
+    assert_eq!(parse_admin_command("unfreeze foo"), Some(Unfreeze("foo".to_string())));
+    assert_eq!(parse_admin_command("set foo -12"), Some(Set("foo".to_string(), -12)));
+    assert_eq!(parse_admin_command("set foo bar"), None);
+    assert_eq!(parse_admin_command("set foo"), None);
+    assert_eq!(parse_admin_command("normalize Foo::Bar"), Some(Normalize("Foo::Bar".to_string())));
+    assert_eq!(parse_admin_command("variants foo.bar"), Some(Variants("foo.bar".to_string())));
+    assert_eq!(parse_admin_command("export csv"), Some(ExportCsv));
+    assert_eq!(parse_admin_command("export"), None);
+    assert_eq!(parse_admin_command("reset foo"), Some(Reset("foo".to_string())));
+    assert_eq!(parse_admin_command("restore Foo::Bar"), Some(Restore("Foo::Bar".to_string())));
+    assert_eq!(parse_admin_command("purge"), Some(Purge));
+    assert_eq!(parse_admin_command("purge foo"), None);
+    assert_eq!(parse_admin_command("mute #chan"), Some(Mute("#chan".to_string(), None)));
+    assert_eq!(parse_admin_command("mute #chan 2h"),
+               Some(Mute("#chan".to_string(), Some(Duration::from_secs(2 * 60 * 60)))));
+    assert_eq!(parse_admin_command("mute #chan soon"), None);
+    assert_eq!(parse_admin_command("unmute #chan"), Some(Unmute("#chan".to_string())));
+    assert_eq!(parse_admin_command("unmute"), None);
+    assert_eq!(parse_admin_command("addall foo.* 1"), Some(AdjustPrefix("foo.".to_string(), 1)));
+    assert_eq!(parse_admin_command("addall * -2"), Some(AdjustPrefix("".to_string(), -2)));
+    assert_eq!(parse_admin_command("addall foo 1"), None);
+    assert_eq!(parse_admin_command("nick rotbot"), Some(Nick("rotbot".to_string())));
+    assert_eq!(parse_admin_command("nick"), None);
+    assert_eq!(parse_admin_command("haveop #chan"), Some(HaveOp("#chan".to_string())));
+    assert_eq!(parse_admin_command("haveop"), None);
+    assert_eq!(parse_admin_command("confirm 1a2b3c"), Some(Confirm("1a2b3c".to_string())));
+    assert_eq!(parse_admin_command("confirm"), None);
+    assert_eq!(parse_admin_command("prune 3 90d"),
+               Some(Prune(3, Duration::from_secs(90 * 24 * 60 * 60))));
+    assert_eq!(parse_admin_command("prune 3"), None);
+    assert_eq!(parse_admin_command("prune 3 soon"), None);
+    assert_eq!(parse_admin_command("addall foo.* x"), None);
+}
@@ -0,0 +1,837 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use crate::error::RotError;
+use crate::rotdb::{Decay, KeyMode};
+use crate::schedule::{QuietHours, Schedule};
+use crate::template::{self, Templates, Verbosity, KEY_PLACEHOLDERS};
+
+use encoding_rs::Encoding;
+
+/// Settings loaded from the optional config file.  The file format is one
+/// `setting = value` per line; blank lines and lines starting with `#` are
+/// ignored.
+///
+/// On Unix, the file is read again on SIGHUP and most settings take effect
+/// immediately.  `base_db`, `max_db_lines`, `key_separators` and turning
+/// `history` or `user_totals` off need a restart, since they affect how the
+/// db was loaded, as does `bind_address`.
+/// `username` and `realname` are only sent when connecting, so they take
+/// effect on the next reconnect.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// `decay = linear <amount> <period>` or `decay = exponential <percent> <period>`
+    pub decay: Option<Decay>,
+    /// `min_value = <n>` for the lowest value a decrement can take a key to,
+    /// such as 0 for upvotes only.  Admins can still set any value.
+    pub min_value: Option<i64>,
+    /// `max_keys = <n>` to stop tracking new keys once `n` are stored.
+    /// Keys already stored can still change.  Reset and denied keys don't
+    /// count toward `n`, the same as they aren't counted by `?count`.
+    pub max_keys: Option<usize>,
+    /// `addressed_only = true` to ignore channel messages not starting with
+    /// `botnick:` or `botnick,`
+    pub addressed_only: bool,
+    /// `history = true` to record who made each change, enabling the
+    /// `?whodid` and `?mine` queries.  Off by default for privacy.
+    pub history: bool,
+    /// `user_totals = true` to count the karma each nick gives, and receives
+    /// when their nick is used as a key, for `?karma-given nick` and
+    /// `?karma-received nick`.  Off by default for privacy.
+    pub user_totals: bool,
+    /// `encoding = <label>` (e.g. `latin1`) for servers not using UTF-8.
+    /// Defaults to UTF-8, with invalid bytes replaced.
+    pub encoding: Option<&'static Encoding>,
+    /// `channels = #chan1, #chan2` to join, in addition to any given on the
+    /// command line
+    pub channels: Vec<String>,
+    /// `admins = nick1, nick2` may send admin commands in a private message.
+    /// Once the server's account-notify says which services account someone
+    /// is logged in to, that has to be listed instead of their nick.  Before
+    /// then they're recognized by nick alone, so this should only be used on
+    /// networks where nicks are protected by services.
+    pub admins: Vec<String>,
+    /// `schedule = <interval> <target> <message>`, which may be repeated.
+    /// `{top}` in the message is replaced with the current leaderboard.
+    pub schedules: Vec<Schedule>,
+    /// `quiet_hours = <HH:MM>-<HH:MM> UTC[+-offset] [#chan ...]`, which may
+    /// be repeated.  Changes are still made during the window, but the
+    /// channels (all of them, if none are listed) get no replies.
+    pub quiet_hours: Vec<QuietHours>,
+    /// `query_prefix = <prefix> [#chan ...]` (e.g. `!karma`) to write
+    /// queries as `!karma foo` instead of `?foo`, in the channels given or
+    /// everywhere else if there are none.  May be repeated.
+    pub query_prefix: String,
+    /// Channels with their own `query_prefix`, by lowercase name
+    pub channel_query_prefixes: HashMap<String, String>,
+    /// `query_template`, `change_template` and `top_entry_template` may use
+    /// the `{key}`, `{value}` and `{rank}` placeholders
+    pub templates: Templates,
+    /// `verbosity = value`, `rank` or `full` for how much queries say: just
+    /// the `query_template`, or with the key's rank, or with its rank and
+    /// when it was last changed
+    pub verbosity: Verbosity,
+    /// `report_absent = true` to answer queries for keys that have never
+    /// been changed with "No data" instead of a value of 0, or with
+    /// `absent_template = <template>`, which may use `{key}`.  Keys which
+    /// are 0 after being changed still show their value.
+    pub report_absent: bool,
+    /// `private_query_channel = #chan` to answer `?key` queries sent in a
+    /// private message in that channel instead, for senders who are in it.
+    /// The channel sees the key asked about and its value, but not who asked
+    /// or the rest of the message, and anything other than a query is still
+    /// answered privately.
+    pub private_query_channel: Option<String>,
+    /// `base_db = <filename>` for a shared, read-only db underneath our own.
+    /// Keys we have changed override the base.
+    pub base_db: Option<String>,
+    /// `min_presence = <duration>` to ignore changes from nicks until they
+    /// have been seen in the channel for that long, to discourage sockpuppets
+    pub min_presence: Option<Duration>,
+    /// `recent_window = <duration>` for how far back `?recent` looks.  The
+    /// default is an hour.
+    pub recent_window: Duration,
+    /// `max_idle = <duration>` to reconnect when nothing at all has been
+    /// received from the server for that long, even if our PINGs seem to be
+    /// answered
+    pub max_idle: Option<Duration>,
+    /// `max_message_age = <duration>` to ignore messages whose `server-time`
+    /// is older than that, such as a bouncer's backlog.  Messages from before
+    /// we connected are always ignored.
+    pub max_message_age: Option<Duration>,
+    /// `keepalive = ping|notice|none` for what to send every 5 minutes to
+    /// check the connection, see `Keepalive`.  The default is `ping`.
+    pub keepalive: Keepalive,
+    /// `save_debounce = <duration>` to save the db that long after the last
+    /// of a burst of changes, instead of only every 15 minutes.  A steady
+    /// stream of changes is still saved at least every 15 minutes.
+    pub save_debounce: Option<Duration>,
+    /// `key_rate_limit = <count>/<duration>` (e.g. `10/1m`) to ignore
+    /// changes to a key beyond that many in the period, whoever makes them
+    pub key_rate_limit: Option<RateLimit>,
+    /// `combo = <people>/<duration> <bonus>` (e.g. `3/2m 1`) to add a bonus
+    /// (more than 0) to a key incremented by that many different people
+    /// within the period.  People are told apart by user@host.
+    pub combo: Option<Combo>,
+    /// `prune = <min_abs> <age>` (e.g. `3 180d`) to remove the keys closer
+    /// to zero than `min_abs` that haven't changed within `age`, checked
+    /// every hour.  Keys from before changes were timestamped count as old.
+    pub prune: Option<Prune>,
+    /// `join_notice = <message>` to send a notice to each channel when the
+    /// bot joins it, such as a short explanation of the syntax
+    pub join_notice: Option<String>,
+    /// `join_notice_on_reconnect = true` to send the `join_notice` again
+    /// after reconnecting, not just the first time a channel is joined
+    pub join_notice_on_reconnect: bool,
+    /// `max_db_lines = <count>` to refuse to load a larger db, which is
+    /// probably corrupt.  There is no limit by default.
+    pub max_db_lines: Option<usize>,
+    /// `ban_patterns` and `ban_backoff`, see `BanPolicy`
+    pub ban: BanPolicy,
+    /// `max_ops = <count>` limits how many operations one message can make
+    pub max_ops: usize,
+    /// `warn_max_ops = true` to say when operations beyond `max_ops` are ignored
+    pub warn_max_ops: bool,
+    /// `restrict_decrements = true` to only accept `--` from channel
+    /// operators and admins
+    pub restrict_decrements: bool,
+    /// `warn_restricted = true` to send a notice when a decrement is ignored
+    pub warn_restricted: bool,
+    /// `denied_keys = key1, key2` that can't be changed (even by admins),
+    /// queried or listed, such as slurs or reserved names.  They're compared
+    /// in normalized form, and any value already stored is kept.
+    pub denied_keys: Vec<String>,
+    /// `denied_keys_file = <filename>` with more `denied_keys`, one per line.
+    /// Blank lines and lines starting with `#` are ignored.
+    pub denied_keys_file: Option<String>,
+    /// `warn_denied = true` to send a notice when a denied key is ignored
+    pub warn_denied: bool,
+    /// `thanks = thanks, thx, ty` for words that count as `++` when thanking
+    /// someone in the channel, as in `thanks alice` or `alice: thx`, or with
+    /// an increment, as in `foo++ thanks`.  Off unless words are given.
+    pub thanks: Vec<String>,
+    /// `sandbox_channels = #chan1, #chan2` where changes are kept in memory
+    /// for that channel only and never saved, for demos and testing
+    pub sandbox_channels: Vec<String>,
+    /// `key_separators = distinct` to keep `foo.bar`, `foo::bar` and
+    /// `foo->bar` as different keys.  The default is `same`.
+    pub key_mode: KeyMode,
+    /// `username = <ident>` for the USER line, instead of the nick
+    pub username: Option<String>,
+    /// `realname = <name>` for the USER line, instead of the nick
+    pub realname: Option<String>,
+    /// `bind_address = <ip>` to connect from that local address, such as a
+    /// vhost on a multi-homed host
+    pub bind_address: Option<IpAddr>,
+    /// `sasl = external` to authenticate with SASL when connecting, see
+    /// `SaslMechanism`.  The `rot` binary can't present a client
+    /// certificate yet, so the client refuses to connect with this set
+    /// unless it's given a connector that can, with `set_connector`.
+    pub sasl: Option<SaslMechanism>,
+    /// `reply_threads = true` to tag replies with the message they answer,
+    /// so clients can thread them.  This only works on servers with the
+    /// `message-tags` capability.
+    pub reply_threads: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            decay: None,
+            min_value: None,
+            max_keys: None,
+            addressed_only: false,
+            history: false,
+            user_totals: false,
+            encoding: None,
+            channels: Vec::new(),
+            admins: Vec::new(),
+            schedules: Vec::new(),
+            quiet_hours: Vec::new(),
+            query_prefix: "?".to_string(),
+            channel_query_prefixes: HashMap::new(),
+            templates: Templates::default(),
+            verbosity: Verbosity::default(),
+            report_absent: false,
+            private_query_channel: None,
+            base_db: None,
+            min_presence: None,
+            recent_window: Duration::from_secs(60 * 60),
+            max_idle: None,
+            max_message_age: None,
+            keepalive: Keepalive::default(),
+            save_debounce: None,
+            key_rate_limit: None,
+            combo: None,
+            prune: None,
+            join_notice: None,
+            join_notice_on_reconnect: false,
+            max_db_lines: None,
+            ban: BanPolicy::default(),
+            max_ops: 5,
+            warn_max_ops: false,
+            restrict_decrements: false,
+            warn_restricted: false,
+            denied_keys: Vec::new(),
+            denied_keys_file: None,
+            warn_denied: false,
+            thanks: Vec::new(),
+            sandbox_channels: Vec::new(),
+            key_mode: KeyMode::default(),
+            username: None,
+            realname: None,
+            bind_address: None,
+            sasl: None,
+            reply_threads: false,
+        }
+    }
+}
+
+/// How we check that the connection is still alive when it's quiet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Keepalive {
+    /// PING the server, reconnecting if there's no PONG
+    #[default]
+    Ping,
+    /// Send a NOTICE to ourselves, for bouncers that don't like client
+    /// PINGs.  Nothing is expected back, so a dead connection is only
+    /// noticed by `max_idle` or a failed write.
+    Notice,
+    /// Send nothing, relying on the server's PINGs and `max_idle`
+    Off,
+}
+
+/// How to authenticate with SASL while registering
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaslMechanism {
+    /// Let the server identify us by the client certificate of the
+    /// connection.  There's no TLS support built in, so the certificate
+    /// has to come from a connector passed to `IrcClient::set_connector`.
+    External,
+}
+
+/// A bonus added to a key when `people` different people increment it
+/// within `window`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Combo {
+    pub people: usize,
+    pub window: Duration,
+    pub bonus: i64,
+}
+
+/// Stale keys to remove: those closer to zero than `min_abs` which haven't
+/// changed within `age`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Prune {
+    pub min_abs: i64,
+    pub age: Duration,
+}
+
+/// At most `count` of something in any `window`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub count: usize,
+    pub window: Duration,
+}
+
+/// How to tell when we've been banned from the server, and how long to wait
+/// before reconnecting when we have.  Being killed always counts as a ban.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BanPolicy {
+    /// `ban_patterns = k-lined, banned`, matched case-insensitively against
+    /// the server's ERROR message
+    pub patterns: Vec<String>,
+    /// `ban_backoff = <duration>`, or `never` to stop reconnecting
+    pub backoff: Option<Duration>,
+}
+
+impl Default for BanPolicy {
+    fn default() -> BanPolicy {
+        BanPolicy {
+            patterns: ["k-lined", "g-lined", "z-lined", "banned"].iter()
+                                                                 .map(|p| p.to_string())
+                                                                 .collect(),
+            backoff: Some(Duration::from_secs(30 * 60)),
+        }
+    }
+}
+
+impl BanPolicy {
+    pub fn matches(&self, error: &str) -> bool {
+        let error = error.to_lowercase();
+        self.patterns.iter().any(|pattern| error.contains(pattern.as_str()))
+    }
+}
+
+impl Config {
+    pub fn load(filename: &str) -> Result<Config, RotError> {
+        let mut config = Config::parse(&fs::read_to_string(filename)?)?;
+        if let Some(denied_filename) = &config.denied_keys_file {
+            let text = fs::read_to_string(denied_filename).map_err(|err| {
+                io::Error::new(err.kind(), format!("{}: {}", denied_filename, err))
+            })?;
+            config.denied_keys.extend(parse_denied_keys(&text));
+        }
+        Ok(config)
+    }
+
+    pub fn parse(text: &str) -> Result<Config, RotError> {
+        let mut config = Config::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let result = match line.split_once('=') {
+                Some((key, value)) => config.set(key.trim(), value.trim()),
+                None => Err(format!("Expected \"setting = value\", got \"{}\"", line)),
+            };
+            if let Err(message) = result {
+                return Err(RotError::Parse { line: index + 1, message });
+            }
+        }
+        Ok(config)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "decay" => self.decay = parse_decay(value)?,
+            "min_value" => match value.parse() {
+                Ok(min_value) => self.min_value = Some(min_value),
+                Err(_) => return Err(format!("Invalid min_value \"{}\"", value)),
+            },
+            "max_keys" => match value.parse() {
+                Ok(max_keys) => self.max_keys = Some(max_keys),
+                Err(_) => return Err(format!("Invalid max_keys \"{}\"", value)),
+            },
+            "addressed_only" => self.addressed_only = parse_bool(value)?,
+            "history" => self.history = parse_bool(value)?,
+            "user_totals" => self.user_totals = parse_bool(value)?,
+            "encoding" => match Encoding::for_label(value.as_bytes()) {
+                Some(encoding) => self.encoding = Some(encoding),
+                None => return Err(format!("Unknown encoding \"{}\"", value)),
+            }
+            "channels" => self.channels = parse_list(value),
+            "admins" => self.admins = parse_list(value),
+            "thanks" => self.thanks = parse_list(value),
+            "schedule" => self.schedules.push(parse_schedule(value)?),
+            "quiet_hours" => self.quiet_hours.push(parse_quiet_hours(value)?),
+            "query_prefix" => {
+                let mut parts = value.split_whitespace();
+                let prefix = parts.next().ok_or("Missing query_prefix")?;
+                let channels: Vec<String> = parts.flat_map(parse_list).collect();
+                if channels.is_empty() {
+                    self.query_prefix = prefix.to_string();
+                }
+                for channel in channels {
+                    self.channel_query_prefixes.insert(channel.to_ascii_lowercase(),
+                                                       prefix.to_string());
+                }
+            }
+            "query_template" => self.templates.query = parse_template(value, KEY_PLACEHOLDERS)?,
+            "change_template" => self.templates.change = parse_template(value, KEY_PLACEHOLDERS)?,
+            "report_absent" => self.report_absent = parse_bool(value)?,
+            "private_query_channel" => self.private_query_channel = Some(value.to_string()),
+            "absent_template" => self.templates.absent = parse_template(value, &["key"])?,
+            "base_db" => self.base_db = Some(value.to_string()),
+            "ban_patterns" => {
+                self.ban.patterns = parse_list(value).iter().map(|p| p.to_lowercase()).collect();
+            }
+            "min_presence" => self.min_presence = Some(parse_duration(value)?),
+            "max_message_age" => self.max_message_age = Some(parse_duration(value)?),
+            "key_rate_limit" => self.key_rate_limit = Some(parse_rate_limit(value)?),
+            "combo" => self.combo = Some(parse_combo(value)?),
+            "prune" => self.prune = Some(parse_prune(value)?),
+            "save_debounce" => self.save_debounce = Some(parse_duration(value)?),
+            "recent_window" => self.recent_window = parse_duration(value)?,
+            "max_idle" => match parse_duration(value)? {
+                idle if idle.as_secs() > 0 => self.max_idle = Some(idle),
+                _ => return Err("max_idle must be non-zero".to_string()),
+            }
+            "join_notice" => self.join_notice = Some(value.to_string()),
+            "join_notice_on_reconnect" => self.join_notice_on_reconnect = parse_bool(value)?,
+            "max_db_lines" => match value.parse::<usize>() {
+                Ok(count) if count > 0 => self.max_db_lines = Some(count),
+                _ => return Err(format!("Invalid line limit \"{}\"", value)),
+            }
+            "max_ops" => match value.parse::<usize>() {
+                Ok(count) if count > 0 => self.max_ops = count,
+                _ => return Err(format!("Invalid operation limit \"{}\"", value)),
+            }
+            "verbosity" => self.verbosity = match value {
+                "value" => Verbosity::Value,
+                "rank" => Verbosity::Rank,
+                "full" => Verbosity::Full,
+                _ => return Err(format!("Invalid verbosity \"{}\"", value)),
+            },
+            "key_separators" => self.key_mode = match value {
+                "same" => KeyMode::Unified,
+                "distinct" => KeyMode::Distinct,
+                _ => return Err(format!("Invalid key_separators \"{}\"", value)),
+            },
+            "username" => {
+                if value.is_empty() || value.contains(char::is_whitespace) {
+                    return Err(format!("Invalid username \"{}\"", value));
+                }
+                self.username = Some(value.to_string());
+            }
+            "realname" => self.realname = Some(value.to_string()),
+            "keepalive" => self.keepalive = match value {
+                "ping" => Keepalive::Ping,
+                "notice" => Keepalive::Notice,
+                "none" => Keepalive::Off,
+                _ => return Err(format!("Invalid keepalive \"{}\"", value)),
+            },
+            "sasl" => self.sasl = match value {
+                "none" => None,
+                "external" => Some(SaslMechanism::External),
+                _ => return Err(format!("Unsupported SASL mechanism \"{}\"", value)),
+            },
+            "bind_address" => match value.parse::<IpAddr>() {
+                Ok(addr) => self.bind_address = Some(addr),
+                Err(_) => return Err(format!("Invalid bind_address \"{}\"", value)),
+            },
+            "warn_max_ops" => self.warn_max_ops = parse_bool(value)?,
+            "restrict_decrements" => self.restrict_decrements = parse_bool(value)?,
+            "warn_restricted" => self.warn_restricted = parse_bool(value)?,
+            "reply_threads" => self.reply_threads = parse_bool(value)?,
+            "denied_keys" => self.denied_keys = parse_list(value),
+            "denied_keys_file" => self.denied_keys_file = Some(value.to_string()),
+            "warn_denied" => self.warn_denied = parse_bool(value)?,
+            "sandbox_channels" => {
+                self.sandbox_channels = parse_list(value).iter()
+                                            .map(|c| c.to_ascii_lowercase()).collect();
+            }
+            "ban_backoff" if value == "never" => self.ban.backoff = None,
+            "ban_backoff" => self.ban.backoff = Some(parse_duration(value)?),
+            "top_entry_template" => {
+                self.templates.top_entry = parse_template(value, KEY_PLACEHOLDERS)?;
+            }
+            _ => return Err(format!("Unknown setting \"{}\"", key)),
+        }
+        Ok(())
+    }
+}
+
+/// Parse a duration given as a number with an optional `s`, `m`, `h`, `d`
+/// or `w` suffix (seconds if omitted)
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let (number, scale) = match text.char_indices().last() {
+        Some((pos, 's')) => (&text[..pos], 1),
+        Some((pos, 'm')) => (&text[..pos], 60),
+        Some((pos, 'h')) => (&text[..pos], 60 * 60),
+        Some((pos, 'd')) => (&text[..pos], 24 * 60 * 60),
+        Some((pos, 'w')) => (&text[..pos], 7 * 24 * 60 * 60),
+        _ => (text, 1),
+    };
+    match number.parse::<u64>() {
+        Ok(value) => Ok(Duration::from_secs(value.saturating_mul(scale))),
+        Err(_) => Err(format!("Invalid duration \"{}\"", text)),
+    }
+}
+
+pub fn parse_bool(text: &str) -> Result<bool, String> {
+    match text {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => Err(format!("Expected true or false, got \"{}\"", text)),
+    }
+}
+
+/// Split a comma and/or space separated list
+pub fn parse_list(text: &str) -> Vec<String> {
+    text.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn parse_denied_keys(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.lines().map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+}
+
+fn parse_decay(value: &str) -> Result<Option<Decay>, String> {
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    if parts == ["none"] {
+        return Ok(None);
+    }
+    if parts.len() != 3 {
+        return Err(format!("Invalid decay \"{}\"", value));
+    }
+    let rate = match parts[1].trim_end_matches('%').parse::<i64>() {
+        Ok(rate) if rate > 0 => rate,
+        _ => return Err(format!("Invalid decay rate \"{}\"", parts[1])),
+    };
+    let period = parse_duration(parts[2])?;
+    if period.as_secs() == 0 {
+        return Err("Decay period must be non-zero".to_string());
+    }
+    match parts[0] {
+        "linear" => Ok(Some(Decay::Linear { amount: rate, period })),
+        "exponential" if rate <= 100 => Ok(Some(Decay::Exponential { percent: rate, period })),
+        "exponential" => Err(format!("Invalid decay percentage \"{}\"", parts[1])),
+        _ => Err(format!("Unknown decay type \"{}\"", parts[0])),
+    }
+}
+
+#[test]
+fn test_config_errors() {
+    match Config::parse("history = true\n\n# comment\nhistroy = true") {
+        Err(RotError::Parse { line, message }) => {
+            assert_eq!(line, 4);
+            assert_eq!(message, "Unknown setting \"histroy\"");
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(matches!(Config::parse("decay"), Err(RotError::Parse { line: 1, .. })));
+    assert_eq!(Config::parse("max_ops = -1").unwrap_err().to_string(),
+               "line 1: Invalid operation limit \"-1\"");
+    match Config::load("no_such_config.conf") {
+        Err(RotError::Io(err)) => assert_eq!(err.kind(), std::io::ErrorKind::NotFound),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn test_denied_keys_file() {
+    std::fs::write("test_denied.conf", "denied_keys = foo\ndenied_keys_file = test_denied.txt\n").unwrap();
+    std::fs::write("test_denied.txt", "# reserved\nbar\n\n  Baz::Quux \n").unwrap();
+    assert_eq!(Config::load("test_denied.conf").unwrap().denied_keys, vec!["foo", "bar", "Baz::Quux"]);
+    std::fs::remove_file("test_denied.txt").unwrap();
+    match Config::load("test_denied.conf") {
+        Err(RotError::Io(err)) => assert!(err.to_string().starts_with("test_denied.txt: ")),
+        other => panic!("unexpected {:?}", other),
+    }
+    std::fs::remove_file("test_denied.conf").unwrap();
+}
+
+#[test]
+fn test_config() {
+    let day = Duration::from_secs(24 * 60 * 60);
+    assert_eq!(Config::parse("").unwrap().decay, None);
+    assert_eq!(Config::parse("# comment\n\ndecay = none\n").unwrap().decay, None);
+    assert_eq!(Config::parse("decay = linear 2 1d").unwrap().decay,
+               Some(Decay::Linear { amount: 2, period: day }));
+    assert_eq!(Config::parse("decay=exponential 10% 2w").unwrap().decay,
+               Some(Decay::Exponential { percent: 10, period: day * 14 }));
+    assert!(Config::parse("decay = exponential 200 1d").is_err());
+    assert!(Config::parse("decay = linear 1 0s").is_err());
+    assert!(Config::parse("decay = sideways 1 1d").is_err());
+    assert!(Config::parse("decay").is_err());
+    assert_eq!(Config::parse("").unwrap().min_value, None);
+    assert_eq!(Config::parse("min_value = -10").unwrap().min_value, Some(-10));
+    assert!(Config::parse("min_value = low").is_err());
+    assert_eq!(Config::parse("").unwrap().max_keys, None);
+    assert_eq!(Config::parse("max_keys = 50000").unwrap().max_keys, Some(50000));
+    assert!(Config::parse("max_keys = -1").is_err());
+    assert!(Config::parse("bogus = 1").is_err());
+
+    assert!(!Config::parse("").unwrap().addressed_only);
+    assert!(Config::parse("addressed_only = yes").unwrap().addressed_only);
+    assert!(!Config::parse("addressed_only = off").unwrap().addressed_only);
+    assert!(Config::parse("addressed_only = maybe").is_err());
+    assert!(!Config::parse("").unwrap().history);
+    assert!(Config::parse("history = true").unwrap().history);
+    assert!(!Config::parse("").unwrap().user_totals);
+    assert!(Config::parse("user_totals = on").unwrap().user_totals);
+    assert_eq!(Config::parse("").unwrap().encoding, None);
+    assert_eq!(Config::parse("encoding = latin1").unwrap().encoding,
+               Some(encoding_rs::WINDOWS_1252));
+    assert!(Config::parse("encoding = klingon").is_err());
+    assert_eq!(Config::parse("admins = alice, bob carol").unwrap().admins,
+               vec!["alice", "bob", "carol"]);
+    assert!(Config::parse("").unwrap().thanks.is_empty());
+    assert_eq!(Config::parse("thanks = thanks, ty").unwrap().thanks, vec!["thanks", "ty"]);
+    assert_eq!(Config::parse("").unwrap().key_mode, KeyMode::Unified);
+    assert_eq!(Config::parse("key_separators = distinct").unwrap().key_mode, KeyMode::Distinct);
+    assert_eq!(Config::parse("channels = #rust, &local,zot").unwrap().channels,
+               vec!["#rust", "&local", "zot"]);
+    assert_eq!(Config::parse("").unwrap().username, None);
+    assert_eq!(Config::parse("username = rotbot").unwrap().username, Some("rotbot".to_string()));
+    assert!(Config::parse("username = rot bot").is_err());
+    assert_eq!(Config::parse("realname = The rot bot").unwrap().realname,
+               Some("The rot bot".to_string()));
+    assert!(Config::parse("key_separators = maybe").is_err());
+    assert_eq!(Config::parse("").unwrap().verbosity, Verbosity::Value);
+    assert_eq!(Config::parse("verbosity = full").unwrap().verbosity, Verbosity::Full);
+    assert!(Config::parse("verbosity = loud").is_err());
+    assert!(!Config::parse("").unwrap().reply_threads);
+    assert_eq!(Config::parse("").unwrap().keepalive, Keepalive::Ping);
+    assert_eq!(Config::parse("keepalive = notice").unwrap().keepalive, Keepalive::Notice);
+    assert_eq!(Config::parse("keepalive = none").unwrap().keepalive, Keepalive::Off);
+    assert!(Config::parse("keepalive = pong").is_err());
+    assert_eq!(Config::parse("").unwrap().sasl, None);
+    assert_eq!(Config::parse("sasl = external").unwrap().sasl, Some(SaslMechanism::External));
+    assert!(Config::parse("sasl = plain").is_err());
+    assert_eq!(Config::parse("").unwrap().bind_address, None);
+    assert_eq!(Config::parse("bind_address = 192.0.2.7").unwrap().bind_address,
+               Some(IpAddr::from([192, 0, 2, 7])));
+    assert_eq!(Config::parse("bind_address = ::1").unwrap().bind_address,
+               Some("::1".parse().unwrap()));
+    assert!(Config::parse("bind_address = irc.example.com").is_err());
+    assert!(Config::parse("reply_threads = on").unwrap().reply_threads);
+    assert_eq!(Config::parse("denied_keys = Foo::Bar, baz").unwrap().denied_keys,
+               vec!["Foo::Bar", "baz"]);
+    assert_eq!(Config::parse("sandbox_channels = #Demo, #test").unwrap().sandbox_channels,
+               vec!["#demo", "#test"]);
+
+    let config = Config::parse("schedule = 1h #chan Top karma: {top}\n\
+                                schedule = 30m #other Hi").unwrap();
+    assert_eq!(config.schedules, vec![
+        Schedule { interval: Duration::from_secs(60 * 60), target: "#chan".to_string(),
+                   template: "Top karma: {top}".to_string() },
+        Schedule { interval: Duration::from_secs(30 * 60), target: "#other".to_string(),
+                   template: "Hi".to_string() },
+    ]);
+    assert!(Config::parse("schedule = 1h #chan").is_err());
+    let config = Config::parse("quiet_hours = 22:00-07:30 UTC+2 #Sleepy, #late\n\
+                                quiet_hours = 12:00-13:00 UTC-5:30").unwrap();
+    assert_eq!(config.quiet_hours, vec![
+        QuietHours { start: 22 * 60, end: 7 * 60 + 30, utc_offset: 120,
+                     channels: vec!["#sleepy".to_string(), "#late".to_string()] },
+        QuietHours { start: 12 * 60, end: 13 * 60, utc_offset: -330, channels: Vec::new() },
+    ]);
+    assert_eq!(Config::parse("quiet_hours = 0:00-6:00 UTC").unwrap().quiet_hours[0].end, 360);
+    assert!(Config::parse("quiet_hours = 22:00-07:00").is_err());
+    assert!(Config::parse("quiet_hours = 22:00-24:00 UTC").is_err());
+    assert!(Config::parse("quiet_hours = 22:00-22:00 UTC").is_err());
+    assert!(Config::parse("quiet_hours = 22:00-7 UTC").is_err());
+    assert!(Config::parse("quiet_hours = 22:00-07:00 EST").is_err());
+    assert!(Config::parse("quiet_hours = 22:00-07:00 UTC+2x").is_err());
+    let config = Config::parse("query_prefix = !karma #One, #two\n\
+                                query_prefix = rot:").unwrap();
+    assert_eq!(config.query_prefix, "rot:");
+    assert_eq!(config.channel_query_prefixes.get("#one").map(String::as_str), Some("!karma"));
+    assert_eq!(config.channel_query_prefixes.get("#two").map(String::as_str), Some("!karma"));
+    assert_eq!(Config::default().query_prefix, "?");
+    assert!(Config::parse("query_prefix =").is_err());
+    assert!(Config::parse("schedule = 0s #chan Hi").is_err());
+    assert!(Config::parse("schedule = 1h #chan {bottom}").is_err());
+
+    assert_eq!(Config::parse("").unwrap().templates, Templates::default());
+    let config = Config::parse("query_template = {key} has {value} points (#{rank})").unwrap();
+    assert_eq!(config.templates.query, "{key} has {value} points (#{rank})");
+    assert!(Config::parse("change_template = {key} is now {vaule}").is_err());
+    assert!(!Config::parse("").unwrap().report_absent);
+    assert!(Config::parse("report_absent = true").unwrap().report_absent);
+    assert_eq!(Config::parse("").unwrap().private_query_channel, None);
+    assert_eq!(Config::parse("private_query_channel = #karma").unwrap().private_query_channel,
+               Some("#karma".to_string()));
+    assert_eq!(Config::parse("absent_template = I don't track '{key}'").unwrap().templates.absent,
+               "I don't track '{key}'");
+    assert!(Config::parse("absent_template = {key} = {value}").is_err());
+    assert_eq!(Config::parse("base_db = /srv/shared.db").unwrap().base_db,
+               Some("/srv/shared.db".to_string()));
+
+    let ban = Config::parse("").unwrap().ban;
+    assert!(ban.matches("Closing Link: host (K-Lined: go away)"));
+    assert!(!ban.matches("Closing Link: host (Ping timeout)"));
+    let ban = Config::parse("ban_patterns = Spammer, abuse\nban_backoff = 2h").unwrap().ban;
+    assert_eq!(ban.patterns, vec!["spammer", "abuse"]);
+    assert_eq!(ban.backoff, Some(Duration::from_secs(2 * 60 * 60)));
+    assert!(ban.matches("Closing Link: (SPAMMER)"));
+    assert!(!ban.matches("Closing Link: (K-Lined)"));
+    assert_eq!(Config::parse("ban_backoff = never").unwrap().ban.backoff, None);
+
+    assert_eq!(Config::parse("max_idle = 15m").unwrap().max_idle, Some(Duration::from_secs(15 * 60)));
+    assert!(Config::parse("max_idle = 0s").is_err());
+    assert_eq!(Config::parse("").unwrap().max_message_age, None);
+    assert_eq!(Config::parse("max_message_age = 10m").unwrap().max_message_age,
+               Some(Duration::from_secs(10 * 60)));
+    assert_eq!(Config::parse("").unwrap().recent_window, Duration::from_secs(60 * 60));
+    assert_eq!(Config::parse("recent_window = 1d").unwrap().recent_window, day);
+    assert_eq!(Config::parse("").unwrap().key_rate_limit, None);
+    assert_eq!(Config::parse("key_rate_limit = 10/1m").unwrap().key_rate_limit,
+               Some(RateLimit { count: 10, window: Duration::from_secs(60) }));
+    assert_eq!(Config::parse("key_rate_limit = 3 / 30s").unwrap().key_rate_limit,
+               Some(RateLimit { count: 3, window: Duration::from_secs(30) }));
+    assert!(Config::parse("key_rate_limit = 10").is_err());
+    assert_eq!(Config::parse("").unwrap().combo, None);
+    assert_eq!(Config::parse("combo = 3/2m 5").unwrap().combo,
+               Some(Combo { people: 3, window: Duration::from_secs(2 * 60), bonus: 5 }));
+    assert_eq!(Config::parse("combo = 2 / 30s  1").unwrap().combo,
+               Some(Combo { people: 2, window: Duration::from_secs(30), bonus: 1 }));
+    assert!(Config::parse("combo = 2/30s 0").is_err());
+    assert!(Config::parse("combo = 2/30s -1").is_err());
+    assert!(Config::parse("combo = 1/2m 5").is_err());
+    assert!(Config::parse("combo = 3/2m").is_err());
+    assert!(Config::parse("combo = 3/2m lots").is_err());
+    assert_eq!(Config::parse("").unwrap().prune, None);
+    assert_eq!(Config::parse("prune = 3 180d").unwrap().prune,
+               Some(Prune { min_abs: 3, age: Duration::from_secs(180 * 24 * 60 * 60) }));
+    assert!(Config::parse("prune = 3").is_err());
+    assert!(Config::parse("prune = few 180d").is_err());
+    assert!(Config::parse("prune = 3 soon").is_err());
+    assert!(Config::parse("key_rate_limit = 0/1m").is_err());
+    assert!(Config::parse("key_rate_limit = 10/0s").is_err());
+    assert!(Config::parse("key_rate_limit = lots/1m").is_err());
+    assert_eq!(Config::parse("save_debounce = 10s").unwrap().save_debounce,
+               Some(Duration::from_secs(10)));
+    assert_eq!(Config::parse("min_presence = 2h").unwrap().min_presence,
+               Some(Duration::from_secs(2 * 60 * 60)));
+    assert_eq!(Config::parse("join_notice = Try foo++ or ?foo").unwrap().join_notice,
+               Some("Try foo++ or ?foo".to_string()));
+    assert_eq!(Config::parse("").unwrap().max_db_lines, None);
+    assert_eq!(Config::parse("max_db_lines = 5000000").unwrap().max_db_lines, Some(5_000_000));
+    assert!(Config::parse("max_db_lines = lots").is_err());
+    assert_eq!(Config::parse("").unwrap().max_ops, 5);
+    assert_eq!(Config::parse("max_ops = 3").unwrap().max_ops, 3);
+    assert!(Config::parse("max_ops = 0").is_err());
+    assert!(!Config::parse("").unwrap().restrict_decrements);
+    assert!(Config::parse("restrict_decrements = on").unwrap().restrict_decrements);
+
+    assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+    assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(15 * 60)));
+    assert_eq!(parse_duration("3h"), Ok(Duration::from_secs(3 * 60 * 60)));
+    assert!(parse_duration("h").is_err());
+    assert!(parse_duration("-1s").is_err());
+}
+
+fn parse_template(value: &str, allowed: &[&str]) -> Result<String, String> {
+    template::validate(value, allowed)?;
+    Ok(value.to_string())
+}
+
+fn parse_rate_limit(value: &str) -> Result<RateLimit, String> {
+    let invalid = || format!("Invalid rate limit \"{}\"", value);
+    let (count, window) = value.split_once('/').ok_or_else(invalid)?;
+    let count = count.trim().parse::<usize>().map_err(|_| invalid())?;
+    let window = parse_duration(window.trim())?;
+    if count == 0 || window.as_secs() == 0 {
+        return Err(invalid());
+    }
+    Ok(RateLimit { count, window })
+}
+
+fn parse_combo(value: &str) -> Result<Combo, String> {
+    let invalid = || format!("Invalid combo \"{}\"", value);
+    let (limit, bonus) = value.trim().rsplit_once(char::is_whitespace).ok_or_else(invalid)?;
+    let RateLimit { count: people, window } = parse_rate_limit(limit)?;
+    if people < 2 {
+        return Err("A combo needs at least 2 people".to_string());
+    }
+    match bonus.parse::<i64>() {
+        Ok(bonus) if bonus > 0 => Ok(Combo { people, window, bonus }),
+        Ok(_) => Err("A combo bonus must be more than 0".to_string()),
+        Err(_) => Err(invalid()),
+    }
+}
+
+fn parse_prune(value: &str) -> Result<Prune, String> {
+    let invalid = || format!("Invalid prune \"{}\"", value);
+    let (min_abs, age) = value.trim().split_once(char::is_whitespace).ok_or_else(invalid)?;
+    let min_abs = min_abs.parse::<i64>().map_err(|_| invalid())?;
+    let age = parse_duration(age.trim())?;
+    Ok(Prune { min_abs, age })
+}
+
+/// `HH:MM` as minutes after midnight
+fn parse_time_of_day(text: &str) -> Option<u32> {
+    let (hours, minutes) = text.split_once(':')?;
+    if minutes.len() != 2 {
+        return None;
+    }
+    match (hours.parse::<u32>(), minutes.parse::<u32>()) {
+        (Ok(hours), Ok(minutes)) if hours < 24 && minutes < 60 => Some(hours * 60 + minutes),
+        _ => None,
+    }
+}
+
+/// `UTC`, `UTC+2` or `UTC-5:30` as minutes east of UTC
+fn parse_utc_offset(text: &str) -> Option<i32> {
+    let offset = text.strip_prefix("UTC")?;
+    if offset.is_empty() {
+        return Some(0);
+    }
+    let (sign, offset) = match offset.split_at(1) {
+        ("+", offset) => (1, offset),
+        ("-", offset) => (-1, offset),
+        _ => return None,
+    };
+    let (hours, minutes) = offset.split_once(':').unwrap_or((offset, "0"));
+    match (hours.parse::<i32>(), minutes.parse::<i32>()) {
+        (Ok(hours), Ok(minutes)) if hours <= 14 && minutes < 60 => {
+            Some(sign * (hours * 60 + minutes))
+        }
+        _ => None,
+    }
+}
+
+fn parse_quiet_hours(value: &str) -> Result<QuietHours, String> {
+    let invalid = || format!("Invalid quiet_hours \"{}\"", value);
+    let mut parts = value.split_whitespace();
+    let (window, offset) = match (parts.next(), parts.next()) {
+        (Some(window), Some(offset)) => (window, offset),
+        _ => return Err(invalid()),
+    };
+    let (start, end) = window.split_once('-').ok_or_else(invalid)?;
+    let start = parse_time_of_day(start).ok_or_else(invalid)?;
+    let end = parse_time_of_day(end).ok_or_else(invalid)?;
+    if start == end {
+        return Err(invalid());
+    }
+    let utc_offset = parse_utc_offset(offset).ok_or_else(invalid)?;
+    let channels = parts.flat_map(parse_list).map(|c| c.to_ascii_lowercase()).collect();
+    Ok(QuietHours { start, end, utc_offset, channels })
+}
+
+fn parse_schedule(value: &str) -> Result<Schedule, String> {
+    let mut parts = value.splitn(3, char::is_whitespace);
+    let (interval, target, template) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(interval), Some(target), Some(template)) if !template.trim().is_empty() => {
+            (interval, target, template.trim())
+        }
+        _ => return Err(format!("Invalid schedule \"{}\"", value)),
+    };
+    let interval = parse_duration(interval)?;
+    if interval.as_secs() == 0 {
+        return Err("Schedule interval must be non-zero".to_string());
+    }
+    let template = parse_template(template, &["top"])?;
+    Ok(Schedule { interval, target: target.to_string(), template })
+}
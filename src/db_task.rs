@@ -0,0 +1,120 @@
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::error::RotError;
+use crate::store::KarmaStore;
+
+/// A query for the db task, with where to send the answer
+enum DbRequest {
+    Get { key: String, reply: oneshot::Sender<Option<i64>> },
+    Rank { key: String, reply: oneshot::Sender<Option<(usize, usize)>> },
+    Top { count: usize, reply: oneshot::Sender<Vec<(String, i64)>> },
+}
+
+// Queries waiting for the db task before senders have to wait too
+const REQUEST_QUEUE: usize = 32;
+
+/// A cheap, cloneable handle for querying a store owned by its own task, so
+/// other tasks (such as a web or metrics server) never hold a lock across an
+/// await.  Each query is answered in the order it arrives.
+#[derive(Clone)]
+pub struct DbHandle {
+    requests: mpsc::Sender<DbRequest>,
+}
+
+impl DbHandle {
+    /// Move `db` into a new task which answers queries until every handle is
+    /// dropped, then hands the store back so it can be closed
+    pub fn spawn(db: Box<dyn KarmaStore>) -> (DbHandle, JoinHandle<Box<dyn KarmaStore>>) {
+        let (requests, receiver) = mpsc::channel(REQUEST_QUEUE);
+        (DbHandle { requests }, tokio::spawn(serve(db, receiver)))
+    }
+
+    /// The value of `key`, or None if it has never been stored
+    pub async fn get(&self, key: &str) -> Result<Option<i64>, RotError> {
+        self.request(|reply| DbRequest::Get { key: key.to_string(), reply }).await
+    }
+
+    /// The rank of `key` and the number of keys, as `KarmaStore::rank`
+    pub async fn rank(&self, key: &str) -> Result<Option<(usize, usize)>, RotError> {
+        self.request(|reply| DbRequest::Rank { key: key.to_string(), reply }).await
+    }
+
+    /// The `count` highest values, highest first
+    pub async fn top(&self, count: usize) -> Result<Vec<(String, i64)>, RotError> {
+        self.request(|reply| DbRequest::Top { count, reply }).await
+    }
+
+    async fn request<T>(&self, request: impl FnOnce(oneshot::Sender<T>) -> DbRequest)
+            -> Result<T, RotError> {
+        let (reply, response) = oneshot::channel();
+        self.requests.send(request(reply)).await.map_err(|_| RotError::DbClosed)?;
+        response.await.map_err(|_| RotError::DbClosed)
+    }
+}
+
+async fn serve(db: Box<dyn KarmaStore>, mut requests: mpsc::Receiver<DbRequest>)
+        -> Box<dyn KarmaStore> {
+    while let Some(request) = requests.recv().await {
+        // A query whose sender gave up waiting doesn't need an answer
+        match request {
+            DbRequest::Get { key, reply } => {
+                let _ = reply.send(db.get(&key));
+            }
+            DbRequest::Rank { key, reply } => {
+                let _ = reply.send(db.rank(&key));
+            }
+            DbRequest::Top { count, reply } => {
+                let _ = reply.send(db.top(count));
+            }
+        }
+    }
+    db
+}
+
+#[tokio::test]
+async fn test_concurrent_queries() {
+    use std::time::SystemTime;
+    use crate::store::MemoryStore;
+
+    let mut db = MemoryStore::default();
+    for (n, key) in ["a", "b", "c", "d"].iter().enumerate() {
+        db.add(key, n as i64, SystemTime::now());
+    }
+    let (handle, task) = DbHandle::spawn(Box::new(db));
+
+    let queries = (0..20).map(|n| {
+        let handle = handle.clone();
+        tokio::spawn(async move {
+            let key = ["a", "b", "c", "d", "missing"][n % 5];
+            (key, handle.get(key).await.unwrap(), handle.rank(key).await.unwrap())
+        })
+    }).collect::<Vec<_>>();
+    let (top, value) = tokio::join!(handle.top(2), handle.get("D"));
+    assert_eq!(top.unwrap(), vec![("d".to_string(), 3), ("c".to_string(), 2)]);
+    assert_eq!(value.unwrap(), Some(3));
+    for query in queries {
+        match query.await.unwrap() {
+            ("missing", value, rank) => assert_eq!((value, rank), (None, None)),
+            ("a", value, rank) => assert_eq!((value, rank), (Some(0), Some((4, 4)))),
+            ("d", value, rank) => assert_eq!((value, rank), (Some(3), Some((1, 4)))),
+            (_, value, rank) => assert!(value.is_some() && rank.is_some()),
+        }
+    }
+
+    // The store comes back once every handle is gone
+    let other = handle.clone();
+    drop(handle);
+    assert_eq!(other.get("b").await.unwrap(), Some(1));
+    drop(other);
+    let db = task.await.unwrap();
+    assert_eq!(db.get("c"), Some(2));
+}
+
+#[tokio::test]
+async fn test_stopped_task() {
+    let (handle, task) = DbHandle::spawn(Box::new(crate::store::MemoryStore::default()));
+    task.abort();
+    let _ = task.await;
+    assert!(matches!(handle.get("foo").await, Err(RotError::DbClosed)));
+}
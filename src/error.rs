@@ -0,0 +1,24 @@
+use std::io;
+
+use thiserror::Error;
+
+/// Errors from the db, the config and the connection to the server
+#[derive(Debug, Error)]
+pub enum RotError {
+    /// Reading or writing a file failed
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    /// A config file couldn't be understood
+    #[error("line {line}: {message}")]
+    Parse { line: usize, message: String },
+    /// The server's address can't be connected to, so there's no point
+    /// in trying again
+    #[error("Could not connect to {addr}: {source}")]
+    Connection { addr: String, source: io::Error },
+    /// The server told us to go away, such as by banning us
+    #[error("{0}")]
+    Protocol(String),
+    /// The task answering db queries has stopped
+    #[error("The db task has stopped")]
+    DbClosed,
+}
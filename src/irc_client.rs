@@ -1,42 +1,282 @@
-use crate::rotdb::RotDb;
-use crate::line_parse::{ParsedLine, parse_line};
+use crate::admin::{AdminCommand, parse_admin_command};
+use crate::config::{Config, Keepalive, SaslMechanism};
+use crate::error::RotError;
+use crate::rotdb::{RotDb, Contribution};
+use crate::store::KarmaStore;
+use crate::schedule::Scheduler;
+use crate::template::{self, Verbosity};
+use crate::line_parse::{ParsedLine, Thanks, is_space, parse_ops, parse_thanks,
+                        replace_query_prefix};
+
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::hash_map::RandomState;
+use std::fs::File;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufWriter};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use encoding_rs::{Encoding, UTF_8};
+use lazy_static::lazy_static;
+use regex::Regex;
 
 use tokio::time::{Duration, Instant};
 use tokio::sync::mpsc;
-use tokio::net::TcpStream;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The token an admin has to send back to go ahead with a command, and until
+/// when it's accepted
+struct Confirmation {
+    token: String,
+    expires: Instant,
+}
+
+/// Bytes read from the server which don't make up a whole line yet
+#[derive(Default)]
+struct LineBuffer {
+    partial: Vec<u8>,
+    // Whether the rest of the current line is dropped, after it grew longer
+    // than MAX_LINE_LENGTH
+    overflowed: bool,
+}
+
+impl LineBuffer {
+    /// Add `data` read from the server, returning the lines it completes
+    /// with their newlines.  A line is only dropped once it's longer than
+    /// MAX_LINE_LENGTH, no matter how many reads it arrives in.
+    fn extend(&mut self, mut data: &[u8]) -> Vec<u8> {
+        if self.overflowed {
+            match data.iter().position(|c| *c == b'\n') {
+                Some(pos) => {
+                    self.overflowed = false;
+                    data = &data[pos + 1..];
+                }
+                None => return Vec::new(),
+            }
+        }
+        self.partial.extend_from_slice(data);
+        let end = self.partial.iter().rposition(|c| *c == b'\n').map_or(0, |pos| pos + 1);
+        let complete = self.partial.drain(..end).collect();
+        if self.partial.len() > MAX_LINE_LENGTH {
+            eprintln!("Dropping a line longer than {} bytes", MAX_LINE_LENGTH);
+            self.partial = Vec::new();
+            self.overflowed = true;
+        }
+        complete
+    }
+}
 
-#[derive(PartialEq)]
+#[derive(Debug, PartialEq)]
 enum PingState {
     Reset,
     Waiting,
-    PingPending,
+    /// Waiting for a PONG with this token
+    PingPending(String),
+}
+
+/// Changes in the state of the connection to the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    Connected,
+    /// The server has accepted our registration
+    Registered,
+    Disconnected,
+    /// Waiting to connect again
+    Reconnecting,
+}
+
+/// A change to the value of a key, for integrations such as bridges and
+/// webhooks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KarmaEvent {
+    pub sender: String,
+    /// The key in its normalized form
+    pub key: String,
+    pub old_value: i64,
+    pub new_value: i64,
+    /// Where the change was made, or None for a private message
+    pub channel: Option<String>,
+}
+
+/// Anything the client can talk to the server over
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+pub type Connection = Box<dyn Transport>;
+pub type ConnectFuture = Pin<Box<dyn Future<Output = io::Result<Connection>> + Send>>;
+/// Opens a connection to the given remote address
+pub type Connector = Box<dyn FnMut(&str) -> ConnectFuture + Send>;
+
+/// The in-memory db of one of the `sandbox_channels`, layered over a copy
+/// of the real db
+struct Sandbox {
+    db: Box<dyn KarmaStore>,
+    // The real db's change count when it was copied, so it's only copied
+    // again once it has changed
+    base_changes: Option<u64>,
+}
+
+impl Sandbox {
+    fn new(config: &Config) -> Sandbox {
+        Sandbox { db: sandbox_db(config), base_changes: None }
+    }
+}
+
+/// What we know about the people in a channel, by lowercase nick
+#[derive(Default)]
+struct ChannelStatus {
+    ops: HashSet<String>,
+    voiced: HashSet<String>,
+    first_seen: HashMap<String, Instant>,
 }
 
 pub struct IrcClient {
-    db: RotDb,
+    db: Box<dyn KarmaStore>,
+    config: Config,
     remote_addr: String,
     nick: String,
+    // Channels to join, including the '#'
     channels: Vec<String>,
-    shutdown_recv: mpsc::Receiver<bool>,
+    // Keys for the channels that need one, by lowercase name
+    channel_keys: HashMap<String, String>,
+    // Channels given on the command line, by lowercase name, which a config
+    // reload never parts
+    arg_channels: HashSet<String>,
+    // Channels the server forwarded us to this session, by lowercase name,
+    // with the channel we asked to join.  The original is the one saved.
+    forwards: HashMap<String, String>,
+    db_filename: String,
+    // Where the channels we're in are saved, to rejoin them next time
+    channels_filename: String,
+    shutdown_recv: mpsc::Receiver<()>,
+    // Where the config was loaded from, to read it again on SIGHUP
+    config_filename: Option<String>,
+    reload_recv: mpsc::Receiver<()>,
     ping_state: PingState,
+    // How many PINGs we've sent, to make each token unique
+    pings: u64,
+    // Keyed by lowercase channel name
+    topics: HashMap<String, String>,
+    // Who has operator or voice status in each channel, by lowercase name
+    statuses: HashMap<String, ChannelStatus>,
+    // The services account each lowercase nick is logged in to, or None if
+    // they logged out, as far as account-notify has told us
+    accounts: HashMap<String, Option<String>>,
+    // Channels an admin has muted, by lowercase name, with when the mute
+    // ends if it isn't indefinite
+    muted: HashMap<String, Option<Instant>>,
+    // Destructive admin commands waiting for the admin to confirm them
+    confirmations: HashMap<(String, AdminCommand), Confirmation>,
+    // Lowercase names of the channels the join notice has been sent to
+    announced: HashSet<String>,
+    // Notices to send to the sender of the message being handled
+    notices: Vec<String>,
+    // Raw lines to send to the server after handling a message
+    outgoing: Vec<String>,
+    // The nick an admin asked us to change to, and who asked, until the
+    // server confirms or refuses it
+    pending_nick: Option<(String, String)>,
+    // Tokens from the server's RPL_ISUPPORT lines, such as NICKLEN
+    isupport: HashMap<String, String>,
+    // In-memory dbs for each of the sandbox channels, by lowercase name
+    sandboxes: HashMap<String, Sandbox>,
+    // When each key was recently changed, by normalized key, for the
+    // key_rate_limit
+    key_changes: HashMap<String, VecDeque<Instant>>,
+    // The latest increment of each key by each lowercase user@host, oldest
+    // first, toward a combo
+    combos: HashMap<String, VecDeque<(String, Instant)>>,
+    // The lowercase user@host of whoever sent the message being handled,
+    // which unlike their nick can't be changed at will
+    sender_host: String,
+    // Why the server killed or banned us, so the next reconnect waits longer
+    banned: Option<String>,
+    // Capabilities the server has acknowledged on this connection, and how
+    // many of our requests (including the CAP LS) it hasn't answered yet
+    caps: HashSet<String>,
+    caps_pending: usize,
+    // Whether capability negotiation is still open, so CAP END is only sent
+    // once per registration
+    negotiating: bool,
+    // Capabilities the server has listed so far in reply to CAP LS
+    available_caps: HashSet<String>,
+    // The end of the last read from the server, until the rest of the line
+    lines: LineBuffer,
+    // Whether we're waiting for the result of SASL authentication
+    sasl_pending: bool,
+    connector: Connector,
+    // Whether `connector` was set by `set_connector`, rather than being one
+    // that can't present a client certificate
+    custom_connector: bool,
+    events: Option<mpsc::Sender<ConnectionEvent>>,
+    karma_events: Option<mpsc::Sender<KarmaEvent>>,
+    started: Instant,
+    // When the current connection was made, if we're connected, and the
+    // same by the server's clock, once it has timestamped a message of its own
+    connected_at: Option<Instant>,
+    connected_time: Option<SystemTime>,
 }
 
 const DB_SAVE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+const DECAY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// How much earlier than the server's first timestamp a new message may be
+// stamped, since its own messages and relayed ones may be out of order
+const CLOCK_SKEW: Duration = Duration::from_secs(5);
 const PING_INTERVAL: Duration = Duration::from_secs(5 * 60);
 const TIMEOUT_DURATION: Duration = Duration::from_secs(60);
+// How long to wait for the server to close the connection after our QUIT
+const QUIT_TIMEOUT: Duration = Duration::from_secs(5);
+const RECONNECT_DELAY: Duration = Duration::from_secs(60);
+const CONTRIBUTION_LIMIT: usize = 5;
+const TOP_COUNT: usize = 5;
+// How many keys an `addall` may change without being confirmed
+const ADJUST_CONFIRM_LIMIT: usize = 10;
+// The longest line kept while waiting for the rest of it, which is room for
+// the 8191 bytes of tags IRCv3 allows and a 512 byte message
+const MAX_LINE_LENGTH: usize = 8191 + 512;
+// How long an admin has to confirm a destructive command
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+// How many keys' recent changes are kept before forgetting the stale ones
+const KEY_CHANGES_LIMIT: usize = 1024;
+// The most keys listed in reply to `?eq`
+const EQUAL_LIMIT: usize = 50;
+// The most keys listed in reply to `?recent`
+const RECENT_LIMIT: usize = 50;
+// The most keys listed in reply to `?find`
+const FIND_LIMIT: usize = 50;
+// The most other forms of a key listed in reply to `?key`
+const VARIANTS_LIMIT: usize = 3;
+// Leave room for the prefix the server adds when relaying our messages
+const MESSAGE_LIMIT: usize = 400;
+// The port used for an address without one.  There's no TLS support built
+// in, so it's always the plaintext port.
+const DEFAULT_PORT: u16 = 6667;
 
 macro_rules! connect_sock {
     ($self:ident, $reconnect:expr) => {
-        match $self.connect($reconnect).await {
+        match $self.connect($reconnect).await? {
             Some(sock) => sock,
-            None => return,
+            None => return Ok(()),
         }
     }
 }
 
 impl IrcClient {
-    pub fn new(filename: &str, remote_addr: &str, nick: &str) -> IrcClient {
+    /// Keep the karma in the flat file db `filename`
+    pub fn new(filename: &str, remote_addr: &str, nick: &str, config: Config) -> IrcClient {
+        let db = open_db(filename, &config);
+        IrcClient::with_store(Box::new(db), filename, remote_addr, nick, config)
+    }
+
+    /// Keep the karma in `db`.  Channels we're in and exports are still
+    /// saved as files named after `filename`.
+    pub fn with_store(db: Box<dyn KarmaStore>, filename: &str, remote_addr: &str,
+                      nick: &str, config: Config) -> IrcClient {
         let (shutdown_send, shutdown_recv) = mpsc::channel(1);
 
         tokio::spawn(async move {
@@ -46,204 +286,4697 @@ impl IrcClient {
                     eprintln!("Failed to wait for Ctrl+C signal: {}", err);
                 }
             }
-            let _ = shutdown_send.send(true).await;
+            let _ = shutdown_send.send(()).await;
+        });
+
+        let (reload_send, reload_recv) = mpsc::channel(1);
+        #[cfg(unix)]
+        tokio::spawn(async move {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut hangup = match signal(SignalKind::hangup()) {
+                Ok(hangup) => hangup,
+                Err(err) => {
+                    eprintln!("Failed to wait for SIGHUP: {}", err);
+                    return;
+                }
+            };
+            while hangup.recv().await.is_some() {
+                // A reload is already pending if the channel is full
+                let _ = reload_send.try_send(());
+            }
         });
+        #[cfg(not(unix))]
+        drop(reload_send);
+
+        IrcClient::build(db, filename, remote_addr, nick, config, shutdown_recv, reload_recv)
+    }
+
+    /// Like `with_store`, but without handling any signals, for an app that
+    /// handles its own.  `run` returns when something is sent on `shutdown`
+    /// or its sender is dropped.  The config is never reloaded.
+    pub fn without_signals(db: Box<dyn KarmaStore>, filename: &str, remote_addr: &str,
+                           nick: &str, config: Config,
+                           shutdown: mpsc::Receiver<()>) -> IrcClient {
+        let (_, reload_recv) = mpsc::channel(1);
+        IrcClient::build(db, filename, remote_addr, nick, config, shutdown, reload_recv)
+    }
 
-        IrcClient {
-            db: RotDb::new(filename),
+    fn build(mut db: Box<dyn KarmaStore>, filename: &str, remote_addr: &str, nick: &str,
+             config: Config, shutdown_recv: mpsc::Receiver<()>,
+             reload_recv: mpsc::Receiver<()>) -> IrcClient {
+        db.set_decay(config.decay);
+        db.set_floor(config.min_value);
+        db.set_max_keys(config.max_keys);
+        db.set_denied_keys(&config.denied_keys);
+        if config.history {
+            db.enable_history();
+        }
+        if config.user_totals {
+            db.enable_user_totals();
+        }
+        let sandboxes = config.sandbox_channels.iter().map(|channel| {
+            (channel.clone(), Sandbox::new(&config))
+        }).collect();
+
+        let channels = config.channels.clone();
+        let bind_address = config.bind_address;
+        let mut client = IrcClient {
+            db,
+            config,
             remote_addr: remote_addr.to_string(),
             nick: nick.to_string(),
             channels: Vec::new(),
+            channel_keys: HashMap::new(),
+            arg_channels: HashSet::new(),
+            forwards: HashMap::new(),
+            db_filename: filename.to_string(),
+            channels_filename: format!("{}.channels", filename),
             shutdown_recv,
+            config_filename: None,
+            reload_recv,
             ping_state: PingState::Reset,
+            pings: 0,
+            topics: HashMap::new(),
+            statuses: HashMap::new(),
+            accounts: HashMap::new(),
+            muted: HashMap::new(),
+            confirmations: HashMap::new(),
+            announced: HashSet::new(),
+            notices: Vec::new(),
+            outgoing: Vec::new(),
+            pending_nick: None,
+            isupport: HashMap::new(),
+            sandboxes,
+            key_changes: HashMap::new(),
+            combos: HashMap::new(),
+            sender_host: String::new(),
+            banned: None,
+            caps: HashSet::new(),
+            caps_pending: 0,
+            negotiating: false,
+            available_caps: HashSet::new(),
+            lines: LineBuffer::default(),
+            sasl_pending: false,
+            connector: Box::new(move |remote_addr| default_connect(remote_addr, bind_address)),
+            custom_connector: false,
+            events: None,
+            karma_events: None,
+            started: Instant::now(),
+            connected_at: None,
+            connected_time: None,
+        };
+        for channel in &channels {
+            client.join(channel);
+        }
+        client
+    }
+
+    /// Read the config from `filename` again on SIGHUP
+    pub fn set_config_file(&mut self, filename: &str) {
+        self.config_filename = Some(filename.to_string());
+    }
+
+    /// Send connection state changes to `events`.  Events are dropped if the
+    /// receiver falls behind.
+    pub fn set_events(&mut self, events: mpsc::Sender<ConnectionEvent>) {
+        self.events = Some(events);
+    }
+
+    /// Send every change made by `++`, `--` or a combo bonus to `events`,
+    /// except those in sandbox channels.  Admin commands aren't included.
+    /// Events are dropped if the receiver falls behind.
+    pub fn set_karma_events(&mut self, events: mpsc::Sender<KarmaEvent>) {
+        self.karma_events = Some(events);
+    }
+
+    /// Use `connector` instead of connecting to the server over TCP, or a
+    /// Unix socket for a `unix:/path` address
+    pub fn set_connector(&mut self, connector: Connector) {
+        self.connector = connector;
+        self.custom_connector = true;
+    }
+
+    fn disconnected(&mut self) {
+        self.connected_at = None;
+        self.connected_time = None;
+        self.emit(ConnectionEvent::Disconnected);
+    }
+
+    fn emit(&self, event: ConnectionEvent) {
+        if let Some(events) = &self.events {
+            let _ = events.try_send(event);
         }
     }
 
+    /// Join `channel` once connected.  The '#' may be left out, since it's
+    /// awkward to pass on the command line.
     pub fn join(&mut self, channel: &str) {
-        self.channels.push(channel.to_string());
+        let channel = self.channel_name(channel);
+        if !self.channels.iter().any(|chan| chan.eq_ignore_ascii_case(&channel)) {
+            self.channels.push(channel);
+        }
+    }
+
+    /// Join `channel` once connected, using `key` to get in.  The key is
+    /// not saved with the channels we're in.
+    pub fn join_with_key(&mut self, channel: &str, key: &str) {
+        let channel = self.channel_name(channel);
+        self.channel_keys.insert(channel.to_ascii_lowercase(), key.to_string());
+        self.join(&channel);
+    }
+
+    /// Join `channel`, given on the command line, once connected, using
+    /// `key` if there is one.  Unlike the config's channels, reloading the
+    /// config never parts it.
+    pub fn join_from_args(&mut self, channel: &str, key: Option<&str>) {
+        match key {
+            Some(key) => self.join_with_key(channel, key),
+            None => self.join(channel),
+        }
+        self.arg_channels.insert(self.channel_name(channel).to_ascii_lowercase());
+    }
+
+    /// The JOIN command for `channel`, with its key if it has one
+    fn join_command(&self, channel: &str) -> String {
+        match self.channel_keys.get(&channel.to_ascii_lowercase()) {
+            Some(key) => format!("JOIN {} {}", channel, key),
+            None => format!("JOIN {}", channel),
+        }
+    }
+
+    fn channel_name(&self, channel: &str) -> String {
+        if self.is_channel(channel) {
+            channel.to_string()
+        } else {
+            format!("#{}", channel)
+        }
+    }
+
+    /// Add the channels we were in last time to the ones to join
+    fn load_channels(&mut self) {
+        let saved = match std::fs::read_to_string(&self.channels_filename) {
+            Ok(saved) => saved,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return,
+            Err(err) => {
+                eprintln!("Could not read {}: {}", self.channels_filename, err);
+                return;
+            }
+        };
+        for channel in saved.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            self.join(channel);
+        }
+    }
+
+    fn save_channels(&self) {
+        let mut saved = self.channels.join("\n");
+        saved.push('\n');
+        if let Err(err) = std::fs::write(&self.channels_filename, saved) {
+            eprintln!("Could not write {}: {}", self.channels_filename, err);
+        }
+    }
+
+    /// Keep track of the channels we're in as the server confirms joins and
+    /// parts, so we can rejoin them later
+    fn joined(&mut self, channel: &str) {
+        if self.forwards.contains_key(&channel.to_ascii_lowercase()) {
+            return;
+        }
+        let count = self.channels.len();
+        self.join(channel);
+        if self.channels.len() != count {
+            self.save_channels();
+        }
+    }
+
+    fn left(&mut self, channel: &str) {
+        self.statuses.remove(&channel.to_ascii_lowercase());
+        let statuses = &self.statuses;
+        self.accounts.retain(|nick, _| {
+            statuses.values().any(|status| status.first_seen.contains_key(nick))
+        });
+        // Leaving the channel we were forwarded to leaves the one we asked for
+        if let Some(from) = self.forwards.remove(&channel.to_ascii_lowercase()) {
+            self.left(&from);
+        }
+        let count = self.channels.len();
+        self.channels.retain(|chan| !chan.eq_ignore_ascii_case(channel));
+        if self.channels.len() != count {
+            self.save_channels();
+        }
+    }
+
+    /// Remember that joining `from` put us in `to` instead.  `from` is still
+    /// the channel saved and joined on reconnect, but its key isn't sent
+    /// anywhere else.
+    fn forwarded(&mut self, from: &str, to: &str) {
+        self.forwards.insert(to.to_ascii_lowercase(), from.to_string());
+    }
+
+    /// Run until interrupted (or until there's no point trying to connect
+    /// again), then save the db
+    pub async fn run(mut self) -> Result<(), RotError> {
+        let result = self.run_loop().await;
+        match (result, self.db.close()) {
+            (Err(err), Err(save_err)) => {
+                eprintln!("Failed to save the zot db: {}", save_err);
+                Err(err)
+            }
+            (result, saved) => result.and(saved),
+        }
     }
 
-    pub async fn run(&mut self) {
+    async fn run_loop(&mut self) -> Result<(), RotError> {
         let mut save_timer = tokio::time::interval(DB_SAVE_INTERVAL);
         save_timer.tick().await;    // The first tick comes immediately
+        let mut decay_timer = tokio::time::interval(DECAY_INTERVAL);
+        let mut prune_timer = tokio::time::interval(PRUNE_INTERVAL);
+        let mut scheduler = Scheduler::new(self.config.schedules.clone());
+
+        self.load_channels();
+        if self.channels.is_empty() {
+            println!("No channels given, only answering private messages");
+        }
 
         let mut sock = connect_sock!(self, false);
 
         let ping_timer = tokio::time::sleep(PING_INTERVAL);
         tokio::pin!(ping_timer);
+        let idle_timer = tokio::time::sleep(PING_INTERVAL);
+        tokio::pin!(idle_timer);
+        let mut last_read = Instant::now();
+        // When unsaved changes were first made, and how many had been made
+        // when we last looked, for debouncing saves
+        let save_debounce = tokio::time::sleep(DB_SAVE_INTERVAL);
+        tokio::pin!(save_debounce);
+        let mut unsaved_since = None;
+        let mut seen_changes = self.db.changes();
 
-        let mut chunk = Vec::<u8>::new();
         let mut buf = [0; 1024];
         loop {
             if self.ping_state == PingState::Reset {
                 ping_timer.as_mut().reset(Instant::now() + PING_INTERVAL);
                 self.ping_state = PingState::Waiting;
             }
+            if let Some(debounce) = self.config.save_debounce {
+                if self.db.changes() != seen_changes {
+                    seen_changes = self.db.changes();
+                    let now = Instant::now();
+                    let since = *unsaved_since.get_or_insert(now);
+                    save_debounce.as_mut().reset((now + debounce).min(since + DB_SAVE_INTERVAL));
+                }
+            }
+            if let Some(max_idle) = self.config.max_idle {
+                let since = self.connected_at.map_or(last_read, |at| at.max(last_read));
+                idle_timer.as_mut().reset(since + max_idle);
+            }
 
             tokio::select! {
                 result = sock.read(&mut buf) => match result {
                     Ok(0) => {
                         eprintln!("Server closed the connection");
+                        self.disconnected();
                         sock = connect_sock!(self, true);
                     }
                     Ok(n) => {
-                        chunk.extend(&buf[0..n]);
-                        chunk = self.process_lines(&chunk, &mut sock).await;
+                        last_read = Instant::now();
+                        let complete = self.lines.extend(&buf[0..n]);
+                        self.process_lines(&complete, &mut sock).await;
                     }
+                    // The connection is still fine, so just read again
+                    Err(err) if is_transient(&err) => (),
                     Err(err) => {
                         eprintln!("Failed to read from server: {}", err);
+                        self.disconnected();
                         sock = connect_sock!(self, true);
                     }
                 },
                 _ = &mut ping_timer => match self.ping_state {
                     PingState::Reset => unreachable!(),
                     PingState::Waiting => {
-                        let _ = sock.write_all(b"PING :rot\r\n").await;
-                        self.ping_state = PingState::PingPending;
+                        if let Some(line) = self.keepalive() {
+                            let _ = self.send(&mut sock, &line).await;
+                        }
+                        // Only waits this long if the keepalive needs an answer
                         ping_timer.as_mut().reset(Instant::now() + TIMEOUT_DURATION);
                     }
-                    PingState::PingPending => {
+                    PingState::PingPending(_) => {
                         eprintln!("No PING response from server");
+                        self.disconnected();
                         sock = connect_sock!(self, true);
                     }
                 },
-                _ = save_timer.tick() => self.db.sync(),
+                _ = &mut idle_timer, if self.config.max_idle.is_some() => {
+                    eprintln!("Nothing received from the server for {} sec",
+                              self.config.max_idle.unwrap_or_default().as_secs());
+                    let _ = sock.shutdown().await;
+                    self.disconnected();
+                    sock = connect_sock!(self, true);
+                }
+                _ = save_timer.tick() => {
+                    self.db.sync();
+                    unsaved_since = None;
+                }
+                _ = &mut save_debounce, if unsaved_since.is_some() => {
+                    self.db.sync();
+                    unsaved_since = None;
+                }
+                _ = decay_timer.tick(), if self.config.decay.is_some() => {
+                    self.db.apply_decay(SystemTime::now());
+                }
+                _ = prune_timer.tick(), if self.config.prune.is_some() => {
+                    if let Some(prune) = self.config.prune {
+                        self.prune(prune.min_abs, prune.age);
+                    }
+                }
+                due = scheduler.wait(), if !scheduler.is_empty() => {
+                    for schedule in due {
+                        if self.is_quiet(&schedule.target, SystemTime::now()) {
+                            continue;
+                        }
+                        for message in self.render_announcement(&schedule.template) {
+                            let _ = self.send_privmsg(&mut sock, &schedule.target, &message).await;
+                        }
+                    }
+                }
+                Some(()) = self.reload_recv.recv() => {
+                    if let Some(config) = self.reload_config() {
+                        scheduler = Scheduler::new(config.schedules.clone());
+                        for line in self.apply_config(config) {
+                            let _ = self.send(&mut sock, &line).await;
+                        }
+                    }
+                }
                 _ = self.shutdown_recv.recv() => break,
             }
         }
 
         // Still connected, so try to perform a graceful departure
-        let _ = sock.write_all(b"QUIT :--rot!\r\n").await;
+        self.quit(&mut sock).await;
+        self.disconnected();
+        Ok(())
+    }
+
+    /// Send QUIT and give the server a moment to close the connection, so
+    /// we're seen to leave rather than to drop off
+    async fn quit<S>(&self, sock: &mut S)
+        where S: AsyncRead + AsyncWrite + Unpin
+    {
+        if self.send(sock, "QUIT :--rot!").await.is_err() || sock.flush().await.is_err() {
+            return;
+        }
+        // Only our side is closed, so the server can still answer
+        let _ = sock.shutdown().await;
+        let mut buf = [0; 1024];
+        let _ = tokio::time::timeout(QUIT_TIMEOUT, async {
+            while let Ok(n) = sock.read(&mut buf).await {
+                if n == 0 {
+                    break;
+                }
+            }
+        }).await;
+    }
+
+    fn reload_config(&self) -> Option<Config> {
+        let filename = match &self.config_filename {
+            Some(filename) => filename,
+            None => {
+                eprintln!("Ignoring SIGHUP, since no config file was given");
+                return None;
+            }
+        };
+        match Config::load(filename) {
+            Ok(config) => {
+                eprintln!("Reloaded {}", filename);
+                Some(config)
+            }
+            Err(err) => {
+                eprintln!("Could not reload {}, keeping the old config: {}", filename, err);
+                None
+            }
+        }
+    }
+
+    /// Switch to a reloaded `config`, returning the JOINs and PARTs needed
+    /// for the channels it adds or removes.  See `Config` for the settings
+    /// which can't change this way.
+    fn apply_config(&mut self, config: Config) -> Vec<String> {
+        // The db's keys were normalized with this, so keep it until
+        // restarted, for new sandboxes too
+        let config = Config { key_mode: self.config.key_mode, ..config };
+        let mut lines = Vec::new();
+        let old_channels: Vec<String> = self.config.channels.iter()
+                .map(|channel| self.channel_name(channel)).collect();
+        let new_channels: Vec<String> = config.channels.iter()
+                .map(|channel| self.channel_name(channel)).collect();
+        let contains = |channels: &[String], channel: &str| {
+            channels.iter().any(|chan| chan.eq_ignore_ascii_case(channel))
+        };
+        for channel in &old_channels {
+            if !contains(&new_channels, channel) && contains(&self.channels, channel)
+                    && !self.arg_channels.contains(&channel.to_ascii_lowercase()) {
+                self.left(channel);
+                lines.push(format!("PART {}", channel));
+            }
+        }
+        for channel in &new_channels {
+            if !contains(&self.channels, channel) {
+                self.join(channel);
+                lines.push(self.join_command(channel));
+            }
+        }
+
+        self.sandboxes.retain(|channel, _| config.sandbox_channels.contains(channel));
+        for channel in &config.sandbox_channels {
+            if !self.sandboxes.contains_key(channel) {
+                self.sandboxes.insert(channel.clone(), Sandbox::new(&config));
+            }
+        }
+
+        self.db.set_decay(config.decay);
+        self.db.set_floor(config.min_value);
+        self.db.set_max_keys(config.max_keys);
+        self.db.set_denied_keys(&config.denied_keys);
+        for sandbox in self.sandboxes.values_mut() {
+            sandbox.db.set_floor(config.min_value);
+            sandbox.db.set_max_keys(config.max_keys);
+            sandbox.db.set_denied_keys(&config.denied_keys);
+        }
+        if config.history {
+            self.db.enable_history();
+        }
+        if config.user_totals {
+            self.db.enable_user_totals();
+        }
+
+        // The db was loaded with these, so keep them until restarted
+        let base_db = std::mem::take(&mut self.config.base_db);
+        let max_db_lines = self.config.max_db_lines;
+        let history = self.config.history || config.history;
+        let user_totals = self.config.user_totals || config.user_totals;
+        self.config = Config { base_db, max_db_lines, history, user_totals, ..config };
+        lines
     }
 
-    async fn process_lines(&mut self, mut chunk: &[u8], sock: &mut TcpStream) -> Vec<u8> {
+    /// Handle each line in `chunk`, which only holds whole lines, as handed
+    /// out by the line buffer
+    async fn process_lines<W>(&mut self, mut chunk: &[u8], sock: &mut W)
+        where W: AsyncWrite + Unpin
+    {
         while let Some(pos) = chunk.iter().position(|c| *c == b'\n') {
-            let parts = irc_split(&chunk[0..pos]);
+            let line = decode_line(&chunk[0..pos], self.config.encoding);
+            let line = line.strip_suffix('\r').unwrap_or(&line);
+            let (tags, line) = split_tags(line);
+            // Prefer the server's timestamp, which is more accurate for
+            // messages replayed from a buffer
+            let server_time = tags.and_then(|tags| tag_value(tags, "time"))
+                                  .and_then(parse_server_time);
+            let time = server_time.unwrap_or_else(SystemTime::now);
+            let parts = irc_split(line.as_bytes());
             chunk = &chunk[pos + 1..];
+            // The server's own messages are never replayed, so the first one
+            // it timestamps says when we connected by its clock, however far
+            // that is from ours
+            if self.connected_at.is_some() && self.connected_time.is_none()
+                    && !parts.first().is_some_and(|prefix| prefix.contains('!')) {
+                self.connected_time = server_time;
+            }
 
-            if parts.len() >= 2 && parts[0] == "PING" {
-                let _ = sock.write_all(format!("PONG {}\r\n", parts[1]).as_bytes()).await;
-            } else if parts.len() >= 2 && parts[1] == "PONG" {
-                // The timer itself will be reset by the event loop.
-                self.ping_state = PingState::Reset;
-            } else if parts.len() >= 4 && parts[1] == "PRIVMSG" {
-                let sender = match parts[0].split('!').next() {
-                    Some(name) => trim_marker(name),
-                    None => trim_marker(&parts[0]),
+            if parts.len() >= 2 && parts[0] == "ERROR" {
+                let error = trim_marker(&parts[1]);
+                eprintln!("Server error: {}", error);
+                if self.config.ban.matches(error) {
+                    self.banned = Some(error.to_string());
+                }
+            } else if parts.len() >= 3 && parts[1] == "KILL"
+                    && trim_marker(&parts[2]).eq_ignore_ascii_case(&self.nick) {
+                let reason = format!("Killed by {}: {}", trim_marker(&parts[0]),
+                                     parts.get(3).map_or("", |reason| trim_marker(reason)));
+                eprintln!("{}", reason);
+                self.banned = Some(reason);
+            } else if parts.len() >= 2 && parts[0] == "PING" {
+                let _ = self.send(sock, &format!("PONG {}", parts[1])).await;
+            } else if parts.len() >= 5 && parts[1] == "CAP" && parts[3] == "LS" {
+                // A long list is split over several lines, all but the last
+                // with a `*` before it.  Version 302 adds values to some,
+                // like `sasl=EXTERNAL,PLAIN`.
+                let more = parts.len() >= 6 && parts[4] == "*";
+                let listed = trim_marker(&parts[if more { 5 } else { 4 }]);
+                self.available_caps.extend(listed.split_whitespace().map(|cap| {
+                    cap.split('=').next().unwrap_or(cap).to_string()
+                }));
+                if !more {
+                    self.request_caps(sock).await;
+                }
+            } else if parts.len() >= 4 && parts[1] == "CAP"
+                    && (parts[3] == "ACK" || parts[3] == "NAK") {
+                if parts[3] == "ACK" {
+                    let acked = parts.get(4).map_or("", |caps| trim_marker(caps));
+                    for cap in acked.split_whitespace() {
+                        match cap.strip_prefix('-') {
+                            Some(cap) => self.caps.remove(cap),
+                            None => self.caps.insert(cap.to_string()),
+                        };
+                    }
+                }
+                if parts[3] == "ACK" && self.config.sasl.is_some() && self.caps.contains("sasl")
+                        && !self.sasl_pending {
+                    self.sasl_pending = true;
+                    let _ = self.send(sock, "AUTHENTICATE EXTERNAL").await;
+                }
+                self.caps_pending = self.caps_pending.saturating_sub(1);
+                self.end_negotiation(sock).await;
+            } else if parts.len() >= 2 && parts[0] == "AUTHENTICATE" && parts[1] == "+"
+                    && self.sasl_pending {
+                // EXTERNAL sends an empty response, to use the identity of
+                // the certificate
+                let _ = self.send(sock, "AUTHENTICATE +").await;
+            } else if parts.len() >= 2 && parts[1] == "908" {
+                // RPL_SASLMECHS, usually followed by a 904
+                eprintln!("The server only supports SASL {}",
+                          parts.get(3).map_or("", |mechs| trim_marker(mechs)));
+            } else if parts.len() >= 2 && SASL_RESULTS.contains(&parts[1].as_str()) {
+                if parts[1] == "903" {
+                    eprintln!("Authenticated with SASL EXTERNAL");
+                } else {
+                    eprintln!("SASL authentication failed: {}",
+                              parts.last().map_or("", |reason| trim_marker(reason)));
+                }
+                self.sasl_pending = false;
+                self.end_negotiation(sock).await;
+            } else if parts.len() >= 2 && parts[1] == "001" {
+                // RPL_WELCOME is addressed to the nick the server gave us,
+                // which may have been shortened from what we asked for
+                if let Some(nick) = parts.get(2).filter(|nick| **nick != self.nick) {
+                    eprintln!("The server calls us {}", nick);
+                    self.nick = nick.clone();
+                }
+                self.emit(ConnectionEvent::Registered);
+            } else if parts.len() >= 4 && parts[1] == "005" {
+                // RPL_ISUPPORT, with a trailing "are supported" message
+                for token in parts[3..].iter().filter(|token| !token.starts_with(':')) {
+                    self.set_isupport(token);
+                }
+            } else if parts.len() >= 2 && (parts[1] == "376" || parts[1] == "422") {
+                // End of (or no) MOTD, so registration is complete and the
+                // server's limits are known
+                self.join_channels(sock).await;
+            } else if parts.len() >= 4 && (parts[1] == "432" || parts[1] == "433")
+                    && self.pending_nick.as_ref().is_some_and(|(nick, _)| parts[3].eq_ignore_ascii_case(nick)) {
+                // ERR_ERRONEUSNICKNAME or ERR_NICKNAMEINUSE for a change an
+                // admin asked for, so keep the nick we have
+                if let Some((nick, admin)) = self.pending_nick.take() {
+                    let reason = parts.get(4).map_or("", |reason| trim_marker(reason));
+                    let reply = format!("Could not change nick to {} ({}), still {}",
+                                        nick, reason, self.nick);
+                    eprintln!("{}", reply);
+                    let _ = self.send_privmsg(sock, &admin, &reply).await;
+                }
+            } else if parts.len() >= 4 && parts[1] == "433"
+                    && parts[3].eq_ignore_ascii_case(&self.nick) {
+                // ERR_NICKNAMEINUSE
+                self.nick = alt_nick(&self.nick, self.nick_len());
+                eprintln!("Nick {} is in use, trying {}", parts[3], self.nick);
+                let _ = self.send(sock, &format!("NICK {}", self.nick)).await;
+            } else if parts.len() >= 5 && parts[1] == "470" {
+                // ERR_LINKCHANNEL, the join was forwarded to another channel
+                eprintln!("Forwarded from {} to {}", parts[3], parts[4]);
+                self.forwarded(&parts[3], &parts[4]);
+            } else if parts.len() >= 4 && JOIN_ERRORS.contains(&parts[1].as_str()) {
+                // Trying again won't help, so forget the channel rather than
+                // failing to rejoin it on every reconnect
+                eprintln!("Could not join {}: {}", parts[3],
+                          parts.get(4).map_or("", |reason| trim_marker(reason)));
+                self.left(&parts[3]);
+            } else if parts.len() >= 3 && parts[1] == "PONG" {
+                // Only the answer to our own PING counts, and the timer
+                // itself will be reset by the event loop.
+                let token = trim_marker(&parts[parts.len() - 1]);
+                if self.ping_state == PingState::PingPending(token.to_string()) {
+                    self.ping_state = PingState::Reset;
+                }
+            } else if parts.len() >= 5 && parts[1] == "332" {
+                // RPL_TOPIC, sent when joining a channel
+                self.set_topic(&parts[3], trim_marker(&parts[4]));
+            } else if parts.len() >= 4 && parts[1] == "331" {
+                // RPL_NOTOPIC
+                self.set_topic(&parts[3], "");
+            } else if parts.len() >= 3 && parts[1] == "JOIN" && self.is_me(&parts[0]) {
+                let channel = trim_marker(&parts[2]);
+                self.joined(channel);
+                if let Some(notice) = &self.config.join_notice {
+                    if self.announced.insert(channel.to_ascii_lowercase()) {
+                        let _ = self.send_notice(sock, channel, notice).await;
+                    }
+                }
+            } else if parts.len() >= 3 && parts[1] == "JOIN" {
+                self.seen(trim_marker(&parts[2]), prefix_nick(&parts[0]));
+            } else if parts.len() >= 3 && parts[1] == "PART" {
+                let channel = trim_marker(&parts[2]);
+                if self.is_me(&parts[0]) {
+                    self.left(channel);
+                } else {
+                    self.forget_member(Some(channel), prefix_nick(&parts[0]));
+                }
+            } else if parts.len() >= 4 && parts[1] == "KICK" {
+                if parts[3].eq_ignore_ascii_case(&self.nick) {
+                    eprintln!("Kicked from {} by {}", parts[2], prefix_nick(&parts[0]));
+                    self.left(&parts[2]);
+                } else {
+                    self.forget_member(Some(&parts[2]), &parts[3]);
+                }
+            } else if parts.len() >= 2 && parts[1] == "QUIT" {
+                self.forget_member(None, prefix_nick(&parts[0]));
+            } else if parts.len() >= 3 && parts[1] == "NICK" {
+                let new_nick = trim_marker(&parts[2]);
+                if self.is_me(&parts[0]) {
+                    self.nick = new_nick.to_string();
+                    self.pending_nick = None;
+                }
+                self.rename_member(prefix_nick(&parts[0]), new_nick);
+                let old_nick = prefix_nick(&parts[0]).to_ascii_lowercase();
+                if let Some(account) = self.accounts.remove(&old_nick) {
+                    self.accounts.insert(new_nick.to_ascii_lowercase(), account);
+                }
+            } else if parts.len() >= 3 && parts[1] == "ACCOUNT" {
+                // account-notify: the sender has logged in to an account,
+                // or out if it's `*`
+                let nick = prefix_nick(&parts[0]).to_ascii_lowercase();
+                match trim_marker(&parts[2]) {
+                    "*" => self.accounts.insert(nick, None),
+                    account => self.accounts.insert(nick, Some(account.to_string())),
                 };
-                let dest = {
-                    let name = trim_marker(&parts[2]);
-                    if name == self.nick {
-                        sender
-                    } else {
-                        name
+            } else if parts.len() >= 4 && parts[1] == "INVITE"
+                    && parts[2].eq_ignore_ascii_case(&self.nick) {
+                let channel = trim_marker(&parts[3]);
+                if self.is_channel(channel) {
+                    let _ = self.send(sock, &format!("JOIN {}", channel)).await;
+                }
+            } else if parts.len() >= 6 && parts[1] == "353" {
+                // RPL_NAMREPLY, listing who is in a channel
+                for name in trim_marker(&parts[5]).split_whitespace() {
+                    let nick = name.trim_start_matches(['~', '&', '@', '%', '+']);
+                    let prefixes = &name[..name.len() - nick.len()];
+                    self.set_status(&parts[4], nick, 'o', prefixes.contains(['~', '&', '@']));
+                    self.set_status(&parts[4], nick, 'v', prefixes.contains('+'));
+                    self.seen(&parts[4], nick);
+                }
+            } else if parts.len() >= 4 && parts[1] == "MODE" && self.is_channel(&parts[2]) {
+                let args: Vec<&str> = parts[4..].iter().map(|arg| trim_marker(arg)).collect();
+                for (mode, nick) in mode_changes(trim_marker(&parts[3]), &args) {
+                    let (sign, mode) = mode.split_at(1);
+                    if let Some(mode) = mode.chars().next() {
+                        self.set_status(&parts[2], nick, mode, sign == "+");
                     }
+                }
+            } else if parts.len() >= 4 && parts[1] == "TOPIC" {
+                self.set_topic(&parts[2], trim_marker(&parts[3]));
+            } else if parts.len() >= 2 && parts[1] == "PRIVMSG" && parts[0].contains('!')
+                    && !self.is_me(&parts[0]) {
+                // Only users have a nick!user@host prefix.  Anything else (as
+                // well as NOTICEs and WALLOPS) comes from the server or
+                // services, and shouldn't count as karma even if it looks
+                // like it.  Our own messages are skipped whether or not
+                // echo-message was negotiated, since a bouncer may send them
+                // back to us too, and they'd be taken as someone else
+                // repeating our replies.
+                let message = match message_body(&parts) {
+                    Some(message) => message,
+                    None => continue,
                 };
-                let message = trim_marker(&parts[3]);
-
-                let parsed = match parse_line(message) {
-                    ParsedLine::Nothing => None,
-                    ParsedLine::Increment(name) => Some((self.db.increment(&name), name)),
-                    ParsedLine::Decrement(name) => Some((self.db.decrement(&name), name)),
-                    ParsedLine::Query(name) => Some((self.db.value(&name), name)),
+                // A bouncer's backlog may have been counted already, and any
+                // answer would be out of date
+                if server_time.is_some_and(|time| self.is_replayed(time)) {
+                    continue;
+                }
+                let sender = prefix_nick(&parts[0]);
+                self.sender_host = trim_marker(&parts[0]).split_once('!')
+                                                        .map(|(_, host)| host.to_ascii_lowercase())
+                                                        .unwrap_or_default();
+                // Replies to channel messages go back to the channel.  Anything
+                // else was sent privately to us, so the reply goes to the sender;
+                // this is all the bot can respond to when it has no channels.
+                let channel = self.reply_channel(trim_marker(&parts[2]));
+                let private = channel.is_none();
+                let dest = channel.unwrap_or(sender);
+                if !private {
+                    self.seen(dest, sender);
+                }
+                let mut replies = self.handle_message(sender, dest, private, &message, time);
+                let reply_dest = match private {
+                    true => self.private_reply_dest(sender, &message).to_string(),
+                    false => dest.to_string(),
                 };
-
-                if let Some((value, name)) = parsed {
-                    let _ = sock.write_all(
-                                format!("PRIVMSG {} :{} = {}\r\n", dest, name, value).as_bytes()
-                            ).await;
+                if self.is_channel(&reply_dest) && self.is_quiet(&reply_dest, time) {
+                    replies.clear();
+                }
+                // A private message can't be threaded onto in a channel
+                let msgid = tags.and_then(|tags| tag_value(tags, "msgid"))
+                                .filter(|_| reply_dest == dest);
+                for reply in replies {
+                    let _ = self.send_reply(sock, &reply_dest, &reply, msgid).await;
+                }
+                for notice in std::mem::take(&mut self.notices) {
+                    let _ = self.send_notice(sock, sender, &notice).await;
+                }
+                for line in std::mem::take(&mut self.outgoing) {
+                    let _ = self.send(sock, &line).await;
                 }
             }
         }
-        // Return the remainder for the next call
-        chunk.to_owned()
     }
 
-    async fn reconnect_delay(&mut self) -> bool {
-        eprintln!("Retrying in 60 sec...");
-        tokio::select! {
-            _ = tokio::time::sleep(Duration::from_secs(60)) => true,
-            _ = self.shutdown_recv.recv() => false,
+    /// Where the replies to a private `message` from `sender` go: the
+    /// `private_query_channel` if the message is only queries and the sender
+    /// is in the channel, or else back to the sender
+    fn private_reply_dest<'a>(&'a self, sender: &'a str, message: &str) -> &'a str {
+        let channel = match &self.config.private_query_channel {
+            Some(channel) if self.is_member(channel, sender) => channel,
+            _ => return sender,
+        };
+        let ops = parse_ops(&replace_query_prefix(message, &self.config.query_prefix));
+        let queries = !ops.is_empty() && ops.iter().all(|op| {
+            matches!(op, ParsedLine::Query(_) | ParsedLine::RankedQuery(_))
+        });
+        if queries { channel } else { sender }
+    }
+
+    /// Whether a message the server says was sent at `time` is from before
+    /// we connected, or older than `max_message_age`
+    fn is_replayed(&self, time: SystemTime) -> bool {
+        let age = SystemTime::now().duration_since(time).unwrap_or_default();
+        self.connected_time.is_some_and(|connected| time + CLOCK_SKEW < connected)
+            || self.config.max_message_age.is_some_and(|max_age| age > max_age)
+    }
+
+    /// Whether replies to `channel` are left out at `time`
+    fn is_quiet(&self, channel: &str, time: SystemTime) -> bool {
+        self.is_muted(channel)
+            || self.config.quiet_hours.iter().any(|quiet| quiet.contains(channel, time))
+    }
+
+    fn is_muted(&self, channel: &str) -> bool {
+        match self.muted.get(&channel.to_ascii_lowercase()) {
+            Some(Some(until)) => Instant::now() < *until,
+            Some(None) => true,
+            None => false,
         }
     }
 
-    async fn connect(&mut self, initial_delay: bool) -> Option<TcpStream> {
-        if initial_delay && !self.reconnect_delay().await {
-            return None;
+    /// The capabilities we'd use: message timestamps, message tags if
+    /// replies are threaded, account changes if there are admins, and SASL
+    /// if we're authenticating
+    fn wanted_caps(&self) -> Vec<&'static str> {
+        let mut caps = vec!["server-time"];
+        if self.config.reply_threads {
+            caps.push("message-tags");
+        }
+        if !self.config.admins.is_empty() {
+            caps.push("account-notify");
         }
+        if self.config.sasl.is_some() {
+            caps.push("sasl");
+        }
+        caps
+    }
 
-        let mut sock = loop {
-            let connect_fut = TcpStream::connect(&self.remote_addr);
-            match tokio::time::timeout(TIMEOUT_DURATION, connect_fut).await {
-                Ok(Ok(sock)) => break sock,
-                Ok(Err(err)) => {
-                    eprintln!("Failed to connect to {}: {}", self.remote_addr, err);
-                }
-                Err(_) => eprintln!("Connection timed out"),
-            };
+    /// Once the server has listed its capabilities, ask for the ones we want.
+    /// They're requested separately so a NAK for one doesn't refuse the rest.
+    async fn request_caps<W>(&mut self, sock: &mut W)
+        where W: AsyncWrite + Unpin
+    {
+        let caps: Vec<&str> = self.wanted_caps().into_iter()
+                                  .filter(|cap| self.available_caps.contains(*cap))
+                                  .collect();
+        self.caps_pending = caps.len();
+        for cap in caps {
+            let _ = self.send(sock, &format!("CAP REQ :{}", cap)).await;
+        }
+        self.end_negotiation(sock).await;
+    }
 
-            if !self.reconnect_delay().await {
-                return None;
-            }
-        };
+    /// Finish capability negotiation once every request has been answered
+    /// and any SASL exchange is over.  Replies after that, like a cap-notify
+    /// ACK, leave it closed.
+    async fn end_negotiation<W>(&mut self, sock: &mut W)
+        where W: AsyncWrite + Unpin
+    {
+        if self.negotiating && self.caps_pending == 0 && !self.sasl_pending {
+            self.negotiating = false;
+            let _ = self.send(sock, "CAP END").await;
+        }
+    }
 
-        let peer_name = match sock.peer_addr() {
-            Ok(addr) => addr.to_string(),
-            Err(_) => "<unknown>".to_string(),
-        };
-        println!("Connected to {}", peer_name);
+    /// The line to send when the connection has been quiet, if any.  A PING
+    /// must be answered, but any other keepalive just restarts the wait.
+    fn keepalive(&mut self) -> Option<String> {
+        match self.config.keepalive {
+            Keepalive::Ping => {
+                let token = self.ping_token();
+                self.ping_state = PingState::PingPending(token.clone());
+                Some(format!("PING :{}", token))
+            }
+            Keepalive::Notice => {
+                self.ping_state = PingState::Reset;
+                Some(format!("NOTICE {} :keepalive", self.nick))
+            }
+            Keepalive::Off => {
+                self.ping_state = PingState::Reset;
+                None
+            }
+        }
+    }
 
-        // Minimal identification necessary to satisfy the IRC server
-        let _ = sock.write_all(
-                    format!("NICK {0}\r\n\
-                             USER {0} . . :{0}\r\n", self.nick).as_bytes()
-                ).await;
+    /// A new token for our next PING, which the PONG must echo
+    fn ping_token(&mut self) -> String {
+        self.pings += 1;
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+                                     .map_or(0, |elapsed| elapsed.subsec_nanos());
+        format!("rot{}.{:x}", self.pings, nanos)
+    }
 
-        // Join the requested IRC channel(s)
-        for chan in &self.channels {
-            let _ = sock.write_all(format!("JOIN #{}\r\n", chan).as_bytes()).await;
+    fn set_isupport(&mut self, token: &str) {
+        if let Some(name) = token.strip_prefix('-') {
+            self.isupport.remove(name);
+        } else {
+            let (name, value) = token.split_once('=').unwrap_or((token, ""));
+            self.isupport.insert(name.to_string(), value.to_string());
         }
+    }
 
-        // Signal reset of the ping timer
-        self.ping_state = PingState::Reset;
+    fn nick_len(&self) -> Option<usize> {
+        self.isupport.get("NICKLEN").and_then(|len| len.parse().ok())
+    }
 
-        // If we lost the connection during the writes above, we'll catch it
-        // when we try to read from the socket in the main loop.
-        Some(sock)
+    /// How many channels of `chantype` we may join, if the server says.  The
+    /// limit is shared by all the types listed with it (e.g. `#&:20`), which
+    /// are returned along with it.
+    fn chan_limit(&self, chantype: char) -> Option<(&str, usize)> {
+        self.isupport.get("CHANLIMIT")?.split(',').find_map(|limit| {
+            let (types, count) = limit.split_once(':')?;
+            if types.contains(chantype) { Some((types, count.parse().ok()?)) } else { None }
+        })
     }
-}
 
-fn irc_split(mut line: &[u8]) -> Vec<String> {
-    let mut parts = vec![];
-    let mut scan = 0;
+    /// Whether `prefix` (a nick!user@host) is us
+    fn is_me(&self, prefix: &str) -> bool {
+        prefix_nick(prefix).eq_ignore_ascii_case(&self.nick)
+    }
 
-    while scan < line.len() {
-        if line[scan].is_ascii_whitespace() {
-            parts.push(String::from_utf8_lossy(&line[0..scan]).to_string());
-            while scan < line.len() && line[scan].is_ascii_whitespace() {
-                scan += 1;
-            }
-            line = &line[scan..];
-            scan = 0;
-            if line.starts_with(b":") {
-                parts.push(String::from_utf8_lossy(line).to_string());
-                break;
+    fn is_channel(&self, target: &str) -> bool {
+        let chantypes = self.isupport.get("CHANTYPES").map_or("#&+!", String::as_str);
+        target.starts_with(|c| chantypes.contains(c))
+    }
+
+    /// The channel to answer a message sent to `targets`, or None if it was
+    /// only sent to us.  A message can be sent to several targets at once
+    /// (`#a,#b`), but it's only handled once, for the first channel we're
+    /// in (or just the first channel, if we're in none of them), so that
+    /// karma isn't counted twice.
+    fn reply_channel<'a>(&self, targets: &'a str) -> Option<&'a str> {
+        let mut channels = targets.split(',').map(trim_marker)
+                                  .filter(|target| self.is_channel(target)).peekable();
+        let first = channels.peek().copied();
+        channels.find(|target| self.channels.iter().any(|chan| chan.eq_ignore_ascii_case(target)))
+                .or(first)
+    }
+
+    async fn join_channels<W>(&self, sock: &mut W)
+        where W: AsyncWrite + Unpin
+    {
+        let mut joined: HashMap<&str, usize> = HashMap::new();
+        for chan in &self.channels {
+            if let Some((types, limit)) = chan.chars().next().and_then(|c| self.chan_limit(c)) {
+                let count = joined.entry(types).or_insert(0);
+                if *count >= limit {
+                    eprintln!("The server only allows joining {} {} channels, skipping {}",
+                              limit, types, chan);
+                    continue;
+                }
+                *count += 1;
             }
-        } else {
-            scan += 1;
+            let _ = self.send(sock, &self.join_command(chan)).await;
         }
     }
-    if scan != 0 {
-        parts.push(String::from_utf8_lossy(line).to_string());
+
+    /// The prefix queries in `dest` are written with, such as the `?` of
+    /// `?foo`
+    fn query_prefix(&self, dest: &str, private: bool) -> &str {
+        if private {
+            return &self.config.query_prefix;
+        }
+        self.config.channel_query_prefixes.get(&dest.to_ascii_lowercase())
+            .unwrap_or(&self.config.query_prefix)
     }
 
-    parts
-}
+    /// Handle a message sent to `dest`, which is either a channel or (when
+    /// `private` is set) the sender.  Returns the replies to send back.
+    fn handle_message(&mut self, sender: &str, dest: &str, private: bool,
+                      mut message: &str, time: SystemTime) -> Vec<String> {
+        if private && self.is_admin(sender) {
+            if let Some(command) = parse_admin_command(message) {
+                return vec![self.admin_command(sender, command)];
+            }
+        }
 
-fn trim_marker(msg: &str) -> &str {
-    if let Some(stripped) = msg.strip_prefix(':') {
-        stripped
-    } else {
-        msg
+        // Private messages are always addressed to us
+        if self.config.addressed_only && !private {
+            match strip_address(message, &self.nick) {
+                Some(stripped) => message = stripped,
+                None => return vec![],
+            }
+        }
+
+        let message = replace_query_prefix(message, self.query_prefix(dest, private));
+        let mut ops = parse_ops(&message);
+        if ops.is_empty() {
+            match parse_thanks(&message, &self.config.thanks) {
+                Some(Thanks::Key(key)) => ops.push(ParsedLine::Increment(key)),
+                Some(Thanks::Nick(nick)) if !private && self.is_member(dest, &nick) => {
+                    ops.push(ParsedLine::Increment(nick));
+                }
+                _ => (),
+            }
+        }
+
+        // Operations in a sandbox channel see the real db, but their changes
+        // go to the channel's own in-memory db instead
+        let channel = dest.to_ascii_lowercase();
+        match self.sandboxes.remove(&channel) {
+            Some(mut sandbox) => {
+                let changes = self.db.changes();
+                if sandbox.base_changes != Some(changes) {
+                    sandbox.db.rebase(self.db.as_ref());
+                    sandbox.base_changes = Some(changes);
+                }
+                // Only the boxes are swapped, not the dbs themselves
+                std::mem::swap(&mut self.db, &mut sandbox.db);
+                let replies = self.handle_ops(ops, sender, dest, private, time);
+                std::mem::swap(&mut self.db, &mut sandbox.db);
+                self.sandboxes.insert(channel, sandbox);
+                replies
+            }
+            None => self.handle_ops(ops, sender, dest, private, time),
+        }
     }
+
+    fn handle_ops(&mut self, mut ops: Vec<ParsedLine>, sender: &str, dest: &str,
+                  private: bool, time: SystemTime) -> Vec<String> {
+        let ignored = ops.len().saturating_sub(self.config.max_ops);
+        ops.truncate(self.config.max_ops);
+        if ops.len() == 1 && ignored == 0 {
+            return self.handle_op(ops.remove(0), sender, dest, private, time);
+        }
+
+        let mut replies: Vec<String> = ops.into_iter()
+            .flat_map(|op| self.handle_op(op, sender, dest, private, time))
+            .collect();
+        if ignored > 0 && self.config.warn_max_ops {
+            replies.push(format!("ignored {} more (limit is {})", ignored, self.config.max_ops));
+        }
+        flow_entries("", &replies, MESSAGE_LIMIT)
+    }
+
+    fn handle_op(&mut self, op: ParsedLine, sender: &str, dest: &str,
+                 private: bool, time: SystemTime) -> Vec<String> {
+        match op {
+            ParsedLine::Nothing => vec![],
+            ParsedLine::Increment(ref name) | ParsedLine::Decrement(ref name)
+                    | ParsedLine::Query(ref name) | ParsedLine::RankedQuery(ref name)
+                    | ParsedLine::WhoDid(ref name) if self.is_denied(name) => {
+                self.denied()
+            }
+            ParsedLine::Diff(ref a, ref b) if self.is_denied(a) || self.is_denied(b) => {
+                self.denied()
+            }
+            ParsedLine::Increment(_) | ParsedLine::Decrement(_)
+                    if !self.is_established(sender, dest, private) => vec![],
+            ParsedLine::Decrement(name) if !self.may_decrement(sender, dest, private) => {
+                if self.config.warn_restricted {
+                    self.notices.push(format!("Only channel operators can decrement {}", name));
+                }
+                vec![]
+            }
+            ParsedLine::Increment(name) | ParsedLine::Decrement(name)
+                    if self.is_rate_limited(&name) => vec![],
+            ParsedLine::Increment(name) | ParsedLine::Decrement(name)
+                    if self.db.at_key_limit(&name) => {
+                self.notices.push(format!("Not tracking any new keys, so {} can't be changed",
+                                          name));
+                vec![]
+            }
+            ParsedLine::Increment(name) => {
+                let reply = self.apply_change(sender, &name, 1, dest, private, time);
+                let combo = self.check_combo(sender, &name, dest, private, time);
+                std::iter::once(reply).chain(combo).collect()
+            }
+            ParsedLine::Decrement(name) => {
+                vec![self.apply_change(sender, &name, -1, dest, private, time)]
+            }
+            ParsedLine::Query(name) => vec![self.format_query(&name, self.config.verbosity, time)],
+            ParsedLine::RankedQuery(name) => {
+                vec![self.format_query(&name, self.config.verbosity.max(Verbosity::Rank), time)]
+            }
+            ParsedLine::WhoDid(name) => vec![self.format_whodid(&name)],
+            ParsedLine::Diff(a, b) => vec![self.format_diff(&a, &b)],
+            // Listing keys by value is an admin tool, like the admin commands
+            ParsedLine::Equal(_) if !private || !self.is_admin(sender) => vec![],
+            ParsedLine::Equal(value) => self.format_equal(value),
+            ParsedLine::Mine => vec![self.format_mine(sender)],
+            ParsedLine::Top => self.format_top(),
+            ParsedLine::Recent => self.format_recent(time),
+            ParsedLine::Find(text) => self.format_find(&text),
+            ParsedLine::Count => vec![format!("Tracking {} identifiers", self.db.len())],
+            ParsedLine::KarmaGiven(nick) => vec![self.format_karma_given(&nick)],
+            ParsedLine::KarmaReceived(nick) => vec![self.format_karma_received(&nick)],
+            ParsedLine::Uptime => vec![self.format_uptime()],
+            ParsedLine::Topic if private => vec!["?topic only works in a channel".to_string()],
+            ParsedLine::Topic => vec![self.format_topic(dest)],
+        }
+    }
+
+    /// Send a single line to the server in the configured encoding
+    async fn send<W>(&self, sock: &mut W, line: &str) -> io::Result<()>
+        where W: AsyncWrite + Unpin
+    {
+        let mut data = encode_line(line, self.config.encoding);
+        data.extend(b"\r\n");
+        sock.write_all(&data).await
+    }
+
+    /// Send `text` to `target`, sanitized so it can't break out of the message
+    async fn send_privmsg<W>(&self, sock: &mut W, target: &str, text: &str) -> io::Result<()>
+        where W: AsyncWrite + Unpin
+    {
+        self.send_text(sock, "PRIVMSG", target, text).await
+    }
+
+    /// Send `text` to `target` in reply to the message with id `msgid`.  If
+    /// the server can't pass the reply tag on, this is just a PRIVMSG.
+    async fn send_reply<W>(&self, sock: &mut W, target: &str, text: &str,
+                           msgid: Option<&str>) -> io::Result<()>
+        where W: AsyncWrite + Unpin
+    {
+        let threaded = self.config.reply_threads && self.caps.contains("message-tags");
+        match msgid.filter(|_| threaded) {
+            Some(msgid) => match format_text("PRIVMSG", target, text) {
+                Some(line) => self.send(sock, &format!("@+draft/reply={} {}", msgid, line)).await,
+                None => Ok(()),
+            },
+            None => self.send_privmsg(sock, target, text).await,
+        }
+    }
+
+    async fn send_notice<W>(&self, sock: &mut W, target: &str, text: &str) -> io::Result<()>
+        where W: AsyncWrite + Unpin
+    {
+        self.send_text(sock, "NOTICE", target, text).await
+    }
+
+    async fn send_text<W>(&self, sock: &mut W, command: &str, target: &str,
+                          text: &str) -> io::Result<()>
+        where W: AsyncWrite + Unpin
+    {
+        match format_text(command, target, text) {
+            Some(line) => self.send(sock, &line).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Format the current value of `name` with one of the key templates
+    fn format_value(&self, template: &str, name: &str) -> String {
+        let value = self.db.value(name).to_string();
+        // Finding the rank means scanning the whole db, so skip it if unused
+        let rank = if template.contains("{rank}") {
+            self.db.rank(name).map_or("-".to_string(), |(rank, _)| rank.to_string())
+        } else {
+            String::new()
+        };
+        template::render(template, &[("key", name), ("value", &value), ("rank", &rank)])
+    }
+
+    /// The reply to `?name`, saying as much as `verbosity` asks for
+    fn format_query(&self, name: &str, verbosity: Verbosity, time: SystemTime) -> String {
+        if self.config.report_absent && self.db.get(name).is_none() {
+            return template::render(&self.config.templates.absent, &[("key", name)]);
+        }
+        let reply = self.format_value(&self.config.templates.query, name);
+        if verbosity == Verbosity::Value {
+            return self.with_variants(reply, name);
+        }
+        let mut details = match self.db.rank(name) {
+            Some((rank, total)) => vec![format!("rank {}/{}", rank, total)],
+            None => vec!["unranked".to_string()],
+        };
+        if verbosity == Verbosity::Full {
+            if let Some(modified) = self.db.modified(name) {
+                let elapsed = time.duration_since(modified).unwrap_or_default();
+                details.push(format!("changed {} ago", format_elapsed(elapsed)));
+            }
+        }
+        self.with_variants(format!("{} ({})", reply, details.join(", ")), name)
+    }
+
+    /// `reply` with a note of the other forms `name` has been changed as,
+    /// which share its value, as long as it still fits in a message.  Forms
+    /// only differing in case aren't worth mentioning.
+    fn with_variants(&self, reply: String, name: &str) -> String {
+        let variants = self.db.variants(name);
+        let mut others: Vec<&String> = Vec::new();
+        for variant in &variants {
+            if !variant.eq_ignore_ascii_case(name)
+                    && !others.iter().any(|other| other.eq_ignore_ascii_case(variant)) {
+                others.push(variant);
+            }
+        }
+        if others.is_empty() {
+            return reply;
+        }
+        let mut listed: Vec<&str> = others.iter().take(VARIANTS_LIMIT)
+                                          .map(|variant| variant.as_str()).collect();
+        let more = format!("and {} more", others.len() - listed.len());
+        if others.len() > listed.len() {
+            listed.push(&more);
+        }
+        let note = format!("{} (also counted as: {})", reply, listed.join(", "));
+        if note.len() > MESSAGE_LIMIT {
+            return reply;
+        }
+        note
+    }
+
+    fn top_entries(&self) -> Vec<String> {
+        let top = self.db.top(TOP_COUNT);
+        let mut rank = 0;
+        let mut entries = Vec::new();
+        for (pos, (name, value)) in top.iter().enumerate() {
+            if pos == 0 || *value != top[pos - 1].1 {
+                rank = pos + 1;
+            }
+            let (value, rank) = (value.to_string(), rank.to_string());
+            entries.push(template::render(&self.config.templates.top_entry,
+                                          &[("key", name), ("value", &value), ("rank", &rank)]));
+        }
+        entries
+    }
+
+    fn format_top(&self) -> Vec<String> {
+        let entries = self.top_entries();
+        if entries.is_empty() {
+            return vec!["Top karma: nothing yet".to_string()];
+        }
+        flow_entries("Top karma: ", &entries, MESSAGE_LIMIT)
+    }
+
+    fn format_equal(&self, value: i64) -> Vec<String> {
+        let mut keys = self.db.keys_with_value(value);
+        if keys.is_empty() {
+            return vec![format!("No keys equal {}", value)];
+        }
+        if keys.len() > EQUAL_LIMIT {
+            let more = keys.len() - EQUAL_LIMIT;
+            keys.truncate(EQUAL_LIMIT);
+            keys.push(format!("and {} more", more));
+        }
+        flow_entries(&format!("Keys equal to {}: ", value), &keys, MESSAGE_LIMIT)
+    }
+
+    fn format_recent(&self, time: SystemTime) -> Vec<String> {
+        let window = self.config.recent_window;
+        let since = time.checked_sub(window).unwrap_or(UNIX_EPOCH);
+        let recent = self.db.recently_modified(since);
+        if recent.is_empty() {
+            return vec![format!("Nothing has changed in the last {}", format_elapsed(window))];
+        }
+        let mut entries: Vec<String> = recent.iter().take(RECENT_LIMIT)
+            .map(|(key, value, _)| format!("{} = {}", key, value)).collect();
+        if recent.len() > RECENT_LIMIT {
+            entries.push(format!("and {} more", recent.len() - RECENT_LIMIT));
+        }
+        flow_entries(&format!("Changed in the last {}: ", format_elapsed(window)),
+                     &entries, MESSAGE_LIMIT)
+    }
+
+    fn format_find(&self, text: &str) -> Vec<String> {
+        let found = self.db.search(text);
+        if found.is_empty() {
+            return vec![format!("No keys contain {}", text)];
+        }
+        let mut entries: Vec<String> = found.iter().take(FIND_LIMIT)
+            .map(|(key, value)| format!("{} = {}", key, value)).collect();
+        if found.len() > FIND_LIMIT {
+            entries.push(format!("and {} more", found.len() - FIND_LIMIT));
+        }
+        flow_entries(&format!("Keys containing {}: ", text), &entries, MESSAGE_LIMIT)
+    }
+
+    fn render_announcement(&self, template: &str) -> Vec<String> {
+        let (before, after) = match template.split_once("{top}") {
+            Some(parts) => parts,
+            None => return vec![template.to_string()],
+        };
+        let mut entries = self.top_entries();
+        match entries.last_mut() {
+            Some(last) => last.push_str(after),
+            None => entries.push(format!("nothing yet{}", after)),
+        }
+        flow_entries(before, &entries, MESSAGE_LIMIT)
+    }
+
+    fn set_topic(&mut self, channel: &str, topic: &str) {
+        let channel = channel.to_ascii_lowercase();
+        let topic = topic.trim();
+        if topic.is_empty() {
+            self.topics.remove(&channel);
+        } else {
+            self.topics.insert(channel, topic.to_string());
+        }
+    }
+
+    fn format_topic(&self, channel: &str) -> String {
+        match self.topics.get(&channel.to_ascii_lowercase()) {
+            Some(topic) => format!("Topic for {}: {}", channel, topic),
+            None => format!("No topic is set for {}", channel),
+        }
+    }
+
+    /// Give or take away operator (`o`) or voice (`v`) status.  Other modes
+    /// are ignored.
+    fn set_status(&mut self, channel: &str, nick: &str, mode: char, set: bool) {
+        let status = self.statuses.entry(channel.to_ascii_lowercase()).or_default();
+        let nicks = match mode {
+            'o' => &mut status.ops,
+            'v' => &mut status.voiced,
+            _ => return,
+        };
+        if set {
+            nicks.insert(nick.to_ascii_lowercase());
+        } else {
+            nicks.remove(&nick.to_ascii_lowercase());
+        }
+    }
+
+    /// Forget `nick`'s status in `channel`, or in every channel if None
+    fn forget_member(&mut self, channel: Option<&str>, nick: &str) {
+        let nick = nick.to_ascii_lowercase();
+        for (name, status) in &mut self.statuses {
+            if channel.is_none_or(|channel| channel.eq_ignore_ascii_case(name)) {
+                status.ops.remove(&nick);
+                status.voiced.remove(&nick);
+                status.first_seen.remove(&nick);
+            }
+        }
+        // The server stops telling us about the accounts of people we no
+        // longer share a channel with, so whoever takes the nick next
+        // mustn't inherit it
+        if !self.is_present(&nick) {
+            self.accounts.remove(&nick);
+        }
+    }
+
+    fn rename_member(&mut self, old_nick: &str, new_nick: &str) {
+        let old_nick = old_nick.to_ascii_lowercase();
+        for status in self.statuses.values_mut() {
+            for nicks in [&mut status.ops, &mut status.voiced] {
+                if nicks.remove(&old_nick) {
+                    nicks.insert(new_nick.to_ascii_lowercase());
+                }
+            }
+            if let Some(first_seen) = status.first_seen.remove(&old_nick) {
+                status.first_seen.insert(new_nick.to_ascii_lowercase(), first_seen);
+            }
+        }
+    }
+
+    /// Note that `nick` is in `channel`, if we haven't already
+    fn seen(&mut self, channel: &str, nick: &str) {
+        self.statuses.entry(channel.to_ascii_lowercase()).or_default()
+                     .first_seen.entry(nick.to_ascii_lowercase()).or_insert_with(Instant::now);
+    }
+
+    /// Whether `nick` is in `channel`, as far as we know
+    fn is_member(&self, channel: &str, nick: &str) -> bool {
+        self.statuses.get(&channel.to_ascii_lowercase())
+                     .is_some_and(|status| status.first_seen.contains_key(&nick.to_ascii_lowercase()))
+    }
+
+    /// Whether `nick` is in any channel we know of
+    fn is_present(&self, nick: &str) -> bool {
+        let nick = nick.to_ascii_lowercase();
+        self.statuses.values().any(|status| status.first_seen.contains_key(&nick))
+    }
+
+    /// Whether `nick` has been around long enough for their changes to
+    /// count.  Private messages count if `nick` is established anywhere.
+    fn is_established(&self, nick: &str, dest: &str, private: bool) -> bool {
+        let min_presence = match self.config.min_presence {
+            Some(min_presence) => min_presence,
+            None => return true,
+        };
+        let nick = nick.to_ascii_lowercase();
+        let now = Instant::now();
+        self.statuses.iter()
+            .filter(|(channel, _)| private || channel.eq_ignore_ascii_case(dest))
+            .filter_map(|(_, status)| status.first_seen.get(&nick))
+            .any(|first_seen| now - *first_seen >= min_presence)
+    }
+
+    pub fn is_op(&self, channel: &str, nick: &str) -> bool {
+        self.statuses.get(&channel.to_ascii_lowercase())
+                     .is_some_and(|status| status.ops.contains(&nick.to_ascii_lowercase()))
+    }
+
+    /// Whether we're a channel operator in `channel`, and so could moderate it
+    pub fn have_op(&self, channel: &str) -> bool {
+        self.is_op(channel, &self.nick)
+    }
+
+    pub fn is_voiced(&self, channel: &str, nick: &str) -> bool {
+        self.statuses.get(&channel.to_ascii_lowercase())
+                     .is_some_and(|status| status.voiced.contains(&nick.to_ascii_lowercase()))
+    }
+
+    fn may_decrement(&self, sender: &str, dest: &str, private: bool) -> bool {
+        !self.config.restrict_decrements || self.is_admin(sender)
+            || (!private && self.is_op(dest, sender))
+    }
+
+    fn format_diff(&self, a: &str, b: &str) -> String {
+        let (a_value, b_value) = (self.db.value(a), self.db.value(b));
+        match a_value.cmp(&b_value) {
+            Ordering::Greater => format!("{} leads {} by {}", a, b, a_value.abs_diff(b_value)),
+            Ordering::Less => format!("{} leads {} by {}", b, a, a_value.abs_diff(b_value)),
+            Ordering::Equal => format!("{} and {} are tied at {}", a, b, a_value),
+        }
+    }
+
+    fn format_uptime(&self) -> String {
+        let now = Instant::now();
+        let connected = match self.connected_at {
+            Some(connected_at) => format!("connected for {}", format_elapsed(now - connected_at)),
+            None => "not connected".to_string(),
+        };
+        format!("rot {}: up {}, {}", env!("CARGO_PKG_VERSION"),
+                format_elapsed(now - self.started), connected)
+    }
+
+    /// Whether `name` has already been changed as often as the
+    /// `key_rate_limit` allows
+    fn is_rate_limited(&self, name: &str) -> bool {
+        let limit = match self.config.key_rate_limit {
+            Some(limit) => limit,
+            None => return false,
+        };
+        let now = Instant::now();
+        self.key_changes.get(&self.db.normalize(name)).is_some_and(|changes| {
+            changes.iter().filter(|time| now - **time < limit.window).count() >= limit.count
+        })
+    }
+
+    /// Count an increment of `name` by `sender` toward a combo, adding the
+    /// bonus and returning the announcement if this completes one.  Only the
+    /// latest increment by each person counts, so nobody can make a combo
+    /// alone.  People are told apart by user@host, since changing nick
+    /// doesn't make someone a different person.
+    fn check_combo(&mut self, sender: &str, name: &str, dest: &str, private: bool,
+                   time: SystemTime) -> Option<String> {
+        let combo = self.config.combo?;
+        if self.db.is_frozen(name) {
+            return None;
+        }
+        let now = Instant::now();
+        if self.combos.len() >= KEY_CHANGES_LIMIT {
+            self.combos.retain(|_, increments| {
+                increments.back().is_some_and(|(_, time)| now - *time < combo.window)
+            });
+        }
+        let key = self.db.normalize(name);
+        let increments = self.combos.entry(key.clone()).or_default();
+        let host = &self.sender_host;
+        increments.retain(|(person, time)| person != host && now - *time < combo.window);
+        increments.push_back((host.clone(), now));
+        if increments.len() < combo.people {
+            return None;
+        }
+
+        // The next combo needs everyone again
+        self.combos.remove(&key);
+        let old_value = self.db.value(name);
+        let value = self.db.add(name, combo.bonus, time);
+        self.emit_change(sender, name, old_value, dest, private);
+        Some(format!("Combo! {} people incremented {} within {}, {:+} ({} = {})", combo.people,
+                     name, format_elapsed(combo.window), combo.bonus, name, value))
+    }
+
+    fn record_key_change(&mut self, name: &str) {
+        let limit = match self.config.key_rate_limit {
+            Some(limit) => limit,
+            None => return,
+        };
+        let now = Instant::now();
+        // Forget keys which haven't changed lately, so this doesn't grow
+        // with every key ever changed
+        if self.key_changes.len() >= KEY_CHANGES_LIMIT {
+            self.key_changes.retain(|_, changes| {
+                changes.back().is_some_and(|time| now - *time < limit.window)
+            });
+        }
+        let changes = self.key_changes.entry(self.db.normalize(name)).or_default();
+        while changes.front().is_some_and(|time| now - *time >= limit.window) {
+            changes.pop_front();
+        }
+        changes.push_back(now);
+    }
+
+    fn apply_change(&mut self, sender: &str, name: &str, delta: i64, dest: &str,
+                    private: bool, time: SystemTime) -> String {
+        if self.db.is_frozen(name) {
+            return format!("{} (frozen)", self.format_value(&self.config.templates.change, name));
+        }
+        let old_value = self.db.value(name);
+        let new_value = self.db.add(name, delta, time);
+        self.emit_change(sender, name, old_value, dest, private);
+        // A change held back by the floor didn't happen, so nobody made it,
+        // and it doesn't count toward the key's rate limit
+        if new_value != old_value {
+            self.record_key_change(name);
+            self.db.record(sender, name, delta, time);
+            let target = if self.is_present(name) { Some(name) } else { None };
+            self.db.record_user_change(sender, target, delta);
+        }
+        self.format_value(&self.config.templates.change, name)
+    }
+
+    /// Tell the karma event receiver that `name` changed from `old_value`,
+    /// if it did and this isn't a sandbox channel
+    fn emit_change(&self, sender: &str, name: &str, old_value: i64, dest: &str, private: bool) {
+        let events = match &self.karma_events {
+            Some(events) => events,
+            None => return,
+        };
+        let new_value = self.db.value(name);
+        let sandbox = self.config.sandbox_channels.iter()
+                          .any(|channel| channel.eq_ignore_ascii_case(dest));
+        if new_value == old_value || sandbox {
+            return;
+        }
+        let _ = events.try_send(KarmaEvent {
+            sender: sender.to_string(),
+            key: self.db.normalize(name),
+            old_value,
+            new_value,
+            channel: if private { None } else { Some(dest.to_string()) },
+        });
+    }
+
+    fn is_denied(&self, name: &str) -> bool {
+        self.db.is_denied(name)
+    }
+
+    /// Ignore an operation on a denied key
+    fn denied(&mut self) -> Vec<String> {
+        if self.config.warn_denied {
+            self.notices.push("That key can't be used".to_string());
+        }
+        vec![]
+    }
+
+    /// Whether `nick` may send admin commands.  Once account-notify has told
+    /// us which account someone is logged in to, that has to be one of the
+    /// admins, so someone else using an admin's nick is refused.  Until then
+    /// the nick is trusted.
+    fn is_admin(&self, nick: &str) -> bool {
+        let listed = |name: &str| {
+            self.config.admins.iter().any(|admin| admin.eq_ignore_ascii_case(name))
+        };
+        match self.accounts.get(&nick.to_ascii_lowercase()) {
+            Some(account) => account.as_deref().is_some_and(listed),
+            None => listed(nick),
+        }
+    }
+
+    fn admin_command(&mut self, sender: &str, command: AdminCommand) -> String {
+        if let AdminCommand::Confirm(token) = command {
+            return self.confirm(sender, &token);
+        }
+        if let Some(name) = command.key().filter(|name| self.is_denied(name)) {
+            return format!("{} can't be used", name);
+        }
+        match self.describe_destructive(&command) {
+            Some(description) => self.request_confirmation(sender, command, &description),
+            None => self.run_admin_command(sender, command),
+        }
+    }
+
+    /// What `command` would do, if it's destructive enough to be confirmed
+    /// first
+    fn describe_destructive(&self, command: &AdminCommand) -> Option<String> {
+        match command {
+            AdminCommand::Reset(name) if self.db.get(name).is_some() => {
+                Some(format!("reset {}", name))
+            }
+            AdminCommand::Purge => Some("purge every reset key".to_string()),
+            AdminCommand::Prune(min_abs, age) => {
+                Some(format!("remove the keys closer to zero than {} unchanged for {}",
+                             min_abs, format_elapsed(*age)))
+            }
+            AdminCommand::AdjustPrefix(prefix, delta) => {
+                let count = self.db.keys_with_prefix(prefix).len();
+                (count > ADJUST_CONFIRM_LIMIT).then(|| {
+                    format!("add {} to the {} keys matching {}*", delta, count, prefix)
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn request_confirmation(&mut self, sender: &str, command: AdminCommand,
+                            description: &str) -> String {
+        // Only needs to be hard to send by mistake, not to guess
+        let token = format!("{:06x}", RandomState::new().build_hasher().finish() & 0xff_ffff);
+        let expires = Instant::now() + CONFIRM_TIMEOUT;
+        let reply = format!("Send \"confirm {}\" within {} to {}",
+                            token, format_elapsed(CONFIRM_TIMEOUT), description);
+        self.confirmations.insert((sender.to_ascii_lowercase(), command),
+                                  Confirmation { token, expires });
+        reply
+    }
+
+    fn confirm(&mut self, sender: &str, token: &str) -> String {
+        let now = Instant::now();
+        self.confirmations.retain(|_, confirmation| now < confirmation.expires);
+        let admin = sender.to_ascii_lowercase();
+        let key = self.confirmations.iter()
+                      .find(|((waiting, _), confirmation)| {
+                          *waiting == admin && confirmation.token == token
+                      })
+                      .map(|(key, _)| key.clone());
+        match key {
+            Some(key) => {
+                self.confirmations.remove(&key);
+                self.run_admin_command(sender, key.1)
+            }
+            None => format!("Nothing is waiting for confirmation {}", token),
+        }
+    }
+
+    fn run_admin_command(&mut self, sender: &str, command: AdminCommand) -> String {
+        match command {
+            AdminCommand::Freeze(name) => {
+                format!("{} is frozen at {}", name, self.db.freeze(&name))
+            }
+            AdminCommand::Unfreeze(name) => {
+                if self.db.unfreeze(&name) {
+                    format!("{} is no longer frozen", name)
+                } else {
+                    format!("{} is not frozen", name)
+                }
+            }
+            AdminCommand::Normalize(name) => {
+                format!("{} is stored as {}", name, self.db.normalize(&name))
+            }
+            AdminCommand::Variants(name) => {
+                let variants = self.db.variants(&name);
+                if variants.is_empty() {
+                    format!("No changes to {} have been seen", name)
+                } else {
+                    format!("{} has been changed as: {}", self.db.normalize(&name), variants.join(", "))
+                }
+            }
+            AdminCommand::Reset(name) => {
+                if self.db.reset(&name) {
+                    format!("{} is reset, and can be restored until purged", name)
+                } else {
+                    format!("{} has no value to reset", name)
+                }
+            }
+            AdminCommand::Restore(name) => match self.db.restore(&name) {
+                Some(value) => format!("{} is restored at {}", name, value),
+                None => format!("{} was not reset", name),
+            },
+            AdminCommand::Purge => format!("Purged {} reset keys", self.db.purge()),
+            AdminCommand::Prune(min_abs, age) => {
+                format!("Pruned {} stale keys", self.prune(min_abs, age))
+            }
+            AdminCommand::Mute(channel, _) if !self.is_channel(&channel) => {
+                format!("{} is not a channel", channel)
+            }
+            AdminCommand::Mute(channel, duration) => {
+                let now = Instant::now();
+                self.muted.retain(|_, until| until.is_none_or(|until| now < until));
+                self.muted.insert(channel.to_ascii_lowercase(),
+                                  duration.map(|duration| now + duration));
+                match duration {
+                    Some(duration) => format!("{} is muted for {}", channel, format_elapsed(duration)),
+                    None => format!("{} is muted", channel),
+                }
+            }
+            AdminCommand::AdjustPrefix(prefix, delta) => {
+                format!("Changed {} keys matching {}*", self.db.adjust_prefix(&prefix, delta), prefix)
+            }
+            AdminCommand::Nick(nick) if nick.eq_ignore_ascii_case(&self.nick) => {
+                format!("Already using {}", self.nick)
+            }
+            AdminCommand::Nick(nick) if !is_valid_nick(&nick) => format!("Invalid nick {}", nick),
+            AdminCommand::Nick(nick) => {
+                // Our nick only changes once the server says it has
+                self.outgoing.push(format!("NICK {}", nick));
+                self.pending_nick = Some((nick.clone(), sender.to_string()));
+                format!("Changing nick to {}", nick)
+            }
+            AdminCommand::HaveOp(channel) if self.have_op(&channel) => {
+                format!("I have ops in {}", channel)
+            }
+            AdminCommand::HaveOp(channel) => format!("I don't have ops in {}", channel),
+            AdminCommand::Unmute(channel) => {
+                if self.muted.remove(&channel.to_ascii_lowercase()).is_some() {
+                    format!("{} is no longer muted", channel)
+                } else {
+                    format!("{} is not muted", channel)
+                }
+            }
+            AdminCommand::ExportCsv => {
+                // The db may be far too large to send over IRC, so write it
+                // to a file next to the db instead
+                let filename = format!("{}.csv", self.db_filename);
+                let result = File::create(&filename).map_err(RotError::from)
+                        .and_then(|file| self.db.export_csv(&mut BufWriter::new(file)));
+                match result {
+                    Ok(()) => format!("Exported to {}", filename),
+                    Err(err) => {
+                        eprintln!("Could not export to {}: {}", filename, err);
+                        format!("Could not export to {}", filename)
+                    }
+                }
+            }
+            AdminCommand::Set(name, value) => {
+                if self.db.is_frozen(&name) {
+                    format!("{} = {} (frozen)", name, self.db.value(&name))
+                } else {
+                    format!("{} = {}", name, self.db.set(&name, value))
+                }
+            }
+            AdminCommand::Confirm(token) => self.confirm(sender, &token),
+        }
+    }
+
+    /// Remove the keys closer to zero than `min_abs` which haven't changed
+    /// within `age`, returning how many
+    fn prune(&mut self, min_abs: i64, age: Duration) -> usize {
+        let older_than = SystemTime::now().checked_sub(age).unwrap_or(UNIX_EPOCH);
+        let pruned = self.db.prune(min_abs, older_than);
+        if pruned > 0 {
+            println!("Pruned {} stale keys", pruned);
+        }
+        pruned
+    }
+
+    fn format_whodid(&self, name: &str) -> String {
+        if !self.db.history_enabled() {
+            return "History tracking is disabled".to_string();
+        }
+        let contributions = self.db.contributions_to(name);
+        if contributions.is_empty() {
+            format!("Nobody has changed {}", name)
+        } else {
+            format!("{} was changed by {}", name, format_contributions(&contributions))
+        }
+    }
+
+    fn format_karma_given(&self, nick: &str) -> String {
+        if !self.db.user_totals_enabled() {
+            return "User totals are disabled".to_string();
+        }
+        let totals = self.db.user_totals(nick);
+        if totals.given_ups + totals.given_downs == 0 {
+            format!("{} hasn't given any karma", nick)
+        } else {
+            format!("{} has given {} (+{}/-{})", nick, totals.given(), totals.given_ups,
+                    totals.given_downs)
+        }
+    }
+
+    fn format_karma_received(&self, nick: &str) -> String {
+        if !self.db.user_totals_enabled() {
+            return "User totals are disabled".to_string();
+        }
+        let totals = self.db.user_totals(nick);
+        if totals.received_ups + totals.received_downs == 0 {
+            format!("{} hasn't received any karma", nick)
+        } else {
+            format!("{} has received {} (+{}/-{})", nick, totals.received(),
+                    totals.received_ups, totals.received_downs)
+        }
+    }
+
+    fn format_mine(&self, sender: &str) -> String {
+        if !self.db.history_enabled() {
+            return "History tracking is disabled".to_string();
+        }
+        let contributions = self.db.contributions_by(sender);
+        if contributions.is_empty() {
+            format!("{} hasn't changed anything", sender)
+        } else {
+            format!("{} has changed {}", sender, format_contributions(&contributions))
+        }
+    }
+
+    /// How long to wait before reconnecting, or None if we shouldn't
+    fn next_reconnect_delay(&mut self) -> Option<Duration> {
+        if self.banned.take().is_some() {
+            self.config.ban.backoff
+        } else {
+            Some(RECONNECT_DELAY)
+        }
+    }
+
+    /// Wait to reconnect, returning false if we were interrupted
+    async fn reconnect_delay(&mut self) -> Result<bool, RotError> {
+        let reason = self.banned.clone().unwrap_or_default();
+        let delay = match self.next_reconnect_delay() {
+            Some(delay) => delay,
+            None => {
+                return Err(RotError::Protocol(format!("Banned from the server ({}), giving up",
+                                                      reason)));
+            }
+        };
+        eprintln!("Retrying in {} sec...", delay.as_secs());
+        self.emit(ConnectionEvent::Reconnecting);
+        Ok(tokio::select! {
+            _ = tokio::time::sleep(delay) => true,
+            _ = self.shutdown_recv.recv() => false,
+        })
+    }
+
+    /// Connect to the server, returning None if we were interrupted
+    async fn connect(&mut self, initial_delay: bool) -> Result<Option<Connection>, RotError> {
+        if initial_delay && !self.reconnect_delay().await? {
+            return Ok(None);
+        }
+        if self.config.sasl == Some(SaslMechanism::External) && !self.custom_connector {
+            let err = io::Error::new(io::ErrorKind::InvalidInput,
+                                     "sasl = external needs a client certificate connector");
+            return Err(RotError::Connection { addr: self.remote_addr.clone(), source: err });
+        }
+
+        let sock = loop {
+            let connect_fut = (self.connector)(&self.remote_addr);
+            match tokio::time::timeout(TIMEOUT_DURATION, connect_fut).await {
+                Ok(Ok(mut sock)) => {
+                    self.emit(ConnectionEvent::Connected);
+                    self.connected_at = Some(Instant::now());
+                    self.connected_time = None;
+                    // Carrying on half-registered would only fail later
+                    match self.register(&mut sock).await {
+                        Ok(()) => break sock,
+                        Err(err) => {
+                            eprintln!("Failed to register with {}: {}", self.remote_addr, err);
+                            self.disconnected();
+                        }
+                    }
+                }
+                // Such as an address without a port, which won't get better
+                Ok(Err(err)) if err.kind() == io::ErrorKind::InvalidInput => {
+                    return Err(RotError::Connection { addr: self.remote_addr.clone(),
+                                                      source: err });
+                }
+                Ok(Err(err)) => {
+                    eprintln!("Failed to connect to {}: {}", self.remote_addr, err);
+                }
+                Err(_) => eprintln!("Connection timed out"),
+            };
+
+            if !self.reconnect_delay().await? {
+                return Ok(None);
+            }
+        };
+
+        if self.config.join_notice_on_reconnect {
+            self.announced.clear();
+        }
+
+        // The requested IRC channel(s) are joined once registration is
+        // complete, and we know how many the server allows.
+
+        // Signal reset of the ping timer
+        self.ping_state = PingState::Reset;
+
+        // If we lose the connection later on, we'll catch it when we try to
+        // read from the socket in the main loop.
+        Ok(Some(sock))
+    }
+
+    /// Forget everything learned from the server over the last connection,
+    /// since none of it can be trusted on a new one.  Capabilities, SASL, the
+    /// channels' members and ops all start again as registration goes on.
+    fn reset_session(&mut self) {
+        self.caps.clear();
+        self.caps_pending = 0;
+        self.negotiating = false;
+        self.available_caps.clear();
+        self.sasl_pending = false;
+        self.pending_nick = None;
+        self.accounts.clear();
+        self.statuses.clear();
+        self.forwards.clear();
+        self.topics.clear();
+        self.isupport.clear();
+        self.lines = LineBuffer::default();
+        self.notices.clear();
+        self.outgoing.clear();
+    }
+
+    async fn register<W>(&mut self, sock: &mut W) -> io::Result<()>
+        where W: AsyncWrite + Unpin
+    {
+        self.reset_session();
+        // Ask which capabilities the server has, so only those it offers
+        // are requested.  Servers without capability support will just
+        // ignore this, and the others wait for every ACK or NAK.
+        self.caps_pending = 1;
+        self.negotiating = true;
+        self.send(sock, "CAP LS 302").await?;
+
+        // Minimal identification necessary to satisfy the IRC server
+        self.send(sock, &format!("NICK {}", self.nick)).await?;
+        let user = format!("USER {} . . :{}",
+                           self.config.username.as_deref().unwrap_or(&self.nick),
+                           self.config.realname.as_deref().unwrap_or(&self.nick));
+        self.send(sock, &user).await
+    }
+}
+
+/// Open the flat file db `filename`, as the config says to
+pub fn open_db(filename: &str, config: &Config) -> RotDb {
+    let mut db = match &config.base_db {
+        Some(base_filename) => RotDb::layered(base_filename, filename, config.max_db_lines),
+        None => RotDb::open(filename, config.max_db_lines),
+    };
+    db.set_key_mode(config.key_mode);
+    db.set_denied_keys(&config.denied_keys);
+    db
+}
+
+/// An in-memory db for one of the `sandbox_channels`
+fn sandbox_db(config: &Config) -> Box<dyn KarmaStore> {
+    let mut sandbox = RotDb::ephemeral();
+    sandbox.set_key_mode(config.key_mode);
+    sandbox.set_denied_keys(&config.denied_keys);
+    sandbox.set_floor(config.min_value);
+    sandbox.set_max_keys(config.max_keys);
+    if config.history {
+        sandbox.enable_history();
+    }
+    if config.user_totals {
+        sandbox.enable_user_totals();
+    }
+    Box::new(sandbox)
+}
+
+/// Replies to a JOIN we can't recover from: ERR_NOSUCHCHANNEL,
+/// ERR_TOOMANYCHANNELS, ERR_CHANNELISFULL, ERR_INVITEONLYCHAN,
+/// ERR_BANNEDFROMCHAN, ERR_BADCHANNELKEY, ERR_BADCHANMASK and the common
+/// ERR_NEEDREGGEDNICK
+const JOIN_ERRORS: &[&str] = &["403", "405", "471", "473", "474", "475", "476", "477"];
+
+/// RPL_SASLSUCCESS, ERR_SASLFAIL, ERR_SASLTOOLONG, ERR_SASLABORTED and
+/// ERR_SASLALREADY, which all end the SASL exchange
+const SASL_RESULTS: &[&str] = &["903", "904", "905", "906", "907"];
+
+/// Connect to a `unix:/path/to/socket` address over a Unix socket, and to
+/// anything else over TCP
+fn default_connect(remote_addr: &str, bind_address: Option<IpAddr>) -> ConnectFuture {
+    match remote_addr.strip_prefix("unix:") {
+        Some(path) => unix_connect(path),
+        None => tcp_connect(remote_addr, bind_address),
+    }
+}
+
+#[cfg(unix)]
+fn unix_connect(path: &str) -> ConnectFuture {
+    let path = path.to_string();
+    Box::pin(async move {
+        let sock = tokio::net::UnixStream::connect(&path).await?;
+        println!("Connected to unix:{}", path);
+        Ok(Box::new(sock) as Connection)
+    })
+}
+
+#[cfg(not(unix))]
+fn unix_connect(_path: &str) -> ConnectFuture {
+    Box::pin(async {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "Unix sockets aren't supported here"))
+    })
+}
+
+/// Connect over TCP, from `bind_address` if it's given.  Failing to bind is
+/// reported as `InvalidInput`, since retrying won't help.
+fn tcp_connect(remote_addr: &str, bind_address: Option<IpAddr>) -> ConnectFuture {
+    let remote_addr = with_default_port(remote_addr);
+    Box::pin(async move {
+        let sock = match bind_address {
+            Some(bind_address) => bound_connect(&remote_addr, bind_address).await?,
+            None => TcpStream::connect(&remote_addr).await?,
+        };
+        let peer_name = match sock.peer_addr() {
+            Ok(addr) => addr.to_string(),
+            Err(_) => "<unknown>".to_string(),
+        };
+        println!("Connected to {}", peer_name);
+        Ok(Box::new(sock) as Connection)
+    })
+}
+
+/// `remote_addr` as `host:port`, adding the default port if it has none.  An
+/// IPv6 address may be given with or without brackets, but needs them to be
+/// given a port, as in `[::1]:6667`.
+fn with_default_port(remote_addr: &str) -> String {
+    let has_port = match remote_addr.strip_prefix('[') {
+        Some(bracketed) => !bracketed.ends_with(']'),
+        None => remote_addr.parse::<Ipv6Addr>().is_err() && remote_addr.contains(':'),
+    };
+    if has_port {
+        remote_addr.to_string()
+    } else if remote_addr.parse::<Ipv6Addr>().is_ok() {
+        format!("[{}]:{}", remote_addr, DEFAULT_PORT)
+    } else {
+        format!("{}:{}", remote_addr, DEFAULT_PORT)
+    }
+}
+
+async fn bound_connect(remote_addr: &str, bind_address: IpAddr) -> io::Result<TcpStream> {
+    let bind_error = |err: io::Error| {
+        io::Error::new(io::ErrorKind::InvalidInput,
+                       format!("Could not bind to {}: {}", bind_address, err))
+    };
+    // Only addresses of the same family can be reached from the bind address
+    let addr = tokio::net::lookup_host(remote_addr).await?
+        .find(|addr| addr.is_ipv4() == bind_address.is_ipv4())
+        .ok_or_else(|| bind_error(io::Error::new(io::ErrorKind::AddrNotAvailable,
+                                                 "the server has no address of that family")))?;
+    let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    socket.bind(SocketAddr::new(bind_address, 0)).map_err(bind_error)?;
+    socket.connect(addr).await
+}
+
+#[cfg(test)]
+impl IrcClient {
+    /// Run `text` through the line handler, returning everything sent back
+    async fn feed(&mut self, text: &str) -> String {
+        assert!(text.is_empty() || text.ends_with('\n'));
+        let mut output = Vec::new();
+        self.process_lines(text.as_bytes(), &mut output).await;
+        String::from_utf8(output).unwrap()
+    }
+
+    /// Connect to each of `transports` in turn instead of the server, and
+    /// refuse any connection after the last
+    fn connect_to<T: Transport + 'static>(&mut self, mut transports: Vec<T>) {
+        transports.reverse();
+        self.set_connector(Box::new(move |_| {
+            let transport = transports.pop();
+            Box::pin(async move {
+                transport.map(|t| Box::new(t) as Connection)
+                         .ok_or_else(|| io::Error::from(io::ErrorKind::ConnectionRefused))
+            })
+        }));
+    }
+
+    /// The services account `nick` is logged in to, if account-notify has
+    /// told us about it since we connected.  Only logins and logouts after
+    /// we joined are seen (there's no extended-join, account-tag or WHOX
+    /// support), so None doesn't mean someone isn't logged in.
+    fn account(&self, nick: &str) -> Option<&str> {
+        self.accounts.get(&nick.to_ascii_lowercase()).and_then(|account| account.as_deref())
+    }
+}
+
+/// Whether a read failed without losing the connection, and can be retried
+fn is_transient(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock)
+}
+
+fn decode_line(line: &[u8], encoding: Option<&'static Encoding>) -> String {
+    let (text, _) = encoding.unwrap_or(UTF_8).decode_without_bom_handling(line);
+    text.into_owned()
+}
+
+fn encode_line(line: &str, encoding: Option<&'static Encoding>) -> Vec<u8> {
+    let (data, _, _) = encoding.unwrap_or(UTF_8).encode(line);
+    data.into_owned()
+}
+
+/// Split the IRCv3 `@tags` off the front of a line
+fn split_tags(line: &str) -> (Option<&str>, &str) {
+    if !line.starts_with('@') {
+        return (None, line);
+    }
+    match line.find(' ') {
+        Some(end) => (Some(&line[1..end]), line[end..].trim_start()),
+        None => (Some(&line[1..]), ""),
+    }
+}
+
+fn tag_value<'a>(tags: &'a str, name: &str) -> Option<&'a str> {
+    tags.split(';').find_map(|tag| match tag.split_once('=') {
+        Some((key, value)) if key == name => Some(value),
+        _ => None,
+    })
+}
+
+/// Parse a `server-time` timestamp, such as `2011-10-19T16:40:51.620Z`
+fn parse_server_time(stamp: &str) -> Option<SystemTime> {
+    lazy_static! {
+        static ref RE_TIME: Regex = Regex::new(
+                r"^(\d{4})-(\d{2})-(\d{2})T(\d{2}):(\d{2}):(\d{2})(?:\.(\d{1,9}))?Z$").unwrap();
+    }
+    let caps = RE_TIME.captures(stamp)?;
+    let field = |n: usize| caps[n].parse::<i64>().unwrap();
+    let (year, month, day) = (field(1), field(2), field(3));
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day)
+            || field(4) > 23 || field(5) > 59 || field(6) > 60 {
+        return None;
+    }
+
+    // Days since the epoch, counting years from March so leap days come last
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    let secs = days * 86400 + field(4) * 3600 + field(5) * 60 + field(6);
+    let nanos = caps.get(7).map_or(0, |frac| {
+        format!("{:0<9}", frac.as_str()).parse::<u32>().unwrap()
+    });
+    if secs < 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::new(secs as u64, nanos))
+}
+
+/// Split a message into its parameters, the same way for any whitespace
+/// `is_space` accepts, so tabs separate parameters as spaces do
+fn irc_split(mut line: &[u8]) -> Vec<String> {
+    let mut parts = vec![];
+    let mut scan = 0;
+
+    while scan < line.len() {
+        if is_space(line[scan] as char) {
+            if scan > 0 {
+                parts.push(String::from_utf8_lossy(&line[0..scan]).to_string());
+            }
+            while scan < line.len() && is_space(line[scan] as char) {
+                scan += 1;
+            }
+            line = &line[scan..];
+            scan = 0;
+            if line.starts_with(b":") {
+                parts.push(String::from_utf8_lossy(line).to_string());
+                break;
+            }
+        } else {
+            scan += 1;
+        }
+    }
+    if scan != 0 {
+        parts.push(String::from_utf8_lossy(line).to_string());
+    }
+
+    parts
+}
+
+/// A `command target :text` line, with the text sanitized so it can't break
+/// out of the message.  Returns None (logging why) if the target isn't a
+/// single word, since rewriting it would send the message somewhere else.
+fn format_text(command: &str, target: &str, text: &str) -> Option<String> {
+    if target.is_empty() || target.contains(|c: char| c.is_whitespace() || c == '\0') {
+        eprintln!("Not sending {} to invalid target {:?}", command, target);
+        return None;
+    }
+    Some(format!("{} {} :{}", command, truncate(target, MESSAGE_LIMIT),
+                 sanitize(text, MESSAGE_LIMIT)))
+}
+
+fn format_contributions(contributions: &[Contribution]) -> String {
+    let mut text = contributions.iter()
+        .take(CONTRIBUTION_LIMIT)
+        .map(|c| format!("{} (+{}/-{})", c.name, c.ups, c.downs))
+        .collect::<Vec<String>>()
+        .join(", ");
+    if contributions.len() > CONTRIBUTION_LIMIT {
+        text += &format!(" and {} more", contributions.len() - CONTRIBUTION_LIMIT);
+    }
+    text
+}
+
+/// Make text safe to include in a line sent to the server, replacing the
+/// characters that would end the line (or confuse the server) with spaces
+/// and truncating it to `limit` bytes
+fn sanitize(text: &str, limit: usize) -> String {
+    let text: String = text.chars()
+                           .map(|c| if matches!(c, '\r' | '\n' | '\0') { ' ' } else { c })
+                           .collect();
+    truncate(&text, limit).to_string()
+}
+
+/// Truncate `text` to at most `limit` bytes without splitting a character
+fn truncate(text: &str, limit: usize) -> &str {
+    if text.len() <= limit {
+        return text;
+    }
+    let mut end = limit;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// Join `entries` with commas after `prefix`, starting a new line whenever
+/// the next entry won't fit within `limit` bytes.  Entries are never split
+/// across lines, but an entry too long to fit on a line by itself is
+/// truncated.
+fn flow_entries(prefix: &str, entries: &[String], limit: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = prefix.to_string();
+    let mut line_empty = true;
+    for entry in entries {
+        let entry = truncate(entry, limit);
+        let needed = if line_empty { entry.len() } else { entry.len() + 2 };
+        if line.len() + needed > limit && !line.is_empty() {
+            lines.push(line.trim_end().to_string());
+            line = String::new();
+            line_empty = true;
+        }
+        if !line_empty {
+            line.push_str(", ");
+        }
+        line.push_str(entry);
+        line_empty = false;
+    }
+    if !line.is_empty() {
+        lines.push(line.trim_end().to_string());
+    }
+    lines
+}
+
+/// The nick from a `:nick!user@host` prefix
+fn prefix_nick(prefix: &str) -> &str {
+    trim_marker(prefix).split('!').next().unwrap_or_default()
+}
+
+/// Pair each mode in a MODE change like `+ov-b` with its argument, for the
+/// modes that take one
+fn mode_changes<'a>(modes: &str, args: &[&'a str]) -> Vec<(String, &'a str)> {
+    let mut changes = Vec::new();
+    let mut args = args.iter();
+    let mut sign = '+';
+    for mode in modes.chars() {
+        match mode {
+            '+' | '-' => sign = mode,
+            // Modes that take an argument both when set and unset
+            'o' | 'v' | 'h' | 'a' | 'q' | 'b' | 'e' | 'I' | 'k' => {
+                if let Some(arg) = args.next() {
+                    changes.push((format!("{}{}", sign, mode), *arg));
+                }
+            }
+            'l' if sign == '+' => {
+                args.next();
+            }
+            _ => {}
+        }
+    }
+    changes
+}
+
+/// Format a duration as days, hours and minutes, leaving out leading zeroes
+fn format_elapsed(elapsed: Duration) -> String {
+    let minutes = elapsed.as_secs() / 60;
+    let (days, hours, minutes) = (minutes / (24 * 60), minutes / 60 % 24, minutes % 60);
+    if days > 0 {
+        format!("{}d {}h {}m", days, hours, minutes)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else {
+        format!("{}m", minutes)
+    }
+}
+
+/// Whether `nick` could be sent in a NICK command.  The server may still
+/// refuse it.
+fn is_valid_nick(nick: &str) -> bool {
+    !nick.is_empty() && !nick.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == ':')
+        && !nick.contains(|c: char| c.is_whitespace() || c.is_control() || ",*?!@#".contains(c))
+}
+
+/// Another nick to try when `nick` is in use, no longer than `limit`
+fn alt_nick(nick: &str, limit: Option<usize>) -> String {
+    let limit = limit.unwrap_or(usize::MAX);
+    if nick.chars().count() < limit {
+        return format!("{}_", nick);
+    }
+    // No room to grow, so cycle the last character through the digits
+    let mut alt: String = nick.chars().take(limit.saturating_sub(1)).collect();
+    let next = match nick.chars().nth(alt.chars().count()).and_then(|c| c.to_digit(10)) {
+        Some(digit) => (digit + 1) % 10,
+        None => 0,
+    };
+    alt.push(std::char::from_digit(next, 10).unwrap());
+    alt
+}
+
+/// The text of a PRIVMSG, without the `:` marking the trailing parameter.
+/// Spaces within the text are kept as they were sent.
+fn message_body(parts: &[String]) -> Option<String> {
+    let body = parts.get(3)?;
+    let body = body.strip_suffix('\r').unwrap_or(body);
+    Some(trim_marker(body).to_string())
+}
+
+fn trim_marker(msg: &str) -> &str {
+    if let Some(stripped) = msg.strip_prefix(':') {
+        stripped
+    } else {
+        msg
+    }
+}
+
+/// If `msg` starts with `nick:` or `nick,`, return the rest of the message
+fn strip_address<'a>(msg: &'a str, nick: &str) -> Option<&'a str> {
+    let msg = msg.trim_start();
+    match msg.get(0..nick.len()) {
+        Some(start) if start.eq_ignore_ascii_case(nick) => {
+            let rest = &msg[nick.len()..];
+            rest.strip_prefix(':')
+                .or_else(|| rest.strip_prefix(','))
+                .map(str::trim_start)
+        }
+        _ => None,
+    }
+}
+
+#[test]
+fn test_encoding() {
+    let latin1 = Encoding::for_label(b"latin1");
+    let line = b":Ren\xe9!user@host PRIVMSG #caf\xe9 :cr\xe8me++";
+    assert_eq!(irc_split(decode_line(line, latin1).as_bytes()),
+               vec![":Ren\u{e9}!user@host", "PRIVMSG", "#caf\u{e9}", ":cr\u{e8}me++"]);
+    assert_eq!(encode_line("PRIVMSG #caf\u{e9} :cr\u{e8}me = 1", latin1),
+               b"PRIVMSG #caf\xe9 :cr\xe8me = 1".to_vec());
+
+    // UTF-8 remains the default, replacing invalid sequences
+    assert_eq!(decode_line(b"caf\xc3\xa9", None), "caf\u{e9}");
+    assert_eq!(decode_line(b"caf\xe9", None), "caf\u{fffd}");
+    assert_eq!(encode_line("caf\u{e9}", None), b"caf\xc3\xa9".to_vec());
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_split_arbitrary(line in proptest::collection::vec(proptest::num::u8::ANY, 0..600)) {
+        let line = decode_line(&line, None);
+        let (_, line) = split_tags(&line);
+        let parts = irc_split(line.as_bytes());
+        message_body(&parts);
+        proptest::prop_assert!(parts.iter().all(|part| !part.is_empty()));
+    }
+}
+
+#[test]
+fn test_flow_entries() {
+    let entries: Vec<String> = (0..50)
+        .map(|n| format!("some_rather_long_identifier_number_{} = {}", n, n * 1000))
+        .collect();
+    let lines = flow_entries("Top karma: ", &entries, 120);
+    assert!(lines.len() > 1);
+    assert!(lines[0].starts_with("Top karma: some_rather_long_identifier_number_0 = 0, "));
+    for line in &lines {
+        assert!(line.len() <= 120, "Line too long: {}", line);
+    }
+    let flowed: Vec<&str> = lines.iter().flat_map(|line| line.split(", ")).collect();
+    assert_eq!(flowed[0], "Top karma: some_rather_long_identifier_number_0 = 0");
+    assert_eq!(flowed[1..], entries[1..].iter().map(String::as_str).collect::<Vec<&str>>()[..]);
+
+    assert_eq!(flow_entries("Top: ", &["a = 1".to_string(), "b = 2".to_string()], 100),
+               vec!["Top: a = 1, b = 2"]);
+    assert_eq!(flow_entries("Top: ", &["a = 1".to_string(), "b = 2".to_string()], 10),
+               vec!["Top: a = 1", "b = 2"]);
+    assert_eq!(flow_entries("Longer prefix: ", &["a = 1".to_string()], 10),
+               vec!["Longer prefix:", "a = 1"]);
+    assert_eq!(flow_entries("", &["\u{e9}\u{e9}\u{e9}".to_string()], 5), vec!["\u{e9}\u{e9}"]);
+    assert_eq!(flow_entries("Top: ", &[], 10), vec!["Top:"]);
+}
+
+#[test]
+fn test_message_body() {
+    let body = |line: &str| message_body(&irc_split(line.as_bytes()));
+    assert_eq!(body(":u!u@h PRIVMSG #chan :foo++\r"), Some("foo++".to_string()));
+    assert_eq!(body(":u!u@h PRIVMSG #chan :rot: foo::bar++ :)"),
+               Some("rot: foo::bar++ :)".to_string()));
+    assert_eq!(body(":u!u@h PRIVMSG #chan ::colon"), Some(":colon".to_string()));
+    assert_eq!(body(":u!u@h PRIVMSG #chan :  spaced   out  \r"),
+               Some("  spaced   out  ".to_string()));
+    assert_eq!(body(":u!u@h PRIVMSG #chan foo++\r"), Some("foo++".to_string()));
+    assert_eq!(body(":u!u@h PRIVMSG #chan :\r"), Some("".to_string()));
+    assert_eq!(body(":u!u@h PRIVMSG #chan :"), Some("".to_string()));
+    assert_eq!(body(":u!u@h PRIVMSG #chan"), None);
+    assert_eq!(irc_split(b"  PING  :x"), vec!["PING", ":x"]);
+    assert_eq!(irc_split(b"PING\t \t:x \ty"), vec!["PING", ":x \ty"]);
+    assert_eq!(irc_split(b"NOTICE\tme\t\thi"), vec!["NOTICE", "me", "hi"]);
+    assert_eq!(irc_split("NOTICE\u{a0}me".as_bytes()), vec!["NOTICE\u{a0}me"]);
+    assert_eq!(body(":u!u@h PRIVMSG\t#chan\t:\tfoo++\t"), Some("\tfoo++\t".to_string()));
+}
+
+#[tokio::test]
+async fn test_keepalive() {
+    let mut client = IrcClient::new("test_keepalive.db", "localhost:6667", "rot",
+                                    Config::default());
+    let line = client.keepalive().unwrap();
+    match &client.ping_state {
+        PingState::PingPending(token) => assert_eq!(line, format!("PING :{}", token)),
+        state => panic!("Unexpected {:?}", state),
+    }
+
+    client.config.keepalive = Keepalive::Notice;
+    assert_eq!(client.keepalive().as_deref(), Some("NOTICE rot :keepalive"));
+    assert_eq!(client.ping_state, PingState::Reset);
+
+    client.ping_state = PingState::Waiting;
+    client.config.keepalive = Keepalive::Off;
+    assert_eq!(client.keepalive(), None);
+    assert_eq!(client.ping_state, PingState::Reset);
+    crate::rotdb::remove_test_db("test_keepalive.db");
+}
+
+#[tokio::test]
+async fn test_pong_token() {
+    let mut client = IrcClient::new("test_pong.db", "localhost:6667", "rot", Config::default());
+    let token = client.ping_token();
+    assert_ne!(client.ping_token(), token);
+
+    client.ping_state = PingState::PingPending(token.clone());
+    client.feed(":irc.example.com PONG irc.example.com :rot\r\n").await;
+    client.feed(&format!(":irc.example.com PONG irc.example.com :{}x\r\n", token)).await;
+    client.feed(":irc.example.com PONG irc.example.com\r\n").await;
+    assert_eq!(client.ping_state, PingState::PingPending(token.clone()));
+    client.feed(&format!(":irc.example.com PONG irc.example.com :{}\r\n", token)).await;
+    assert_eq!(client.ping_state, PingState::Reset);
+
+    // A late PONG doesn't count for the next PING
+    client.ping_state = PingState::Waiting;
+    client.feed(&format!(":irc.example.com PONG irc.example.com :{}\r\n", token)).await;
+    assert_eq!(client.ping_state, PingState::Waiting);
+    crate::rotdb::remove_test_db("test_pong.db");
+}
+
+#[tokio::test]
+async fn test_sanitize() {
+    assert_eq!(sanitize("foo = 1", 10), "foo = 1");
+    assert_eq!(sanitize("a\r\nb\0c\nd", 10), "a  b c d");
+    assert_eq!(sanitize("\u{e9}\u{e9}\u{e9}", 5), "\u{e9}\u{e9}");
+
+    let mut client = IrcClient::new("test_sanitize.db", "localhost:6667", "rot",
+                                    Config::default());
+    let mut output = Vec::new();
+    client.send_privmsg(&mut output, "#chan", "foo\r\nPRIVMSG #other :pwned").await.unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(),
+               "PRIVMSG #chan :foo  PRIVMSG #other :pwned\r\n");
+    // A target can't be fixed up, so nothing is sent to it
+    let mut output = Vec::new();
+    for target in ["#chan\r\nQUIT", "#chan QUIT", "#chan\0", ""] {
+        client.send_privmsg(&mut output, target, "foo").await.unwrap();
+        client.send_notice(&mut output, target, "foo").await.unwrap();
+    }
+    assert!(output.is_empty());
+
+    client.set_topic("#chan", "Hi\r\nPRIVMSG #other :pwned\0");
+    let replies = client.feed(":user!u@h PRIVMSG #chan :?topic\r\n").await;
+    assert_eq!(replies, "PRIVMSG #chan :Topic for #chan: Hi  PRIVMSG #other :pwned \r\n");
+
+    let long = format!("{}\nQUIT", "x".repeat(MESSAGE_LIMIT));
+    client.set_topic("#chan", &long);
+    let replies = client.feed(":user!u@h PRIVMSG #chan :?topic\r\n").await;
+    assert_eq!(replies.matches("\r\n").count(), 1);
+    assert!(!replies.contains("QUIT"));
+}
+
+#[test]
+fn test_strip_address() {
+    assert_eq!(strip_address("rot: ++foo", "rot"), Some("++foo"));
+    assert_eq!(strip_address("rot, ?bar", "rot"), Some("?bar"));
+    assert_eq!(strip_address("  ROT:foo--", "rot"), Some("foo--"));
+    assert_eq!(strip_address("rot:", "rot"), Some(""));
+    assert_eq!(strip_address("++foo", "rot"), None);
+    assert_eq!(strip_address("rot ++foo", "rot"), None);
+    assert_eq!(strip_address("rotten: ++foo", "rot"), None);
+    assert_eq!(strip_address("ro", "rot"), None);
+    assert_eq!(strip_address("hey rot: ++foo", "rot"), None);
+}
+
+#[tokio::test]
+async fn test_topic() {
+    let mut client = IrcClient::new("test_topic.db", "localhost:6667", "rot", Config::default());
+    assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?topic\r\n").await,
+               "PRIVMSG #chan :No topic is set for #chan\r\n");
+
+    client.feed(":server 332 rot #Chan :Welcome to #chan: be nice\r\n").await;
+    assert_eq!(client.topics.get("#chan").map(String::as_str), Some("Welcome to #chan: be nice"));
+    assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?topic\r\n").await,
+               "PRIVMSG #chan :Topic for #chan: Welcome to #chan: be nice\r\n");
+
+    client.feed(":user!u@h TOPIC #chan :New topic\r\n").await;
+    assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?topic\r\n").await,
+               "PRIVMSG #chan :Topic for #chan: New topic\r\n");
+
+    client.feed(":user!u@h TOPIC #chan :\r\n").await;
+    assert_eq!(client.topics.get("#chan"), None);
+    client.feed(":server 332 rot #chan :Restored\r\n").await;
+    client.feed(":server 331 rot #chan :No topic is set\r\n").await;
+    assert_eq!(client.topics.get("#chan"), None);
+
+    assert_eq!(client.feed(":user!u@h PRIVMSG rot :?topic\r\n").await,
+               "PRIVMSG user :?topic only works in a channel\r\n");
+}
+
+#[tokio::test]
+async fn test_private_only() {
+    crate::rotdb::remove_test_db("test_private.db");
+    {
+        let mut client = IrcClient::new("test_private.db", "localhost:6667", "rot",
+                                        Config::default());
+        assert!(client.channels.is_empty());
+        assert_eq!(client.feed(":user!u@h PRIVMSG rot :?foo\r\n").await,
+                   "PRIVMSG user :foo = 0\r\n");
+        assert_eq!(client.feed(":user!u@h PRIVMSG Rot :foo++\r\n").await,
+                   "PRIVMSG user :foo = 1\r\n");
+        assert_eq!(client.feed(":other!u@h PRIVMSG rot :?foo\r\n").await,
+                   "PRIVMSG other :foo = 1\r\n");
+        assert_eq!(client.feed(":other!u@h PRIVMSG #chan :?foo\r\n").await,
+                   "PRIVMSG #chan :foo = 1\r\n");
+    }
+    crate::rotdb::remove_test_db("test_private.db");
+}
+
+#[tokio::test]
+async fn test_admin_normalize() {
+    let config = Config { admins: vec!["Boss".to_string()], ..Default::default() };
+    let mut client = IrcClient::new("test_normalize.db", "localhost:6667", "rot", config);
+    assert_eq!(client.feed(":boss!u@h PRIVMSG rot :normalize Foo::Bar\r\n").await,
+               "PRIVMSG boss :Foo::Bar is stored as foo.bar\r\n");
+    assert_eq!(client.feed(":boss!u@h PRIVMSG rot :normalize A->B.C::D\r\n").await,
+               "PRIVMSG boss :A->B.C::D is stored as a.b.c.d\r\n");
+
+    // Only admins may use admin commands, and only in private
+    assert_eq!(client.feed(":user!u@h PRIVMSG rot :normalize Foo::Bar\r\n").await, "");
+    assert_eq!(client.feed(":boss!u@h PRIVMSG #chan :normalize Foo::Bar\r\n").await, "");
+}
+
+#[tokio::test]
+async fn test_admin_reset() {
+    crate::rotdb::remove_test_db("test_admin_reset.db");
+    {
+        let config = Config { admins: vec!["boss".to_string()], ..Default::default() };
+        let mut client = IrcClient::new("test_admin_reset.db", "localhost:6667", "rot", config);
+        client.db.set("foo", 4);
+        let token = confirmation_token(&client.feed(":boss!u@h PRIVMSG rot :reset foo\r\n").await);
+        assert_eq!(client.db.get("foo"), Some(4));
+        assert_eq!(client.feed(&format!(":boss!u@h PRIVMSG rot :confirm {}\r\n", token)).await,
+                   "PRIVMSG boss :foo is reset, and can be restored until purged\r\n");
+        assert_eq!(client.feed(":boss!u@h PRIVMSG rot :reset nope\r\n").await,
+                   "PRIVMSG boss :nope has no value to reset\r\n");
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?foo\r\n").await,
+                   "PRIVMSG #chan :foo = 0\r\n");
+        assert_eq!(client.feed(":boss!u@h PRIVMSG rot :restore foo\r\n").await,
+                   "PRIVMSG boss :foo is restored at 4\r\n");
+        assert_eq!(client.feed(":boss!u@h PRIVMSG rot :restore foo\r\n").await,
+                   "PRIVMSG boss :foo was not reset\r\n");
+        let token = confirmation_token(&client.feed(":boss!u@h PRIVMSG rot :reset foo\r\n").await);
+        client.feed(&format!(":boss!u@h PRIVMSG rot :confirm {}\r\n", token)).await;
+        let reply = client.feed(":boss!u@h PRIVMSG rot :purge\r\n").await;
+        assert!(reply.ends_with(" within 2m to purge every reset key\r\n"));
+        assert_eq!(client.feed(&format!(":boss!u@h PRIVMSG rot :confirm {}\r\n",
+                                        confirmation_token(&reply))).await,
+                   "PRIVMSG boss :Purged 1 reset keys\r\n");
+        assert_eq!(client.db.get("foo"), None);
+    }
+    crate::rotdb::remove_test_db("test_admin_reset.db");
+}
+
+/// The token in a reply asking to confirm an admin command
+#[cfg(test)]
+fn confirmation_token(reply: &str) -> String {
+    let (_, rest) = reply.split_once("\"confirm ").unwrap();
+    rest.split('"').next().unwrap().to_string()
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_admin_confirm() {
+    crate::rotdb::remove_test_db("test_admin_confirm.db");
+    {
+        let config = Config { admins: vec!["boss".to_string(), "other".to_string()],
+                              ..Default::default() };
+        let mut client = IrcClient::new("test_admin_confirm.db", "localhost:6667", "rot", config);
+        client.db.set("foo", 4);
+        client.db.set("bar", 2);
+        let confirm = |nick: &str, token: &str| {
+            format!(":{}!u@h PRIVMSG rot :confirm {}\r\n", nick, token)
+        };
+        let reply = client.feed(":boss!u@h PRIVMSG rot :reset foo\r\n").await;
+        assert!(reply.starts_with("PRIVMSG boss :Send \"confirm "));
+        assert!(reply.ends_with("\" within 2m to reset foo\r\n"));
+        let foo_token = confirmation_token(&reply);
+        let bar_token = confirmation_token(&client.feed(":boss!u@h PRIVMSG rot :reset bar\r\n").await);
+
+        // Wrong tokens, and other admins' tokens, change nothing
+        assert_eq!(client.feed(&confirm("boss", "nope")).await,
+                   "PRIVMSG boss :Nothing is waiting for confirmation nope\r\n");
+        assert_eq!(client.feed(&confirm("other", &foo_token)).await,
+                   format!("PRIVMSG other :Nothing is waiting for confirmation {}\r\n", foo_token));
+        assert_eq!(client.db.get("foo"), Some(4));
+
+        // Each pending command has its own token, and can only be confirmed once
+        assert_eq!(client.feed(&confirm("Boss", &foo_token)).await,
+                   "PRIVMSG Boss :foo is reset, and can be restored until purged\r\n");
+        assert_eq!(client.db.get("foo"), None);
+        assert_eq!(client.feed(&confirm("boss", &foo_token)).await,
+                   format!("PRIVMSG boss :Nothing is waiting for confirmation {}\r\n", foo_token));
+
+        // Tokens expire
+        tokio::time::advance(CONFIRM_TIMEOUT).await;
+        assert_eq!(client.feed(&confirm("boss", &bar_token)).await,
+                   format!("PRIVMSG boss :Nothing is waiting for confirmation {}\r\n", bar_token));
+        assert_eq!(client.db.get("bar"), Some(2));
+    }
+    crate::rotdb::remove_test_db("test_admin_confirm.db");
+}
+
+#[tokio::test]
+async fn test_admin_prune() {
+    crate::rotdb::remove_test_db("test_admin_prune.db");
+    {
+        let config = Config { admins: vec!["boss".to_string()], ..Default::default() };
+        let mut client = IrcClient::new("test_admin_prune.db", "localhost:6667", "rot", config);
+        let old = SystemTime::now() - Duration::from_secs(100 * 24 * 60 * 60);
+        client.db.add("typo", 1, old);
+        client.db.add("popular", 10, old);
+        client.db.add("fresh", 1, SystemTime::now());
+
+        let reply = client.feed(":boss!u@h PRIVMSG rot :prune 3 30d\r\n").await;
+        assert!(reply.ends_with(" to remove the keys closer to zero than 3 \
+                                 unchanged for 30d 0h 0m\r\n"));
+        assert_eq!(client.db.get("typo"), Some(1));
+        assert_eq!(client.feed(&format!(":boss!u@h PRIVMSG rot :confirm {}\r\n",
+                                        confirmation_token(&reply))).await,
+                   "PRIVMSG boss :Pruned 1 stale keys\r\n");
+        assert_eq!(client.db.get("typo"), None);
+        assert_eq!(client.db.get("popular"), Some(10));
+        assert_eq!(client.db.get("fresh"), Some(1));
+    }
+    crate::rotdb::remove_test_db("test_admin_prune.db");
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_admin_mute() {
+    crate::rotdb::remove_test_db("test_admin_mute.db");
+    {
+        let config = Config { admins: vec!["boss".to_string()], ..Default::default() };
+        let mut client = IrcClient::new("test_admin_mute.db", "localhost:6667", "rot", config);
+        assert_eq!(client.feed(":boss!u@h PRIVMSG rot :mute #Chan\r\n").await,
+                   "PRIVMSG boss :#Chan is muted\r\n");
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :foo++\r\n").await, "");
+        assert_eq!(client.db.value("foo"), 1);
+        assert_eq!(client.feed(":user!u@h PRIVMSG #other :foo++\r\n").await,
+                   "PRIVMSG #other :foo = 2\r\n");
+        // Private messages are still answered
+        assert_eq!(client.feed(":user!u@h PRIVMSG rot :?foo\r\n").await,
+                   "PRIVMSG user :foo = 2\r\n");
+        assert_eq!(client.feed(":boss!u@h PRIVMSG rot :unmute #chan\r\n").await,
+                   "PRIVMSG boss :#chan is no longer muted\r\n");
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :foo++\r\n").await,
+                   "PRIVMSG #chan :foo = 3\r\n");
+        assert_eq!(client.feed(":boss!u@h PRIVMSG rot :unmute #chan\r\n").await,
+                   "PRIVMSG boss :#chan is not muted\r\n");
+        assert_eq!(client.feed(":boss!u@h PRIVMSG rot :mute foo\r\n").await,
+                   "PRIVMSG boss :foo is not a channel\r\n");
+
+        assert_eq!(client.feed(":boss!u@h PRIVMSG rot :mute #chan 1h\r\n").await,
+                   "PRIVMSG boss :#chan is muted for 1h 0m\r\n");
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?foo\r\n").await, "");
+        tokio::time::advance(Duration::from_secs(60 * 60)).await;
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?foo\r\n").await,
+                   "PRIVMSG #chan :foo = 3\r\n");
+    }
+    crate::rotdb::remove_test_db("test_admin_mute.db");
+}
+
+#[tokio::test]
+async fn test_verbosity() {
+    crate::rotdb::remove_test_db("test_verbosity.db");
+    {
+        let mut client = IrcClient::new("test_verbosity.db", "localhost:6667", "rot",
+                                        Config::default());
+        client.feed("@time=2024-03-01T21:30:00Z :user!u@h PRIVMSG #chan :foo++\r\n").await;
+        client.feed("@time=2024-03-01T21:30:00Z :user!u@h PRIVMSG #chan :bar++; bar++\r\n").await;
+        let query = "@time=2024-03-01T23:05:00Z :user!u@h PRIVMSG #chan :?foo\r\n";
+
+        assert_eq!(client.feed(query).await, "PRIVMSG #chan :foo = 1\r\n");
+        client.config.verbosity = Verbosity::Rank;
+        assert_eq!(client.feed(query).await, "PRIVMSG #chan :foo = 1 (rank 2/2)\r\n");
+        client.config.verbosity = Verbosity::Full;
+        assert_eq!(client.feed(query).await,
+                   "PRIVMSG #chan :foo = 1 (rank 2/2, changed 1h 35m ago)\r\n");
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?nope\r\n").await,
+                   "PRIVMSG #chan :nope = 0 (unranked)\r\n");
+    }
+    crate::rotdb::remove_test_db("test_verbosity.db");
+}
+
+#[tokio::test]
+async fn test_ranked_query() {
+    crate::rotdb::remove_test_db("test_ranked_query.db");
+    {
+        let mut client = IrcClient::new("test_ranked_query.db", "localhost:6667", "rot",
+                                        Config::default());
+        client.feed(":user!u@h PRIVMSG #chan :foo++; foo++; bar++; baz--\r\n").await;
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?!bar\r\n").await,
+                   "PRIVMSG #chan :bar = 1 (rank 2/3)\r\n");
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?bar\r\n").await,
+                   "PRIVMSG #chan :bar = 1\r\n");
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?!nope\r\n").await,
+                   "PRIVMSG #chan :nope = 0 (unranked)\r\n");
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?!foo; ?baz\r\n").await,
+                   "PRIVMSG #chan :foo = 2 (rank 1/3), baz = -1\r\n");
+        client.config.report_absent = true;
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?!nope\r\n").await,
+                   "PRIVMSG #chan :No data for nope\r\n");
+    }
+    crate::rotdb::remove_test_db("test_ranked_query.db");
+}
+
+#[tokio::test]
+async fn test_admin_addall() {
+    crate::rotdb::remove_test_db("test_admin_addall.db");
+    {
+        let config = Config { admins: vec!["boss".to_string()], ..Default::default() };
+        let mut client = IrcClient::new("test_admin_addall.db", "localhost:6667", "rot", config);
+        client.db.set("event.alice", 1);
+        client.db.set("event.bob", 2);
+        client.db.set("other", 3);
+        assert_eq!(client.feed(":boss!u@h PRIVMSG rot :addall event.* 5\r\n").await,
+                   "PRIVMSG boss :Changed 2 keys matching event.*\r\n");
+        assert_eq!(client.db.value("event.alice"), 6);
+        assert_eq!(client.db.value("event.bob"), 7);
+        assert_eq!(client.db.value("other"), 3);
+        assert_eq!(client.feed(":user!u@h PRIVMSG rot :addall event.* 5\r\n").await, "");
+        assert_eq!(client.db.value("event.bob"), 7);
+
+        // Large matches need to be confirmed
+        for n in 0..ADJUST_CONFIRM_LIMIT {
+            client.db.set(&format!("big.{}", n), 0);
+        }
+        client.db.set("big.last", 0);
+        let reply = client.feed(":boss!u@h PRIVMSG rot :addall big* 1\r\n").await;
+        assert!(reply.ends_with(" within 2m to add 1 to the 11 keys matching big*\r\n"));
+        assert_eq!(client.db.value("big.last"), 0);
+        let token = confirmation_token(&client.feed(":boss!u@h PRIVMSG rot :addall big* 2\r\n").await);
+        assert_eq!(client.feed(&format!(":boss!u@h PRIVMSG rot :confirm {}\r\n", token)).await,
+                   "PRIVMSG boss :Changed 11 keys matching big*\r\n");
+        assert_eq!(client.db.value("big.last"), 2);
+        assert_eq!(client.db.value("event.bob"), 7);
+    }
+    crate::rotdb::remove_test_db("test_admin_addall.db");
+}
+
+#[tokio::test]
+async fn test_recent() {
+    crate::rotdb::remove_test_db("test_recent.db");
+    {
+        let mut client = IrcClient::new("test_recent.db", "localhost:6667", "rot",
+                                        Config::default());
+        let at = |time: &str, message: &str| {
+            format!("@time=2024-03-01T{}Z :user!u@h PRIVMSG #chan :{}\r\n", time, message)
+        };
+        assert_eq!(client.feed(&at("12:00:00", "?recent")).await,
+                   "PRIVMSG #chan :Nothing has changed in the last 1h 0m\r\n");
+        client.feed(&at("10:00:00", "old++")).await;
+        client.feed(&at("11:30:00", "foo++")).await;
+        client.feed(&at("11:45:00", "bar--")).await;
+        assert_eq!(client.feed(&at("12:00:00", "?recent")).await,
+                   "PRIVMSG #chan :Changed in the last 1h 0m: bar = -1, foo = 1\r\n");
+
+        client.config.recent_window = Duration::from_secs(3 * 60 * 60);
+        for n in 0..RECENT_LIMIT + 2 {
+            client.feed(&at("11:50:00", &format!("key{}++", n))).await;
+        }
+        let reply = client.feed(&at("12:00:00", "?recent")).await;
+        let lines: Vec<&str> = reply.lines().collect();
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|line| line.len() <= MESSAGE_LIMIT + "PRIVMSG #chan :\r".len()));
+        assert!(lines[0].starts_with("PRIVMSG #chan :Changed in the last 3h 0m: key0 = 1, key1 = 1"));
+        assert!(reply.ends_with(", and 5 more\r\n"));
+    }
+    crate::rotdb::remove_test_db("test_recent.db");
+}
+
+#[tokio::test]
+async fn test_replayed_backlog() {
+    crate::rotdb::remove_test_db("test_replayed_backlog.db");
+    {
+        let mut client = IrcClient::new("test_replayed_backlog.db", "localhost:6667", "rot",
+                                        Config::default());
+        client.connected_time = Some(SystemTime::now());
+        for message in ["foo++", "?foo", "?top"] {
+            let line = format!("@time=2024-03-01T21:30:00.000Z :user!u@h PRIVMSG #chan :{}\r\n",
+                               message);
+            assert_eq!(client.feed(&line).await, "");
+        }
+        assert_eq!(client.db.get("foo"), None);
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :foo++\r\n").await,
+                   "PRIVMSG #chan :foo = 1\r\n");
+
+        // Without a connection time, only the age limit applies
+        client.connected_time = None;
+        assert_eq!(client.feed("@time=2024-03-01T21:30:00Z :user!u@h PRIVMSG #chan :?foo\r\n")
+                         .await, "PRIVMSG #chan :foo = 1\r\n");
+        client.config.max_message_age = Some(Duration::from_secs(60 * 60));
+        assert_eq!(client.feed("@time=2024-03-01T21:30:00Z :user!u@h PRIVMSG #chan :?foo\r\n")
+                         .await, "");
+        assert!(!client.is_replayed(SystemTime::now() - Duration::from_secs(60)));
+        client.connected_time = Some(SystemTime::now());
+        assert!(!client.is_replayed(SystemTime::now() - Duration::from_secs(1)));
+        assert!(client.is_replayed(SystemTime::now() - Duration::from_secs(60)));
+
+        // Once connected, the server's clock is the one that counts, even if
+        // it's far behind ours
+        client.config.max_message_age = None;
+        client.connected_at = Some(Instant::now());
+        client.connected_time = None;
+        client.feed("@time=2024-03-01T21:30:00Z :user!u@h PRIVMSG #chan :foo++\r\n\
+                     @time=2024-03-01T22:00:00Z :irc.example.com NOTICE rot :Hello\r\n").await;
+        assert_eq!(client.db.get("foo"), Some(2));
+        assert_eq!(client.feed("@time=2024-03-01T21:59:58Z :user!u@h PRIVMSG #chan :foo++\r\n")
+                         .await, "PRIVMSG #chan :foo = 3\r\n");
+        assert_eq!(client.feed("@time=2024-03-01T21:30:00Z :user!u@h PRIVMSG #chan :foo++\r\n")
+                         .await, "");
+    }
+    crate::rotdb::remove_test_db("test_replayed_backlog.db");
+}
+
+#[tokio::test]
+async fn test_thanks() {
+    crate::rotdb::remove_test_db("test_thanks.db");
+    {
+        let mut client = IrcClient::new("test_thanks.db", "localhost:6667", "rot",
+                                        Config::default());
+        client.feed(":irc.example.com 353 rot = #chan :rot alice @Bob\r\n").await;
+        // Off by default
+        assert_eq!(client.feed(":carol!u@h PRIVMSG #chan :thanks alice\r\n").await, "");
+
+        client.config.thanks = vec!["thanks".to_string(), "thx".to_string()];
+        assert_eq!(client.feed(":carol!u@h PRIVMSG #chan :thanks alice\r\n").await,
+                   "PRIVMSG #chan :alice = 1\r\n");
+        assert_eq!(client.feed(":carol!u@h PRIVMSG #chan :bob: THX!\r\n").await,
+                   "PRIVMSG #chan :bob = 1\r\n");
+        assert_eq!(client.feed(":carol!u@h PRIVMSG #chan :vim++ thanks\r\n").await,
+                   "PRIVMSG #chan :vim = 1\r\n");
+        // Nobody called all, dave isn't here, and anything longer isn't thanks
+        for message in ["thanks all", "thanks dave", "thanks alice for that", "no thanks"] {
+            assert_eq!(client.feed(&format!(":carol!u@h PRIVMSG #chan :{}\r\n", message)).await,
+                       "");
+        }
+        // Nicks are only known in channels
+        assert_eq!(client.feed(":carol!u@h PRIVMSG rot :thanks alice\r\n").await, "");
+        assert_eq!(client.db.get("alice"), Some(1));
+        assert_eq!(client.db.get("all"), None);
+    }
+    crate::rotdb::remove_test_db("test_thanks.db");
+}
+
+#[tokio::test]
+async fn test_query_prefix() {
+    crate::rotdb::remove_test_db("test_query_prefix.db");
+    {
+        let config = Config::parse("query_prefix = !karma #Other").unwrap();
+        let mut client = IrcClient::new("test_query_prefix.db", "localhost:6667", "rot", config);
+        client.db.set("foo", 3);
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?foo\r\n").await,
+                   "PRIVMSG #chan :foo = 3\r\n");
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :!karma foo\r\n").await, "");
+        assert_eq!(client.feed(":user!u@h PRIVMSG #other :?foo\r\n").await, "");
+        assert_eq!(client.feed(":user!u@h PRIVMSG #other :!karma foo\r\n").await,
+                   "PRIVMSG #other :foo = 3\r\n");
+        assert_eq!(client.feed(":user!u@h PRIVMSG #other :foo++; ?foo\r\n").await,
+                   "PRIVMSG #other :foo = 4\r\n");
+        // Private messages use the global prefix
+        assert_eq!(client.feed(":user!u@h PRIVMSG rot :?foo\r\n").await,
+                   "PRIVMSG user :foo = 4\r\n");
+    }
+    crate::rotdb::remove_test_db("test_query_prefix.db");
+}
+
+#[tokio::test]
+async fn test_long_lines() {
+    crate::rotdb::remove_test_db("test_long_lines.db");
+    {
+        let mut client = IrcClient::new("test_long_lines.db", "localhost:6667", "rot",
+                                        Config::default());
+        let mut lines = LineBuffer::default();
+        let mut output = Vec::new();
+
+        // A long but valid line still counts, even one byte at a time
+        let tags = format!("@+example={}", "x".repeat(8000));
+        let line = format!("{} :user!u@h PRIVMSG #chan :foo++\r\n", tags);
+        assert!(line.len() < MAX_LINE_LENGTH);
+        for byte in line.as_bytes() {
+            let complete = lines.extend(std::slice::from_ref(byte));
+            client.process_lines(&complete, &mut output).await;
+        }
+        assert_eq!(String::from_utf8(std::mem::take(&mut output)).unwrap(),
+                   "PRIVMSG #chan :foo = 1\r\n");
+
+        // One that never ends is dropped without keeping all of it, up to
+        // the end of the line
+        let endless = format!(":user!u@h PRIVMSG #chan :foo++ {}", "y".repeat(1000));
+        for _ in 0..20 {
+            assert!(lines.extend(endless.as_bytes()).is_empty());
+            assert!(lines.partial.len() <= MAX_LINE_LENGTH);
+        }
+        let complete = lines.extend(b"yyy\r\n:user!u@h PRIVMSG #chan :bar++\r\n:user!u@h");
+        client.process_lines(&complete, &mut output).await;
+        assert_eq!(String::from_utf8(std::mem::take(&mut output)).unwrap(),
+                   "PRIVMSG #chan :bar = 1\r\n");
+        assert_eq!(client.db.get("foo"), Some(1));
+
+        // The line after it is kept whole
+        let complete = lines.extend(b" PRIVMSG #chan :baz++\r\n");
+        client.process_lines(&complete, &mut output).await;
+        assert_eq!(String::from_utf8(output).unwrap(), "PRIVMSG #chan :baz = 1\r\n");
+    }
+    crate::rotdb::remove_test_db("test_long_lines.db");
+}
+
+#[tokio::test]
+async fn test_private_query_channel() {
+    crate::rotdb::remove_test_db("test_private_query_channel.db");
+    {
+        let mut client = IrcClient::new("test_private_query_channel.db", "localhost:6667", "rot",
+                                        Config::default());
+        client.db.set("foo", 3);
+        client.feed(":irc.example.com 353 rot = #karma :rot alice\r\n").await;
+        // Private by default
+        assert_eq!(client.feed(":alice!u@h PRIVMSG rot :?foo\r\n").await,
+                   "PRIVMSG alice :foo = 3\r\n");
+
+        client.config.private_query_channel = Some("#karma".to_string());
+        assert_eq!(client.feed(":alice!u@h PRIVMSG rot :?foo; ?!bar\r\n").await,
+                   "PRIVMSG #karma :foo = 3, bar = 0 (unranked)\r\n");
+        // Changes, other commands and senders who aren't in the channel stay
+        // private
+        assert_eq!(client.feed(":alice!u@h PRIVMSG rot :foo++\r\n").await,
+                   "PRIVMSG alice :foo = 4\r\n");
+        assert_eq!(client.feed(":alice!u@h PRIVMSG rot :foo++; ?foo\r\n").await,
+                   "PRIVMSG alice :foo = 5, foo = 5\r\n");
+        assert_eq!(client.feed(":alice!u@h PRIVMSG rot :?top\r\n").await,
+                   "PRIVMSG alice :Top karma: foo = 5\r\n");
+        assert_eq!(client.feed(":carol!u@h PRIVMSG rot :?foo\r\n").await,
+                   "PRIVMSG carol :foo = 5\r\n");
+        // Channel messages are answered where they were sent
+        assert_eq!(client.feed(":alice!u@h PRIVMSG #chan :?foo\r\n").await,
+                   "PRIVMSG #chan :foo = 5\r\n");
+    }
+    crate::rotdb::remove_test_db("test_private_query_channel.db");
+}
+
+#[tokio::test]
+async fn test_multiple_targets() {
+    crate::rotdb::remove_test_db("test_multiple_targets.db");
+    {
+        let mut client = IrcClient::new("test_multiple_targets.db", "localhost:6667", "rot",
+                                        Config::default());
+        assert_eq!(client.reply_channel("#a,#b"), Some("#a"));
+        assert_eq!(client.reply_channel("rot,#b"), Some("#b"));
+        assert_eq!(client.reply_channel("rot,other"), None);
+        client.join("#B");
+        assert_eq!(client.reply_channel("#a,#b"), Some("#b"));
+        assert_eq!(client.reply_channel("#a,#c"), Some("#a"));
+
+        assert_eq!(client.feed(":user!u@h PRIVMSG #a,#b :foo++\r\n").await,
+                   "PRIVMSG #b :foo = 1\r\n");
+        assert_eq!(client.db.get("foo"), Some(1));
+        assert_eq!(client.feed(":user!u@h PRIVMSG rot,#b :?foo\r\n").await,
+                   "PRIVMSG #b :foo = 1\r\n");
+        assert_eq!(client.feed(":user!u@h PRIVMSG rot,other :?foo\r\n").await,
+                   "PRIVMSG user :foo = 1\r\n");
+    }
+    crate::rotdb::remove_test_db("test_multiple_targets.db");
+}
+
+#[tokio::test]
+async fn test_history_queries() {
+    crate::rotdb::remove_test_db("test_history_queries.db");
+    {
+        let mut client = IrcClient::new("test_history_queries.db", "localhost:6667", "rot",
+                                        Config::default());
+        client.feed(":alice!u@h PRIVMSG #chan :foo++\r\n").await;
+        assert_eq!(client.feed(":alice!u@h PRIVMSG #chan :?whodid foo\r\n").await,
+                   "PRIVMSG #chan :History tracking is disabled\r\n");
+        assert_eq!(client.feed(":alice!u@h PRIVMSG #chan :?mine\r\n").await,
+                   "PRIVMSG #chan :History tracking is disabled\r\n");
+
+        client.db.enable_history();
+        client.feed(":alice!u@h PRIVMSG #chan :foo++\r\n:Bob!u@h PRIVMSG #chan :foo--\r\n\
+                     :alice!u@h PRIVMSG #chan :bar++\r\n:alice!u@h PRIVMSG #chan :Foo++\r\n")
+              .await;
+        assert_eq!(client.feed(":carol!u@h PRIVMSG #chan :?whodid foo\r\n").await,
+                   "PRIVMSG #chan :foo was changed by alice (+2/-0), bob (+0/-1)\r\n");
+        assert_eq!(client.feed(":carol!u@h PRIVMSG #chan :?whodid baz\r\n").await,
+                   "PRIVMSG #chan :Nobody has changed baz\r\n");
+        assert_eq!(client.feed(":alice!u@h PRIVMSG rot :?mine\r\n").await,
+                   "PRIVMSG alice :alice has changed foo (+2/-0), bar (+1/-0)\r\n");
+        assert_eq!(client.feed(":carol!u@h PRIVMSG #chan :?mine\r\n").await,
+                   "PRIVMSG #chan :carol hasn't changed anything\r\n");
+    }
+    crate::rotdb::remove_test_db("test_history_queries.db");
+}
+
+#[tokio::test]
+async fn test_user_totals() {
+    crate::rotdb::remove_test_db("test_user_totals_client.db");
+    {
+        let mut client = IrcClient::new("test_user_totals_client.db", "localhost:6667", "rot",
+                                        Config::default());
+        assert_eq!(client.feed(":alice!u@h PRIVMSG #chan :?karma-given alice\r\n").await,
+                   "PRIVMSG #chan :User totals are disabled\r\n");
+
+        client.config.user_totals = true;
+        client.db.enable_user_totals();
+        client.feed(":irc.example.com 353 rot = #chan :rot Alice Bob\r\n").await;
+        client.feed(":alice!u@h PRIVMSG #chan :bob++\r\n\
+                     :Alice!u@h PRIVMSG #chan :BOB++\r\n\
+                     :alice!u@h PRIVMSG #chan :vim--\r\n\
+                     :bob!u@h PRIVMSG #chan :alice--\r\n").await;
+        assert_eq!(client.feed(":carol!u@h PRIVMSG #chan :?karma-given ALICE\r\n").await,
+                   "PRIVMSG #chan :ALICE has given 1 (+2/-1)\r\n");
+        assert_eq!(client.feed(":carol!u@h PRIVMSG #chan :?karma-received Bob\r\n").await,
+                   "PRIVMSG #chan :Bob has received 2 (+2/-0)\r\n");
+        assert_eq!(client.feed(":carol!u@h PRIVMSG #chan :?karma-received alice\r\n").await,
+                   "PRIVMSG #chan :alice has received -1 (+0/-1)\r\n");
+        // vim isn't anyone's nick, and carol hasn't changed anything
+        assert_eq!(client.feed(":carol!u@h PRIVMSG #chan :?karma-received vim\r\n").await,
+                   "PRIVMSG #chan :vim hasn't received any karma\r\n");
+        assert_eq!(client.feed(":bob!u@h PRIVMSG #chan :?karma-given carol\r\n").await,
+                   "PRIVMSG #chan :carol hasn't given any karma\r\n");
+        client.db.sync();
+    }
+    {
+        // The totals are saved alongside the db
+        let config = Config { user_totals: true, ..Config::default() };
+        let mut client = IrcClient::new("test_user_totals_client.db", "localhost:6667", "rot",
+                                        config);
+        assert_eq!(client.feed(":carol!u@h PRIVMSG #chan :?karma-received bob\r\n").await,
+                   "PRIVMSG #chan :bob has received 2 (+2/-0)\r\n");
+    }
+    crate::rotdb::remove_test_db("test_user_totals_client.db");
+}
+
+#[tokio::test]
+async fn test_query_variants() {
+    crate::rotdb::remove_test_db("test_query_variants.db");
+    {
+        let mut client = IrcClient::new("test_query_variants.db", "localhost:6667", "rot",
+                                        Config::default());
+        let say = |message: &str| format!(":user!u@h PRIVMSG #chan :{}\r\n", message);
+        client.feed(&say("foo.bar++")).await;
+        client.feed(&say("FOO.BAR++")).await;
+        // Only differing in case
+        assert_eq!(client.feed(&say("?foo.bar")).await, "PRIVMSG #chan :foo.bar = 2\r\n");
+
+        client.feed(&say("Foo::Bar++")).await;
+        client.feed(&say("foo->bar++")).await;
+        assert_eq!(client.feed(&say("?Foo::Bar")).await,
+                   "PRIVMSG #chan :Foo::Bar = 4 (also counted as: FOO.BAR, foo->bar)\r\n");
+        assert_eq!(client.feed(&say("?!foo.bar")).await,
+                   "PRIVMSG #chan :foo.bar = 4 (rank 1/1) \
+                    (also counted as: Foo::Bar, foo->bar)\r\n");
+        assert_eq!(client.feed(&say("?foo::bar; ?baz")).await,
+                   "PRIVMSG #chan :foo::bar = 4 \
+                    (also counted as: FOO.BAR, foo->bar), baz = 0\r\n");
+
+        for key in ["a.b.c", "a::b.c", "a->b::c", "a.b->c", "a::b::c"] {
+            client.feed(&say(&format!("{}++", key))).await;
+        }
+        assert_eq!(client.feed(&say("?a.b.c")).await,
+                   "PRIVMSG #chan :a.b.c = 5 (also counted as: a->b::c, a.b->c, a::b.c, \
+                    and 1 more)\r\n");
+    }
+    crate::rotdb::remove_test_db("test_query_variants.db");
+}
+
+#[tokio::test]
+async fn test_count() {
+    crate::rotdb::remove_test_db("test_count.db");
+    {
+        let config = Config { admins: vec!["boss".to_string()], ..Default::default() };
+        let mut client = IrcClient::new("test_count.db", "localhost:6667", "rot", config);
+        let say = |message: &str| format!(":user!u@h PRIVMSG #chan :{}\r\n", message);
+        assert_eq!(client.feed(&say("?count")).await, "PRIVMSG #chan :Tracking 0 identifiers\r\n");
+        // Queries don't add keys, and different forms of a key count once
+        client.feed(&say("foo++; bar++; baz--")).await;
+        client.feed(&say("Foo++; ?nothing")).await;
+        client.feed(&say("baz++")).await;
+        assert_eq!(client.feed(&say("?count")).await, "PRIVMSG #chan :Tracking 3 identifiers\r\n");
+
+        // Frozen keys still count, but reset ones don't until restored
+        client.feed(":boss!u@h PRIVMSG rot :freeze foo\r\n").await;
+        client.db.reset("bar");
+        assert_eq!(client.feed(&say("?count")).await, "PRIVMSG #chan :Tracking 2 identifiers\r\n");
+        client.db.restore("bar");
+        assert_eq!(client.feed(&say("?count")).await, "PRIVMSG #chan :Tracking 3 identifiers\r\n");
+    }
+    crate::rotdb::remove_test_db("test_count.db");
+}
+
+#[tokio::test]
+async fn test_find() {
+    crate::rotdb::remove_test_db("test_find.db");
+    {
+        let config = Config { denied_keys: vec!["rust.sucks".to_string()], ..Config::default() };
+        let mut client = IrcClient::new("test_find.db", "localhost:6667", "rot", config);
+        for (key, value) in [("rust", 10), ("trusty", 3), ("rust.sucks", 1), ("ruby", 20)] {
+            client.db.set(key, value);
+        }
+        let find = |text: &str| format!(":user!u@h PRIVMSG #chan :?find {}\r\n", text);
+        assert_eq!(client.feed(&find("RUST")).await,
+                   "PRIVMSG #chan :Keys containing RUST: rust = 10, trusty = 3\r\n");
+        assert_eq!(client.feed(&find("java")).await, "PRIVMSG #chan :No keys contain java\r\n");
+
+        for n in 0..FIND_LIMIT + 3 {
+            client.db.set(&format!("many{}", n), n as i64);
+        }
+        let reply = client.feed(&find("many")).await;
+        let lines: Vec<&str> = reply.lines().collect();
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|line| line.len() <= MESSAGE_LIMIT + "PRIVMSG #chan :\r".len()));
+        assert!(lines[0].starts_with("PRIVMSG #chan :Keys containing many: many52 = 52, many51 = 51"));
+        assert!(reply.ends_with(", and 3 more\r\n"));
+    }
+    crate::rotdb::remove_test_db("test_find.db");
+}
+
+#[tokio::test]
+async fn test_denied_keys() {
+    crate::rotdb::remove_test_db("test_denied_keys.db");
+    {
+        let config = Config { denied_keys: vec!["Bad::Word".to_string()], ..Config::default() };
+        let mut client = IrcClient::new("test_denied_keys.db", "localhost:6667", "rot", config);
+        for message in &["bad.word++", "--BAD->word", "?bad::word", "?!bad.word",
+                         "?whodid bad.word", "?diff foo bad.word"] {
+            assert_eq!(client.feed(&format!(":user!u@h PRIVMSG #chan :{}\r\n", message)).await, "");
+        }
+        assert_eq!(client.db.get("bad.word"), None);
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :good++; bad.word++\r\n").await,
+                   "PRIVMSG #chan :good = 1\r\n");
+        assert_eq!(client.db.get("bad.word"), None);
+
+        client.config.warn_denied = true;
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :bad.word++\r\n").await,
+                   "NOTICE user :That key can't be used\r\n");
+
+        // Nor can admins change them
+        client.config.admins = vec!["boss".to_string()];
+        assert_eq!(client.feed(":boss!u@h PRIVMSG rot :set Bad::Word 5\r\n").await,
+                   "PRIVMSG boss :Bad::Word can't be used\r\n");
+        assert_eq!(client.feed(":boss!u@h PRIVMSG rot :freeze bad.word\r\n").await,
+                   "PRIVMSG boss :bad.word can't be used\r\n");
+        assert_eq!(client.feed(":boss!u@h PRIVMSG rot :addall * 1\r\n").await,
+                   "PRIVMSG boss :Changed 1 keys matching *\r\n");
+        assert_eq!(client.db.get("bad.word"), None);
+
+        // A reloaded config can change the list
+        let mut config = client.config.clone();
+        config.denied_keys.clear();
+        client.apply_config(config);
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :bad.word++\r\n").await,
+                   "PRIVMSG #chan :bad.word = 1\r\n");
+    }
+    crate::rotdb::remove_test_db("test_denied_keys.db");
+}
+
+#[tokio::test]
+async fn test_max_keys() {
+    crate::rotdb::remove_test_db("test_max_keys.db");
+    {
+        let config = Config { max_keys: Some(2), ..Config::default() };
+        let mut client = IrcClient::new("test_max_keys.db", "localhost:6667", "rot", config);
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :foo++; bar--\r\n").await,
+                   "PRIVMSG #chan :foo = 1, bar = -1\r\n");
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :baz++\r\n").await,
+                   "NOTICE user :Not tracking any new keys, so baz can't be changed\r\n");
+        assert_eq!(client.db.get("baz"), None);
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :FOO++; baz++\r\n").await,
+                   "PRIVMSG #chan :FOO = 2\r\n\
+                    NOTICE user :Not tracking any new keys, so baz can't be changed\r\n");
+
+        // A reloaded config can raise the limit
+        let config = Config { max_keys: Some(3), ..client.config.clone() };
+        client.apply_config(config);
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :baz++\r\n").await,
+                   "PRIVMSG #chan :baz = 1\r\n");
+    }
+    crate::rotdb::remove_test_db("test_max_keys.db");
+}
+
+#[tokio::test]
+async fn test_account_notify() {
+    let mut client = IrcClient::new("test_account_notify.db", "localhost:6667", "rot",
+                                    Config::default());
+    assert_eq!(client.account("alice"), None);
+    client.feed(":Alice!u@h ACCOUNT alice_acct\r\n").await;
+    assert_eq!(client.account("ALICE"), Some("alice_acct"));
+    client.feed(":alice!u@h ACCOUNT :alice2\r\n").await;
+    assert_eq!(client.account("alice"), Some("alice2"));
+
+    // The account follows a nick change, and goes when they log out or quit
+    client.feed(":alice!u@h NICK :ally\r\n").await;
+    assert_eq!(client.account("alice"), None);
+    assert_eq!(client.account("ally"), Some("alice2"));
+    client.feed(":ally!u@h ACCOUNT *\r\n").await;
+    assert_eq!(client.account("ally"), None);
+    client.feed(":bob!u@h ACCOUNT bob\r\n:bob!u@h QUIT :bye\r\n").await;
+    assert_eq!(client.account("bob"), None);
+
+    // Or when they leave the last channel we share with them
+    client.feed(":irc.example.com 353 rot = #a :rot carol dave\r\n\
+                 :irc.example.com 353 rot = #b :rot carol\r\n\
+                 :carol!u@h ACCOUNT carol\r\n:dave!u@h ACCOUNT dave\r\n\
+                 :carol!u@h PART #a\r\n").await;
+    assert_eq!(client.account("carol"), Some("carol"));
+    client.feed(":op!u@h KICK #b carol :bye\r\n").await;
+    assert_eq!(client.account("carol"), None);
+    // Or when we leave it
+    client.feed(":rot!r@h PART #a\r\n").await;
+    assert_eq!(client.account("dave"), None);
+    crate::rotdb::remove_test_db("test_account_notify.db");
+}
+
+#[tokio::test]
+async fn test_admin_account() {
+    let config = Config { admins: vec!["boss".to_string()], ..Default::default() };
+    let mut client = IrcClient::new("test_admin_account.db", "localhost:6667", "rot", config);
+    let normalize = |nick: &str| format!(":{}!u@h PRIVMSG rot :normalize Foo\r\n", nick);
+    let reply = |nick: &str| format!("PRIVMSG {} :Foo is stored as foo\r\n", nick);
+    // Until account-notify says otherwise, the nick is trusted
+    assert_eq!(client.feed(&normalize("boss")).await, reply("boss"));
+
+    // Then it's the account that counts, whatever the nick
+    client.feed(":boss!u@h ACCOUNT mallory\r\n:alt!u@h ACCOUNT Boss\r\n").await;
+    assert_eq!(client.feed(&normalize("boss")).await, "");
+    assert_eq!(client.feed(&normalize("alt")).await, reply("alt"));
+    client.feed(":alt!u@h ACCOUNT *\r\n").await;
+    assert_eq!(client.feed(&normalize("alt")).await, "");
+    client.feed(":alt!u@h NICK :boss2\r\n:boss2!u@h ACCOUNT boss\r\n").await;
+    assert_eq!(client.feed(&normalize("boss2")).await, reply("boss2"));
+    crate::rotdb::remove_test_db("test_admin_account.db");
+}
+
+#[tokio::test]
+async fn test_have_op() {
+    crate::rotdb::remove_test_db("test_have_op.db");
+    {
+        let config = Config { admins: vec!["boss".to_string()], ..Default::default() };
+        let mut client = IrcClient::new("test_have_op.db", "localhost:6667", "rot", config);
+        client.feed(":irc.example.com 353 rot = #chan :rot @Op\r\n\
+                     :irc.example.com 353 rot = #other :@rot\r\n").await;
+        assert!(!client.have_op("#chan"));
+        assert!(client.have_op("#other"));
+
+        client.feed(":Op!u@h MODE #chan +vo user ROT\r\n").await;
+        assert!(client.have_op("#CHAN"));
+        assert_eq!(client.feed(":boss!u@h PRIVMSG rot :haveop #chan\r\n").await,
+                   "PRIVMSG boss :I have ops in #chan\r\n");
+
+        // Ops follow our nick, and are lost when taken away
+        client.feed(":rot!u@h NICK :rotbot\r\n").await;
+        assert!(client.have_op("#chan"));
+        client.feed(":Op!u@h MODE #chan -o rotbot\r\n").await;
+        assert!(!client.have_op("#chan"));
+        assert!(client.have_op("#other"));
+        assert_eq!(client.feed(":boss!u@h PRIVMSG rotbot :haveop #chan\r\n").await,
+                   "PRIVMSG boss :I don't have ops in #chan\r\n");
+        assert!(!client.have_op("#nowhere"));
+    }
+    crate::rotdb::remove_test_db("test_have_op.db");
+}
+
+#[tokio::test]
+async fn test_admin_nick() {
+    crate::rotdb::remove_test_db("test_admin_nick.db");
+    {
+        let config = Config { admins: vec!["boss".to_string()], ..Default::default() };
+        let mut client = IrcClient::new("test_admin_nick.db", "localhost:6667", "rot", config);
+        assert_eq!(client.feed(":boss!u@h PRIVMSG rot :nick rotbot\r\n").await,
+                   "PRIVMSG boss :Changing nick to rotbot\r\nNICK rotbot\r\n");
+        // Nothing changes until the server confirms it
+        assert_eq!(client.nick, "rot");
+        client.feed(":rot!u@h NICK :rotbot\r\n").await;
+        assert_eq!(client.nick, "rotbot");
+        assert_eq!(client.pending_nick, None);
+
+        // Refused by the server
+        assert_eq!(client.feed(":boss!u@h PRIVMSG rotbot :nick taken\r\n").await,
+                   "PRIVMSG boss :Changing nick to taken\r\nNICK taken\r\n");
+        assert_eq!(client.feed(":irc.example.com 433 rotbot taken :Nickname is already in use\r\n").await,
+                   "PRIVMSG boss :Could not change nick to taken (Nickname is already in use), \
+                    still rotbot\r\n");
+        assert_eq!(client.nick, "rotbot");
+        assert_eq!(client.pending_nick, None);
+
+        assert_eq!(client.feed(":boss!u@h PRIVMSG rotbot :nick RotBot\r\n").await,
+                   "PRIVMSG boss :Already using rotbot\r\n");
+        assert_eq!(client.feed(":boss!u@h PRIVMSG rotbot :nick 9lives\r\n").await,
+                   "PRIVMSG boss :Invalid nick 9lives\r\n");
+        assert_eq!(client.feed(":user!u@h PRIVMSG rotbot :nick mine\r\n").await, "");
+    }
+    crate::rotdb::remove_test_db("test_admin_nick.db");
+}
+
+#[tokio::test]
+async fn test_admin_export() {
+    crate::rotdb::remove_test_db("test_admin_export.db");
+    {
+        let config = Config { admins: vec!["boss".to_string()], ..Default::default() };
+        let mut client = IrcClient::new("test_admin_export.db", "localhost:6667", "rot", config);
+        client.feed(":user!u@h PRIVMSG #chan :foo++\r\n").await;
+        assert_eq!(client.feed(":boss!u@h PRIVMSG rot :export csv\r\n").await,
+                   "PRIVMSG boss :Exported to test_admin_export.db.csv\r\n");
+        let csv = std::fs::read_to_string("test_admin_export.db.csv").unwrap();
+        assert!(csv.starts_with("key,value,ups,downs,last_modified\nfoo,1,,,"));
+        assert_eq!(client.feed(":user!u@h PRIVMSG rot :export csv\r\n").await, "");
+    }
+    crate::rotdb::remove_test_db("test_admin_export.db");
+}
+
+#[tokio::test]
+async fn test_reply_templates() {
+    crate::rotdb::remove_test_db("test_templates.db");
+    {
+        let mut config = Config::default();
+        config.templates.query = "{key} has {value} points (rank {rank})".to_string();
+        config.templates.change = "{key} is now {value}".to_string();
+        config.templates.top_entry = "#{rank} {key} ({value})".to_string();
+        let mut client = IrcClient::new("test_templates.db", "localhost:6667", "rot", config);
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :foo++\r\n").await,
+                   "PRIVMSG #chan :foo is now 1\r\n");
+        client.feed(":user!u@h PRIVMSG #chan :bar++\r\n").await;
+        client.feed(":user!u@h PRIVMSG #chan :baz--\r\n").await;
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?baz\r\n").await,
+                   "PRIVMSG #chan :baz has -1 points (rank 3)\r\n");
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?nothing\r\n").await,
+                   "PRIVMSG #chan :nothing has 0 points (rank -)\r\n");
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?top\r\n").await,
+                   "PRIVMSG #chan :Top karma: #1 bar (1), #1 foo (1), #3 baz (-1)\r\n");
+    }
+    crate::rotdb::remove_test_db("test_templates.db");
+}
+
+#[tokio::test]
+async fn test_report_absent() {
+    crate::rotdb::remove_test_db("test_absent.db");
+    crate::rotdb::remove_test_db("test_absent2.db");
+    {
+        let mut client = IrcClient::new("test_absent.db", "localhost:6667", "rot",
+                                        Config::default());
+        client.feed(":user!u@h PRIVMSG #chan :zero++\r\n:user!u@h PRIVMSG #chan :zero--\r\n").await;
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?zero\r\n").await,
+                   "PRIVMSG #chan :zero = 0\r\n");
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?absent\r\n").await,
+                   "PRIVMSG #chan :absent = 0\r\n");
+
+        let config = Config { report_absent: true, ..Default::default() };
+        let mut client = IrcClient::new("test_absent2.db", "localhost:6667", "rot", config);
+        client.feed(":user!u@h PRIVMSG #chan :zero++\r\n:user!u@h PRIVMSG #chan :zero--\r\n").await;
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?zero\r\n").await,
+                   "PRIVMSG #chan :zero = 0\r\n");
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?absent\r\n").await,
+                   "PRIVMSG #chan :No data for absent\r\n");
+
+        client.config = Config::parse("report_absent = true\n\
+                                       absent_template = I don't track '{key}'").unwrap();
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?zero\r\n").await,
+                   "PRIVMSG #chan :zero = 0\r\n");
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?Absent\r\n").await,
+                   "PRIVMSG #chan :I don't track 'Absent'\r\n");
+        // The template is only used with report_absent
+        client.config.report_absent = false;
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?absent\r\n").await,
+                   "PRIVMSG #chan :absent = 0\r\n");
+    }
+    crate::rotdb::remove_test_db("test_absent.db");
+    crate::rotdb::remove_test_db("test_absent2.db");
+}
+
+#[tokio::test]
+async fn test_ban_backoff() {
+    let mut client = IrcClient::new("test_ban.db", "localhost:6667", "rot", Config::default());
+    assert_eq!(client.next_reconnect_delay(), Some(RECONNECT_DELAY));
+
+    client.feed(":oper!o@h KILL someone :Spamming\r\n").await;
+    assert_eq!(client.next_reconnect_delay(), Some(RECONNECT_DELAY));
+    client.feed(":oper!o@h KILL Rot :Spamming\r\n").await;
+    assert_eq!(client.next_reconnect_delay(), client.config.ban.backoff);
+    assert!(client.config.ban.backoff.unwrap() > RECONNECT_DELAY);
+    // Only the next reconnect is affected
+    assert_eq!(client.next_reconnect_delay(), Some(RECONNECT_DELAY));
+
+    client.feed("ERROR :Closing Link: host (Ping timeout)\r\n").await;
+    assert_eq!(client.next_reconnect_delay(), Some(RECONNECT_DELAY));
+    client.feed("ERROR :Closing Link: host (K-Lined: no bots)\r\n").await;
+    assert_eq!(client.next_reconnect_delay(), client.config.ban.backoff);
+
+    client.config.ban.backoff = None;
+    client.feed(":oper!o@h KILL rot :Go away\r\n").await;
+    match client.reconnect_delay().await {
+        Err(RotError::Protocol(message)) => {
+            assert_eq!(message, "Banned from the server (Killed by oper!o@h: Go away), giving up");
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_max_ops() {
+    crate::rotdb::remove_test_db("test_max_ops.db");
+    {
+        let config = Config { max_ops: 2, ..Default::default() };
+        let mut client = IrcClient::new("test_max_ops.db", "localhost:6667", "rot", config);
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :a++; b++; c++; d++\r\n").await,
+                   "PRIVMSG #chan :a = 1, b = 1\r\n");
+        assert_eq!(client.db.get("c"), None);
+        assert_eq!(client.db.get("d"), None);
+
+        client.config.warn_max_ops = true;
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :a++; b--; c++\r\n").await,
+                   "PRIVMSG #chan :a = 2, b = 0, ignored 1 more (limit is 2)\r\n");
+        assert_eq!(client.db.get("c"), None);
+
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?a; ?top\r\n").await, "");
+
+        // The warning still comes when only one is allowed
+        client.config.max_ops = 1;
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :a++; b++; c++\r\n").await,
+                   "PRIVMSG #chan :a = 3, ignored 2 more (limit is 1)\r\n");
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :a++\r\n").await,
+                   "PRIVMSG #chan :a = 4\r\n");
+    }
+    crate::rotdb::remove_test_db("test_max_ops.db");
+}
+
+#[test]
+fn test_server_time() {
+    assert_eq!(split_tags(":a PRIVMSG #b :c"), (None, ":a PRIVMSG #b :c"));
+    assert_eq!(split_tags("@time=x;account=y :a PRIVMSG #b :c"),
+               (Some("time=x;account=y"), ":a PRIVMSG #b :c"));
+    assert_eq!(tag_value("account=y;time=x", "time"), Some("x"));
+    assert_eq!(tag_value("account=y;timer=x;+draft/time", "time"), None);
+
+    assert_eq!(parse_server_time("1970-01-01T00:00:00Z"), Some(UNIX_EPOCH));
+    assert_eq!(parse_server_time("2011-10-19T16:40:51.620Z"),
+               Some(UNIX_EPOCH + Duration::from_millis(1_319_042_451_620)));
+    assert_eq!(parse_server_time("2024-02-29T23:59:59Z"),
+               Some(UNIX_EPOCH + Duration::from_secs(1_709_251_199)));
+    assert_eq!(parse_server_time("2011-10-19 16:40:51Z"), None);
+    assert_eq!(parse_server_time("2011-13-19T16:40:51Z"), None);
+    assert_eq!(parse_server_time("1969-12-31T23:59:59Z"), None);
+}
+
+#[tokio::test]
+async fn test_cap_negotiation() {
+    let config = Config { reply_threads: true, admins: vec!["boss".to_string()],
+                          ..Config::default() };
+    let mut client = IrcClient::new("test_cap_negotiation.db", "localhost:6667", "rot", config);
+    let mut output = Vec::new();
+    client.register(&mut output).await.unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(),
+               "CAP LS 302\r\nNICK rot\r\nUSER rot . . :rot\r\n");
+
+    // Nothing is requested until the whole list is in, and only what's offered
+    assert_eq!(client.feed(":irc.example.com CAP * LS * :multi-prefix server-time\r\n").await,
+               "");
+    assert_eq!(client.feed(":irc.example.com CAP * LS :sasl=PLAIN message-tags\r\n").await,
+               "CAP REQ :server-time\r\nCAP REQ :message-tags\r\n");
+    assert_eq!(client.feed(":irc.example.com CAP * ACK :server-time\r\n").await, "");
+    assert_eq!(client.feed(":irc.example.com CAP * NAK :message-tags\r\n").await,
+               "CAP END\r\n");
+    assert!(client.caps.contains("server-time"));
+    assert!(!client.caps.contains("message-tags"));
+    // Ending it only once, even if the server answers again
+    assert_eq!(client.feed(":irc.example.com CAP * NAK :message-tags\r\n").await, "");
+    assert_eq!(client.feed(":irc.example.com CAP * ACK :-server-time\r\n").await, "");
+    assert!(!client.caps.contains("server-time"));
+
+    // With none of ours offered, negotiation ends straight away
+    client.register(&mut Vec::new()).await.unwrap();
+    assert!(client.caps.is_empty());
+    assert_eq!(client.feed(":irc.example.com CAP * LS :multi-prefix\r\n").await, "CAP END\r\n");
+    crate::rotdb::remove_test_db("test_cap_negotiation.db");
+}
+
+#[tokio::test]
+async fn test_reply_threads() {
+    let config = Config { reply_threads: true, ..Config::default() };
+    let mut client = IrcClient::new("test_reply_threads.db", "localhost:6667", "rot", config);
+    client.caps_pending = 2;
+    client.negotiating = true;
+    let message = "@msgid=abc123;time=2011-10-19T16:40:51.620Z :user!u@h PRIVMSG #chan :?foo\r\n";
+
+    // Without message-tags, it's a plain reply
+    assert_eq!(client.feed(message).await, "PRIVMSG #chan :foo = 0\r\n");
+    assert_eq!(client.feed(":irc.example.com CAP * ACK :server-time\r\n").await, "");
+    assert_eq!(client.feed(":irc.example.com CAP * ACK :message-tags\r\n").await,
+               "CAP END\r\n");
+    assert_eq!(client.feed(message).await,
+               "@+draft/reply=abc123 PRIVMSG #chan :foo = 0\r\n");
+    assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?foo\r\n").await,
+               "PRIVMSG #chan :foo = 0\r\n");
+
+    // Or when threading is turned off
+    client.config.reply_threads = false;
+    assert_eq!(client.feed(message).await, "PRIVMSG #chan :foo = 0\r\n");
+
+    // Dropped with cap-notify, after negotiation
+    client.config.reply_threads = true;
+    assert_eq!(client.feed(":irc.example.com CAP * ACK :-message-tags\r\n").await, "");
+    assert_eq!(client.feed(message).await, "PRIVMSG #chan :foo = 0\r\n");
+}
+
+#[tokio::test]
+async fn test_sasl_external() {
+    use tokio::io::AsyncBufReadExt;
+
+    let mut config = Config { sasl: Some(crate::config::SaslMechanism::External), ..Config::default() };
+    config.ban.backoff = None;
+    let mut client = IrcClient::new("test_sasl.db", "localhost:6667", "rot", config);
+    let (transport, server) = tokio::io::duplex(1024);
+    client.connect_to(vec![transport]);
+
+    let server = async move {
+        let (reader, mut writer) = tokio::io::split(server);
+        let mut lines = tokio::io::BufReader::new(reader).lines();
+        // What the server sends after receiving each of the client's lines
+        let script: &[(&str, &[u8])] = &[
+            ("CAP LS 302", b""),
+            ("NICK rot", b""),
+            ("USER rot . . :rot", b":irc.example.com CAP * LS :server-time sasl=EXTERNAL\r\n"),
+            ("CAP REQ :server-time", b""),
+            ("CAP REQ :sasl", b":irc.example.com CAP * ACK :server-time\r\n\
+                                :irc.example.com CAP * ACK :sasl\r\n"),
+            ("AUTHENTICATE EXTERNAL", b"AUTHENTICATE +\r\n"),
+            ("AUTHENTICATE +", b":irc.example.com 900 rot rot!rot@h rot :You are now logged in\r\n\
+                                 :irc.example.com 903 rot :SASL authentication successful\r\n"),
+            ("CAP END", b""),
+        ];
+        for (expected, response) in script {
+            assert_eq!(lines.next_line().await.unwrap().as_deref(), Some(*expected));
+            writer.write_all(response).await.unwrap();
+        }
+        writer.write_all(b"ERROR :Closing Link: rot (K-lined)\r\n").await.unwrap();
+    };
+    let (result, _) = tokio::join!(client.run(), server);
+    assert!(matches!(result, Err(RotError::Protocol(_))));
+    crate::rotdb::remove_test_db("test_sasl.db");
+}
+
+#[test]
+fn test_default_port() {
+    assert_eq!(with_default_port("irc.example.net"), "irc.example.net:6667");
+    assert_eq!(with_default_port("irc.example.net:7000"), "irc.example.net:7000");
+    assert_eq!(with_default_port("127.0.0.1"), "127.0.0.1:6667");
+    assert_eq!(with_default_port("127.0.0.1:6697"), "127.0.0.1:6697");
+    assert_eq!(with_default_port("[2001:db8::1]:6697"), "[2001:db8::1]:6697");
+    assert_eq!(with_default_port("[2001:db8::1]"), "[2001:db8::1]:6667");
+    assert_eq!(with_default_port("2001:db8::1"), "[2001:db8::1]:6667");
+    assert_eq!(with_default_port("::1"), "[::1]:6667");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_unix_socket() {
+    use tokio::io::AsyncBufReadExt;
+
+    let path = "test_unix_socket.sock";
+    let _ = std::fs::remove_file(path);
+    let listener = tokio::net::UnixListener::bind(path).unwrap();
+    let mut config = Config::default();
+    config.ban.backoff = None;
+    let client = IrcClient::new("test_unix_socket.db", &format!("unix:{}", path), "rot",
+                                    config);
+
+    let server = async move {
+        let (sock, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = tokio::io::split(sock);
+        let mut lines = tokio::io::BufReader::new(reader).lines();
+        for expected in ["CAP LS 302", "NICK rot", "USER rot . . :rot"] {
+            assert_eq!(lines.next_line().await.unwrap().as_deref(), Some(expected));
+        }
+        writer.write_all(b"ERROR :Closing Link: rot (K-lined)\r\n").await.unwrap();
+    };
+    let (result, _) = tokio::join!(client.run(), server);
+    assert!(matches!(result, Err(RotError::Protocol(_))));
+    let _ = std::fs::remove_file(path);
+    crate::rotdb::remove_test_db("test_unix_socket.db");
+}
+
+#[tokio::test]
+async fn test_sasl_failure() {
+    let config = Config { sasl: Some(crate::config::SaslMechanism::External), ..Config::default() };
+    let mut client = IrcClient::new("test_sasl_failure.db", "localhost:6667", "rot", config);
+    client.caps_pending = 2;
+    client.negotiating = true;
+    assert_eq!(client.feed(":irc.example.com CAP * ACK :sasl\r\n").await,
+               "AUTHENTICATE EXTERNAL\r\n");
+    assert_eq!(client.feed(":irc.example.com CAP * NAK :server-time\r\n").await, "");
+    assert_eq!(client.feed(":irc.example.com 908 rot PLAIN :are available SASL mechanisms\r\n\
+                            :irc.example.com 904 rot :SASL authentication failed\r\n").await,
+               "CAP END\r\n");
+    // An AUTHENTICATE we didn't ask for is ignored
+    assert_eq!(client.feed("AUTHENTICATE +\r\n").await, "");
+
+    // Without SASL configured, an ACK for it doesn't start authenticating
+    client.config.sasl = None;
+    client.caps_pending = 1;
+    client.negotiating = true;
+    assert_eq!(client.feed(":irc.example.com CAP * ACK :sasl\r\n").await, "CAP END\r\n");
+    crate::rotdb::remove_test_db("test_sasl_failure.db");
+}
+
+#[tokio::test]
+async fn test_sasl_needs_certificate() {
+    // The default connectors can't present a client certificate
+    let config = Config { sasl: Some(SaslMechanism::External), ..Config::default() };
+    let client = IrcClient::new("test_sasl_cert.db", "localhost:6667", "rot", config);
+    match client.run().await {
+        Err(RotError::Connection { source, .. }) => {
+            assert_eq!(source.kind(), io::ErrorKind::InvalidInput);
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    crate::rotdb::remove_test_db("test_sasl_cert.db");
+}
+
+#[tokio::test]
+async fn test_tagged_message() {
+    crate::rotdb::remove_test_db("test_tagged.db");
+    {
+        let mut client = IrcClient::new("test_tagged.db", "localhost:6667", "rot",
+                                        Config::default());
+        assert_eq!(client.feed(":irc.example.com CAP * ACK :server-time\r\n").await, "");
+        client.feed("@time=2011-10-19T16:40:51.620Z :user!u@h PRIVMSG #chan :foo++\r\n").await;
+        client.feed(":user!u@h PRIVMSG #chan :bar++\r\n").await;
+        assert_eq!(client.db.value("foo"), 1);
+        client.db.sync();
+
+        let saved = std::fs::read_to_string("test_tagged.db").unwrap();
+        assert!(saved.lines().any(|line| line == "foo:1:1319042451"));
+        let bar = saved.lines().find(|line| line.starts_with("bar:")).unwrap();
+        assert_ne!(bar, "bar:1:1319042451");
+    }
+    crate::rotdb::remove_test_db("test_tagged.db");
+}
+
+#[tokio::test]
+async fn test_sandbox() {
+    crate::rotdb::remove_test_db("test_sandbox.db");
+    {
+        let config = Config { sandbox_channels: vec!["#demo".to_string()], ..Default::default() };
+        let mut client = IrcClient::new("test_sandbox.db", "localhost:6667", "rot", config);
+        client.feed(":user!u@h PRIVMSG #chan :foo++\r\n").await;
+        assert_eq!(client.feed(":user!u@h PRIVMSG #Demo :foo++; bar--\r\n").await,
+                   "PRIVMSG #Demo :foo = 2, bar = -1\r\n");
+        assert_eq!(client.feed(":user!u@h PRIVMSG #demo :?foo\r\n").await,
+                   "PRIVMSG #demo :foo = 2\r\n");
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?foo\r\n").await,
+                   "PRIVMSG #chan :foo = 1\r\n");
+        assert_eq!(client.db.get("bar"), None);
+
+        // Changes outside the sandbox show up in it
+        client.feed(":user!u@h PRIVMSG #chan :baz++\r\n").await;
+        assert_eq!(client.feed(":user!u@h PRIVMSG #demo :?baz\r\n").await,
+                   "PRIVMSG #demo :baz = 1\r\n");
+    }
+    {
+        let db = RotDb::new("test_sandbox.db");
+        assert_eq!(db.get("foo"), Some(1));
+        assert_eq!(db.get("bar"), None);
+    }
+    crate::rotdb::remove_test_db("test_sandbox.db");
+}
+
+#[tokio::test]
+async fn test_isupport() {
+    let mut client = IrcClient::new("test_isupport.db", "localhost:6667", "rot",
+                                    Config::default());
+    client.join("one");
+    client.join("two");
+    client.join("three");
+    client.feed(":irc.example.com 005 rot CHANTYPES=#& NICKLEN=9 CHANLIMIT=#&:2,+: EXCEPTS \
+                 :are supported by this server\r\n").await;
+    assert_eq!(client.isupport.get("NICKLEN").map(String::as_str), Some("9"));
+    assert_eq!(client.isupport.get("EXCEPTS").map(String::as_str), Some(""));
+    assert_eq!(client.nick_len(), Some(9));
+    assert_eq!(client.chan_limit('#'), Some(("#&", 2)));
+    assert_eq!(client.chan_limit('+'), None);
+    assert!(client.is_channel("&local"));
+    assert!(!client.is_channel("+modeless"));
+
+    assert_eq!(client.feed(":irc.example.com 376 rot :End of /MOTD command.\r\n").await,
+               "JOIN #one\r\nJOIN #two\r\n");
+
+    client.feed(":irc.example.com 005 rot -EXCEPTS :are supported by this server\r\n").await;
+    assert_eq!(client.isupport.get("EXCEPTS"), None);
+
+    client.nick = "rotbot123".to_string();
+    assert_eq!(client.feed(":irc.example.com 433 * rotbot123 :Nickname is already in use\r\n").await,
+               "NICK rotbot124\r\n");
+
+    // Without a NICKLEN, a collision while registering just adds a `_`, and
+    // the server's welcome confirms it
+    client.isupport.remove("NICKLEN");
+    client.nick = "rot".to_string();
+    assert_eq!(client.feed(":irc.example.com 433 * rot :Nickname is already in use\r\n").await,
+               "NICK rot_\r\n");
+    client.feed(":irc.example.com 001 rot_ :Welcome to the network\r\n").await;
+    assert_eq!(client.nick, "rot_");
+}
+
+#[test]
+fn test_alt_nick() {
+    assert_eq!(alt_nick("rot", None), "rot_");
+    assert_eq!(alt_nick("rot", Some(4)), "rot_");
+    assert_eq!(alt_nick("rot_", Some(4)), "rot0");
+    assert_eq!(alt_nick("rot9", Some(4)), "rot0");
+    assert_eq!(alt_nick("rotbot", Some(4)), "rot0");
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_connection_events() {
+    let mut config = Config::default();
+    config.ban.backoff = None;
+    let mut client = IrcClient::new("test_events.db", "localhost:6667", "rot", config);
+    let (first, mut first_server) = tokio::io::duplex(1024);
+    let (second, mut second_server) = tokio::io::duplex(1024);
+    client.connect_to(vec![first, second]);
+    let (events_send, mut events) = mpsc::channel(16);
+    client.set_events(events_send);
+
+    let server = async move {
+        let mut buf = [0; 1024];
+        let _ = first_server.read(&mut buf).await;
+        first_server.write_all(b":irc.example.com 001 rot :Welcome\r\n").await.unwrap();
+        drop(first_server);
+
+        let _ = second_server.read(&mut buf).await;
+        second_server.write_all(b"ERROR :Closing Link: rot (K-lined)\r\n").await.unwrap();
+        drop(second_server);
+    };
+    let (result, _) = tokio::join!(client.run(), server);
+    assert!(matches!(result, Err(RotError::Protocol(_))));
+
+    let mut received = Vec::new();
+    while let Some(event) = events.recv().await {
+        received.push(event);
+    }
+    use ConnectionEvent::*;
+    assert_eq!(received, vec![Connected, Registered, Disconnected, Reconnecting, Connected,
+                              Disconnected]);
+    crate::rotdb::remove_test_db("test_events.db");
+}
+
+#[tokio::test]
+async fn test_karma_events() {
+    crate::rotdb::remove_test_db("test_karma_events.db");
+    let config = Config { min_value: Some(0), sandbox_channels: vec!["#demo".to_string()],
+                          ..Config::parse("combo = 2/1m 5").unwrap() };
+    let mut client = IrcClient::new("test_karma_events.db", "localhost:6667", "rot", config);
+    let (events_send, mut events) = mpsc::channel(16);
+    client.set_karma_events(events_send);
+    client.feed(":alice!alice@a.example PRIVMSG #chan :foo++; Bar::Baz--\r\n").await;
+    client.feed(":bob!bob@b.example PRIVMSG #chan :Foo++\r\n").await;
+    client.feed(":carol!u@h PRIVMSG rot :foo--\r\n").await;
+    client.feed(":dave!u@h PRIVMSG #demo :foo++\r\n").await;
+    drop(client);
+
+    let mut received = Vec::new();
+    while let Some(event) = events.recv().await {
+        received.push(event);
+    }
+    let event = |sender: &str, old_value, new_value, channel: Option<&str>| KarmaEvent {
+        sender: sender.to_string(), key: "foo".to_string(), old_value, new_value,
+        channel: channel.map(str::to_string),
+    };
+    // Bar::Baz stays at the floor, so it doesn't change
+    assert_eq!(received, vec![event("alice", 0, 1, Some("#chan")),
+                              event("bob", 1, 2, Some("#chan")),
+                              event("bob", 2, 7, Some("#chan")),
+                              event("carol", 7, 6, None)]);
+    crate::rotdb::remove_test_db("test_karma_events.db");
+}
+
+#[tokio::test]
+async fn test_floor_not_recorded() {
+    crate::rotdb::remove_test_db("test_floor_recorded.db");
+    {
+        let config = Config { min_value: Some(0), history: true, user_totals: true,
+                              ..Config::default() };
+        let mut client = IrcClient::new("test_floor_recorded.db", "localhost:6667", "rot",
+                                        config);
+        assert_eq!(client.feed(":alice!u@h PRIVMSG #chan :foo--\r\n").await,
+                   "PRIVMSG #chan :foo = 0\r\n");
+        assert!(client.db.contributions_to("foo").is_empty());
+        assert_eq!(client.db.user_totals("alice").given_downs, 0);
+        client.feed(":alice!u@h PRIVMSG #chan :foo++\r\n").await;
+        assert_eq!(client.db.contributions_to("foo").len(), 1);
+        assert_eq!(client.db.user_totals("alice").given_ups, 1);
+    }
+    crate::rotdb::remove_test_db("test_floor_recorded.db");
+}
+
+/// A transport that fails its first reads with the given errors, and
+/// optionally every write
+#[cfg(test)]
+struct FlakyTransport {
+    inner: tokio::io::DuplexStream,
+    errors: Vec<io::ErrorKind>,
+    fail_writes: bool,
+}
+
+#[cfg(test)]
+impl AsyncRead for FlakyTransport {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>,
+                 buf: &mut tokio::io::ReadBuf<'_>) -> std::task::Poll<io::Result<()>> {
+        match self.errors.pop() {
+            Some(kind) => std::task::Poll::Ready(Err(io::Error::from(kind))),
+            None => Pin::new(&mut self.inner).poll_read(cx, buf),
+        }
+    }
+}
+
+#[cfg(test)]
+impl AsyncWrite for FlakyTransport {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>,
+                  buf: &[u8]) -> std::task::Poll<io::Result<usize>> {
+        if self.fail_writes {
+            return std::task::Poll::Ready(Err(io::Error::from(io::ErrorKind::BrokenPipe)));
+        }
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>)
+            -> std::task::Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>)
+            -> std::task::Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// A transport that only passes writes on when flushed, and loses anything
+/// unflushed when it's shut down
+#[cfg(test)]
+struct BufferedTransport {
+    inner: tokio::io::DuplexStream,
+    buffer: Vec<u8>,
+}
+
+#[cfg(test)]
+impl AsyncRead for BufferedTransport {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>,
+                 buf: &mut tokio::io::ReadBuf<'_>) -> std::task::Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+#[cfg(test)]
+impl AsyncWrite for BufferedTransport {
+    fn poll_write(mut self: Pin<&mut Self>, _cx: &mut std::task::Context<'_>,
+                  buf: &[u8]) -> std::task::Poll<io::Result<usize>> {
+        self.buffer.extend(buf);
+        std::task::Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>)
+            -> std::task::Poll<io::Result<()>> {
+        let this = &mut *self;
+        while !this.buffer.is_empty() {
+            match Pin::new(&mut this.inner).poll_write(cx, &this.buffer) {
+                std::task::Poll::Ready(Ok(n)) => drop(this.buffer.drain(..n)),
+                other => return other.map_ok(drop),
+            }
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>)
+            -> std::task::Poll<io::Result<()>> {
+        self.buffer.clear();
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_reconnect_session() {
+    use tokio::io::{AsyncBufReadExt, DuplexStream, ReadHalf, WriteHalf, BufReader, Lines};
+
+    type ServerLines = Lines<BufReader<ReadHalf<DuplexStream>>>;
+    // Answer a full registration, up to joining the channel again
+    async fn register(lines: &mut ServerLines, writer: &mut WriteHalf<DuplexStream>) {
+        let script: &[(&str, &[u8])] = &[
+            ("CAP LS 302", b""),
+            ("NICK rot", b""),
+            ("USER rot . . :rot", b":irc.example.com CAP * LS :server-time\r\n"),
+            ("CAP REQ :server-time", b":irc.example.com CAP * ACK :server-time\r\n"),
+            ("CAP END", b":irc.example.com 001 rot :Welcome\r\n\
+                          :irc.example.com 376 rot :End of MOTD\r\n"),
+            ("JOIN #chan secret", b":rot!u@h JOIN #chan\r\n"),
+        ];
+        for (expected, response) in script {
+            assert_eq!(lines.next_line().await.unwrap().as_deref(), Some(*expected));
+            writer.write_all(response).await.unwrap();
+        }
+    }
+
+    crate::rotdb::remove_test_db("test_reconnect_session.db");
+    let mut config = Config { restrict_decrements: true, ..Config::default() };
+    config.ban.backoff = None;
+    let mut client = IrcClient::new("test_reconnect_session.db", "localhost:6667", "rot", config);
+    client.join_with_key("#chan", "secret");
+    let (first, first_server) = tokio::io::duplex(4096);
+    let (second, second_server) = tokio::io::duplex(4096);
+    client.connect_to(vec![first, second]);
+
+    let server = async move {
+        let (reader, mut writer) = tokio::io::split(first_server);
+        let mut lines = BufReader::new(reader).lines();
+        register(&mut lines, &mut writer).await;
+        // alice is an op, so her decrement counts
+        writer.write_all(b":irc.example.com 353 rot = #chan :rot @alice\r\n\
+                           :alice!u@h PRIVMSG #chan :bob--\r\n").await.unwrap();
+        assert_eq!(lines.next_line().await.unwrap().as_deref(), Some("PRIVMSG #chan :bob = -1"));
+        // Then a partial line, and the connection drops
+        writer.write_all(b":alice!u@h PRIVMSG #chan :bob-").await.unwrap();
+        drop((lines, writer));
+
+        // Everything is done again, and alice isn't an op until the server
+        // says so on this connection
+        let (reader, mut writer) = tokio::io::split(second_server);
+        let mut lines = BufReader::new(reader).lines();
+        register(&mut lines, &mut writer).await;
+        writer.write_all(b"-\r\n:alice!u@h PRIVMSG #chan :bob--\r\n\
+                           :alice!u@h PRIVMSG #chan :?bob\r\n").await.unwrap();
+        assert_eq!(lines.next_line().await.unwrap().as_deref(), Some("PRIVMSG #chan :bob = -1"));
+        writer.write_all(b"ERROR :Closing Link: rot (K-lined)\r\n").await.unwrap();
+        (lines, writer)
+    };
+    let (result, _server) = tokio::join!(client.run(), server);
+    assert!(matches!(result, Err(RotError::Protocol(_))));
+    crate::rotdb::remove_test_db("test_reconnect_session.db");
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_quit_flushed() {
+    let (shutdown_send, shutdown) = mpsc::channel(1);
+    let mut client = IrcClient::without_signals(Box::new(crate::store::MemoryStore::default()),
+                                                "test_quit_flushed.db", "localhost:6667",
+                                                "rot", Config::default(), shutdown);
+    let (transport, mut server) = tokio::io::duplex(4096);
+    client.connect_to(vec![BufferedTransport { inner: transport, buffer: Vec::new() }]);
+    shutdown_send.send(()).await.unwrap();
+
+    // The server never closes the connection, so only the timeout ends the wait
+    let start = Instant::now();
+    let server = async move {
+        let mut received = String::new();
+        server.read_to_string(&mut received).await.unwrap();
+        (received, server)
+    };
+    let (result, (received, _server)) = tokio::join!(client.run(), server);
+    assert!(result.is_ok());
+    assert!(received.ends_with("QUIT :--rot!\r\n"));
+    let waited = Instant::now() - start;
+    assert!(waited >= QUIT_TIMEOUT && waited < TIMEOUT_DURATION);
+    crate::rotdb::remove_test_db("test_quit_flushed.db");
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_transient_read_error() {
+    let mut config = Config::default();
+    config.ban.backoff = None;
+    let mut client = IrcClient::new("test_transient.db", "localhost:6667", "rot", config);
+    let (transport, mut server) = tokio::io::duplex(1024);
+    client.connect_to(vec![FlakyTransport {
+        inner: transport,
+        errors: vec![io::ErrorKind::WouldBlock, io::ErrorKind::Interrupted],
+        fail_writes: false,
+    }]);
+    let (events_send, mut events) = mpsc::channel(16);
+    client.set_events(events_send);
+
+    let server = async move {
+        let mut buf = [0; 1024];
+        let _ = server.read(&mut buf).await;
+        server.write_all(b":irc.example.com 001 rot :Welcome\r\n").await.unwrap();
+        server.write_all(b"ERROR :Closing Link: rot (K-lined)\r\n").await.unwrap();
+    };
+    let (result, _) = tokio::join!(client.run(), server);
+    assert!(matches!(result, Err(RotError::Protocol(_))));
+
+    // The same connection was still registered after the errors
+    let mut received = Vec::new();
+    while let Some(event) = events.recv().await {
+        received.push(event);
+    }
+    use ConnectionEvent::*;
+    assert_eq!(received, vec![Connected, Registered, Disconnected]);
+    crate::rotdb::remove_test_db("test_transient.db");
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_registration_write_error() {
+    let mut config = Config::default();
+    config.ban.backoff = None;
+    let mut client = IrcClient::new("test_register.db", "localhost:6667", "rot", config);
+    // The first connection can be read from, but not written to
+    let (first, _first_server) = tokio::io::duplex(1024);
+    let (second, mut second_server) = tokio::io::duplex(1024);
+    client.connect_to(vec![
+        FlakyTransport { inner: first, errors: vec![], fail_writes: true },
+        FlakyTransport { inner: second, errors: vec![], fail_writes: false },
+    ]);
+    let (events_send, mut events) = mpsc::channel(16);
+    client.set_events(events_send);
+
+    let start = Instant::now();
+    let server = async move {
+        let mut buf = [0; 1024];
+        let n = second_server.read(&mut buf).await.unwrap();
+        assert!(String::from_utf8_lossy(&buf[..n]).starts_with("CAP LS 302\r\n"));
+        // Reconnected after the usual delay, not after waiting for a PING
+        // on the half-registered connection to time out
+        assert!(Instant::now() - start < PING_INTERVAL);
+        second_server.write_all(b"ERROR :Closing Link: rot (K-lined)\r\n").await.unwrap();
+    };
+    let (result, _) = tokio::join!(client.run(), server);
+    assert!(matches!(result, Err(RotError::Protocol(_))));
+
+    let mut received = Vec::new();
+    while let Some(event) = events.recv().await {
+        received.push(event);
+    }
+    use ConnectionEvent::*;
+    assert_eq!(received, vec![Connected, Disconnected, Reconnecting, Connected, Disconnected]);
+    crate::rotdb::remove_test_db("test_register.db");
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_uptime() {
+    assert_eq!(format_elapsed(Duration::from_secs(59)), "0m");
+    assert_eq!(format_elapsed(Duration::from_secs(61 * 60)), "1h 1m");
+    assert_eq!(format_elapsed(Duration::from_secs(((2 * 24 + 3) * 60 + 4) * 60 + 5)), "2d 3h 4m");
+    assert_eq!(format_elapsed(Duration::from_secs(24 * 60 * 60)), "1d 0h 0m");
+
+    let mut client = IrcClient::new("test_uptime.db", "localhost:6667", "rot", Config::default());
+    tokio::time::advance(Duration::from_secs(90 * 60)).await;
+    let version = env!("CARGO_PKG_VERSION");
+    assert_eq!(client.feed(":user!u@h PRIVMSG #chan :!uptime\r\n").await,
+               format!("PRIVMSG #chan :rot {}: up 1h 30m, not connected\r\n", version));
+
+    client.connected_at = Some(Instant::now());
+    tokio::time::advance(Duration::from_secs(25 * 60 * 60)).await;
+    assert_eq!(client.feed(":user!u@h PRIVMSG #chan :!uptime\r\n").await,
+               format!("PRIVMSG #chan :rot {}: up 1d 2h 30m, connected for 1d 1h 0m\r\n",
+                       version));
+}
+
+#[tokio::test]
+async fn test_saved_channels() {
+    crate::rotdb::remove_test_db("test_channels.db");
+    std::fs::write("test_channels.db.channels", "#saved\n&local\n#Cli\n").unwrap();
+    {
+        let mut client = IrcClient::new("test_channels.db", "localhost:6667", "rot",
+                                        Config::default());
+        client.join("cli");
+        client.load_channels();
+        assert_eq!(client.feed(":irc.example.com 376 rot :End of /MOTD command.\r\n").await,
+                   "JOIN #cli\r\nJOIN #saved\r\nJOIN &local\r\n");
+
+        client.feed(":rot!r@h JOIN #cli\r\n:rot!r@h JOIN :#new\r\n:other!o@h JOIN #other\r\n\
+                     :rot!r@h PART #saved :bye\r\n:op!o@h KICK &local rot :out\r\n").await;
+        assert_eq!(client.feed(":op!o@h INVITE rot :#invited\r\n").await, "JOIN #invited\r\n");
+        assert_eq!(client.feed(":op!o@h INVITE someone :#invited\r\n").await, "");
+    }
+    assert_eq!(std::fs::read_to_string("test_channels.db.channels").unwrap(), "#cli\n#new\n");
+    {
+        let mut client = IrcClient::new("test_channels.db", "localhost:6667", "rot",
+                                        Config::default());
+        client.load_channels();
+        assert_eq!(client.feed(":irc.example.com 422 rot :MOTD File is missing\r\n").await,
+                   "JOIN #cli\r\nJOIN #new\r\n");
+    }
+    crate::rotdb::remove_test_db("test_channels.db");
+}
+
+#[tokio::test]
+async fn test_channel_keys() {
+    crate::rotdb::remove_test_db("test_channel_keys.db");
+    {
+        let mut client = IrcClient::new("test_channel_keys.db", "localhost:6667", "rot",
+                                        Config::default());
+        client.join("open");
+        client.join_with_key("secret", "hunter2");
+        client.join_with_key("#Other", "a:b");
+        assert_eq!(client.feed(":irc.example.com 376 rot :End of /MOTD command.\r\n").await,
+                   "JOIN #open\r\nJOIN #secret hunter2\r\nJOIN #Other a:b\r\n");
+        // Keys aren't saved with the channels
+        client.feed(":rot!r@h JOIN #new\r\n").await;
+        assert_eq!(std::fs::read_to_string("test_channel_keys.db.channels").unwrap(),
+                   "#open\n#secret\n#Other\n#new\n");
+    }
+    crate::rotdb::remove_test_db("test_channel_keys.db");
+}
+
+#[tokio::test]
+async fn test_forwarded_join() {
+    crate::rotdb::remove_test_db("test_forward.db");
+    {
+        let mut client = IrcClient::new("test_forward.db", "localhost:6667", "rot",
+                                        Config::default());
+        client.join("#first");
+        client.join_with_key("#old", "sekrit");
+        client.join("#last");
+        client.feed(":irc.example.com 376 rot :End of /MOTD command.\r\n").await;
+        client.feed(":irc.example.com 470 rot #old #new :Forwarding to another channel\r\n\
+                     :rot!r@h JOIN #new\r\n").await;
+        // The channel asked for is still the one saved and rejoined
+        assert_eq!(client.channels, vec!["#first", "#old", "#last"]);
+        assert!(!std::path::Path::new("test_forward.db.channels").exists());
+        assert_eq!(client.feed(":user!u@h PRIVMSG #new :foo++\r\n").await,
+                   "PRIVMSG #new :foo = 1\r\n");
+
+        // A key we have for the target isn't replaced by the original's
+        client.join_with_key("#other", "hunter2");
+        client.feed(":irc.example.com 470 rot #last #other :Forwarding\r\n").await;
+        assert_eq!(client.feed(":irc.example.com 422 rot :MOTD File is missing\r\n").await,
+                   "JOIN #first\r\nJOIN #old sekrit\r\nJOIN #last\r\nJOIN #other hunter2\r\n");
+
+        // Being kicked from where we were forwarded leaves the original
+        client.feed(":op!u@h KICK #new rot :bye\r\n").await;
+        assert_eq!(client.channels, vec!["#first", "#last", "#other"]);
+    }
+    crate::rotdb::remove_test_db("test_forward.db");
+}
+
+#[tokio::test]
+async fn test_join_errors() {
+    crate::rotdb::remove_test_db("test_join_errors.db");
+    {
+        let mut client = IrcClient::new("test_join_errors.db", "localhost:6667", "rot",
+                                        Config::default());
+        client.join("#ok");
+        client.join("#nope");
+        client.join("#secret");
+        client.feed(":irc.example.com 376 rot :End of /MOTD command.\r\n").await;
+        client.feed(":irc.example.com 403 rot #nope :No such channel\r\n\
+                     :irc.example.com 473 rot #Secret :Cannot join channel (+i)\r\n").await;
+        assert_eq!(client.channels, vec!["#ok"]);
+        assert_eq!(std::fs::read_to_string("test_join_errors.db.channels").unwrap(), "#ok\n");
+        assert_eq!(client.feed(":irc.example.com 422 rot :MOTD File is missing\r\n").await,
+                   "JOIN #ok\r\n");
+    }
+    crate::rotdb::remove_test_db("test_join_errors.db");
+}
+
+#[tokio::test]
+async fn test_diff() {
+    crate::rotdb::remove_test_db("test_diff.db");
+    {
+        let mut client = IrcClient::new("test_diff.db", "localhost:6667", "rot", Config::default());
+        client.db.set("vim", 5);
+        client.db.set("emacs", 2);
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?diff vim emacs\r\n").await,
+                   "PRIVMSG #chan :vim leads emacs by 3\r\n");
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?diff emacs vim\r\n").await,
+                   "PRIVMSG #chan :vim leads emacs by 3\r\n");
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?diff nano emacs\r\n").await,
+                   "PRIVMSG #chan :emacs leads nano by 2\r\n");
+        client.db.set("emacs", 5);
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?diff vim emacs\r\n").await,
+                   "PRIVMSG #chan :vim and emacs are tied at 5\r\n");
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?diff nano ed\r\n").await,
+                   "PRIVMSG #chan :nano and ed are tied at 0\r\n");
+        // Further apart than an i64 can count
+        client.db.set("vim", i64::MAX);
+        client.db.set("emacs", i64::MIN);
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?diff emacs vim\r\n").await,
+                   "PRIVMSG #chan :vim leads emacs by 18446744073709551615\r\n");
+    }
+    crate::rotdb::remove_test_db("test_diff.db");
+}
+
+#[tokio::test]
+async fn test_equal() {
+    crate::rotdb::remove_test_db("test_equal.db");
+    {
+        let config = Config { admins: vec!["boss".to_string()], ..Default::default() };
+        let mut client = IrcClient::new("test_equal.db", "localhost:6667", "rot", config);
+        assert_eq!(client.feed(":boss!u@h PRIVMSG rot :?eq 0\r\n").await,
+                   "PRIVMSG boss :No keys equal 0\r\n");
+        client.db.set("vim", 5);
+        client.db.set("emacs", 0);
+        client.db.set("nano", 0);
+        assert_eq!(client.feed(":boss!u@h PRIVMSG rot :?eq 0\r\n").await,
+                   "PRIVMSG boss :Keys equal to 0: emacs, nano\r\n");
+        assert_eq!(client.feed(":boss!u@h PRIVMSG rot :?eq 5\r\n").await,
+                   "PRIVMSG boss :Keys equal to 5: vim\r\n");
+
+        for i in 0..60 {
+            client.db.set(&format!("unused_key_{:02}", i), -1);
+        }
+        let reply = client.feed(":boss!u@h PRIVMSG rot :?eq -1\r\n").await;
+        let lines: Vec<&str> = reply.lines().collect();
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|line| line.len() <= MESSAGE_LIMIT + "PRIVMSG boss :\r".len()));
+        assert!(lines[0].starts_with("PRIVMSG boss :Keys equal to -1: unused_key_00, unused_key_01"));
+        assert!(reply.contains("unused_key_49, and 10 more\r\n"));
+        assert!(!reply.contains("unused_key_50"));
+
+        // Only admins may list keys by value, and only in private
+        assert_eq!(client.feed(":user!u@h PRIVMSG rot :?eq 5\r\n").await, "");
+        assert_eq!(client.feed(":boss!u@h PRIVMSG #chan :?eq 5\r\n").await, "");
+    }
+    crate::rotdb::remove_test_db("test_equal.db");
+}
+
+#[tokio::test]
+async fn test_ignore_non_users() {
+    crate::rotdb::remove_test_db("test_non_users.db");
+    {
+        let mut client = IrcClient::new("test_non_users.db", "localhost:6667", "rot",
+                                        Config::default());
+        assert_eq!(client.feed(":user!u@h NOTICE #chan :++foo\r\n\
+                                :user!u@h NOTICE rot :foo++\r\n\
+                                :irc.example.com NOTICE * :*** ++foo\r\n\
+                                :oper!o@h WALLOPS :foo++\r\n\
+                                :irc.example.com PRIVMSG #chan :++foo\r\n\
+                                :irc.example.com PRIVMSG rot :?foo\r\n\
+                                NOTICE AUTH :++foo\r\n").await, "");
+        assert_eq!(client.db.get("foo"), None);
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :++foo\r\n").await,
+                   "PRIVMSG #chan :foo = 1\r\n");
+    }
+    crate::rotdb::remove_test_db("test_non_users.db");
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_combo() {
+    crate::rotdb::remove_test_db("test_combo.db");
+    {
+        let config = Config::parse("combo = 3/1m 2").unwrap();
+        let mut client = IrcClient::new("test_combo.db", "localhost:6667", "rot", config);
+        // One person can't make a combo, however often they try
+        for _ in 0..5 {
+            client.feed(":a!a@a.example PRIVMSG #chan :foo++\r\n").await;
+        }
+        assert_eq!(client.feed(":A!a@a.example PRIVMSG #chan :foo++\r\n").await,
+                   "PRIVMSG #chan :foo = 6\r\n");
+        assert_eq!(client.feed(":b!b@b.example PRIVMSG #chan :foo++\r\n").await,
+                   "PRIVMSG #chan :foo = 7\r\n");
+        assert_eq!(client.feed(":c!c@c.example PRIVMSG #chan :Foo++\r\n").await,
+                   "PRIVMSG #chan :Foo = 8\r\n\
+                    PRIVMSG #chan :Combo! 3 people incremented Foo within 1m, +2 (Foo = 10)\r\n");
+
+        // Everyone has to join in again for the next one, within the window
+        client.feed(":a!a@a.example PRIVMSG #chan :foo++\r\n\
+                     :b!b@b.example PRIVMSG #chan :foo++\r\n").await;
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert_eq!(client.feed(":c!c@c.example PRIVMSG #chan :foo++\r\n").await,
+                   "PRIVMSG #chan :foo = 13\r\n");
+        // Decrements don't count
+        client.feed(":d!d@d.example PRIVMSG #chan :foo--\r\n").await;
+        assert_eq!(client.feed(":e!e@e.example PRIVMSG #chan :foo++\r\n").await,
+                   "PRIVMSG #chan :foo = 13\r\n");
+        assert!(client.feed(":f!f@f.example PRIVMSG #chan :foo++\r\n").await
+                      .ends_with(":Combo! 3 people incremented foo within 1m, +2 (foo = 16)\r\n"));
+
+        // Nor can someone changing nick between increments
+        client.feed(":a!a@a.example PRIVMSG #chan :foo++\r\n\
+                     :a!a@a.example NICK a2\r\n\
+                     :a2!a@a.example PRIVMSG #chan :foo++\r\n\
+                     :a2!a@a.example NICK a3\r\n").await;
+        assert_eq!(client.feed(":a3!a@a.example PRIVMSG #chan :foo++\r\n").await,
+                   "PRIVMSG #chan :foo = 19\r\n");
+    }
+    crate::rotdb::remove_test_db("test_combo.db");
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_key_rate_limit() {
+    crate::rotdb::remove_test_db("test_key_rate.db");
+    {
+        let config = Config::parse("key_rate_limit = 3/1m").unwrap();
+        let mut client = IrcClient::new("test_key_rate.db", "localhost:6667", "rot", config);
+        assert_eq!(client.feed(":a!u@h PRIVMSG #chan :foo++\r\n").await,
+                   "PRIVMSG #chan :foo = 1\r\n");
+        tokio::time::advance(Duration::from_secs(20)).await;
+        assert_eq!(client.feed(":b!u@h PRIVMSG #chan :Foo--\r\n").await,
+                   "PRIVMSG #chan :Foo = 0\r\n");
+        assert_eq!(client.feed(":c!u@h PRIVMSG #chan :foo++\r\n").await,
+                   "PRIVMSG #chan :foo = 1\r\n");
+        // Everyone's changes count toward the same limit
+        assert_eq!(client.feed(":a!u@h PRIVMSG #chan :foo++\r\n\
+                                :d!u@h PRIVMSG #chan :foo--\r\n").await, "");
+        assert_eq!(client.feed(":a!u@h PRIVMSG #chan :bar++; foo++\r\n").await,
+                   "PRIVMSG #chan :bar = 1\r\n");
+        assert_eq!(client.feed(":a!u@h PRIVMSG #chan :?foo\r\n").await,
+                   "PRIVMSG #chan :foo = 1\r\n");
+
+        // The first change has left the window
+        tokio::time::advance(Duration::from_secs(40)).await;
+        assert_eq!(client.feed(":a!u@h PRIVMSG #chan :foo++\r\n").await,
+                   "PRIVMSG #chan :foo = 2\r\n");
+        assert_eq!(client.feed(":a!u@h PRIVMSG #chan :foo++\r\n").await, "");
+
+        // Changes that aren't applied, such as to a frozen key, don't count
+        client.db.freeze("baz");
+        for _ in 0..3 {
+            client.feed(":a!u@h PRIVMSG #chan :baz++\r\n").await;
+        }
+        client.db.unfreeze("baz");
+        assert_eq!(client.feed(":a!u@h PRIVMSG #chan :baz++\r\n").await,
+                   "PRIVMSG #chan :baz = 1\r\n");
+    }
+    crate::rotdb::remove_test_db("test_key_rate.db");
+}
+
+#[tokio::test]
+async fn test_restrict_decrements() {
+    crate::rotdb::remove_test_db("test_restrict.db");
+    {
+        let config = Config { restrict_decrements: true, warn_restricted: true,
+                              admins: vec!["boss".to_string()], ..Default::default() };
+        let mut client = IrcClient::new("test_restrict.db", "localhost:6667", "rot", config);
+        client.feed(":irc.example.com 353 rot = #chan :rot @Op +voiced user\r\n").await;
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :foo++\r\n").await,
+                   "PRIVMSG #chan :foo = 1\r\n");
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :--foo\r\n").await,
+                   "NOTICE user :Only channel operators can decrement foo\r\n");
+        assert_eq!(client.feed(":voiced!u@h PRIVMSG #chan :foo--\r\n").await,
+                   "NOTICE voiced :Only channel operators can decrement foo\r\n");
+        assert_eq!(client.db.value("foo"), 1);
+        assert_eq!(client.feed(":op!u@h PRIVMSG #chan :--foo\r\n").await,
+                   "PRIVMSG #chan :foo = 0\r\n");
+        assert_eq!(client.feed(":boss!u@h PRIVMSG #chan :--foo\r\n").await,
+                   "PRIVMSG #chan :foo = -1\r\n");
+        assert_eq!(client.feed(":user!u@h PRIVMSG rot :--foo\r\n").await,
+                   "NOTICE user :Only channel operators can decrement foo\r\n");
+
+        client.feed(":op!u@h MODE #chan +o-o+l user op 10\r\n").await;
+        assert_eq!(client.feed(":user!u@h PRIVMSG #chan :--foo\r\n").await,
+                   "PRIVMSG #chan :foo = -2\r\n");
+        client.config.warn_restricted = false;
+        assert_eq!(client.feed(":op!u@h PRIVMSG #chan :--foo\r\n").await, "");
+        assert_eq!(client.db.value("foo"), -2);
+    }
+    crate::rotdb::remove_test_db("test_restrict.db");
+}
+
+#[test]
+fn test_mode_changes() {
+    assert_eq!(mode_changes("+o", &["a"]), vec![("+o".to_string(), "a")]);
+    assert_eq!(mode_changes("+ob-v", &["a", "*!*@h", "b"]),
+               vec![("+o".to_string(), "a"), ("+b".to_string(), "*!*@h"), ("-v".to_string(), "b")]);
+    assert_eq!(mode_changes("+lo-lt", &["10", "a"]), vec![("+o".to_string(), "a")]);
+    assert_eq!(mode_changes("+oo", &["a"]), vec![("+o".to_string(), "a")]);
+}
+
+#[tokio::test]
+async fn test_channel_status() {
+    let mut client = IrcClient::new("test_status.db", "localhost:6667", "rot", Config::default());
+    client.feed(":irc.example.com 353 rot = #chan :rot @Op +Voiced @+both user\r\n\
+                 :irc.example.com 353 rot @ #other :~owner %half user\r\n").await;
+    assert!(client.is_op("#chan", "op") && !client.is_voiced("#chan", "op"));
+    assert!(client.is_voiced("#Chan", "VOICED") && !client.is_op("#chan", "voiced"));
+    assert!(client.is_op("#chan", "both") && client.is_voiced("#chan", "both"));
+    assert!(!client.is_op("#chan", "user") && !client.is_voiced("#chan", "user"));
+    assert!(client.is_op("#other", "owner") && !client.is_op("#other", "half"));
+    assert!(!client.is_op("#other", "op"));
+
+    client.feed(":op!u@h MODE #chan +ov-o user user both\r\n").await;
+    assert!(client.is_op("#chan", "user") && client.is_voiced("#chan", "user"));
+    assert!(!client.is_op("#chan", "both") && client.is_voiced("#chan", "both"));
+    client.feed(":op!u@h MODE #chan -v+b user *!*@spam\r\n").await;
+    assert!(client.is_op("#chan", "user") && !client.is_voiced("#chan", "user"));
+
+    client.feed(":user!u@h NICK :newname\r\n").await;
+    assert!(!client.is_op("#chan", "user") && client.is_op("#chan", "newname"));
+    client.feed(":newname!u@h PART #chan :bye\r\n").await;
+    assert!(!client.is_op("#chan", "newname"));
+    client.feed(":op!u@h KICK #chan both :out\r\n").await;
+    assert!(!client.is_voiced("#chan", "both"));
+    client.feed(":owner!u@h QUIT :gone\r\n").await;
+    assert!(!client.is_op("#other", "owner"));
+    assert!(client.is_op("#chan", "op"));
+
+    client.feed(":rot!r@h NICK :rot2\r\n").await;
+    assert_eq!(client.nick, "rot2");
+    client.feed(":rot2!r@h PART #chan\r\n").await;
+    assert!(!client.is_op("#chan", "op"));
+}
+
+#[tokio::test]
+async fn test_join_notice() {
+    crate::rotdb::remove_test_db("test_join_notice.db");
+    {
+        let config = Config { join_notice: Some("Use foo++, foo-- or ?foo".to_string()),
+                              ..Default::default() };
+        let mut client = IrcClient::new("test_join_notice.db", "localhost:6667", "rot", config);
+        assert_eq!(client.feed(":rot!r@h JOIN :#Chan\r\n").await,
+                   "NOTICE #Chan :Use foo++, foo-- or ?foo\r\n");
+        assert_eq!(client.feed(":other!o@h JOIN #chan\r\n").await, "");
+        assert_eq!(client.feed(":rot!r@h JOIN #other\r\n").await,
+                   "NOTICE #other :Use foo++, foo-- or ?foo\r\n");
+
+        // Rejoining later in the same session doesn't announce again
+        client.feed(":rot!r@h PART #chan\r\n").await;
+        assert_eq!(client.feed(":rot!r@h JOIN #chan\r\n").await, "");
+    }
+    crate::rotdb::remove_test_db("test_join_notice.db");
+}
+
+#[tokio::test]
+async fn test_welcome_nick() {
+    let config = Config { addressed_only: true, ..Default::default() };
+    let mut client = IrcClient::new("test_welcome.db", "localhost:6667", "rotbot_long", config);
+    client.feed(":irc.example.com 001 rotbot_lo :Welcome to the network\r\n").await;
+    assert_eq!(client.nick, "rotbot_lo");
+    assert_eq!(client.feed(":user!u@h PRIVMSG #chan :rotbot_long: ?foo\r\n").await, "");
+    assert_eq!(client.feed(":user!u@h PRIVMSG #chan :rotbot_lo: ?foo\r\n").await,
+               "PRIVMSG #chan :foo = 0\r\n");
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_min_presence() {
+    crate::rotdb::remove_test_db("test_presence.db");
+    {
+        let config = Config { min_presence: Some(Duration::from_secs(60 * 60)),
+                              ..Default::default() };
+        let mut client = IrcClient::new("test_presence.db", "localhost:6667", "rot", config);
+        client.feed(":irc.example.com 353 rot = #chan :rot regular\r\n\
+                     :old!u@h JOIN #chan\r\n").await;
+        tokio::time::advance(Duration::from_secs(2 * 60 * 60)).await;
+        client.feed(":new!u@h JOIN #chan\r\n").await;
+
+        assert_eq!(client.feed(":new!u@h PRIVMSG #chan :foo++\r\n").await, "");
+        assert_eq!(client.feed(":lurker!u@h PRIVMSG #chan :foo++\r\n").await, "");
+        assert_eq!(client.feed(":new!u@h PRIVMSG rot :foo++\r\n").await, "");
+        assert_eq!(client.feed(":regular!u@h PRIVMSG #chan :foo++\r\n").await,
+                   "PRIVMSG #chan :foo = 1\r\n");
+        assert_eq!(client.feed(":old!u@h PRIVMSG rot :foo--\r\n").await,
+                   "PRIVMSG old :foo = 0\r\n");
+        assert_eq!(client.feed(":new!u@h PRIVMSG #chan :?foo\r\n").await,
+                   "PRIVMSG #chan :foo = 0\r\n");
+
+        // Only time spent in this channel counts
+        assert_eq!(client.feed(":old!u@h PRIVMSG #other :foo++\r\n").await, "");
+
+        // Changing nick keeps the time already spent, but leaving doesn't
+        client.feed(":old!u@h NICK renamed\r\n:regular!u@h PART #chan\r\n\
+                     :regular!u@h JOIN #chan\r\n").await;
+        assert_eq!(client.feed(":renamed!u@h PRIVMSG #chan :foo++\r\n").await,
+                   "PRIVMSG #chan :foo = 1\r\n");
+        assert_eq!(client.feed(":regular!u@h PRIVMSG #chan :foo++\r\n").await, "");
+
+        tokio::time::advance(Duration::from_secs(60 * 60)).await;
+        assert_eq!(client.feed(":new!u@h PRIVMSG #chan :foo++\r\n").await,
+                   "PRIVMSG #chan :foo = 2\r\n");
+    }
+    crate::rotdb::remove_test_db("test_presence.db");
+}
+
+#[tokio::test]
+async fn test_connection_error() {
+    let mut client = IrcClient::new("test_connection_error.db", "localhost", "rot",
+                                    Config::default());
+    client.set_connector(Box::new(|_| Box::pin(async {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid socket address"))
+    })));
+    match client.run().await {
+        Err(RotError::Connection { addr, source }) => {
+            assert_eq!(addr, "localhost");
+            assert_eq!(source.kind(), io::ErrorKind::InvalidInput);
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    crate::rotdb::remove_test_db("test_connection_error.db");
+}
+
+#[tokio::test]
+async fn test_user_line() {
+    let config = Config {
+        username: Some("rotbot".to_string()),
+        realname: Some("The rot bot".to_string()),
+        ..Config::default()
+    };
+    let mut client = IrcClient::new("test_user_line.db", "localhost", "rot", config);
+    let (transport, mut server) = tokio::io::duplex(1024);
+    client.connect_to(vec![transport]);
+    assert!(client.connect(false).await.unwrap().is_some());
+
+    let mut buf = [0; 1024];
+    let len = server.read(&mut buf).await.unwrap();
+    let sent = String::from_utf8_lossy(&buf[..len]).to_string();
+    assert!(sent.contains("NICK rot\r\nUSER rotbot . . :The rot bot\r\n"), "{:?}", sent);
+
+    // Without them, the nick is used for both
+    client.config.username = None;
+    client.config.realname = None;
+    let (transport, mut server) = tokio::io::duplex(1024);
+    client.connect_to(vec![transport]);
+    assert!(client.connect(false).await.unwrap().is_some());
+    let len = server.read(&mut buf).await.unwrap();
+    let sent = String::from_utf8_lossy(&buf[..len]).to_string();
+    assert!(sent.contains("USER rot . . :rot\r\n"), "{:?}", sent);
+    drop(client);
+    crate::rotdb::remove_test_db("test_user_line.db");
+}
+
+#[tokio::test]
+async fn test_apply_config() {
+    crate::rotdb::remove_test_db("test_apply_config.db");
+    {
+        let config = Config::parse("channels = #kept, #removed, gone, #cli\n\
+                                    sandbox_channels = #demo").unwrap();
+        let mut client = IrcClient::new("test_apply_config.db", "localhost:6667", "rot",
+                                        config);
+        client.join_from_args("CLI", None);
+        client.feed(":rot!r@h JOIN #kept\r\n:rot!r@h JOIN #removed\r\n\
+                     :rot!r@h JOIN #cli\r\n").await;
+        assert_eq!(client.channels, vec!["#kept", "#removed", "#gone", "#cli"]);
+        assert!(client.arg_channels.contains("#cli"));
+        client.feed(":user!u@h PRIVMSG #demo :foo++\r\n").await;
+
+        let config = Config::parse("channels = #Kept, #added\n\
+                                    sandbox_channels = #test\n\
+                                    max_ops = 2\n\
+                                    key_separators = distinct").unwrap();
+        assert_eq!(client.apply_config(config),
+                   vec!["PART #removed", "PART #gone", "JOIN #added"]);
+        assert_eq!(client.channels, vec!["#kept", "#cli", "#added"]);
+        assert_eq!(std::fs::read_to_string("test_apply_config.db.channels").unwrap(),
+                   "#kept\n#cli\n");
+        assert_eq!(client.config.max_ops, 2);
+        assert_eq!(client.config.key_mode, crate::rotdb::KeyMode::Unified);
+        assert!(!client.sandboxes.contains_key("#demo"));
+        assert!(client.sandboxes.contains_key("#test"));
+        // New sandboxes also keep the key mode the db was loaded with
+        client.feed(":user!u@h PRIVMSG #test :foo::bar++\r\n").await;
+        assert_eq!(client.feed(":user!u@h PRIVMSG #test :?foo.bar\r\n").await,
+                   "PRIVMSG #test :foo.bar = 1 (also counted as: foo::bar)\r\n");
+
+        // Applying the same config again changes nothing
+        let config = Config::parse("channels = #kept, #added").unwrap();
+        assert_eq!(client.apply_config(config), Vec::<String>::new());
+        assert!(client.sandboxes.is_empty());
+    }
+    crate::rotdb::remove_test_db("test_apply_config.db");
+}
+
+#[tokio::test]
+async fn test_quiet_hours() {
+    crate::rotdb::remove_test_db("test_quiet_hours.db");
+    {
+        let config = Config::parse("quiet_hours = 22:00-07:00 UTC+2 #sleepy").unwrap();
+        let mut client = IrcClient::new("test_quiet_hours.db", "localhost:6667", "rot", config);
+        let at = |time: &str, line: &str| {
+            format!("@time=2024-03-{}Z :user!u@h PRIVMSG {}\r\n", time, line)
+        };
+        // 21:30 UTC is 23:30 in the channel's timezone
+        assert_eq!(client.feed(&at("01T21:30:00", "#sleepy :foo++")).await, "");
+        assert_eq!(client.feed(&at("01T21:30:00", "#awake :foo++")).await,
+                   "PRIVMSG #awake :foo = 2\r\n");
+        assert_eq!(client.feed(&at("01T21:30:00", "rot :?foo")).await,
+                   "PRIVMSG user :foo = 2\r\n");
+        // Past midnight, and still quiet
+        assert_eq!(client.feed(&at("02T03:00:00", "#sleepy :?foo")).await, "");
+        assert_eq!(client.feed(&at("02T05:00:00", "#sleepy :foo++")).await,
+                   "PRIVMSG #sleepy :foo = 3\r\n");
+    }
+    crate::rotdb::remove_test_db("test_quiet_hours.db");
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_idle_watchdog() {
+    let mut config = Config { max_idle: Some(Duration::from_secs(90)), ..Config::default() };
+    config.ban.backoff = None;
+    let mut client = IrcClient::new("test_idle.db", "localhost:6667", "rot", config);
+    let (first, mut first_server) = tokio::io::duplex(1024);
+    let (second, mut second_server) = tokio::io::duplex(1024);
+    client.connect_to(vec![first, second]);
+
+    let server = async move {
+        let mut buf = [0; 1024];
+        let _ = first_server.read(&mut buf).await;
+        first_server.write_all(b":irc.example.com 001 rot :Welcome\r\n").await.unwrap();
+        // Stay connected but silent, until the client gives up on us
+        let start = Instant::now();
+        while first_server.read(&mut buf).await.unwrap() > 0 {}
+        assert_eq!(Instant::now() - start, Duration::from_secs(90));
+        assert!(Instant::now() - start < PING_INTERVAL);
+
+        let _ = second_server.read(&mut buf).await;
+        second_server.write_all(b"ERROR :Closing Link: rot (K-lined)\r\n").await.unwrap();
+    };
+    let (result, _) = tokio::join!(client.run(), server);
+    assert!(matches!(result, Err(RotError::Protocol(_))));
+    crate::rotdb::remove_test_db("test_idle.db");
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_save_debounce() {
+    crate::rotdb::remove_test_db("test_debounce.db");
+    let mut config = Config { save_debounce: Some(Duration::from_secs(5)), ..Config::default() };
+    config.ban.backoff = None;
+    let mut client = IrcClient::new("test_debounce.db", "localhost:6667", "rot", config);
+    let (transport, mut server) = tokio::io::duplex(1024);
+    client.connect_to(vec![transport]);
+
+    let saved = || std::fs::metadata("test_debounce.db").is_ok();
+    let server = async move {
+        let mut buf = [0; 1024];
+        let _ = server.read(&mut buf).await;
+        for _ in 0..3 {
+            server.write_all(b":user!u@h PRIVMSG rot :foo++\r\n").await.unwrap();
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            assert!(!saved());
+        }
+        // The burst is saved once, five seconds after its last change
+        tokio::time::sleep(Duration::from_millis(2900)).await;
+        assert!(!saved());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(saved());
+        assert_eq!(RotDb::new("test_debounce.db").value("foo"), 3);
+        server.write_all(b"ERROR :Closing Link: rot (K-lined)\r\n").await.unwrap();
+    };
+    let (result, _) = tokio::join!(client.run(), server);
+    assert!(matches!(result, Err(RotError::Protocol(_))));
+    crate::rotdb::remove_test_db("test_debounce.db");
+}
+
+#[tokio::test]
+async fn test_own_echo() {
+    crate::rotdb::remove_test_db("test_own_echo.db");
+    {
+        let mut client = IrcClient::new("test_own_echo.db", "localhost:6667", "rot",
+                                        Config::default());
+        // Skipped even without echo-message
+        assert_eq!(client.feed(":Rot!rot@h PRIVMSG #chan :foo++\r\n").await, "");
+        assert_eq!(client.feed(":rot!rot@h PRIVMSG #chan :?top\r\n").await, "");
+        assert_eq!(client.feed(":rot!rot@h PRIVMSG rot :?foo\r\n").await, "");
+        assert_eq!(client.db.get("foo"), None);
+        assert_eq!(client.feed(":rotten!u@h PRIVMSG #chan :foo++\r\n").await,
+                   "PRIVMSG #chan :foo = 1\r\n");
+    }
+    crate::rotdb::remove_test_db("test_own_echo.db");
+}
+
+#[tokio::test]
+async fn test_external_shutdown() {
+    let (shutdown_send, shutdown) = mpsc::channel(1);
+    let mut client = IrcClient::without_signals(Box::new(crate::store::MemoryStore::default()),
+                                                "test_shutdown.db", "localhost:6667", "rot",
+                                                Config::default(), shutdown);
+    let (transport, mut server) = tokio::io::duplex(1024);
+    client.connect_to(vec![transport]);
+
+    let server = async move {
+        let mut buf = [0; 1024];
+        let _ = server.read(&mut buf).await;
+        shutdown_send.send(()).await.unwrap();
+        let mut received = String::new();
+        server.read_to_string(&mut received).await.unwrap();
+        received
+    };
+    let (result, received) = tokio::join!(client.run(), server);
+    assert!(result.is_ok());
+    assert!(received.ends_with("QUIT :--rot!\r\n"));
+    crate::rotdb::remove_test_db("test_shutdown.db");
+}
+
+#[tokio::test]
+async fn test_bind_address() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote_addr = listener.local_addr().unwrap().to_string();
+    // All of 127.0.0.0/8 is loopback on Linux, so the source address can
+    // differ from where we connect to
+    let bind_address: IpAddr = if cfg!(target_os = "linux") {
+        [127, 0, 0, 2].into()
+    } else {
+        [127, 0, 0, 1].into()
+    };
+    let (connected, accepted) = tokio::join!(tcp_connect(&remote_addr, Some(bind_address)),
+                                             listener.accept());
+    assert!(connected.is_ok());
+    assert_eq!(accepted.unwrap().1.ip(), bind_address);
+
+    // An address that isn't ours, or can't reach the server
+    for bind_address in &["192.0.2.1", "::1"] {
+        match tcp_connect(&remote_addr, Some(bind_address.parse().unwrap())).await {
+            Err(err) => {
+                assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+                assert!(err.to_string().starts_with(&format!("Could not bind to {}: ", bind_address)));
+            }
+            Ok(_) => panic!("bound to {}", bind_address),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_custom_store() {
+    let store = crate::store::MemoryStore::default();
+    let syncs = store.syncs.clone();
+    let config = Config { sandbox_channels: vec!["#demo".to_string()], ..Config::default() };
+    let mut client = IrcClient::with_store(Box::new(store), "test_store.db", "localhost:6667",
+                                           "rot", config);
+    assert_eq!(client.feed(":user!u@h PRIVMSG #chan :Foo++; bar--; foo++\r\n").await,
+               "PRIVMSG #chan :Foo = 1, bar = -1, foo = 2\r\n");
+    assert_eq!(client.feed(":user!u@h PRIVMSG #chan :?top\r\n").await,
+               "PRIVMSG #chan :Top karma: foo = 2, bar = -1\r\n");
+    assert_eq!(client.feed(":user!u@h PRIVMSG #demo :foo++\r\n").await,
+               "PRIVMSG #demo :foo = 3\r\n");
+    assert_eq!(client.db.value("FOO"), 2);
+    assert_eq!(client.db.add("foo", 1, SystemTime::now()), 3);
+
+    assert_eq!(syncs.load(std::sync::atomic::Ordering::SeqCst), 0);
+    client.db.close().unwrap();
+    assert_eq!(syncs.load(std::sync::atomic::Ordering::SeqCst), 1);
 }
@@ -0,0 +1,42 @@
+//! A karma bot for IRC, along with the parts of it that are useful on their
+//! own.
+//!
+//! The karma syntax (`foo++`, `--bar`, `?baz` and the `?` queries) is parsed
+//! by `parse_line`, which knows nothing about IRC, so it can be used by any
+//! bot:
+//!
+//! ```
+//! use rot::{parse_line, parse_ops, ParsedLine};
+//!
+//! assert_eq!(parse_line("rust++ // for the borrow checker"),
+//!            ParsedLine::Increment("rust".to_string()));
+//! assert_eq!(parse_line("?Foo::Bar"), ParsedLine::Query("Foo::Bar".to_string()));
+//! assert_eq!(parse_line("just chatting"), ParsedLine::Nothing);
+//! assert_eq!(parse_ops("vim++; emacs--"),
+//!            vec![ParsedLine::Increment("vim".to_string()),
+//!                 ParsedLine::Decrement("emacs".to_string())]);
+//! ```
+//!
+//! `RotDb` is the flat file db the bot keeps karma in, and `IrcClient` is
+//! the bot itself, as run by the `rot` binary.  The client's modules are
+//! private, so only what's re-exported here is part of the API.
+
+pub mod line_parse;
+pub mod rotdb;
+mod error;
+mod config;
+mod irc_client;
+mod admin;
+mod schedule;
+mod template;
+mod store;
+mod db_task;
+
+pub use line_parse::{ParsedLine, SpannedLine, parse_line, parse_line_spans, parse_ops};
+pub use rotdb::RotDb;
+pub use error::RotError;
+pub use config::{Config, SaslMechanism};
+pub use irc_client::{ConnectFuture, Connection, ConnectionEvent, Connector, IrcClient, KarmaEvent,
+                     Transport, open_db};
+pub use store::KarmaStore;
+pub use db_task::DbHandle;
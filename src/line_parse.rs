@@ -1,3 +1,6 @@
+use std::borrow::Cow;
+use std::ops::Range;
+
 use regex::Regex;
 use lazy_static::lazy_static;
 
@@ -7,6 +10,24 @@ pub enum ParsedLine {
     Increment(String),
     Decrement(String),
     Query(String),
+    /// `?!key`, a query that also gives the rank
+    RankedQuery(String),
+    WhoDid(String),
+    Diff(String, String),
+    Equal(i64),
+    Mine,
+    Topic,
+    Top,
+    Recent,
+    /// `?find text`, for keys containing the text
+    Find(String),
+    /// `?count`, for how many keys there are
+    Count,
+    /// `?karma-given nick`
+    KarmaGiven(String),
+    /// `?karma-received nick`
+    KarmaReceived(String),
+    Uptime,
 }
 use ParsedLine::*;
 
@@ -15,26 +36,289 @@ fn parsed_from(op: &str, ident: &str) -> ParsedLine {
         "++" => Increment(ident.to_string()),
         "--" => Decrement(ident.to_string()),
         "?" => Query(ident.to_string()),
+        "?!" => RankedQuery(ident.to_string()),
         _ => Nothing,
     }
 }
 
+/// A parsed line, with where the key and operator were found.  The spans
+/// are byte ranges in the original line, comments included.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SpannedLine {
+    pub parsed: ParsedLine,
+    /// The first key, if the line names any
+    pub key: Option<Range<usize>>,
+    /// The `++`, `--`, `?` or `?!` of an increment, decrement or query.  For
+    /// `++foo++`, this is the first one.
+    pub operator: Option<Range<usize>>,
+}
+
+/// Whitespace between tokens, in both karma lines and IRC messages.  Only
+/// ASCII whitespace counts (space, tab, CR, LF and form feed), so Unicode
+/// spaces such as U+00A0 don't separate anything.
+pub fn is_space(c: char) -> bool {
+    c.is_ascii_whitespace()
+}
+
+/// Build a regex, with every `\s` matching exactly what `is_space` does
+/// Whether `text` is a key that could be written in a karma line, such as
+/// `foo`, `Foo::Bar` or `foo->bar_2`
+pub fn is_key(text: &str) -> bool {
+    lazy_static! {
+        static ref RE_KEY: Regex = Regex::new(
+                r"^[A-Za-z_][A-Za-z0-9_]*(?:(?:\.|->|::)[A-Za-z_][A-Za-z0-9_]*)*$").unwrap();
+    }
+    RE_KEY.is_match(text)
+}
+
+fn spaced_regex(pattern: &str) -> Regex {
+    Regex::new(&pattern.replace(r"\s", r"[\t\n\x0C\r ]")).unwrap()
+}
+
+/// Where the C and C++ style comments in `line` are, in order.  A C comment
+/// ends at the first `*/`, so there may be code between two comments on the
+/// same line, and an unterminated `/*` is left alone.  Neither kind carries
+/// on past a newline.
+///
+/// This is a single pass over the line, since searching for the end of each
+/// `/*` separately (as a regex would) is quadratic on lines full of them.
+fn comment_ranges(line: &str) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut offset = 0;
+    for text in line.split('\n') {
+        let bytes = text.as_bytes();
+        let mut pos = 0;
+        // Once a `/*` has no end, none of the later ones do either
+        let mut unterminated = false;
+        while pos + 1 < bytes.len() {
+            match (bytes[pos], bytes[pos + 1]) {
+                (b'/', b'/') => {
+                    ranges.push(offset + pos..offset + bytes.len());
+                    break;
+                }
+                (b'/', b'*') if !unterminated => match text[pos + 2..].find("*/") {
+                    Some(end) => {
+                        let end = pos + 2 + end + 2;
+                        ranges.push(offset + pos..offset + end);
+                        pos = end;
+                        continue;
+                    }
+                    None => unterminated = true,
+                },
+                _ => (),
+            }
+            pos += 1;
+        }
+        offset += text.len() + 1;
+    }
+    ranges
+}
+
+/// Strip the comments from `line`
+fn clean_line(line: &str) -> Cow<'_, str> {
+    let ranges = comment_ranges(line);
+    if ranges.is_empty() {
+        return Cow::Borrowed(line);
+    }
+    let mut clean = String::with_capacity(line.len());
+    let mut pos = 0;
+    for range in ranges {
+        clean.push_str(&line[pos..range.start]);
+        pos = range.end;
+    }
+    clean.push_str(&line[pos..]);
+    Cow::Owned(clean)
+}
+
 pub fn parse_line(line: &str) -> ParsedLine {
+    parse_clean(&clean_line(line))
+}
+
+/// Like `parse_line`, but also says where the key and operator are
+pub fn parse_line_spans(line: &str) -> SpannedLine {
+    let (parsed, key, operator) = parse_clean_spans(&clean_line(line));
+    let removed = comment_ranges(line);
+    let to_original = |span: Range<usize>| {
+        to_original(&removed, span.start)..to_original(&removed, span.end - 1) + 1
+    };
+    SpannedLine { parsed, key: key.map(to_original), operator: operator.map(to_original) }
+}
+
+/// Where the character at `pos` in the cleaned line came from, given the
+/// comments that were removed, in order
+fn to_original(removed: &[Range<usize>], pos: usize) -> usize {
+    let mut pos = pos;
+    for range in removed {
+        if range.start > pos {
+            break;
+        }
+        pos += range.len();
+    }
+    pos
+}
+
+/// A thank-you found by `parse_thanks`
+#[derive(Debug, PartialEq, Eq)]
+pub enum Thanks {
+    /// `foo++ thanks`, which is an increment either way
+    Key(String),
+    /// `thanks foo` or `foo: thanks`, which should only count if `foo` is
+    /// someone's nick, since `thanks all` is just as likely
+    Nick(String),
+}
+
+/// Parse a thank-you using one of the `triggers` (single words such as
+/// `thanks` or `ty`, in any case), which is `thanks foo`, `thanks, foo`,
+/// `foo: thanks` or `foo++ thanks`, optionally ending with `!` or `.`.
+/// Anything longer, like `thanks for that`, isn't thanks to anyone in
+/// particular, so it's ignored.
+pub fn parse_thanks(line: &str, triggers: &[String]) -> Option<Thanks> {
+    let clean = clean_line(line);
+    let text = clean.trim_matches(|c| is_space(c) || c == '!' || c == '.');
+    let is_trigger = |word: &str| triggers.iter().any(|trigger| trigger.eq_ignore_ascii_case(word));
+    // Only a plain key can be thanked, since that's all that can be queried
+    let as_key = |word: &str| match parse_clean(&format!("++{}", word)) {
+        Increment(key) if key == word => Some(key),
+        _ => None,
+    };
+    let words: Vec<&str> = text.split(is_space).filter(|word| !word.is_empty()).collect();
+    match words.as_slice() {
+        [first, second] if is_trigger(first.trim_end_matches([',', ':'])) => {
+            as_key(second).map(Thanks::Nick)
+        }
+        [first, second] if is_trigger(second) => {
+            if let Increment(key) = parse_clean(first) {
+                Some(Thanks::Key(key))
+            } else {
+                first.strip_suffix([',', ':']).and_then(as_key).map(Thanks::Nick)
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Parse a line which may contain several operations separated by `;`, such
+/// as `foo++; bar--; ?baz`.  Only increments, decrements and queries can be
+/// combined, and if any statement isn't one of them, nothing is returned.
+/// A single statement is parsed the same as `parse_line`.
+pub fn parse_ops(line: &str) -> Vec<ParsedLine> {
+    let clean = clean_line(line);
+    let statements: Vec<&str> = clean.split(';')
+                                     .filter(|statement| !statement.trim_matches(is_space).is_empty())
+                                     .collect();
+    if statements.len() > 1 {
+        let ops: Vec<ParsedLine> = statements.iter().map(|s| parse_clean(s)).collect();
+        if ops.iter().all(|op| matches!(op, Increment(_) | Decrement(_) | Query(_) | RankedQuery(_))) {
+            return ops;
+        }
+        return vec![];
+    }
+    match parse_clean(&clean) {
+        Nothing => vec![],
+        op => vec![op],
+    }
+}
+
+/// Rewrite the queries in `line` written with `prefix` (such as `!karma foo`)
+/// into the `?foo` form the parser expects, dropping any statements using a
+/// plain `?` instead, so only one prefix is recognized.  A `?` prefix leaves
+/// the line as it is.
+pub fn replace_query_prefix<'a>(line: &'a str, prefix: &str) -> Cow<'a, str> {
+    if prefix == "?" {
+        return Cow::Borrowed(line);
+    }
+    let statements: Vec<String> = line.split(';').map(|statement| {
+        let trimmed = statement.trim_start_matches(is_space);
+        match trimmed.strip_prefix(prefix) {
+            // `!karmafoo` isn't `!karma foo`
+            Some(rest) if !(prefix.ends_with(char::is_alphanumeric)
+                            && rest.starts_with(char::is_alphanumeric)) => {
+                format!("?{}", rest.trim_start_matches(is_space))
+            }
+            _ if trimmed.starts_with('?') => String::new(),
+            _ => statement.to_string(),
+        }
+    }).collect();
+    Cow::Owned(statements.join(";"))
+}
+
+/// Parse a single statement with the comments removed.
+///
+/// Any amount of whitespace (see `is_space`) may surround the key and
+/// operator, but an operator can't be split up, so `+ +foo` and `+\t+foo`
+/// change nothing.
+///
+/// The same operator on both sides, as in `++foo++`, is read as a single
+/// change, since that's usually an accident.  Different operators
+/// (`++foo--`) contradict each other, and runs of three or more (`foo+++`,
+/// `+++foo`) could be split more than one way, so they change nothing.
+fn parse_clean(clean: &str) -> ParsedLine {
+    parse_clean_spans(clean).0
+}
+
+/// `parse_clean`, along with the spans of the key and operator in `clean`
+fn parse_clean_spans(clean: &str)
+        -> (ParsedLine, Option<Range<usize>>, Option<Range<usize>>) {
     lazy_static! {
-        static ref RE_CLEAN: Regex = Regex::new(r"(?:/\*(?:[^/]|/[^*])*\*/|//.*)").unwrap();
-        static ref RE_PREOP: Regex = Regex::new(
-                r"^\s*(\+\+|--|\?)\s*([A-Za-z_][A-Za-z0-9_]*(?:(?:\.|->|::)[A-Za-z_][A-Za-z0-9_]*)*)[\s;]*$").unwrap();
-        static ref RE_POSTOP: Regex = Regex::new(
-                r"^\s*([A-Za-z_][A-Za-z0-9_]*(?:(?:\.|->|::)[A-Za-z_][A-Za-z0-9_]*)*)\s*(\+\+|--)[\s;]*$").unwrap();
-    }
-
-    let clean = RE_CLEAN.replace_all(line, "");
-    if let Some(pre_caps) = RE_PREOP.captures(&clean) {
-        parsed_from(&pre_caps[1], &pre_caps[2])
-    } else if let Some(post_caps) = RE_POSTOP.captures(&clean) {
-        parsed_from(&post_caps[2], &post_caps[1])
+        static ref RE_PREOP: Regex = spaced_regex(
+                r"^\s*(\+\+|--|\?!?)\s*([A-Za-z_][A-Za-z0-9_]*(?:(?:\.|->|::)[A-Za-z_][A-Za-z0-9_]*)*)[\s;]*$");
+        static ref RE_POSTOP: Regex = spaced_regex(
+                r"^\s*([A-Za-z_][A-Za-z0-9_]*(?:(?:\.|->|::)[A-Za-z_][A-Za-z0-9_]*)*)\s*(\+\+|--)[\s;]*$");
+        static ref RE_BOTHOP: Regex = spaced_regex(
+                r"^\s*(\+\+|--)\s*([A-Za-z_][A-Za-z0-9_]*(?:(?:\.|->|::)[A-Za-z_][A-Za-z0-9_]*)*)\s*(\+\+|--)[\s;]*$");
+        static ref RE_WHODID: Regex = spaced_regex(
+                r"^\s*\?\s*whodid\s+([A-Za-z_][A-Za-z0-9_]*(?:(?:\.|->|::)[A-Za-z_][A-Za-z0-9_]*)*)[\s;]*$");
+        static ref RE_DIFF: Regex = spaced_regex(
+                r"^\s*\?\s*diff\s+([A-Za-z_][A-Za-z0-9_]*(?:(?:\.|->|::)[A-Za-z_][A-Za-z0-9_]*)*)\s+([A-Za-z_][A-Za-z0-9_]*(?:(?:\.|->|::)[A-Za-z_][A-Za-z0-9_]*)*)[\s;]*$");
+        static ref RE_EQUAL: Regex = spaced_regex(r"^\s*\?\s*eq\s+(-?[0-9]+)[\s;]*$");
+        static ref RE_MINE: Regex = spaced_regex(r"^\s*\?\s*mine[\s;]*$");
+        static ref RE_TOPIC: Regex = spaced_regex(r"^\s*\?\s*topic[\s;]*$");
+        static ref RE_TOP: Regex = spaced_regex(r"^\s*\?\s*top[\s;]*$");
+        static ref RE_RECENT: Regex = spaced_regex(r"^\s*\?\s*recent[\s;]*$");
+        static ref RE_COUNT: Regex = spaced_regex(r"^\s*\?\s*count[\s;]*$");
+        static ref RE_FIND: Regex = spaced_regex(r"^\s*\?\s*find\s+([A-Za-z0-9_.:>-]+)[\s;]*$");
+        static ref RE_KARMA_USER: Regex = spaced_regex(
+                r"^\s*\?\s*karma-(given|received)\s+([A-Za-z\[\]\\`_^{|}][A-Za-z0-9\[\]\\`_^{|}-]*)[\s;]*$");
+        static ref RE_UPTIME: Regex = spaced_regex(r"^\s*!uptime\s*$");
+    }
+
+    let span = |caps: &regex::Captures, group| caps.get(group).map(|m| m.range());
+    if let Some(whodid_caps) = RE_WHODID.captures(clean) {
+        (WhoDid(whodid_caps[1].to_string()), span(&whodid_caps, 1), None)
+    } else if let Some(diff_caps) = RE_DIFF.captures(clean) {
+        (Diff(diff_caps[1].to_string(), diff_caps[2].to_string()), span(&diff_caps, 1), None)
+    } else if let Some(equal_caps) = RE_EQUAL.captures(clean) {
+        (equal_caps[1].parse().map_or(Nothing, Equal), None, None)
+    } else if RE_MINE.is_match(clean) {
+        (Mine, None, None)
+    } else if RE_TOPIC.is_match(clean) {
+        (Topic, None, None)
+    } else if RE_TOP.is_match(clean) {
+        (Top, None, None)
+    } else if RE_RECENT.is_match(clean) {
+        (Recent, None, None)
+    } else if RE_COUNT.is_match(clean) {
+        (Count, None, None)
+    } else if let Some(find_caps) = RE_FIND.captures(clean) {
+        (Find(find_caps[1].to_string()), span(&find_caps, 1), None)
+    } else if let Some(user_caps) = RE_KARMA_USER.captures(clean) {
+        let nick = user_caps[2].to_string();
+        let parsed = if &user_caps[1] == "given" { KarmaGiven(nick) } else { KarmaReceived(nick) };
+        (parsed, span(&user_caps, 2), None)
+    } else if RE_UPTIME.is_match(clean) {
+        (Uptime, None, None)
+    } else if let Some(pre_caps) = RE_PREOP.captures(clean) {
+        (parsed_from(&pre_caps[1], &pre_caps[2]), span(&pre_caps, 2), span(&pre_caps, 1))
+    } else if let Some(post_caps) = RE_POSTOP.captures(clean) {
+        (parsed_from(&post_caps[2], &post_caps[1]), span(&post_caps, 1), span(&post_caps, 2))
+    } else if let Some(both_caps) = RE_BOTHOP.captures(clean) {
+        if both_caps[1] == both_caps[3] {
+            (parsed_from(&both_caps[1], &both_caps[2]), span(&both_caps, 2), span(&both_caps, 1))
+        } else {
+            (Nothing, None, None)
+        }
     } else {
-        Nothing
+        (Nothing, None, None)
     }
 }
 
@@ -100,3 +384,273 @@ fn test_parser() {
     assert_eq!(parse_line("+/* junk */+foo:/* junk */:bar // junk"),
                Increment("foo::bar".to_string()));
 }
+
+#[test]
+fn test_doubled_operators() {
+    assert_eq!(parse_line("++foo++"), Increment("foo".to_string()));
+    assert_eq!(parse_line("--foo--"), Decrement("foo".to_string()));
+    assert_eq!(parse_line(" ++ Foo::Bar ++ ;"), Increment("Foo::Bar".to_string()));
+    assert_eq!(parse_line("++foo--"), Nothing);
+    assert_eq!(parse_line("--foo++"), Nothing);
+    assert_eq!(parse_line("?foo++"), Nothing);
+    assert_eq!(parse_line("++foo++++"), Nothing);
+
+    assert_eq!(parse_line("foo+++"), Nothing);
+    assert_eq!(parse_line("foo++++"), Nothing);
+    assert_eq!(parse_line("+++foo"), Nothing);
+    assert_eq!(parse_line("foo---"), Nothing);
+    assert_eq!(parse_line("---foo"), Nothing);
+    assert_eq!(parse_line("foo+-"), Nothing);
+
+    assert_eq!(parse_ops("++foo++; bar--"), vec![Increment("foo".to_string()),
+                                                 Decrement("bar".to_string())]);
+}
+
+#[test]
+fn test_is_key() {
+    for key in ["foo", "Foo::Bar", "foo->bar_2", "_", "a.b.c"] {
+        assert!(is_key(key), "{}", key);
+    }
+    for text in ["", "9lives", "foo.", "two words", "c++", "foo::", "#chan", " foo"] {
+        assert!(!is_key(text), "{}", text);
+    }
+}
+
+#[test]
+fn test_whitespace() {
+    for sep in &[" ", "\t", "  ", "\t\t", " \t ", "\x0C"] {
+        let line = |format: &str| format.replace('_', sep);
+        assert_eq!(parse_line(&line("_++_foo_")), Increment("foo".to_string()));
+        assert_eq!(parse_line(&line("_foo_--_")), Decrement("foo".to_string()));
+        assert_eq!(parse_line(&line("_?_foo_;_")), Query("foo".to_string()));
+        assert_eq!(parse_line(&line("_++_foo_++_")), Increment("foo".to_string()));
+        assert_eq!(parse_line(&line("?_whodid_foo_")), WhoDid("foo".to_string()));
+        assert_eq!(parse_line(&line("?_diff_foo_bar_")),
+                   Diff("foo".to_string(), "bar".to_string()));
+        assert_eq!(parse_line(&line("?_eq_5_")), Equal(5));
+        assert_eq!(parse_ops(&line("foo++;_bar--;_")),
+                   vec![Increment("foo".to_string()), Decrement("bar".to_string())]);
+
+        // Operators and keys can't be split up
+        assert_eq!(parse_line(&line("+_+foo")), Nothing);
+        assert_eq!(parse_line(&line("foo-_-")), Nothing);
+        assert_eq!(parse_line(&line("++foo_::bar")), Nothing);
+        assert_eq!(parse_line(&line("foo_+_+")), Nothing);
+    }
+
+    // Unicode whitespace isn't a separator
+    assert_eq!(parse_line("++\u{a0}foo"), Nothing);
+    assert_eq!(parse_line("foo++\u{2003}"), Nothing);
+    assert_eq!(parse_ops("foo++;\u{a0};bar++"), vec![]);
+    assert!(is_space('\t'));
+    assert!(!is_space('\u{a0}'));
+    assert!(!is_space('\x0B'));
+}
+
+#[test]
+fn test_spans() {
+    let spans = |line| {
+        let spanned = parse_line_spans(line);
+        (spanned.parsed, spanned.key, spanned.operator)
+    };
+    assert_eq!(spans("foo++"), (Increment("foo".to_string()), Some(0..3), Some(3..5)));
+    assert_eq!(spans("  --Foo::Bar ;"), (Decrement("Foo::Bar".to_string()), Some(4..12), Some(2..4)));
+    assert_eq!(spans("? foo"), (Query("foo".to_string()), Some(2..5), Some(0..1)));
+    assert_eq!(spans("?! foo"), (RankedQuery("foo".to_string()), Some(3..6), Some(0..2)));
+    assert_eq!(spans("++foo++"), (Increment("foo".to_string()), Some(2..5), Some(0..2)));
+    assert_eq!(spans("?whodid bar"), (WhoDid("bar".to_string()), Some(8..11), None));
+    assert_eq!(spans("?top"), (Top, None, None));
+    assert_eq!(spans("hello"), (Nothing, None, None));
+
+    // Spans are in the original line, even with comments before or inside
+    let line = "/* c */ foo/**/++ // done";
+    let spanned = parse_line_spans(line);
+    assert_eq!(spanned.parsed, Increment("foo".to_string()));
+    assert_eq!(&line[spanned.key.clone().unwrap()], "foo");
+    assert_eq!(&line[spanned.operator.clone().unwrap()], "++");
+    let line = "/**/++/**/foo";
+    let spanned = parse_line_spans(line);
+    assert_eq!(&line[spanned.key.unwrap()], "foo");
+    assert_eq!(&line[spanned.operator.unwrap()], "++");
+    // A comment splitting the operator is included in its span
+    assert_eq!(parse_line_spans("foo+/**/+").operator, Some(3..9));
+}
+
+#[test]
+fn test_comments() {
+    assert_eq!(parse_line("/* a */ foo++ /* b */"), Increment("foo".to_string()));
+    assert_eq!(parse_line("/* a /* b */ foo++"), Increment("foo".to_string()));
+    assert_eq!(parse_line("/*/ foo++ */"), Nothing);
+    assert_eq!(parse_line("/**/foo/**/++/**/"), Increment("foo".to_string()));
+    assert_eq!(parse_line("/* unterminated foo++"), Nothing);
+}
+
+#[test]
+fn test_pathological_input() {
+    // The regex crate runs in linear time, but make sure nothing here
+    // (such as finding comments or splitting statements) is quadratic on
+    // long lines
+    let inputs = [
+        "/*/".repeat(20_000),
+        "/*".repeat(20_000) + "*/",
+        "/*".repeat(50_000),
+        "/".repeat(100_000),
+        "*/".repeat(50_000),
+        "/*\n".repeat(30_000),
+        "a::".repeat(20_000) + "++",
+        ";".repeat(60_000),
+        "++".repeat(30_000),
+        "a++;".repeat(15_000),
+    ];
+    for input in &inputs {
+        let start = std::time::Instant::now();
+        parse_line(input);
+        parse_ops(input);
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    }
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_parse_arbitrary(line in "\\PC*") {
+        parse_line(&line);
+        parse_ops(&line);
+    }
+
+    #[test]
+    fn test_comment_scanner(line in "[/* a\n]*") {
+        // Finds the same comments as the simplest regex for them
+        let regex = Regex::new(r"/\*.*?\*/|//.*").unwrap();
+        let expected: Vec<Range<usize>> = regex.find_iter(&line).map(|m| m.range()).collect();
+        proptest::prop_assert_eq!(comment_ranges(&line), expected);
+    }
+
+    #[test]
+    fn test_parse_operator_soup(line in "[a-z_:.>+?/*; -]{0,200}") {
+        let single = parse_line(&line);
+        let ops = parse_ops(&line);
+        if single != Nothing && !line.contains(';') {
+            proptest::prop_assert_eq!(ops, vec![single]);
+        }
+    }
+}
+
+#[test]
+fn test_history_commands() {
+    assert_eq!(parse_line("?whodid foo"), WhoDid("foo".to_string()));
+    assert_eq!(parse_line(" ? whodid  Foo::Bar "), WhoDid("Foo::Bar".to_string()));
+    assert_eq!(parse_line("?whodid"), Query("whodid".to_string()));
+    assert_eq!(parse_line("?whodid foo bar"), Nothing);
+    assert_eq!(parse_line("?mine"), Mine);
+    assert_eq!(parse_line("  ?mine // junk"), Mine);
+    assert_eq!(parse_line("?mine foo"), Nothing);
+    assert_eq!(parse_line("mine++"), Increment("mine".to_string()));
+    assert_eq!(parse_line("?topic"), Topic);
+    assert_eq!(parse_line("? topic ;"), Topic);
+    assert_eq!(parse_line("?topic foo"), Nothing);
+    assert_eq!(parse_line("?top"), Top);
+    assert_eq!(parse_line("?top;"), Top);
+    assert_eq!(parse_line("?topper"), Query("topper".to_string()));
+    assert_eq!(parse_line("?!foo"), RankedQuery("foo".to_string()));
+    assert_eq!(parse_line(" ?! Foo::Bar ;"), RankedQuery("Foo::Bar".to_string()));
+    assert_eq!(parse_line("? !foo"), Nothing);
+    assert_eq!(parse_line("?!!foo"), Nothing);
+    assert_eq!(parse_line("foo?!"), Nothing);
+    assert_eq!(parse_ops("?!foo; ?bar"),
+               vec![RankedQuery("foo".to_string()), Query("bar".to_string())]);
+    assert_eq!(parse_line("?recent"), Recent);
+    assert_eq!(parse_line(" ? recent ;"), Recent);
+    assert_eq!(parse_line("?recent foo"), Nothing);
+    assert_eq!(parse_line("recent++"), Increment("recent".to_string()));
+    assert_eq!(parse_line("?count"), Count);
+    assert_eq!(parse_line(" ? count ;"), Count);
+    assert_eq!(parse_line("?count foo"), Nothing);
+    assert_eq!(parse_line("?counter"), Query("counter".to_string()));
+    assert_eq!(parse_line("?find rust"), Find("rust".to_string()));
+    assert_eq!(parse_line(" ? find  Foo::B ;"), Find("Foo::B".to_string()));
+    assert_eq!(parse_line("?find"), Query("find".to_string()));
+    assert_eq!(parse_line("?find rust go"), Nothing);
+    assert_eq!(parse_line("?findrust"), Query("findrust".to_string()));
+    assert_eq!(parse_line("?karma-given alice"), KarmaGiven("alice".to_string()));
+    assert_eq!(parse_line(" ? karma-received  [Bob]-away ;"),
+               KarmaReceived("[Bob]-away".to_string()));
+    assert_eq!(parse_line("?karma-given"), Nothing);
+    assert_eq!(parse_line("?karma-given alice bob"), Nothing);
+    assert_eq!(parse_line("?karma-taken alice"), Nothing);
+    assert_eq!(parse_line("?karma-given -alice"), Nothing);
+    assert_eq!(parse_line("?diff vim emacs"), Diff("vim".to_string(), "emacs".to_string()));
+    assert_eq!(parse_line(" ? diff  Foo::Bar  baz "), Diff("Foo::Bar".to_string(), "baz".to_string()));
+    assert_eq!(parse_line("?diff vim"), Nothing);
+    assert_eq!(parse_line("?diff vim emacs nano"), Nothing);
+    assert_eq!(parse_line("?diff"), Query("diff".to_string()));
+    assert_eq!(parse_line("?eq 0"), Equal(0));
+    assert_eq!(parse_line(" ? eq  -12 ;"), Equal(-12));
+    assert_eq!(parse_line("?eq"), Query("eq".to_string()));
+    assert_eq!(parse_line("?eq foo"), Nothing);
+    assert_eq!(parse_line("?eq 1 2"), Nothing);
+    assert_eq!(parse_line("?eq 99999999999999999999"), Nothing);
+    assert_eq!(parse_line("!uptime"), Uptime);
+    assert_eq!(parse_line(" !uptime // junk"), Uptime);
+    assert_eq!(parse_line("! uptime"), Nothing);
+    assert_eq!(parse_line("!uptime please"), Nothing);
+}
+
+#[test]
+fn test_multiple_ops() {
+    assert_eq!(parse_ops(""), vec![]);
+    assert_eq!(parse_ops("Hello, world!"), vec![]);
+    assert_eq!(parse_ops("foo++"), vec![Increment("foo".to_string())]);
+    assert_eq!(parse_ops("foo++;;"), vec![Increment("foo".to_string())]);
+    assert_eq!(parse_ops("?top"), vec![Top]);
+    assert_eq!(parse_ops("foo++; --bar; ?baz;"), vec![Increment("foo".to_string()),
+                                                      Decrement("bar".to_string()),
+                                                      Query("baz".to_string())]);
+    assert_eq!(parse_ops("a++;b++ /* ; */ ; c++ // ;"), vec![Increment("a".to_string()),
+                                                             Increment("b".to_string()),
+                                                             Increment("c".to_string())]);
+    assert_eq!(parse_ops("foo++; I agree"), vec![]);
+    assert_eq!(parse_ops("foo++; ?top"), vec![]);
+}
+
+#[test]
+fn test_thanks() {
+    let triggers = vec!["thanks".to_string(), "ty".to_string()];
+    let thanks = |line| parse_thanks(line, &triggers);
+    let nick = |nick: &str| Some(Thanks::Nick(nick.to_string()));
+    assert_eq!(thanks("thanks alice"), nick("alice"));
+    assert_eq!(thanks("  Thanks, alice! "), nick("alice"));
+    assert_eq!(thanks("TY bob."), nick("bob"));
+    assert_eq!(thanks("alice: thanks"), nick("alice"));
+    assert_eq!(thanks("alice, ty!!"), nick("alice"));
+    assert_eq!(thanks("foo.bar++ thanks"), Some(Thanks::Key("foo.bar".to_string())));
+    assert_eq!(thanks("++foo thanks /* for the fix */"), Some(Thanks::Key("foo".to_string())));
+
+    assert_eq!(thanks("thanks"), None);
+    assert_eq!(thanks("thanks for that"), None);
+    assert_eq!(thanks("thanks alice and bob"), None);
+    assert_eq!(thanks("no thanks"), None);
+    assert_eq!(thanks("alice thanks"), None);
+    assert_eq!(thanks("foo-- thanks"), None);
+    assert_eq!(thanks("thanks [alice]"), None);
+    assert_eq!(thanks("thanksalice"), None);
+    assert_eq!(thanks("cheers alice"), None);
+    assert_eq!(parse_thanks("thanks alice", &[]), None);
+}
+
+#[test]
+fn test_query_prefix() {
+    assert_eq!(replace_query_prefix("?foo", "?"), "?foo");
+    assert_eq!(replace_query_prefix("!karma foo", "!karma"), "?foo");
+    assert_eq!(replace_query_prefix("  !karma   top 5", "!karma"), "?top 5");
+    assert_eq!(replace_query_prefix("?foo", "!karma"), "");
+    assert_eq!(replace_query_prefix("bar++; ?foo", "!karma"), "bar++;");
+    assert_eq!(replace_query_prefix("bar++; !karma foo", "!karma"), "bar++;?foo");
+    assert_eq!(replace_query_prefix("!karmafoo", "!karma"), "!karmafoo");
+    assert_eq!(replace_query_prefix("!foo", "!"), "?foo");
+    assert_eq!(replace_query_prefix("foo++", "!karma"), "foo++");
+    assert_eq!(parse_ops(&replace_query_prefix("bar++; ?foo", "!karma")),
+               vec![Increment("bar".to_string())]);
+    assert_eq!(parse_ops(&replace_query_prefix("!karma foo", "!karma")),
+               vec![Query("foo".to_string())]);
+}
@@ -1,27 +1,103 @@
-mod rotdb;
-mod line_parse;
-mod irc_client;
-
 use std::env;
-use irc_client::IrcClient;
+use rot::{Config, IrcClient};
+
+const DB_FILENAME: &str = "zot.db";
 
 #[tokio::main(flavor="current_thread")]
 async fn main() {
-    let mut argp = env::args();
-    let self_exe = argp.next();
+    let mut argp = env::args().peekable();
+    let self_exe = argp.next().unwrap_or_else(|| "<Unknown>".to_string());
+
+    let mut config = Config::default();
+    let mut config_filename = None;
+    if argp.peek().map(String::as_str) == Some("-c") {
+        argp.next();
+        let filename = argp.next().unwrap_or_default();
+        config = match Config::load(&filename) {
+            Ok(config) => config,
+            Err(err) => {
+                eprintln!("Could not load {}: {}", filename, err);
+                std::process::exit(1);
+            }
+        };
+        config_filename = Some(filename);
+    }
+
+    if argp.peek().map(String::as_str) == Some("--seed") && argp.len() == 2 {
+        argp.next();
+        seed(&argp.next().unwrap(), &config);
+        return;
+    }
+
     if argp.len() < 2 {
-        eprintln!("Usage: {} hostname:port nick [channel [...]]",
-                  self_exe.unwrap_or_else(|| "<Unknown>".to_string()));
+        eprintln!("Usage: {} [-c config] hostname[:port]|unix:path nick [channel[:key] [...]]",
+                  self_exe);
+        eprintln!("       {} [-c config] --seed keys.txt", self_exe);
         std::process::exit(1);
     }
 
     let remote_addr = argp.next().unwrap();
     let nick = argp.next().unwrap();
 
-    let mut client = IrcClient::new("zot.db", &remote_addr, &nick);
-    for channel in argp {
-        client.join(&channel);
+    let mut client = IrcClient::new(DB_FILENAME, &remote_addr, &nick, config);
+    if let Some(filename) = &config_filename {
+        client.set_config_file(filename);
+    }
+    for arg in argp {
+        match parse_channel_arg(&arg) {
+            Ok((channel, key)) => client.join_from_args(channel, key),
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Err(err) = client.run().await {
+        eprintln!("{}", err);
+        std::process::exit(1);
     }
+}
+
+/// Add the keys listed in `filename` to the db at 0, without connecting
+fn seed(filename: &str, config: &Config) {
+    let mut db = rot::open_db(DB_FILENAME, config);
+    match db.seed(filename) {
+        Ok(added) => println!("Added {} keys from {}", added, filename),
+        Err(err) => {
+            eprintln!("Could not read {}: {}", filename, err);
+            std::process::exit(1);
+        }
+    }
+    if let Err(err) = db.close() {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+/// Split a `channel:key` argument.  Channel names can't contain a `:`, but
+/// keys can, so the first one separates them.
+fn parse_channel_arg(arg: &str) -> Result<(&str, Option<&str>), String> {
+    let (channel, key) = match arg.split_once(':') {
+        Some((channel, key)) => (channel, Some(key)),
+        None => (arg, None),
+    };
+    if channel.is_empty() {
+        return Err(format!("Missing channel name in \"{}\"", arg));
+    }
+    if key == Some("") {
+        return Err(format!("Missing key for {}", channel));
+    }
+    Ok((channel, key))
+}
 
-    client.run().await;
+#[test]
+fn test_channel_args() {
+    assert_eq!(parse_channel_arg("rust"), Ok(("rust", None)));
+    assert_eq!(parse_channel_arg("#rust"), Ok(("#rust", None)));
+    assert_eq!(parse_channel_arg("secret:hunter2"), Ok(("secret", Some("hunter2"))));
+    assert_eq!(parse_channel_arg("#secret:a:b"), Ok(("#secret", Some("a:b"))));
+    assert!(parse_channel_arg(":hunter2").is_err());
+    assert!(parse_channel_arg("secret:").is_err());
+    assert!(parse_channel_arg("").is_err());
 }
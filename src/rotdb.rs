@@ -1,105 +1,1175 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Write, Result};
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write, Result};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use regex::Regex;
 use lazy_static::lazy_static;
 
+use crate::error::RotError;
+use crate::line_parse::is_key;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decay {
+    /// Move the value `amount` closer to zero every `period`
+    Linear { amount: i64, period: Duration },
+    /// Drop `percent`% of the value (rounding toward zero) every `period`
+    Exponential { percent: i64, period: Duration },
+}
+
+#[derive(Default, Clone)]
+struct Entry {
+    value: i64,
+    // When the value was last changed (not counting decay), or None for
+    // entries loaded from a db written before timestamps were tracked
+    modified: Option<SystemTime>,
+    // Where decay has been applied up to, if it has been since the last
+    // change
+    decayed: Option<SystemTime>,
+    frozen: bool,
+    // Reset by an admin, so hidden everywhere until restored or purged
+    deleted: bool,
+}
+
+struct HistoryEntry {
+    time: SystemTime,
+    sender: String,
+    key: String,
+    delta: i64,
+}
+
+/// Number of increments and decrements made by one sender or to one key
+#[derive(Debug, PartialEq, Eq)]
+pub struct Contribution {
+    pub name: String,
+    pub ups: u64,
+    pub downs: u64,
+}
+
+/// How much karma one nick has given, and received as a key
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UserTotals {
+    pub given_ups: u64,
+    pub given_downs: u64,
+    pub received_ups: u64,
+    pub received_downs: u64,
+}
+
+impl UserTotals {
+    pub fn given(&self) -> i64 {
+        self.given_ups as i64 - self.given_downs as i64
+    }
+
+    pub fn received(&self) -> i64 {
+        self.received_ups as i64 - self.received_downs as i64
+    }
+}
+
 pub struct RotDb {
     filename: String,
-    values: HashMap<String, i64>,
+    values: HashMap<String, Entry>,
+    // Read-only values from a shared db, overridden by any in `values`
+    base: HashMap<String, Entry>,
+    decay: Option<Decay>,
+    // The lowest value `add` will go to, if any
+    floor: Option<i64>,
+    // The most keys `add` will create, if limited
+    max_keys: Option<usize>,
     dirty: bool,
+    // Counts every change, so callers can tell when more have been made
+    changes: u64,
+    // None unless history tracking has been enabled.  Only the most recent
+    // entries are kept, oldest first.
+    history: Option<Vec<HistoryEntry>>,
+    history_saved: usize,
+    // Set when entries were removed, so the file is rewritten rather than
+    // appended to
+    history_rewrite: bool,
+    // Every form of each key that has been changed, keyed by the normalized
+    // key.  Saved in a separate file, since variants may contain colons.
+    variants: HashMap<String, BTreeSet<String>>,
+    variants_dirty: bool,
+    // Totals by lowercase nick, None unless enabled.  Saved in a separate
+    // file, rewritten whenever they change.
+    users: Option<HashMap<String, UserTotals>>,
+    users_dirty: bool,
+    // Set for in-memory dbs, which are never saved
+    ephemeral: bool,
+    key_mode: KeyMode,
+    // Normalized keys which are never changed or listed, although any
+    // stored value is kept
+    denied: HashSet<String>,
+}
+
+/// How keys are matched.  Keys are always compared case-insensitively.
+/// The mode should be chosen when the db is created, since keys are stored
+/// in their normalized form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyMode {
+    /// `::` and `->` are treated the same as `.`, so `Foo::Bar`, `foo->bar`
+    /// and `foo.bar` are all the same key
+    #[default]
+    Unified,
+    /// Each separator is kept as written, so `Foo::Bar` and `foo::bar` are
+    /// the same key, but `foo.bar` and `foo->bar` are both different keys
+    Distinct,
+}
+
+impl KeyMode {
+    pub fn normalize(self, key: &str) -> String {
+        lazy_static! {
+            static ref RE_SEPS: Regex = Regex::new("(::|->)").unwrap();
+        }
+        match self {
+            KeyMode::Unified => RE_SEPS.replace_all(key, ".").to_ascii_lowercase(),
+            KeyMode::Distinct => key.to_ascii_lowercase(),
+        }
+    }
 }
 
-fn normalize_key(key: &str) -> String {
-    lazy_static! {
-        static ref RE_SEPS: Regex = Regex::new("(::|->)").unwrap();
+/// Keys are written to the db and history files with any `:` escaped, since
+/// the files use it to separate fields, and a leading `#` escaped so the line
+/// isn't read back as a comment
+fn escape_key(key: &str) -> String {
+    let key = key.replace(':', "%3A");
+    match key.strip_prefix('#') {
+        Some(rest) => format!("%23{}", rest),
+        None => key,
     }
-    RE_SEPS.replace_all(key, ".").to_ascii_lowercase()
 }
 
-fn parse_db_line(filename: &str, text: &str) -> Option<(String, i64)> {
-    let parts: Vec<&str> = text.splitn(2, ':').collect();
-    if parts.len() != 2 {
+fn unescape_key(key: &str) -> String {
+    let key = key.replace("%3A", ":");
+    match key.strip_prefix("%23") {
+        Some(rest) => format!("#{}", rest),
+        None => key,
+    }
+}
+
+/// Each db line is `key:value[:modified[:flags[:decayed]]]`, where
+/// `modified` and `decayed` are in seconds since the Unix epoch and may be
+/// empty, and `flags` is a comma separated list.  Whitespace around each
+/// field is ignored, for hand-edited dbs.
+fn parse_db_line(filename: &str, text: &str) -> Option<(String, Entry)> {
+    let parts: Vec<&str> = text.split(':').map(str::trim).collect();
+    if parts.len() < 2 || parts.len() > 5 {
         eprintln!("Invalid line format in {}: \"{}\"", filename, text);
         return None;
     }
-    match parts[1].parse::<i64>() {
-        Ok(value) => Some((parts[0].to_string(), value)),
+    let value = match parts[1].parse::<i64>() {
+        Ok(value) => value,
         Err(_) => {
             eprintln!("Invalid value in {}: \"{}\"", filename, text);
+            return None;
+        }
+    };
+    let stamp = |index: usize| match parts.get(index) {
+        None | Some(&"") => Some(None),
+        Some(stamp) => match stamp.parse::<u64>() {
+            Ok(secs) => Some(Some(UNIX_EPOCH + Duration::from_secs(secs))),
+            Err(_) => {
+                eprintln!("Invalid timestamp in {}: \"{}\"", filename, text);
+                None
+            }
+        }
+    };
+    let (modified, decayed) = (stamp(2)?, stamp(4)?);
+    let mut entry = Entry { value, modified, decayed, ..Default::default() };
+    for flag in parts.get(3).unwrap_or(&"").split(',').map(str::trim)
+                            .filter(|flag| !flag.is_empty()) {
+        match flag {
+            "frozen" => entry.frozen = true,
+            "deleted" => entry.deleted = true,
+            _ => eprintln!("Ignoring unknown flag in {}: \"{}\"", filename, text),
+        }
+    }
+    Some((unescape_key(parts[0]), entry))
+}
+
+fn format_db_line(key: &str, entry: &Entry) -> String {
+    let mut line = format!("{}:{}", escape_key(key), entry.value);
+    let stamp = |time: Option<SystemTime>| {
+        time.and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|t| t.as_secs().to_string())
+    };
+    let (modified, decayed) = (stamp(entry.modified), stamp(entry.decayed));
+    let flags: Vec<&str> = [(entry.frozen, "frozen"), (entry.deleted, "deleted")].iter()
+            .filter(|(set, _)| *set).map(|(_, flag)| *flag).collect();
+    if modified.is_some() || !flags.is_empty() || decayed.is_some() {
+        line += &format!(":{}", modified.unwrap_or_default());
+    }
+    if !flags.is_empty() || decayed.is_some() {
+        line += &format!(":{}", flags.join(","));
+    }
+    if let Some(decayed) = decayed {
+        line += &format!(":{}", decayed);
+    }
+    line
+}
+
+fn parse_history_line(filename: &str, text: &str) -> Option<HistoryEntry> {
+    let parts: Vec<&str> = text.splitn(4, ':').collect();
+    if parts.len() != 4 {
+        eprintln!("Invalid line format in {}: \"{}\"", filename, text);
+        return None;
+    }
+    match (parts[0].parse::<u64>(), parts[3].parse::<i64>()) {
+        (Ok(secs), Ok(delta)) => Some(HistoryEntry {
+            time: UNIX_EPOCH + Duration::from_secs(secs),
+            sender: parts[1].to_string(),
+            key: unescape_key(parts[2]),
+            delta,
+        }),
+        _ => {
+            eprintln!("Invalid history entry in {}: \"{}\"", filename, text);
             None
         }
     }
 }
 
-fn parse_zot_db(filename: &str) -> Result<HashMap<String, i64>> {
+fn parse_history(filename: &str) -> Result<Vec<HistoryEntry>> {
     let stream = File::open(filename)?;
-    let values = BufReader::new(stream).lines()
+    let history = BufReader::new(stream).lines()
         .filter_map(|line| {
             match line {
                 Err(err) => {
                     eprintln!("Error reading line from {}:\n{}", filename, err);
                     None
                 }
-                Ok(text) => parse_db_line(filename, &text),
+                Ok(text) => parse_history_line(filename, &text),
             }
         }).collect();
 
+    Ok(history)
+}
+
+/// Each line of the variants file is the normalized key followed by each of
+/// its variants, separated by spaces
+fn parse_variants(filename: &str) -> Result<HashMap<String, BTreeSet<String>>> {
+    let stream = File::open(filename)?;
+    let mut variants = HashMap::new();
+    for line in BufReader::new(stream).lines() {
+        let text = match line {
+            Ok(text) => text,
+            Err(err) => {
+                eprintln!("Error reading line from {}:\n{}", filename, err);
+                continue;
+            }
+        };
+        let mut words = text.split_whitespace();
+        if let Some(key) = words.next() {
+            variants.insert(key.to_string(), words.map(str::to_string).collect());
+        }
+    }
+    Ok(variants)
+}
+
+/// Each line of the user totals file is a lowercase nick followed by the
+/// ups and downs it has given and then received, separated by spaces
+fn parse_user_totals(filename: &str) -> Result<HashMap<String, UserTotals>> {
+    let stream = File::open(filename)?;
+    let mut users = HashMap::new();
+    for line in BufReader::new(stream).lines() {
+        let text = match line {
+            Ok(text) => text,
+            Err(err) => {
+                eprintln!("Error reading line from {}:\n{}", filename, err);
+                continue;
+            }
+        };
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let counts: Option<Vec<u64>> = words.iter().skip(1).map(|word| word.parse().ok()).collect();
+        match (words.first(), counts.as_deref()) {
+            (Some(nick), Some(&[given_ups, given_downs, received_ups, received_downs])) => {
+                users.insert(nick.to_string(), UserTotals {
+                    given_ups, given_downs, received_ups, received_downs
+                });
+            }
+            _ => eprintln!("Invalid user totals in {}: \"{}\"", filename, text),
+        }
+    }
+    Ok(users)
+}
+
+/// Quote a CSV field if necessary.  A field a spreadsheet would take as a
+/// formula gets a `'` in front, since keys come from anyone.
+fn csv_field(text: &str) -> Cow<'_, str> {
+    let text = if text.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        Cow::Owned(format!("'{}", text))
+    } else {
+        Cow::Borrowed(text)
+    };
+    if text.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", text.replace('"', "\"\"")))
+    } else {
+        text
+    }
+}
+
+fn tally<'a>(changes: impl Iterator<Item = (&'a str, i64)>) -> Vec<Contribution> {
+    let mut totals: HashMap<&str, (u64, u64)> = HashMap::new();
+    for (name, delta) in changes {
+        let total = totals.entry(name).or_insert((0, 0));
+        if delta > 0 {
+            total.0 += delta as u64;
+        } else {
+            total.1 += delta.unsigned_abs();
+        }
+    }
+
+    let mut result: Vec<Contribution> = totals.into_iter()
+        .map(|(name, (ups, downs))| Contribution { name: name.to_string(), ups, downs })
+        .collect();
+    result.sort_by(|a, b| (b.ups + b.downs).cmp(&(a.ups + a.downs))
+                                            .then_with(|| a.name.cmp(&b.name)));
+    result
+}
+
+// Dbs this large are loaded with progress reports, in case they're not
+// expected to be this large
+const LARGE_DB_LINES: usize = 1_000_000;
+// Far longer than any real key, so a longer line suggests a corrupt file
+const MAX_LINE_BYTES: usize = 4096;
+// How many lines to read before deciding whether most of them are invalid
+const CORRUPT_SAMPLE_LINES: usize = 1000;
+// How many of the most recent history entries are kept in memory.  The file
+// keeps growing, but only these are loaded and counted.
+const MAX_HISTORY_ENTRIES: usize = 100_000;
+
+/// Read a db file, giving up with `InvalidData` if it has more than
+/// `max_lines` lines or doesn't look like a db at all.  Blank lines and
+/// comments (lines starting with `#`) are skipped, but they aren't kept, so
+/// the next save drops them.  So are invalid lines, including any over
+/// `MAX_LINE_BYTES`, though they're logged.
+fn parse_zot_db(filename: &str, max_lines: Option<usize>) -> Result<HashMap<String, Entry>> {
+    let stream = File::open(filename)?;
+    let mut reader = BufReader::new(stream);
+    let mut values = HashMap::new();
+    let corrupt = |reason: String| {
+        Error::new(ErrorKind::InvalidData, format!("{} looks corrupt: {}", filename, reason))
+    };
+
+    let mut line = Vec::new();
+    let (mut count, mut invalid) = (0, 0);
+    loop {
+        line.clear();
+        // Bound how much is read, in case the file has no line breaks
+        (&mut reader).take(MAX_LINE_BYTES as u64 + 1).read_until(b'\n', &mut line)?;
+        if line.is_empty() {
+            break;
+        }
+        count += 1;
+        if max_lines.is_some_and(|max_lines| count > max_lines) {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  format!("{} has more than {} lines", filename, count - 1)));
+        }
+        if count % LARGE_DB_LINES == 0 {
+            eprintln!("Loaded {} lines from {}...", count, filename);
+        }
+
+        let text = if line.len() > MAX_LINE_BYTES {
+            if !line.ends_with(b"\n") {
+                reader.skip_until(b'\n')?;
+            }
+            eprintln!("Skipping line {} of {}, which is over {} bytes", count, filename,
+                      MAX_LINE_BYTES);
+            None
+        } else {
+            std::str::from_utf8(&line).ok().map(|text| text.trim_end_matches(['\r', '\n']))
+        };
+        if text.is_some_and(|text| text.trim().is_empty() || text.trim_start().starts_with('#')) {
+            continue;
+        }
+        match text.and_then(|text| parse_db_line(filename, text)) {
+            Some((key, entry)) => {
+                values.insert(key, entry);
+            }
+            None => invalid += 1,
+        }
+        if count == CORRUPT_SAMPLE_LINES && invalid > count / 2 {
+            return Err(corrupt(format!("{} of the first {} lines are invalid", invalid, count)));
+        }
+    }
+    if values.is_empty() && invalid > 0 {
+        return Err(corrupt(format!("none of its {} lines are valid", invalid)));
+    }
+    if count >= LARGE_DB_LINES {
+        eprintln!("Warning: {} is very large ({} lines)", filename, count);
+    }
+
     Ok(values)
 }
 
 impl RotDb {
-    pub fn new(filename_ref: &str) -> RotDb {
+    pub fn new(filename: &str) -> RotDb {
+        RotDb::open(filename, None)
+    }
+
+    /// Like `new`, but refuses to load a db with more than `max_lines` lines.
+    /// A db that is refused (or looks corrupt) is left alone, and changes
+    /// are only kept in memory.
+    pub fn open(filename_ref: &str, max_lines: Option<usize>) -> RotDb {
         let filename = filename_ref.to_owned();
-        match parse_zot_db(&filename) {
-            Ok(values) => RotDb { filename, values, dirty: false },
+        let mut ephemeral = false;
+        let values = match parse_zot_db(&filename, max_lines) {
+            Ok(values) => values,
+            Err(err) if err.kind() == ErrorKind::InvalidData => {
+                eprintln!("Refusing to load {}, changes will not be saved", err);
+                ephemeral = true;
+                HashMap::new()
+            }
             Err(_) => {
                 eprintln!("Initializing new zot db");
-                RotDb { filename, values: HashMap::new(), dirty: false }
+                HashMap::new()
+            }
+        };
+        let variants = parse_variants(&format!("{}.variants", filename)).unwrap_or_default();
+        RotDb { filename, values, base: HashMap::new(), decay: None, floor: None,
+                max_keys: None, dirty: false, changes: 0, history: None, history_saved: 0,
+                history_rewrite: false, variants, variants_dirty: false, users: None,
+                users_dirty: false, ephemeral, key_mode: KeyMode::default(),
+                denied: HashSet::new() }
+    }
+
+    /// An in-memory db which is never saved.  Use `rebase` to layer it over
+    /// the contents of another db.
+    pub fn ephemeral() -> RotDb {
+        RotDb { filename: String::new(), values: HashMap::new(), base: HashMap::new(),
+                decay: None, floor: None, max_keys: None, dirty: false, changes: 0,
+                history: None, history_saved: 0, history_rewrite: false,
+                variants: HashMap::new(), variants_dirty: false, users: None,
+                users_dirty: false, ephemeral: true, key_mode: KeyMode::default(),
+                denied: HashSet::new() }
+    }
+
+    /// Open `filename` as a writable layer over a read-only shared db.  A key
+    /// stored in the writable layer overrides the same key in the base (the
+    /// values are not summed), and the first change to a key only found in
+    /// the base copies it to the writable layer.  The base is never written.
+    pub fn layered(base_filename: &str, filename: &str, max_lines: Option<usize>) -> RotDb {
+        let mut db = RotDb::open(filename, max_lines);
+        db.base = match parse_zot_db(base_filename, max_lines) {
+            Ok(values) => values,
+            Err(err) => {
+                eprintln!("Could not read base db {}:\n{}", base_filename, err);
+                HashMap::new()
+            }
+        };
+        db
+    }
+
+    fn lookup(&self, key: &str) -> Option<&Entry> {
+        let key = self.normalize(key);
+        if self.denied.contains(&key) {
+            return None;
+        }
+        self.values.get(&key).or_else(|| self.base.get(&key)).filter(|entry| !entry.deleted)
+    }
+
+    /// All entries in both layers, with the writable layer taking precedence
+    fn entries(&self) -> impl Iterator<Item = (&String, &Entry)> {
+        self.values.iter().chain(self.base.iter().filter(move |(key, _)| {
+            !self.values.contains_key(*key)
+        })).filter(move |(key, entry)| !entry.deleted && !self.denied.contains(*key))
+    }
+
+    /// The writable entry for `key`, copied from the base if necessary
+    fn entry_mut(&mut self, key: &str) -> &mut Entry {
+        let key = self.normalize(key);
+        let base = &self.base;
+        self.values.entry(key).or_insert_with_key(|key| base.get(key).cloned().unwrap_or_default())
+    }
+
+    fn history_filename(&self) -> String {
+        format!("{}.history", self.filename)
+    }
+
+    /// Start recording who changed what, loading any previously saved history.
+    /// History is kept in a separate file alongside the db, although only
+    /// the most recent entries are held in memory.
+    pub fn enable_history(&mut self) {
+        if self.history.is_some() {
+            return;
+        }
+        // Nothing an ephemeral db records is saved, so nothing is loaded
+        let mut history = if self.ephemeral {
+            Vec::new()
+        } else {
+            parse_history(&self.history_filename()).unwrap_or_default()
+        };
+        history.drain(..history.len().saturating_sub(MAX_HISTORY_ENTRIES));
+        self.history_saved = history.len();
+        self.history = Some(history);
+    }
+
+    pub fn history_enabled(&self) -> bool {
+        self.history.is_some()
+    }
+
+    /// Record a change made by `sender`, if history tracking is enabled
+    pub fn record(&mut self, sender: &str, key: &str, delta: i64, time: SystemTime) {
+        if let Some(history) = &mut self.history {
+            history.push(HistoryEntry {
+                time,
+                sender: sender.to_ascii_lowercase(),
+                key: self.key_mode.normalize(key),
+                delta,
+            });
+            // Only entries already in the file are forgotten
+            let dropped = history.len().saturating_sub(MAX_HISTORY_ENTRIES)
+                                 .min(self.history_saved);
+            history.drain(..dropped);
+            self.history_saved -= dropped;
+        }
+    }
+
+    /// Start counting the karma given and received by each nick, loading any
+    /// previously saved totals
+    pub fn enable_user_totals(&mut self) {
+        if self.users.is_none() {
+            let filename = format!("{}.users", self.filename);
+            self.users = Some(if self.ephemeral {
+                HashMap::new()
+            } else {
+                parse_user_totals(&filename).unwrap_or_default()
+            });
+        }
+    }
+
+    pub fn user_totals_enabled(&self) -> bool {
+        self.users.is_some()
+    }
+
+    /// Count a change of `delta` made by `sender`, and received by `target`
+    /// if the key was someone's nick
+    pub fn record_user_change(&mut self, sender: &str, target: Option<&str>, delta: i64) {
+        let users = match &mut self.users {
+            Some(users) => users,
+            None => return,
+        };
+        let given = users.entry(sender.to_ascii_lowercase()).or_default();
+        if delta > 0 {
+            given.given_ups += delta as u64;
+        } else {
+            given.given_downs += delta.unsigned_abs();
+        }
+        if let Some(target) = target {
+            let received = users.entry(target.to_ascii_lowercase()).or_default();
+            if delta > 0 {
+                received.received_ups += delta as u64;
+            } else {
+                received.received_downs += delta.unsigned_abs();
             }
         }
+        self.users_dirty = true;
+    }
+
+    /// What `nick` has given and received, all zero if nothing is known
+    pub fn user_totals(&self, nick: &str) -> UserTotals {
+        self.users.as_ref().and_then(|users| users.get(&nick.to_ascii_lowercase()))
+                  .copied().unwrap_or_default()
+    }
+
+    /// Who has changed `key`, most active first
+    pub fn contributions_to(&self, key: &str) -> Vec<Contribution> {
+        let key = self.normalize(key);
+        let history = self.history.as_deref().unwrap_or_default();
+        tally(history.iter().filter(|change| change.key == key)
+                            .map(|change| (change.sender.as_str(), change.delta)))
+    }
+
+    /// Which keys `sender` has changed, most changed first
+    pub fn contributions_by(&self, sender: &str) -> Vec<Contribution> {
+        let sender = sender.to_ascii_lowercase();
+        let history = self.history.as_deref().unwrap_or_default();
+        tally(history.iter().filter(|change| change.sender == sender)
+                            .map(|change| (change.key.as_str(), change.delta)))
+    }
+
+    pub fn set_decay(&mut self, decay: Option<Decay>) {
+        self.decay = decay;
+    }
+
+    /// Stop `add` from taking any value below `floor`.  Values that are
+    /// already lower (such as ones set by an admin) are left alone.
+    pub fn set_floor(&mut self, floor: Option<i64>) {
+        self.floor = floor;
+    }
+
+    /// Stop `add` from creating keys once `max_keys` are stored.  Keys that
+    /// are already stored (even if reset) can still change.
+    pub fn set_max_keys(&mut self, max_keys: Option<usize>) {
+        self.max_keys = max_keys;
+    }
+
+    /// Whether `add` would refuse `key`, since it's a new key and the db
+    /// already holds as many keys as it's allowed.  Only the keys counted by
+    /// `len` count toward the limit, so reset and denied keys are exempt.
+    pub fn at_key_limit(&self, key: &str) -> bool {
+        self.max_keys.is_some_and(|max_keys| {
+            let key = self.normalize(key);
+            !self.values.contains_key(&key) && !self.base.contains_key(&key)
+                && self.len() >= max_keys
+        })
+    }
+
+    pub fn set_key_mode(&mut self, key_mode: KeyMode) {
+        self.key_mode = key_mode;
+    }
+
+    /// Treat `keys` as if they weren't stored: they can't be changed, and
+    /// they're left out of every lookup and listing.  Their values are still
+    /// saved, so they come back if they're allowed again.  Call this after
+    /// `set_key_mode`, since the keys are normalized here.
+    pub fn set_denied_keys(&mut self, keys: &[String]) {
+        self.denied = keys.iter().map(|key| self.normalize(key)).collect();
+    }
+
+    pub fn is_denied(&self, key: &str) -> bool {
+        !self.denied.is_empty() && self.denied.contains(&self.normalize(key))
+    }
+
+    /// The form `key` is stored as
+    pub fn normalize(&self, key: &str) -> String {
+        self.key_mode.normalize(key)
     }
 
     pub fn value(&self, key: &str) -> i64 {
-        *self.values.get(&normalize_key(key))
-                    .unwrap_or(&0)
+        self.get(key).unwrap_or(0)
     }
 
-    pub fn increment(&mut self, key: &str) -> i64 {
+    /// Like `value`, but returns None if `key` has never been stored
+    pub fn get(&self, key: &str) -> Option<i64> {
+        self.lookup(key).map(|entry| entry.value)
+    }
+
+    /// When `key` was last changed, or None for keys from an old db
+    pub fn modified(&self, key: &str) -> Option<SystemTime> {
+        self.lookup(key).and_then(|entry| entry.modified)
+    }
+
+    /// Every key changed at or after `since`, with its value and when it
+    /// was changed, most recent first
+    pub fn recently_modified(&self, since: SystemTime) -> Vec<(String, i64, SystemTime)> {
+        let mut recent: Vec<(String, i64, SystemTime)> = self.entries().filter_map(|(key, entry)| {
+            entry.modified.filter(|modified| *modified >= since)
+                          .map(|modified| (key.clone(), entry.value, modified))
+        }).collect();
+        recent.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+        recent
+    }
+
+    fn update(&mut self, key: &str, time: SystemTime,
+              update: impl FnOnce(i64) -> i64) -> i64 {
+        if self.is_denied(key) {
+            return 0;
+        }
+        if self.is_frozen(key) || self.is_reset(key) {
+            return self.value(key);
+        }
+        self.add_variant(key);
+        let entry = self.entry_mut(key);
+        entry.value = update(entry.value);
+        entry.modified = Some(time);
+        entry.decayed = None;
+        let value = entry.value;
         self.dirty = true;
-        *self.values.entry(normalize_key(key))
-                    .and_modify(|v| *v += 1)
-                    .or_insert(1)
+        self.changes += 1;
+        value
+    }
+
+    pub fn increment(&mut self, key: &str) -> i64 {
+        self.add(key, 1, SystemTime::now())
     }
 
     pub fn decrement(&mut self, key: &str) -> i64 {
+        self.add(key, -1, SystemTime::now())
+    }
+
+    /// Change `key` by `delta`, counting it as modified at `time`.  The
+    /// value saturates rather than overflowing, and a decrease stops at the
+    /// floor, if there is one.  A new key is left at 0 if the db is at its
+    /// key limit.
+    pub fn add(&mut self, key: &str, delta: i64, time: SystemTime) -> i64 {
+        if self.at_key_limit(key) {
+            return 0;
+        }
+        let floor = self.floor;
+        self.update(key, time, |value| match floor {
+            Some(floor) if delta < 0 => value.saturating_add(delta).max(floor.min(value)),
+            _ => value.saturating_add(delta),
+        })
+    }
+
+    pub fn set(&mut self, key: &str, value: i64) -> i64 {
+        self.update(key, SystemTime::now(), |_| value)
+    }
+
+    fn add_variant(&mut self, key: &str) {
+        let variants = self.variants.entry(self.key_mode.normalize(key)).or_default();
+        if !variants.contains(key) {
+            variants.insert(key.to_string());
+            self.variants_dirty = true;
+        }
+    }
+
+    /// Every form of `key` that has been used to change its value, sorted
+    pub fn variants(&self, key: &str) -> Vec<String> {
+        self.variants.get(&self.normalize(key))
+                     .map_or_else(Vec::new, |variants| variants.iter().cloned().collect())
+    }
+
+    /// Every stored key and its value, in no particular order.  Keys are in
+    /// their normalized form.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, i64)> {
+        self.entries().map(|(key, entry)| (key.as_str(), entry.value))
+    }
+
+    /// The number of stored keys, including those from the base db, frozen
+    /// keys and keys back at zero, but not keys that are reset
+    pub fn len(&self) -> usize {
+        self.entries().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Every key whose value is exactly `value`, sorted
+    pub fn keys_with_value(&self, value: i64) -> Vec<String> {
+        let mut keys: Vec<String> = self.iter().filter(|(_, v)| *v == value)
+                                        .map(|(key, _)| key.to_string()).collect();
+        keys.sort();
+        keys
+    }
+
+    /// Add each key listed in `filename`, one per line, at 0 unless it's
+    /// already stored, so they show up in listings.  Blank lines and lines
+    /// starting with `#` are skipped, and lines that aren't a key anyone
+    /// could change are logged and skipped.  Returns how many keys were
+    /// added.
+    pub fn seed(&mut self, filename: &str) -> Result<usize> {
+        let mut added = 0;
+        for line in BufReader::new(File::open(filename)?).lines() {
+            let line = line?;
+            let key = line.trim();
+            if key.is_empty() || key.starts_with('#') {
+                continue;
+            }
+            if !is_key(key) {
+                eprintln!("Invalid key in {}: \"{}\"", filename, key);
+                continue;
+            }
+            if self.get(key).is_some() {
+                continue;
+            }
+            self.set(key, 0);
+            added += 1;
+        }
+        Ok(added)
+    }
+
+    /// The `count` highest values, highest first
+    pub fn top(&self, count: usize) -> Vec<(String, i64)> {
+        let mut entries: Vec<(String, i64)> = self.iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect();
+        entries.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        entries.truncate(count);
+        entries
+    }
+
+    /// Write every key as CSV, sorted by key, with the total increments and
+    /// decrements recorded in the history (empty if history is disabled) and
+    /// the time of the last change in seconds since the Unix epoch.  Keys
+    /// that a spreadsheet would take as formulas start with a `'`.
+    pub fn export_csv<W: Write>(&self, writer: W) -> std::result::Result<(), RotError> {
+        Ok(self.write_csv(writer)?)
+    }
+
+    fn write_csv<W: Write>(&self, mut writer: W) -> Result<()> {
+        // Tallied like `contributions_by`, but over every sender
+        let history = self.history.as_deref().unwrap_or_default();
+        let totals: HashMap<String, Contribution> =
+            tally(history.iter().map(|change| (change.key.as_str(), change.delta)))
+                .into_iter().map(|total| (total.name.clone(), total)).collect();
+
+        let mut entries: Vec<(&String, &Entry)> = self.entries().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        writeln!(writer, "key,value,ups,downs,last_modified")?;
+        for (key, entry) in entries {
+            let (ups, downs) = match (self.history.is_some(), totals.get(key)) {
+                (false, _) => (String::new(), String::new()),
+                (true, Some(total)) => (total.ups.to_string(), total.downs.to_string()),
+                (true, None) => ("0".to_string(), "0".to_string()),
+            };
+            let modified = entry.modified.and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                                         .map(|t| t.as_secs().to_string())
+                                         .unwrap_or_default();
+            writeln!(writer, "{},{},{},{},{}", csv_field(key), entry.value, ups, downs, modified)?;
+        }
+        Ok(())
+    }
+
+    /// The 1-based position of `key` in the leaderboard (tied values share
+    /// a rank), along with the total number of keys
+    pub fn rank(&self, key: &str) -> Option<(usize, usize)> {
+        let value = self.lookup(key)?.value;
+        let mut total = 0;
+        let mut above = 0;
+        for (_, entry) in self.entries() {
+            total += 1;
+            if entry.value > value {
+                above += 1;
+            }
+        }
+        Some((above + 1, total))
+    }
+
+    pub fn is_frozen(&self, key: &str) -> bool {
+        self.lookup(key).is_some_and(|entry| entry.frozen)
+    }
+
+    /// Lock `key` at its current value, ignoring any further changes until
+    /// it is unfrozen.  Returns the frozen value.
+    pub fn freeze(&mut self, key: &str) -> i64 {
+        if self.is_frozen(key) || self.is_denied(key) {
+            return self.value(key);
+        }
+        let entry = self.entry_mut(key);
+        entry.frozen = true;
+        let value = entry.value;
         self.dirty = true;
-        *self.values.entry(normalize_key(key))
-                    .and_modify(|v| *v -= 1)
-                    .or_insert(-1)
+        self.changes += 1;
+        value
     }
 
-    pub fn sync(&mut self) {
-        if !self.dirty {
-            return;
+    /// Returns false if `key` was not frozen
+    pub fn unfreeze(&mut self, key: &str) -> bool {
+        if !self.is_frozen(key) {
+            return false;
         }
+        self.entry_mut(key).frozen = false;
+        self.dirty = true;
+        self.changes += 1;
+        true
+    }
 
-        let mut stream = match File::create(&self.filename) {
-            Ok(stream) => stream,
-            Err(err) => {
-                eprintln!("Could not open {} for writing:\n{}", self.filename, err);
-                return;
+    /// Whether `key` has been reset, and not yet restored or purged
+    pub fn is_reset(&self, key: &str) -> bool {
+        !self.is_denied(key)
+            && self.values.get(&self.normalize(key)).is_some_and(|entry| entry.deleted)
+    }
+
+    /// Hide `key` everywhere, keeping its value so it can be restored until
+    /// it is purged.  Changes to the key are ignored in the meantime.
+    /// Returns false if there's no such key.
+    pub fn reset(&mut self, key: &str) -> bool {
+        if self.lookup(key).is_none() {
+            return false;
+        }
+        self.entry_mut(key).deleted = true;
+        self.dirty = true;
+        self.changes += 1;
+        true
+    }
+
+    /// Bring back a key that was reset, returning its value, or None if it
+    /// wasn't reset
+    pub fn restore(&mut self, key: &str) -> Option<i64> {
+        if !self.is_reset(key) {
+            return None;
+        }
+        let entry = self.entry_mut(key);
+        entry.deleted = false;
+        let value = entry.value;
+        self.dirty = true;
+        self.changes += 1;
+        Some(value)
+    }
+
+    /// Permanently remove every key that was reset, returning how many there
+    /// were.  Keys from a base db are set to zero instead, since they can't
+    /// be removed from it.
+    pub fn purge(&mut self) -> usize {
+        let base = &self.base;
+        let mut purged = 0;
+        self.values.retain(|key, entry| {
+            if !entry.deleted {
+                return true;
+            }
+            purged += 1;
+            if base.contains_key(key) {
+                *entry = Entry::default();
+                return true;
             }
+            false
+        });
+        if purged > 0 {
+            self.dirty = true;
+            self.changes += 1;
+        }
+        purged
+    }
+
+    /// Permanently remove the keys whose value is closer to zero than
+    /// `min_abs` and which haven't been modified since `older_than`, along
+    /// with their history, variants and received user totals, returning how
+    /// many there were.  Keys of unknown age count as old.  Frozen and reset
+    /// keys and keys from a base db are kept.  If any history goes, the file
+    /// is rewritten with just the entries still held in memory.
+    pub fn prune(&mut self, min_abs: i64, older_than: SystemTime) -> usize {
+        let base = &self.base;
+        let mut pruned = HashSet::new();
+        self.values.retain(|key, entry| {
+            let stale = entry.value.unsigned_abs() < min_abs.unsigned_abs()
+                        && entry.modified.is_none_or(|modified| modified < older_than);
+            if !stale || entry.frozen || entry.deleted || base.contains_key(key) {
+                return true;
+            }
+            pruned.insert(key.clone());
+            false
+        });
+        if pruned.is_empty() {
+            return 0;
+        }
+        self.dirty = true;
+        self.changes += 1;
+
+        if let Some(history) = &mut self.history {
+            let before = history.len();
+            history.retain(|change| !pruned.contains(&change.key));
+            if history.len() < before {
+                self.history_saved = 0;
+                self.history_rewrite = true;
+            }
+        }
+        for key in &pruned {
+            if self.variants.remove(key).is_some() {
+                self.variants_dirty = true;
+            }
+            // Nick keys are normalized to lowercase, as user totals are
+            if let Some(totals) = self.users.as_mut().and_then(|users| users.get_mut(key)) {
+                totals.received_ups = 0;
+                totals.received_downs = 0;
+                self.users_dirty = true;
+            }
+        }
+        if let Some(users) = &mut self.users {
+            users.retain(|_, totals| *totals != UserTotals::default());
+        }
+        pruned.len()
+    }
+
+    /// Decay every value in the writable layer toward zero by the number of
+    /// whole decay periods that have passed since it was last changed or
+    /// decayed.  The partial period left over carries forward to the next
+    /// call.  Decay doesn't count as a change, so decayed keys don't look
+    /// recently changed.  Returns the number of values that changed.
+    pub fn apply_decay(&mut self, now: SystemTime) -> usize {
+        let decay = match self.decay {
+            Some(decay) => decay,
+            None => return 0,
+        };
+        let period = match decay {
+            Decay::Linear { period, .. } | Decay::Exponential { period, .. } => period,
         };
-        for (key, val) in &self.values {
-            if let Some(err) = writeln!(stream, "{}:{}", &key, &val).err() {
-                eprintln!("Could not write to {}:\n{}", self.filename, err);
-                return;
+        if period.as_secs() == 0 {
+            return 0;
+        }
+
+        let mut changed = 0;
+        for entry in self.values.values_mut().filter(|entry| !entry.frozen && !entry.deleted) {
+            let since = match entry.decayed.or(entry.modified) {
+                Some(since) => since,
+                None => {
+                    // Start the decay clock for entries of unknown age now
+                    entry.decayed = Some(now);
+                    self.dirty = true;
+                    continue;
+                }
+            };
+            let elapsed = match now.duration_since(since) {
+                Ok(elapsed) => elapsed,
+                Err(_) => continue,
+            };
+            let periods = elapsed.as_secs() / period.as_secs();
+            if periods == 0 {
+                continue;
+            }
+
+            let old_value = entry.value;
+            entry.value = decay_value(decay, entry.value, periods);
+            entry.decayed = Some(since + Duration::from_secs(period.as_secs() * periods));
+            self.dirty = true;
+            if entry.value != old_value {
+                changed += 1;
             }
         }
+        self.changes += changed as u64;
+        changed
+    }
+
+    /// The number of changes made since the db was opened, which only ever
+    /// increases
+    pub fn changes(&self) -> u64 {
+        self.changes
+    }
+
+    /// Replace the read-only base with `values`, given as each key, its value
+    /// and whether it's frozen
+    pub fn rebase<'a>(&mut self, values: impl Iterator<Item = (&'a str, i64, bool)>) {
+        self.base = values.map(|(key, value, frozen)| {
+            (key.to_string(), Entry { value, frozen, ..Entry::default() })
+        }).collect();
+    }
+
+    fn sync_history(&mut self) -> Result<()> {
+        let history_filename = self.history_filename();
+        let history = match &self.history {
+            Some(history) if history.len() > self.history_saved => history,
+            _ => return Ok(()),
+        };
+
+        let mut options = OpenOptions::new();
+        if self.history_rewrite {
+            options.write(true).truncate(true);
+        } else {
+            options.append(true);
+        }
+        let mut stream = options.create(true).open(&history_filename)
+                .map_err(|err| with_context(err, "Could not open", &history_filename))?;
+        self.history_rewrite = false;
+        for change in &history[self.history_saved..] {
+            let secs = change.time.duration_since(UNIX_EPOCH).map_or(0, |t| t.as_secs());
+            writeln!(stream, "{}:{}:{}:{}", secs, change.sender, escape_key(&change.key),
+                     change.delta)
+                .map_err(|err| with_context(err, "Could not write to", &history_filename))?;
+            self.history_saved += 1;
+        }
+        Ok(())
+    }
+
+    fn sync_variants(&mut self) -> Result<()> {
+        if !self.variants_dirty {
+            return Ok(());
+        }
+
+        let variants_filename = format!("{}.variants", self.filename);
+        let mut stream = File::create(&variants_filename)
+                .map_err(|err| with_context(err, "Could not open", &variants_filename))?;
+        let mut keys: Vec<(&String, &BTreeSet<String>)> = self.variants.iter().collect();
+        keys.sort_unstable_by_key(|(key, _)| *key);
+        for (key, variants) in keys {
+            let variants: Vec<&str> = variants.iter().map(String::as_str).collect();
+            writeln!(stream, "{} {}", key, variants.join(" "))
+                .map_err(|err| with_context(err, "Could not write to", &variants_filename))?;
+        }
+
+        self.variants_dirty = false;
+        Ok(())
+    }
+
+    fn sync_user_totals(&mut self) -> Result<()> {
+        let users = match &self.users {
+            Some(users) if self.users_dirty => users,
+            _ => return Ok(()),
+        };
+
+        let users_filename = format!("{}.users", self.filename);
+        let mut stream = File::create(&users_filename)
+                .map_err(|err| with_context(err, "Could not open", &users_filename))?;
+        let mut users: Vec<(&String, &UserTotals)> = users.iter().collect();
+        users.sort_unstable_by_key(|(nick, _)| *nick);
+        for (nick, totals) in users {
+            writeln!(stream, "{} {} {} {} {}", nick, totals.given_ups, totals.given_downs,
+                     totals.received_ups, totals.received_downs)
+                .map_err(|err| with_context(err, "Could not write to", &users_filename))?;
+        }
+
+        self.users_dirty = false;
+        Ok(())
+    }
+
+    fn sync_values(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        // Sorted, so saving the same values always writes the same file
+        let mut entries: Vec<(&String, &Entry)> = self.values.iter().collect();
+        entries.sort_unstable_by_key(|(key, _)| *key);
+        let mut stream = File::create(&self.filename)
+                .map_err(|err| with_context(err, "Could not open", &self.filename))?;
+        for (key, entry) in entries {
+            writeln!(stream, "{}", format_db_line(key, entry))
+                .map_err(|err| with_context(err, "Could not write to", &self.filename))?;
+        }
 
         self.dirty = false;
+        Ok(())
+    }
+
+    fn try_sync(&mut self) -> Result<()> {
+        if self.ephemeral {
+            return Ok(());
+        }
+        self.sync_values()?;
+        self.sync_history()?;
+        self.sync_variants()?;
+        self.sync_user_totals()
+    }
+
+    /// Save any changes, logging errors.  This is also done when the db is
+    /// dropped, but see `close` for a way to find out if it failed.
+    pub fn sync(&mut self) {
+        if let Err(err) = self.try_sync() {
+            eprintln!("{}", err);
+        }
+    }
+
+    /// Save any changes and close the db, returning any error that prevented
+    /// the changes from being saved
+    pub fn close(mut self) -> std::result::Result<(), RotError> {
+        let result = self.try_sync();
+        // Already reported, so don't try again when dropped
+        self.dirty = false;
+        self.variants_dirty = false;
+        self.users_dirty = false;
+        if let Some(history) = &self.history {
+            self.history_saved = history.len();
+        }
+        Ok(result?)
+    }
+}
+
+fn with_context(err: Error, action: &str, filename: &str) -> Error {
+    Error::new(err.kind(), format!("{} {}: {}", action, filename, err))
+}
+
+fn decay_value(decay: Decay, value: i64, periods: u64) -> i64 {
+    match decay {
+        Decay::Linear { amount, .. } => {
+            let total = (amount as u64).saturating_mul(periods).min(i64::MAX as u64) as i64;
+            if value > 0 {
+                (value - total).max(0)
+            } else {
+                (value + total).min(0)
+            }
+        }
+        Decay::Exponential { percent, .. } => {
+            let mut value = value;
+            for _ in 0..periods {
+                if value == 0 {
+                    break;
+                }
+                // Widened so huge values can't overflow; the result is
+                // never bigger than `value`, so it fits back in an i64
+                value = (value as i128 * (100 - percent) as i128 / 100) as i64;
+            }
+            value
+        }
     }
 }
 
@@ -109,11 +1179,25 @@ impl Drop for RotDb {
     }
 }
 
+/// Remove a db left behind by a test, along with its associated files
+#[cfg(test)]
+pub fn remove_test_db(filename: &str) {
+    for suffix in ["", ".history", ".variants", ".users", ".channels", ".csv"] {
+        let _ = std::fs::remove_file(format!("{}{}", filename, suffix));
+    }
+}
+
+/// Rebase `db` on everything in `base`, as the client does for a sandbox
+#[cfg(test)]
+fn rebase_on(db: &mut RotDb, base: &RotDb) {
+    db.rebase(base.iter().map(|(key, value)| (key, value, base.is_frozen(key))));
+}
+
 #[test]
 fn test_rotdb() {
     {
         // Start from a clean slate
-        let _ = std::fs::remove_file("test.db");
+        remove_test_db("test.db");
         let mut db = RotDb::new("test.db");
         assert_eq!(db.increment("Foo::Bar"), 1);
         assert_eq!(db.decrement("Bar.foo"), -1);
@@ -121,6 +1205,8 @@ fn test_rotdb() {
         assert_eq!(db.value("bar->foo"), -1);
         assert_eq!(db.value("Baz"), 0);
         assert_eq!(db.decrement("foo::bar"), 0);
+        assert_eq!(db.get("foo.bar"), Some(0));
+        assert_eq!(db.get("Baz"), None);
         assert_eq!(db.increment("Foo::Bar"), 1);
     }
     {
@@ -132,5 +1218,838 @@ fn test_rotdb() {
     }
 
     // Get rid of our test artifact
-    let _ = std::fs::remove_file("test.db");
+    remove_test_db("test.db");
+}
+
+#[test]
+fn test_decay() {
+    remove_test_db("test_decay.db");
+    let mut db = RotDb::new("test_decay.db");
+    let start = UNIX_EPOCH + Duration::from_secs(1_000_000);
+    let day = Duration::from_secs(24 * 60 * 60);
+    let set_value = |db: &mut RotDb, key: &str, value: i64| {
+        db.values.insert(key.to_string(),
+                         Entry { value, modified: Some(start), ..Default::default() });
+    };
+
+    // Disabled by default
+    set_value(&mut db, "foo", 10);
+    assert_eq!(db.apply_decay(start + day * 30), 0);
+    assert_eq!(db.value("foo"), 10);
+
+    db.set_decay(Some(Decay::Linear { amount: 2, period: day }));
+    set_value(&mut db, "foo", 10);
+    set_value(&mut db, "bar", -3);
+    assert_eq!(db.apply_decay(start + day / 2), 0);
+    assert_eq!(db.value("foo"), 10);
+    assert_eq!(db.apply_decay(start + day * 3 / 2), 2);
+    assert_eq!(db.value("foo"), 8);
+    assert_eq!(db.value("bar"), -1);
+    // The leftover half day carries over to the next application
+    assert_eq!(db.apply_decay(start + day * 2), 2);
+    assert_eq!(db.value("foo"), 6);
+    assert_eq!(db.value("bar"), 0);
+    assert_eq!(db.apply_decay(start + day * 100), 1);
+    assert_eq!(db.value("foo"), 0);
+    // Decay doesn't count as a change, but where it got to is saved
+    assert_eq!(db.modified("bar"), Some(start));
+    assert_eq!(db.recently_modified(start + day), vec![]);
+    let line = format_db_line("bar", &db.values["bar"]);
+    assert_eq!(line, format!("bar:0:1000000::{}", 1_000_000 + 100 * 24 * 60 * 60));
+    let (_, entry) = parse_db_line("test_decay.db", &line).unwrap();
+    assert_eq!(entry.decayed, db.values["bar"].decayed);
+
+    db.set_decay(Some(Decay::Exponential { percent: 50, period: day }));
+    set_value(&mut db, "foo", 100);
+    set_value(&mut db, "bar", -100);
+    assert_eq!(db.apply_decay(start + day * 2), 2);
+    assert_eq!(db.value("foo"), 25);
+    assert_eq!(db.value("bar"), -25);
+    assert_eq!(db.apply_decay(start + day * 5), 2);
+    assert_eq!(db.value("foo"), 3);
+    assert_eq!(db.value("bar"), -3);
+    assert_eq!(decay_value(Decay::Exponential { percent: 10, period: day }, i64::MAX, 1),
+               8_301_034_833_169_298_226);
+    assert_eq!(decay_value(Decay::Exponential { percent: 10, period: day }, i64::MIN, 1),
+               -8_301_034_833_169_298_227);
+
+    // Reinforcing a value resets its decay clock
+    assert_eq!(db.increment("foo"), 4);
+    assert_eq!(db.apply_decay(SystemTime::now()), 1);
+    assert_eq!(db.value("foo"), 4);
+    assert_eq!(db.value("bar"), 0);
+
+    drop(db);
+    remove_test_db("test_decay.db");
+}
+
+#[test]
+fn test_close() {
+    remove_test_db("test_close.db");
+    let mut db = RotDb::new("test_close.db");
+    db.increment("foo");
+    assert!(db.close().is_ok());
+    assert_eq!(RotDb::new("test_close.db").value("foo"), 1);
+    remove_test_db("test_close.db");
+
+    // Nothing to write, so nothing can fail
+    let db = RotDb::new("no_such_dir/test_close.db");
+    assert!(db.close().is_ok());
+
+    let mut db = RotDb::new("no_such_dir/test_close.db");
+    db.increment("foo");
+    match db.close() {
+        Err(RotError::Io(err)) => {
+            assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+            assert!(err.to_string().starts_with("Could not open no_such_dir/test_close.db: "));
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn test_denied_keys() {
+    let mut db = RotDb::ephemeral();
+    db.set("foo", 5);
+    db.increment("bar");
+    db.set_denied_keys(&["FOO".to_string(), "Baz".to_string()]);
+    assert!(db.is_denied("Foo"));
+    assert!(!db.is_denied("bar"));
+    assert_eq!(db.get("foo"), None);
+    assert_eq!(db.increment("foo"), 0);
+    assert_eq!(db.set("baz", 3), 0);
+    assert_eq!(db.get("baz"), None);
+    assert!(db.variants("baz").is_empty());
+    assert_eq!(db.freeze("foo"), 0);
+    assert!(!db.is_frozen("foo"));
+    assert!(!db.reset("foo"));
+
+    // Denied keys are left out of every listing
+    assert_eq!(db.iter().collect::<Vec<_>>(), vec![("bar", 1)]);
+    assert_eq!(db.top(5), vec![("bar".to_string(), 1)]);
+    assert_eq!(db.len(), 1);
+    assert!(db.keys_with_value(5).is_empty());
+    assert_eq!(db.recently_modified(UNIX_EPOCH).len(), 1);
+
+    // The stored value comes back once the key is allowed again
+    db.set_denied_keys(&[]);
+    assert_eq!(db.get("foo"), Some(5));
+    assert_eq!(db.get("baz"), None);
+}
+
+#[test]
+fn test_max_keys() {
+    let mut db = RotDb::ephemeral();
+    db.set_max_keys(Some(3));
+    assert_eq!(db.increment("foo"), 1);
+    assert_eq!(db.decrement("Bar"), -1);
+    assert_eq!(db.increment("baz"), 1);
+    assert!(db.at_key_limit("quux"));
+    assert_eq!(db.increment("quux"), 0);
+    assert_eq!(db.decrement("quux"), 0);
+    assert_eq!(db.get("quux"), None);
+    assert!(db.variants("quux").is_empty());
+    assert_eq!(db.len(), 3);
+
+    // Keys already stored can still change, in any form
+    assert!(!db.at_key_limit("BAR"));
+    assert_eq!(db.increment("BAR"), 0);
+    assert_eq!(db.increment("foo"), 2);
+    db.freeze("baz");
+    db.reset("foo");
+    assert!(!db.at_key_limit("foo"));
+    assert_eq!(db.increment("quux"), 1);
+
+    db.set_max_keys(None);
+    assert_eq!(db.increment("xyzzy"), 1);
+}
+
+#[test]
+fn test_floor() {
+    let mut db = RotDb::ephemeral();
+    db.set_floor(Some(-2));
+    assert_eq!(db.decrement("foo"), -1);
+    assert_eq!(db.decrement("foo"), -2);
+    assert_eq!(db.decrement("foo"), -2);
+    assert_eq!(db.add("foo", -10, SystemTime::now()), -2);
+    assert_eq!(db.increment("foo"), -1);
+    assert_eq!(db.add("bar", 3, SystemTime::now()), 3);
+    assert_eq!(db.add("bar", -10, SystemTime::now()), -2);
+    assert_eq!(db.add("bar", -1, SystemTime::now()), -2);
+
+    // A value already below the floor isn't raised by a decrement
+    db.set("baz", -5);
+    assert_eq!(db.decrement("baz"), -5);
+    assert_eq!(db.increment("baz"), -4);
+
+    // Separate from saturating at the limits of an i64
+    db.set_floor(None);
+    assert_eq!(db.add("foo", i64::MIN, SystemTime::now()), i64::MIN);
+    db.set("bar", i64::MAX);
+    assert_eq!(db.increment("bar"), i64::MAX);
+}
+
+#[test]
+fn test_parse_db_line() {
+    let parse = |text| parse_db_line("test", text).map(|(key, entry)| {
+        (key, entry.value, entry.modified, entry.frozen)
+    });
+    assert_eq!(parse("foo:10"), Some(("foo".to_string(), 10, None, false)));
+    assert_eq!(parse("foo: 10"), Some(("foo".to_string(), 10, None, false)));
+    assert_eq!(parse("  Foo%3A%3ABar :\t-3 "), Some(("Foo::Bar".to_string(), -3, None, false)));
+    assert_eq!(parse("foo : 10 : 60 : frozen, deleted "),
+               Some(("foo".to_string(), 10, Some(UNIX_EPOCH + Duration::from_secs(60)), true)));
+    assert_eq!(parse("foo: :"), None);
+    assert_eq!(parse("foo:1 0"), None);
+    // Too big for an i64, so skipped rather than wrapped or clamped
+    assert_eq!(parse("foo:9223372036854775807"),
+               Some(("foo".to_string(), i64::MAX, None, false)));
+    assert_eq!(parse("foo:9223372036854775808"), None);
+    assert_eq!(parse("foo:-9223372036854775809"), None);
+}
+
+#[test]
+fn test_db_comments() {
+    remove_test_db("test_db_comments.db");
+    std::fs::write("test_db_comments.db",
+                   "# Karma for the team\n\nfoo:10\n   \r\n  # bar:5\nbaz:-2\n#\n").unwrap();
+    {
+        let mut db = RotDb::new("test_db_comments.db");
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.get("foo"), Some(10));
+        assert_eq!(db.get("bar"), None);
+        assert_eq!(db.get("baz"), Some(-2));
+        db.increment("foo");
+    }
+    // Comments don't survive a save, but keys that look like them do
+    let text = std::fs::read_to_string("test_db_comments.db").unwrap();
+    assert!(!text.contains('#'));
+    {
+        let mut db = RotDb::new("test_db_comments.db");
+        assert_eq!(db.get("foo"), Some(11));
+        db.set("#hashtag", 3);
+    }
+    assert_eq!(RotDb::new("test_db_comments.db").get("#hashtag"), Some(3));
+
+    // Nor do they count as invalid lines when deciding if the db is corrupt
+    std::fs::write("test_db_comments.db", "#\n".repeat(CORRUPT_SAMPLE_LINES) + "foo:1\n").unwrap();
+    assert!(parse_zot_db("test_db_comments.db", None).is_ok());
+    remove_test_db("test_db_comments.db");
+}
+
+#[test]
+fn test_variants() {
+    remove_test_db("test_variants.db");
+    {
+        let mut db = RotDb::new("test_variants.db");
+        assert_eq!(db.variants("foo.bar"), Vec::<String>::new());
+        db.increment("Foo::Bar");
+        db.increment("foo.bar");
+        db.decrement("Foo::Bar");
+        db.set("baz", 1);
+        assert_eq!(db.variants("FOO->BAR"), vec!["Foo::Bar", "foo.bar"]);
+        assert_eq!(db.variants("baz"), vec!["baz"]);
+        // Changes a frozen or reset key ignores don't add variants
+        db.freeze("baz");
+        db.increment("Baz");
+        db.reset("foo.bar");
+        db.increment("FOO.BAR");
+        assert_eq!(db.variants("baz"), vec!["baz"]);
+        assert_eq!(db.variants("foo.bar"), vec!["Foo::Bar", "foo.bar"]);
+    }
+    {
+        let db = RotDb::new("test_variants.db");
+        assert_eq!(db.variants("foo.bar"), vec!["Foo::Bar", "foo.bar"]);
+    }
+    remove_test_db("test_variants.db");
+}
+
+#[test]
+fn test_key_modes() {
+    let keys = ["foo.bar", "foo::bar", "foo->bar", "Foo.Bar", "FOO::BAR", "Foo->bar"];
+    // Whether each pair of keys is the same in each mode, with `.`, `::` and
+    // `->` separators in that order and then a change of case
+    let unified = [[true; 6]; 6];
+    let distinct = [
+        [true,  false, false, true,  false, false],
+        [false, true,  false, false, true,  false],
+        [false, false, true,  false, false, true ],
+        [true,  false, false, true,  false, false],
+        [false, true,  false, false, true,  false],
+        [false, false, true,  false, false, true ],
+    ];
+    for (mode, expected) in [(KeyMode::Unified, unified), (KeyMode::Distinct, distinct)] {
+        for (i, a) in keys.iter().enumerate() {
+            for (j, b) in keys.iter().enumerate() {
+                assert_eq!(mode.normalize(a) == mode.normalize(b), expected[i][j],
+                           "{:?}: {} vs {}", mode, a, b);
+            }
+        }
+    }
+    assert_eq!(KeyMode::Unified.normalize("A::B->C.D"), "a.b.c.d");
+    assert_eq!(KeyMode::Distinct.normalize("A::B->C.D"), "a::b->c.d");
+
+    remove_test_db("test_key_modes.db");
+    {
+        let mut db = RotDb::new("test_key_modes.db");
+        db.set_key_mode(KeyMode::Distinct);
+        db.enable_history();
+        db.increment("Foo::Bar");
+        db.increment("foo::bar");
+        db.decrement("foo.bar");
+        db.record("alice", "Foo::Bar", 1, UNIX_EPOCH);
+        assert_eq!(db.value("FOO::BAR"), 2);
+        assert_eq!(db.value("foo.bar"), -1);
+        assert_eq!(db.value("foo->bar"), 0);
+    }
+    {
+        // Keys containing colons survive a round trip through the files
+        let mut db = RotDb::new("test_key_modes.db");
+        db.set_key_mode(KeyMode::Distinct);
+        db.enable_history();
+        assert_eq!(db.value("foo::bar"), 2);
+        assert_eq!(db.value("foo.bar"), -1);
+        assert_eq!(db.contributions_to("foo::bar").len(), 1);
+        assert_eq!(db.variants("foo::bar"), vec!["Foo::Bar", "foo::bar"]);
+    }
+    remove_test_db("test_key_modes.db");
+}
+
+#[test]
+fn test_iter() {
+    let mut db = RotDb::ephemeral();
+    assert!(db.is_empty());
+    assert_eq!(db.iter().count(), 0);
+    db.set("foo", 3);
+    db.set("Foo::Bar", -2);
+    db.set("baz", 0);
+    db.set("FOO", 4);
+    let mut entries: Vec<(&str, i64)> = db.iter().collect();
+    entries.sort();
+    assert_eq!(entries, vec![("baz", 0), ("foo", 4), ("foo.bar", -2)]);
+    assert_eq!(db.len(), 3);
+    assert!(!db.is_empty());
+
+    // Keys from a base db are included, unless overridden
+    let mut layer = RotDb::ephemeral();
+    rebase_on(&mut layer, &db);
+    layer.set("foo", 10);
+    layer.set("new", 1);
+    let mut entries: Vec<(&str, i64)> = layer.iter().collect();
+    entries.sort();
+    assert_eq!(entries, vec![("baz", 0), ("foo", 10), ("foo.bar", -2), ("new", 1)]);
+    assert_eq!(layer.len(), 4);
+
+    // Empty whenever there are no keys to list, even with reset keys stored
+    let mut db = RotDb::ephemeral();
+    db.set("foo", 1);
+    db.reset("foo");
+    assert!(db.is_empty());
+}
+
+#[test]
+fn test_keys_with_value() {
+    let mut db = RotDb::ephemeral();
+    assert_eq!(db.keys_with_value(0), Vec::<String>::new());
+    for (key, value) in &[("zsh", 0), ("bash", 3), ("Foo::Bar", 0), ("fish", -1), ("csh", 0)] {
+        db.set(key, *value);
+    }
+    assert_eq!(db.keys_with_value(0), vec!["csh", "foo.bar", "zsh"]);
+    assert_eq!(db.keys_with_value(3), vec!["bash"]);
+    assert_eq!(db.keys_with_value(-1), vec!["fish"]);
+    assert_eq!(db.keys_with_value(2), Vec::<String>::new());
+}
+
+#[test]
+fn test_seed() {
+    remove_test_db("test_seed.db");
+    std::fs::write("test_seed.txt",
+                   "# event keys\nfoo\nFoo::Bar\n\n  new.key  \nfoo.bar\nNEW.KEY\n\
+                    two words\n9lives\nfoo.\nc++\n").unwrap();
+    {
+        let mut db = RotDb::new("test_seed.db");
+        db.set("foo", 5);
+        assert_eq!(db.seed("test_seed.txt").unwrap(), 2);
+        assert_eq!(db.get("foo"), Some(5));
+        assert_eq!(db.get("foo.bar"), Some(0));
+        assert_eq!(db.get("new.key"), Some(0));
+        db.close().unwrap();
+    }
+    let mut db = RotDb::new("test_seed.db");
+    assert_eq!(db.len(), 3);
+    assert_eq!(db.seed("no_such_seed.txt").unwrap_err().kind(), ErrorKind::NotFound);
+    drop(db);
+    std::fs::remove_file("test_seed.txt").unwrap();
+    remove_test_db("test_seed.db");
+}
+
+#[test]
+fn test_recently_modified() {
+    let mut db = RotDb::ephemeral();
+    let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+    db.add("old", 1, at(100));
+    db.add("foo", 2, at(300));
+    db.add("bar", -1, at(200));
+    db.add("baz", 5, at(300));
+    assert_eq!(db.recently_modified(at(200)), vec![
+        ("baz".to_string(), 5, at(300)),
+        ("foo".to_string(), 2, at(300)),
+        ("bar".to_string(), -1, at(200)),
+    ]);
+    assert_eq!(db.recently_modified(at(301)), vec![]);
+    db.add("old", 1, at(400));
+    assert_eq!(db.recently_modified(at(350)), vec![("old".to_string(), 2, at(400))]);
+}
+
+#[test]
+fn test_top() {
+    remove_test_db("test_top.db");
+    let mut db = RotDb::new("test_top.db");
+    assert_eq!(db.top(3), vec![]);
+    db.set("foo", 5);
+    db.set("Bar", 10);
+    db.set("baz", -2);
+    db.set("qux", 5);
+    assert_eq!(db.top(3), vec![("bar".to_string(), 10), ("foo".to_string(), 5),
+                               ("qux".to_string(), 5)]);
+    assert_eq!(db.top(10).len(), 4);
+    assert_eq!(db.rank("bar"), Some((1, 4)));
+    assert_eq!(db.rank("foo"), Some((2, 4)));
+    assert_eq!(db.rank("qux"), Some((2, 4)));
+    assert_eq!(db.rank("baz"), Some((4, 4)));
+    assert_eq!(db.rank("nothing"), None);
+
+    drop(db);
+    remove_test_db("test_top.db");
+}
+
+#[test]
+fn test_layered() {
+    remove_test_db("test_layer_overlay.db");
+    std::fs::write("test_layer_base.db", "foo:10\nbar:5\nfrozen:3::frozen\n").unwrap();
+    {
+        let mut db = RotDb::layered("test_layer_base.db", "test_layer_overlay.db", None);
+        // Reads fall through to the base
+        assert_eq!(db.value("foo"), 10);
+        assert_eq!(db.get("bar"), Some(5));
+        assert_eq!(db.get("baz"), None);
+        assert!(db.is_frozen("frozen"));
+        assert_eq!(db.top(2), vec![("foo".to_string(), 10), ("bar".to_string(), 5)]);
+
+        // Changes start from the base value, and then override it
+        assert_eq!(db.increment("bar"), 6);
+        assert_eq!(db.set("baz", 20), 20);
+        assert_eq!(db.increment("frozen"), 3);
+        assert_eq!(db.value("bar"), 6);
+        assert_eq!(db.top(10), vec![("baz".to_string(), 20), ("foo".to_string(), 10),
+                                    ("bar".to_string(), 6), ("frozen".to_string(), 3)]);
+        assert_eq!(db.rank("bar"), Some((3, 4)));
+    }
+    {
+        // Only changed keys were written to the overlay
+        let overlay = RotDb::new("test_layer_overlay.db");
+        assert_eq!(overlay.get("foo"), None);
+        assert_eq!(overlay.get("bar"), Some(6));
+        assert_eq!(overlay.get("baz"), Some(20));
+        assert_eq!(std::fs::read_to_string("test_layer_base.db").unwrap(),
+                   "foo:10\nbar:5\nfrozen:3::frozen\n");
+
+        // Unfreezing a base key only affects the overlay
+        let mut db = RotDb::layered("test_layer_base.db", "test_layer_overlay.db", None);
+        assert_eq!(db.value("bar"), 6);
+        assert!(db.unfreeze("frozen"));
+        assert_eq!(db.increment("frozen"), 4);
+        assert!(RotDb::new("test_layer_base.db").is_frozen("frozen"));
+    }
+
+    remove_test_db("test_layer_base.db");
+    remove_test_db("test_layer_overlay.db");
+}
+
+#[test]
+fn test_freeze() {
+    {
+        remove_test_db("test_freeze.db");
+        let mut db = RotDb::new("test_freeze.db");
+        assert_eq!(db.increment("foo"), 1);
+        assert!(!db.is_frozen("foo"));
+        assert_eq!(db.freeze("Foo"), 1);
+        assert!(db.is_frozen("foo"));
+        assert_eq!(db.increment("foo"), 1);
+        assert_eq!(db.decrement("FOO"), 1);
+        assert_eq!(db.set("foo", 42), 1);
+        assert_eq!(db.value("foo"), 1);
+
+        // Freezing an unknown key locks it at zero
+        assert_eq!(db.freeze("bar"), 0);
+        assert_eq!(db.increment("bar"), 0);
+        assert!(!db.unfreeze("baz"));
+    }
+    {
+        // Frozen keys stay frozen after reloading
+        let mut db = RotDb::new("test_freeze.db");
+        assert!(db.is_frozen("foo"));
+        assert_eq!(db.increment("foo"), 1);
+        assert!(db.unfreeze("foo"));
+        assert!(!db.unfreeze("foo"));
+        assert_eq!(db.increment("foo"), 2);
+        assert_eq!(db.set("foo", 42), 42);
+        assert!(db.is_frozen("bar"));
+    }
+    {
+        let db = RotDb::new("test_freeze.db");
+        assert!(!db.is_frozen("foo"));
+        assert_eq!(db.value("foo"), 42);
+    }
+
+    remove_test_db("test_freeze.db");
+}
+
+#[test]
+fn test_reset() {
+    remove_test_db("test_reset.db");
+    {
+        let mut db = RotDb::new("test_reset.db");
+        db.set("foo", 5);
+        db.set("bar", 3);
+        db.set("baz", -1);
+        assert!(db.reset("Foo"));
+        assert!(db.reset("baz"));
+        assert!(!db.reset("nothing"));
+        assert!(!db.reset("foo"));
+        assert_eq!(db.value("foo"), 0);
+        assert_eq!(db.get("foo"), None);
+        assert_eq!(db.top(5), vec![("bar".to_string(), 3)]);
+        assert_eq!(db.rank("bar"), Some((1, 1)));
+        assert_eq!(db.len(), 1);
+
+        // Changes wait until the key is restored or purged
+        assert_eq!(db.increment("foo"), 0);
+        assert_eq!(db.restore("bar"), None);
+    }
+    {
+        // Still reset after reloading
+        let mut db = RotDb::new("test_reset.db");
+        assert!(db.is_reset("foo"));
+        assert_eq!(db.get("foo"), None);
+        assert_eq!(db.restore("foo"), Some(5));
+        assert_eq!(db.value("foo"), 5);
+        assert_eq!(db.increment("foo"), 6);
+        assert_eq!(db.purge(), 1);
+        assert_eq!(db.restore("baz"), None);
+        assert_eq!(db.purge(), 0);
+    }
+    {
+        let mut db = RotDb::new("test_reset.db");
+        assert_eq!(db.value("foo"), 6);
+        assert!(!db.is_reset("baz"));
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.increment("baz"), 1);
+    }
+    remove_test_db("test_reset.db");
+
+    // Reset keys from a base db are zeroed by purging
+    let mut base = RotDb::ephemeral();
+    base.set("foo", 5);
+    let mut layer = RotDb::ephemeral();
+    rebase_on(&mut layer, &base);
+    assert!(layer.reset("foo"));
+    assert_eq!(layer.get("foo"), None);
+    assert_eq!(layer.purge(), 1);
+    assert_eq!(layer.get("foo"), Some(0));
+    assert_eq!(layer.restore("foo"), None);
+}
+
+#[test]
+fn test_sorted_sync() {
+    let keys = ["pear", "apple", "zebra", "Mango", "banana", "apple.pie", "kiwi"];
+    let mut saved = Vec::new();
+    for (n, filename) in ["test_sorted_sync1.db", "test_sorted_sync2.db"].iter().enumerate() {
+        remove_test_db(filename);
+        {
+            let mut db = RotDb::new(filename);
+            let time = UNIX_EPOCH + Duration::from_secs(1_000_000);
+            // The same values, added in a different order
+            let mut ordered = keys.to_vec();
+            if n == 1 {
+                ordered.reverse();
+            }
+            for key in ordered {
+                db.add(key, key.len() as i64, time);
+            }
+            db.sync();
+            db.add("kiwi", -1, time);
+            db.sync();
+        }
+        saved.push(std::fs::read(filename).unwrap());
+        remove_test_db(filename);
+    }
+    assert_eq!(saved[0], saved[1]);
+    let text = String::from_utf8(saved.remove(0)).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    let saved_keys: Vec<&str> = lines.iter().map(|line| line.split(':').next().unwrap()).collect();
+    assert_eq!(saved_keys, ["apple", "apple.pie", "banana", "kiwi", "mango", "pear", "zebra"]);
+}
+
+#[test]
+fn test_prune() {
+    let mut db = RotDb::ephemeral();
+    let start = UNIX_EPOCH + Duration::from_secs(1_000_000);
+    let day = Duration::from_secs(24 * 60 * 60);
+    let set_value = |db: &mut RotDb, key: &str, value: i64, modified: Option<SystemTime>| {
+        db.values.insert(key.to_string(), Entry { value, modified, ..Default::default() });
+    };
+    set_value(&mut db, "typo", 1, Some(start));
+    set_value(&mut db, "negative", -2, Some(start));
+    set_value(&mut db, "popular", 50, Some(start));
+    set_value(&mut db, "recent", 1, Some(start + day * 10));
+    set_value(&mut db, "unknown", 2, None);
+    set_value(&mut db, "frozen", 1, Some(start));
+    db.freeze("frozen");
+    set_value(&mut db, "reset", 1, Some(start));
+    db.reset("reset");
+    let mut base = RotDb::ephemeral();
+    base.set("based", 5);
+    rebase_on(&mut db, &base);
+    set_value(&mut db, "based", 1, Some(start));
+
+    // Only the key of unknown age is older than the oldest known change
+    assert_eq!(db.prune(3, start), 1);
+    assert_eq!(db.get("unknown"), None);
+    assert_eq!(db.prune(2, start + day * 5), 1);
+    assert_eq!(db.get("typo"), None);
+    assert_eq!(db.get("negative"), Some(-2));
+    assert_eq!(db.prune(-3, start + day * 5), 1);
+    assert_eq!(db.get("negative"), None);
+    assert_eq!(db.prune(100, start + day * 5), 1);
+    assert_eq!(db.get("popular"), None);
+    for key in ["recent", "frozen", "based"] {
+        assert_eq!(db.get(key), Some(1), "{}", key);
+    }
+    assert!(db.is_reset("reset"));
+    assert_eq!(db.prune(100, start + day * 20), 1);
+    assert_eq!(db.get("recent"), None);
+
+    // Nothing else about a pruned key is kept either
+    remove_test_db("test_prune.db");
+    {
+        let mut db = RotDb::new("test_prune.db");
+        db.enable_history();
+        db.enable_user_totals();
+        for (key, sender) in [("Bob", "alice"), ("keep", "bob")] {
+            db.add(key, 1, start);
+            db.record(sender, key, 1, start);
+            db.record_user_change(sender, Some(key), 1);
+        }
+        db.add("keep", 10, start);
+        db.sync();
+        assert_eq!(db.variants("bob"), vec!["Bob"]);
+        assert_eq!(db.prune(2, start + day), 1);
+        assert!(db.contributions_to("bob").is_empty());
+        assert!(db.variants("bob").is_empty());
+        assert_eq!(db.user_totals("bob"), UserTotals { given_ups: 1, ..Default::default() });
+        assert_eq!(db.user_totals("alice").given_ups, 1);
+        assert_eq!(db.contributions_to("keep").len(), 1);
+    }
+    assert_eq!(std::fs::read_to_string("test_prune.db.history").unwrap(),
+               "1000000:bob:keep:1\n");
+    remove_test_db("test_prune.db");
+}
+
+#[test]
+fn test_history() {
+    remove_test_db("test_history.db");
+    let time = UNIX_EPOCH + Duration::from_secs(1_000_000);
+    {
+        let mut db = RotDb::new("test_history.db");
+        // Nothing is recorded until history is enabled
+        db.record("alice", "foo", 1, time);
+        assert_eq!(db.contributions_to("foo"), vec![]);
+
+        db.enable_history();
+        db.record("Alice", "foo", 1, time);
+        db.record("alice", "Foo", 1, time);
+        db.record("alice", "bar", -1, time);
+        db.record("bob", "foo", -1, time);
+        db.record("carol", "foo::baz", 1, time);
+        db.record("carol", "Foo->Baz", -1, time);
+        assert_eq!(db.contributions_to("FOO"), vec![
+            Contribution { name: "alice".to_string(), ups: 2, downs: 0 },
+            Contribution { name: "bob".to_string(), ups: 0, downs: 1 },
+        ]);
+        assert_eq!(db.contributions_to("foo.baz"), vec![
+            Contribution { name: "carol".to_string(), ups: 1, downs: 1 },
+        ]);
+        assert_eq!(db.contributions_by("ALICE"), vec![
+            Contribution { name: "foo".to_string(), ups: 2, downs: 0 },
+            Contribution { name: "bar".to_string(), ups: 0, downs: 1 },
+        ]);
+        assert_eq!(db.contributions_by("dave"), vec![]);
+        db.sync();
+        db.record("dave", "foo", 1, time);
+    }
+    {
+        // History is appended on sync, so nothing is lost or duplicated
+        let mut db = RotDb::new("test_history.db");
+        db.enable_history();
+        assert_eq!(db.contributions_to("foo").len(), 3);
+        assert_eq!(db.contributions_by("alice").len(), 2);
+    }
+
+    remove_test_db("test_history.db");
+}
+
+#[test]
+fn test_history_limit() {
+    remove_test_db("test_history_limit.db");
+    let time = UNIX_EPOCH + Duration::from_secs(1_000_000);
+    let bob = |ups: usize| {
+        vec![Contribution { name: "bob".to_string(), ups: ups as u64, downs: 0 }]
+    };
+    {
+        let mut db = RotDb::new("test_history_limit.db");
+        db.enable_history();
+        db.record("alice", "old", 1, time);
+        for _ in 1..MAX_HISTORY_ENTRIES {
+            db.record("bob", "foo", 1, time);
+        }
+        // Entries not yet saved are kept past the limit
+        db.record("carol", "bar", 1, time);
+        assert_eq!(db.contributions_to("old").len(), 1);
+        db.sync();
+        db.record("dave", "bar", 1, time);
+        assert_eq!(db.contributions_to("old"), vec![]);
+        assert_eq!(db.contributions_to("foo"), bob(MAX_HISTORY_ENTRIES - 2));
+    }
+    {
+        // Only the most recent are loaded, although the file has them all
+        let mut db = RotDb::new("test_history_limit.db");
+        db.enable_history();
+        assert_eq!(db.contributions_to("old"), vec![]);
+        assert_eq!(db.contributions_to("foo"), bob(MAX_HISTORY_ENTRIES - 2));
+        assert_eq!(db.contributions_to("bar").len(), 2);
+        let saved = std::fs::read_to_string("test_history_limit.db.history").unwrap();
+        assert_eq!(saved.lines().count(), MAX_HISTORY_ENTRIES + 2);
+    }
+    remove_test_db("test_history_limit.db");
+}
+
+#[test]
+fn test_load_guard() {
+    remove_test_db("test_load_guard.db");
+    let valid: String = (0..2000).map(|n| format!("key{}:{}\n", n, n)).collect();
+    std::fs::write("test_load_guard.db", &valid).unwrap();
+    {
+        let mut db = RotDb::open("test_load_guard.db", Some(1000));
+        assert_eq!(db.get("key1"), None);
+        // The refused db is never overwritten
+        db.increment("key1");
+        db.sync();
+    }
+    assert_eq!(std::fs::read_to_string("test_load_guard.db").unwrap(), valid);
+    {
+        let db = RotDb::open("test_load_guard.db", Some(2000));
+        assert_eq!(db.get("key1999"), Some(1999));
+        let db = RotDb::new("test_load_guard.db");
+        assert_eq!(db.get("key1999"), Some(1999));
+    }
+
+    // Garbage is refused regardless of the limit, but a few bad lines are not
+    std::fs::write("test_load_guard.db", "not a db\n".repeat(2000)).unwrap();
+    assert!(parse_zot_db("test_load_guard.db", None)
+                .is_err_and(|err| err.kind() == ErrorKind::InvalidData));
+    std::fs::write("test_load_guard.db", valid.clone() + "oops\n").unwrap();
+    assert_eq!(parse_zot_db("test_load_guard.db", None).unwrap().len(), 2000);
+    std::fs::write("test_load_guard.db", "x".repeat(100_000)).unwrap();
+    assert!(parse_zot_db("test_load_guard.db", None)
+                .is_err_and(|err| err.kind() == ErrorKind::InvalidData));
+
+    // An over-long line is skipped, without losing the lines after it
+    std::fs::write("test_load_guard.db", format!("foo:1\n{}:2\nbar:3", "x".repeat(10_000)))
+        .unwrap();
+    let values = parse_zot_db("test_load_guard.db", None).unwrap();
+    assert_eq!((values.len(), values["bar"].value), (2, 3));
+    remove_test_db("test_load_guard.db");
+}
+
+#[test]
+fn test_export_csv() {
+    remove_test_db("test_export.db");
+    let mut db = RotDb::new("test_export.db");
+    db.set("foo", 2);
+    db.values.get_mut("foo").unwrap().modified = Some(UNIX_EPOCH + Duration::from_secs(1000));
+    db.values.insert("bar".to_string(), Entry { value: -1, ..Default::default() });
+
+    let mut csv = Vec::new();
+    db.export_csv(&mut csv).unwrap();
+    assert_eq!(String::from_utf8(csv).unwrap(),
+               "key,value,ups,downs,last_modified\nbar,-1,,,\nfoo,2,,,1000\n");
+
+    db.enable_history();
+    db.record("alice", "foo", 1, UNIX_EPOCH);
+    db.record("bob", "Foo", 1, UNIX_EPOCH);
+    db.record("bob", "foo", -1, UNIX_EPOCH);
+    let mut csv = Vec::new();
+    db.export_csv(&mut csv).unwrap();
+    assert_eq!(String::from_utf8(csv).unwrap(),
+               "key,value,ups,downs,last_modified\nbar,-1,0,0,\nfoo,2,2,1,1000\n");
+
+    assert_eq!(csv_field("a\"b,c"), "\"a\"\"b,c\"");
+    for (key, field) in [("=cmd|' /C calc'!A0", "'=cmd|' /C calc'!A0"), ("+1", "'+1"),
+                         ("-2+3", "'-2+3"), ("@sum(a1)", "'@sum(a1)"),
+                         ("=1,2", "\"'=1,2\""), ("a=b", "a=b")] {
+        assert_eq!(csv_field(key), field);
+    }
+    drop(db);
+    remove_test_db("test_export.db");
+}
+
+#[test]
+fn test_ephemeral_extras() {
+    remove_test_db("test_ephemeral_extras.db");
+    // A refused db is ephemeral, and ignores the files saved alongside it
+    std::fs::write("test_ephemeral_extras.db", "not a db\n").unwrap();
+    std::fs::write("test_ephemeral_extras.db.users", "alice 1 0 0 0\n").unwrap();
+    std::fs::write("test_ephemeral_extras.db.history", "0:alice:foo:1\n").unwrap();
+    let mut db = RotDb::new("test_ephemeral_extras.db");
+    db.enable_user_totals();
+    db.enable_history();
+    assert_eq!(db.user_totals("alice"), UserTotals::default());
+    assert!(db.contributions_to("foo").is_empty());
+    drop(db);
+    remove_test_db("test_ephemeral_extras.db");
+}
+
+#[test]
+fn test_user_totals() {
+    remove_test_db("test_user_totals.db");
+    {
+        let mut db = RotDb::new("test_user_totals.db");
+        // Nothing is counted until user totals are enabled
+        db.record_user_change("alice", Some("bob"), 1);
+        assert_eq!(db.user_totals("alice"), UserTotals::default());
+
+        db.enable_user_totals();
+        db.record_user_change("Alice", Some("bob"), 1);
+        db.record_user_change("alice", Some("BOB"), 1);
+        db.record_user_change("alice", None, -1);
+        db.record_user_change("bob", Some("alice"), -1);
+        assert_eq!(db.user_totals("ALICE"), UserTotals {
+            given_ups: 2, given_downs: 1, received_ups: 0, received_downs: 1,
+        });
+        assert_eq!(db.user_totals("bob"), UserTotals {
+            given_ups: 0, given_downs: 1, received_ups: 2, received_downs: 0,
+        });
+        assert_eq!(db.user_totals("alice").given(), 1);
+        assert_eq!(db.user_totals("bob").received(), 2);
+        assert_eq!(db.user_totals("carol"), UserTotals::default());
+        db.sync();
+    }
+    {
+        let mut db = RotDb::new("test_user_totals.db");
+        db.enable_user_totals();
+        assert_eq!(db.user_totals("bob").received_ups, 2);
+        assert_eq!(db.user_totals("alice").given_downs, 1);
+    }
+    remove_test_db("test_user_totals.db");
 }
@@ -0,0 +1,142 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::{Duration, Instant};
+
+/// A message sent to `target` every `interval`.  Placeholders in the
+/// template are filled in when the message is sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    pub interval: Duration,
+    pub target: String,
+    pub template: String,
+}
+
+pub struct Scheduler {
+    schedules: Vec<Schedule>,
+    deadlines: Vec<Instant>,
+}
+
+impl Scheduler {
+    pub fn new(schedules: Vec<Schedule>) -> Scheduler {
+        let now = Instant::now();
+        let deadlines = schedules.iter().map(|s| now + s.interval).collect();
+        Scheduler { schedules, deadlines }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.schedules.is_empty()
+    }
+
+    /// Wait until one or more schedules are due, and return them.  Dropping
+    /// the future before it completes leaves the schedules untouched.
+    pub async fn wait(&mut self) -> Vec<Schedule> {
+        let next = match self.deadlines.iter().min() {
+            Some(next) => *next,
+            None => return std::future::pending().await,
+        };
+        tokio::time::sleep_until(next).await;
+
+        let now = Instant::now();
+        let mut due = Vec::new();
+        for (schedule, deadline) in self.schedules.iter().zip(self.deadlines.iter_mut()) {
+            if *deadline <= now {
+                due.push(schedule.clone());
+                while *deadline <= now {
+                    *deadline += schedule.interval;
+                }
+            }
+        }
+        due
+    }
+}
+
+/// Daily window when replies are left out, in minutes after midnight at a
+/// fixed UTC offset.  The window wraps past midnight if `end` is before
+/// `start`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuietHours {
+    pub start: u32,
+    pub end: u32,
+    pub utc_offset: i32,
+    /// Lowercase channels the window applies to, or all if empty
+    pub channels: Vec<String>,
+}
+
+const MINUTES_PER_DAY: i64 = 24 * 60;
+
+impl QuietHours {
+    pub fn contains(&self, channel: &str, time: SystemTime) -> bool {
+        if !self.channels.is_empty()
+                && !self.channels.iter().any(|chan| chan.eq_ignore_ascii_case(channel)) {
+            return false;
+        }
+        let minutes = match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => (since.as_secs() / 60) as i64,
+            Err(err) => -((err.duration().as_secs() / 60) as i64),
+        };
+        let local = (minutes + self.utc_offset as i64).rem_euclid(MINUTES_PER_DAY) as u32;
+        if self.start <= self.end {
+            local >= self.start && local < self.end
+        } else {
+            local >= self.start || local < self.end
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_scheduler() {
+    let hourly = Schedule {
+        interval: Duration::from_secs(60 * 60),
+        target: "#hourly".to_string(),
+        template: "Top karma: {top}".to_string(),
+    };
+    let half_hourly = Schedule {
+        interval: Duration::from_secs(30 * 60),
+        target: "#half".to_string(),
+        template: "Half".to_string(),
+    };
+    let start = Instant::now();
+    let mut scheduler = Scheduler::new(vec![hourly.clone(), half_hourly.clone()]);
+
+    // Nothing fires before the first interval has passed
+    let early = tokio::time::timeout(Duration::from_secs(29 * 60), scheduler.wait()).await;
+    assert!(early.is_err());
+
+    assert_eq!(scheduler.wait().await, vec![half_hourly.clone()]);
+    assert_eq!(Instant::now() - start, Duration::from_secs(30 * 60));
+    assert_eq!(scheduler.wait().await, vec![hourly.clone(), half_hourly.clone()]);
+    assert_eq!(Instant::now() - start, Duration::from_secs(60 * 60));
+    assert_eq!(scheduler.wait().await, vec![half_hourly]);
+    assert_eq!(Instant::now() - start, Duration::from_secs(90 * 60));
+    assert_eq!(scheduler.wait().await.len(), 2);
+    assert_eq!(Instant::now() - start, Duration::from_secs(120 * 60));
+}
+
+#[test]
+fn test_quiet_hours() {
+    let at = |hour: u64, minute: u64| UNIX_EPOCH + Duration::from_secs((hour * 60 + minute) * 60);
+    let overnight = QuietHours { start: 22 * 60, end: 7 * 60, utc_offset: 0, channels: Vec::new() };
+    assert!(overnight.contains("#chan", at(23, 0)));
+    assert!(overnight.contains("#chan", at(22, 0)));
+    assert!(overnight.contains("#chan", at(0, 30)));
+    assert!(overnight.contains("#chan", at(6, 59)));
+    assert!(!overnight.contains("#chan", at(7, 0)));
+    assert!(!overnight.contains("#chan", at(12, 0)));
+    assert!(!overnight.contains("#chan", at(21, 59)));
+
+    let lunch = QuietHours { start: 12 * 60, end: 13 * 60, utc_offset: 0, channels: Vec::new() };
+    assert!(lunch.contains("#chan", at(12, 30)));
+    assert!(!lunch.contains("#chan", at(13, 0)));
+    assert!(!lunch.contains("#chan", at(11, 59)));
+
+    // 22:00-07:00 at UTC+2 is 20:00-05:00 UTC, and at UTC-5 is 03:00-12:00
+    let east = QuietHours { utc_offset: 2 * 60, ..overnight.clone() };
+    assert!(east.contains("#chan", at(20, 0)));
+    assert!(!east.contains("#chan", at(5, 0)));
+    let west = QuietHours { utc_offset: -5 * 60, ..overnight.clone() };
+    assert!(west.contains("#chan", at(11, 0)));
+    assert!(!west.contains("#chan", at(2, 0)));
+
+    let some = QuietHours { channels: vec!["#sleepy".to_string()], ..overnight };
+    assert!(some.contains("#Sleepy", at(23, 0)));
+    assert!(!some.contains("#other", at(23, 0)));
+}
@@ -0,0 +1,555 @@
+use std::io::Write;
+use std::time::SystemTime;
+
+use crate::error::RotError;
+use crate::rotdb::{Contribution, Decay, RotDb, UserTotals};
+
+/// Where karma is kept, so the client doesn't depend on how it's stored.
+/// `RotDb` is the flat file implementation.  Keys are matched through
+/// `normalize`, so implementations decide how forgiving that is.
+///
+/// History, variants, user totals and decay are optional, and do nothing by
+/// default.
+pub trait KarmaStore: Send {
+    /// The form `key` is stored as
+    fn normalize(&self, key: &str) -> String;
+
+    /// The value of `key`, or None if it has never been stored
+    fn get(&self, key: &str) -> Option<i64>;
+
+    fn value(&self, key: &str) -> i64 {
+        self.get(key).unwrap_or(0)
+    }
+
+    /// Change `key` by `delta`, returning the new value.  Frozen keys keep
+    /// their value.
+    fn add(&mut self, key: &str, delta: i64, time: SystemTime) -> i64;
+
+    fn increment(&mut self, key: &str) -> i64 {
+        self.add(key, 1, SystemTime::now())
+    }
+
+    fn decrement(&mut self, key: &str) -> i64 {
+        self.add(key, -1, SystemTime::now())
+    }
+
+    /// Replace the value of `key`, unless it is frozen
+    fn set(&mut self, key: &str, value: i64) -> i64;
+
+    /// Every stored key and its value, in no particular order
+    fn iter(&self) -> Box<dyn Iterator<Item = (&str, i64)> + '_>;
+
+    /// The number of stored keys, as listed by `iter`
+    fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// When `key` was last changed, if that's known
+    fn modified(&self, _key: &str) -> Option<SystemTime> {
+        None
+    }
+
+    /// Every key changed at or after `since`, with its value and when it
+    /// was changed, most recent first
+    fn recently_modified(&self, _since: SystemTime) -> Vec<(String, i64, SystemTime)> {
+        Vec::new()
+    }
+
+    /// The `count` highest values, highest first
+    fn top(&self, count: usize) -> Vec<(String, i64)>;
+
+    /// The 1-based position of `key` in the leaderboard (tied values share
+    /// a rank), along with the total number of keys
+    fn rank(&self, key: &str) -> Option<(usize, usize)>;
+
+    /// Every key whose value is exactly `value`, sorted
+    fn keys_with_value(&self, value: i64) -> Vec<String>;
+
+    /// Every key starting with `prefix`, sorted.  The prefix is normalized
+    /// the same way as keys.
+    fn keys_with_prefix(&self, prefix: &str) -> Vec<String> {
+        let prefix = self.normalize(prefix);
+        let mut keys: Vec<String> = self.iter().filter(|(key, _)| key.starts_with(&prefix))
+                                        .map(|(key, _)| key.to_string()).collect();
+        keys.sort();
+        keys
+    }
+
+    /// Every key containing `text` anywhere, with its value, highest first.
+    /// The text is normalized the same way as keys.
+    fn search(&self, text: &str) -> Vec<(String, i64)> {
+        let text = self.normalize(text);
+        let mut found: Vec<(String, i64)> = self.iter().filter(|(key, _)| key.contains(&text))
+                                                .map(|(key, value)| (key.to_string(), value))
+                                                .collect();
+        found.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        found
+    }
+
+    /// Change every unfrozen key starting with `prefix` by `delta`,
+    /// returning how many were changed
+    fn adjust_prefix(&mut self, prefix: &str, delta: i64) -> usize {
+        let now = SystemTime::now();
+        let keys: Vec<String> = self.keys_with_prefix(prefix).into_iter()
+                                    .filter(|key| !self.is_frozen(key)).collect();
+        for key in &keys {
+            self.add(key, delta, now);
+        }
+        keys.len()
+    }
+
+    fn is_frozen(&self, key: &str) -> bool;
+
+    /// Lock `key` at its current value, returning that value
+    fn freeze(&mut self, key: &str) -> i64;
+
+    /// Returns false if `key` was not frozen
+    fn unfreeze(&mut self, key: &str) -> bool;
+
+    /// Hide `key` until it is restored or purged, returning false if there's
+    /// no such key
+    fn reset(&mut self, key: &str) -> bool;
+
+    /// Bring back a key that was reset, returning its value
+    fn restore(&mut self, key: &str) -> Option<i64>;
+
+    /// Permanently remove the keys that were reset, returning how many
+    fn purge(&mut self) -> usize;
+
+    /// Every form of `key` that has been changed
+    fn variants(&self, _key: &str) -> Vec<String> {
+        Vec::new()
+    }
+
+    fn enable_history(&mut self) {}
+
+    fn history_enabled(&self) -> bool {
+        false
+    }
+
+    /// Remember that `sender` changed `key`, if history is enabled
+    fn record(&mut self, _sender: &str, _key: &str, _delta: i64, _time: SystemTime) {}
+
+    fn contributions_to(&self, _key: &str) -> Vec<Contribution> {
+        Vec::new()
+    }
+
+    fn contributions_by(&self, _sender: &str) -> Vec<Contribution> {
+        Vec::new()
+    }
+
+    fn enable_user_totals(&mut self) {}
+
+    fn user_totals_enabled(&self) -> bool {
+        false
+    }
+
+    /// Count a change by `sender` to `target`'s nick (or to any other key if
+    /// None), if user totals are enabled
+    fn record_user_change(&mut self, _sender: &str, _target: Option<&str>, _delta: i64) {}
+
+    fn user_totals(&self, _nick: &str) -> UserTotals {
+        UserTotals::default()
+    }
+
+    fn set_decay(&mut self, _decay: Option<Decay>) {}
+
+    /// The lowest value a decrement can reach, or None for no limit
+    fn set_floor(&mut self, _floor: Option<i64>) {}
+
+    /// The most keys an increment or decrement can create, or None for no
+    /// limit
+    fn set_max_keys(&mut self, _max_keys: Option<usize>) {}
+
+    /// Whether changing `key` would be refused because of the key limit
+    fn at_key_limit(&self, _key: &str) -> bool {
+        false
+    }
+
+    /// Keys which can't be changed, and are left out of lookups and
+    /// listings
+    fn set_denied_keys(&mut self, _keys: &[String]) {}
+
+    fn is_denied(&self, _key: &str) -> bool {
+        false
+    }
+
+    /// Returns the number of values that changed
+    fn apply_decay(&mut self, _now: SystemTime) -> usize {
+        0
+    }
+
+    /// Remove the keys closer to zero than `min_abs` which haven't changed
+    /// since `older_than`, returning how many
+    fn prune(&mut self, _min_abs: i64, _older_than: SystemTime) -> usize {
+        0
+    }
+
+    /// Show everything in `base` underneath this store's own changes, for
+    /// the in-memory stores of sandbox channels
+    fn rebase(&mut self, base: &dyn KarmaStore);
+
+    /// The number of changes made so far, which only ever increases
+    fn changes(&self) -> u64;
+
+    fn export_csv(&self, writer: &mut dyn Write) -> Result<(), RotError>;
+
+    /// Save any changes, logging errors
+    fn sync(&mut self);
+
+    /// Save any changes, returning any error that prevented it
+    fn close(self: Box<Self>) -> Result<(), RotError>;
+}
+
+impl KarmaStore for RotDb {
+    fn normalize(&self, key: &str) -> String {
+        RotDb::normalize(self, key)
+    }
+
+    fn get(&self, key: &str) -> Option<i64> {
+        RotDb::get(self, key)
+    }
+
+    fn add(&mut self, key: &str, delta: i64, time: SystemTime) -> i64 {
+        RotDb::add(self, key, delta, time)
+    }
+
+    fn set(&mut self, key: &str, value: i64) -> i64 {
+        RotDb::set(self, key, value)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&str, i64)> + '_> {
+        Box::new(RotDb::iter(self))
+    }
+
+    fn top(&self, count: usize) -> Vec<(String, i64)> {
+        RotDb::top(self, count)
+    }
+
+    fn rank(&self, key: &str) -> Option<(usize, usize)> {
+        RotDb::rank(self, key)
+    }
+
+    fn keys_with_value(&self, value: i64) -> Vec<String> {
+        RotDb::keys_with_value(self, value)
+    }
+
+    fn modified(&self, key: &str) -> Option<SystemTime> {
+        RotDb::modified(self, key)
+    }
+
+    fn recently_modified(&self, since: SystemTime) -> Vec<(String, i64, SystemTime)> {
+        RotDb::recently_modified(self, since)
+    }
+
+    fn is_frozen(&self, key: &str) -> bool {
+        RotDb::is_frozen(self, key)
+    }
+
+    fn freeze(&mut self, key: &str) -> i64 {
+        RotDb::freeze(self, key)
+    }
+
+    fn unfreeze(&mut self, key: &str) -> bool {
+        RotDb::unfreeze(self, key)
+    }
+
+    fn reset(&mut self, key: &str) -> bool {
+        RotDb::reset(self, key)
+    }
+
+    fn restore(&mut self, key: &str) -> Option<i64> {
+        RotDb::restore(self, key)
+    }
+
+    fn purge(&mut self) -> usize {
+        RotDb::purge(self)
+    }
+
+    fn variants(&self, key: &str) -> Vec<String> {
+        RotDb::variants(self, key)
+    }
+
+    fn enable_history(&mut self) {
+        RotDb::enable_history(self)
+    }
+
+    fn history_enabled(&self) -> bool {
+        RotDb::history_enabled(self)
+    }
+
+    fn record(&mut self, sender: &str, key: &str, delta: i64, time: SystemTime) {
+        RotDb::record(self, sender, key, delta, time)
+    }
+
+    fn contributions_to(&self, key: &str) -> Vec<Contribution> {
+        RotDb::contributions_to(self, key)
+    }
+
+    fn contributions_by(&self, sender: &str) -> Vec<Contribution> {
+        RotDb::contributions_by(self, sender)
+    }
+
+    fn enable_user_totals(&mut self) {
+        RotDb::enable_user_totals(self)
+    }
+
+    fn user_totals_enabled(&self) -> bool {
+        RotDb::user_totals_enabled(self)
+    }
+
+    fn record_user_change(&mut self, sender: &str, target: Option<&str>, delta: i64) {
+        RotDb::record_user_change(self, sender, target, delta)
+    }
+
+    fn user_totals(&self, nick: &str) -> UserTotals {
+        RotDb::user_totals(self, nick)
+    }
+
+    fn set_decay(&mut self, decay: Option<Decay>) {
+        RotDb::set_decay(self, decay)
+    }
+
+    fn set_floor(&mut self, floor: Option<i64>) {
+        RotDb::set_floor(self, floor)
+    }
+
+    fn set_max_keys(&mut self, max_keys: Option<usize>) {
+        RotDb::set_max_keys(self, max_keys)
+    }
+
+    fn set_denied_keys(&mut self, keys: &[String]) {
+        RotDb::set_denied_keys(self, keys)
+    }
+
+    fn is_denied(&self, key: &str) -> bool {
+        RotDb::is_denied(self, key)
+    }
+
+    fn at_key_limit(&self, key: &str) -> bool {
+        RotDb::at_key_limit(self, key)
+    }
+
+    fn apply_decay(&mut self, now: SystemTime) -> usize {
+        RotDb::apply_decay(self, now)
+    }
+
+    fn prune(&mut self, min_abs: i64, older_than: SystemTime) -> usize {
+        RotDb::prune(self, min_abs, older_than)
+    }
+
+    /// Replace the read-only base with a copy of the values and frozen keys
+    /// in `base`
+    fn rebase(&mut self, base: &dyn KarmaStore) {
+        RotDb::rebase(self, base.iter().map(|(key, value)| (key, value, base.is_frozen(key))))
+    }
+
+    fn changes(&self) -> u64 {
+        RotDb::changes(self)
+    }
+
+    fn export_csv(&self, writer: &mut dyn Write) -> std::result::Result<(), RotError> {
+        RotDb::export_csv(self, writer)
+    }
+
+    fn sync(&mut self) {
+        RotDb::sync(self)
+    }
+
+    fn close(self: Box<Self>) -> std::result::Result<(), RotError> {
+        RotDb::close(*self)
+    }
+}
+
+/// A store kept in a `HashMap`, for testing the client without files
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryStore {
+    values: std::collections::HashMap<String, i64>,
+    // Values from `rebase`, overridden by any in `values`
+    base: std::collections::HashMap<String, i64>,
+    frozen: std::collections::HashSet<String>,
+    reset: std::collections::HashMap<String, i64>,
+    changes: u64,
+    /// Counts calls to `sync` and `close`
+    pub syncs: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+}
+
+#[cfg(test)]
+impl KarmaStore for MemoryStore {
+    fn normalize(&self, key: &str) -> String {
+        key.to_lowercase()
+    }
+
+    fn get(&self, key: &str) -> Option<i64> {
+        let key = self.normalize(key);
+        self.values.get(&key).or_else(|| self.base.get(&key)).copied()
+    }
+
+    fn add(&mut self, key: &str, delta: i64, _time: SystemTime) -> i64 {
+        let value = self.value(key) + delta;
+        self.set(key, value)
+    }
+
+    fn set(&mut self, key: &str, value: i64) -> i64 {
+        let key = self.normalize(key);
+        if self.frozen.contains(&key) {
+            return self.values[&key];
+        }
+        self.changes += 1;
+        self.values.insert(key, value);
+        value
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&str, i64)> + '_> {
+        let base = self.base.iter().filter(move |(key, _)| !self.values.contains_key(*key));
+        Box::new(self.values.iter().chain(base).map(|(key, value)| (key.as_str(), *value)))
+    }
+
+    fn top(&self, count: usize) -> Vec<(String, i64)> {
+        let mut entries: Vec<(String, i64)> = self.iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect();
+        entries.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        entries.truncate(count);
+        entries
+    }
+
+    fn rank(&self, key: &str) -> Option<(usize, usize)> {
+        let value = self.get(key)?;
+        let above = self.iter().filter(|(_, v)| *v > value).count();
+        Some((above + 1, self.iter().count()))
+    }
+
+    fn keys_with_value(&self, value: i64) -> Vec<String> {
+        let mut keys: Vec<String> = self.iter().filter(|(_, v)| *v == value)
+                                        .map(|(key, _)| key.to_string()).collect();
+        keys.sort();
+        keys
+    }
+
+    fn is_frozen(&self, key: &str) -> bool {
+        self.frozen.contains(&self.normalize(key))
+    }
+
+    fn freeze(&mut self, key: &str) -> i64 {
+        let value = self.value(key);
+        self.values.insert(self.normalize(key), value);
+        self.frozen.insert(self.normalize(key));
+        value
+    }
+
+    fn unfreeze(&mut self, key: &str) -> bool {
+        self.frozen.remove(&self.normalize(key))
+    }
+
+    fn reset(&mut self, key: &str) -> bool {
+        match self.values.remove(&self.normalize(key)) {
+            Some(value) => {
+                self.reset.insert(self.normalize(key), value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn restore(&mut self, key: &str) -> Option<i64> {
+        let value = self.reset.remove(&self.normalize(key))?;
+        self.values.insert(self.normalize(key), value);
+        Some(value)
+    }
+
+    fn purge(&mut self) -> usize {
+        self.reset.drain().count()
+    }
+
+    fn rebase(&mut self, base: &dyn KarmaStore) {
+        self.base = base.iter().map(|(key, value)| (key.to_string(), value)).collect();
+    }
+
+    fn changes(&self) -> u64 {
+        self.changes
+    }
+
+    fn export_csv(&self, writer: &mut dyn Write) -> Result<(), RotError> {
+        for (key, value) in &self.values {
+            writeln!(writer, "{},{}", key, value)?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) {
+        self.syncs.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    fn close(mut self: Box<Self>) -> Result<(), RotError> {
+        self.sync();
+        Ok(())
+    }
+}
+
+#[test]
+fn test_memory_rebase() {
+    let mut base = MemoryStore::default();
+    base.set("foo", 1);
+    base.set("bar", 2);
+    let mut store = MemoryStore::default();
+    store.rebase(&base);
+    store.increment("bar");
+    assert_eq!((store.get("foo"), store.get("bar")), (Some(1), Some(3)));
+
+    // Like RotDb, the base is replaced rather than only filling in new keys
+    base.set("foo", 5);
+    base.set("baz", 7);
+    store.rebase(&base);
+    assert_eq!((store.get("foo"), store.get("bar"), store.get("baz")), (Some(5), Some(3), Some(7)));
+    assert_eq!(store.top(2), vec![("baz".to_string(), 7), ("foo".to_string(), 5)]);
+    assert_eq!(store.rank("bar"), Some((3, 3)));
+}
+
+#[test]
+fn test_search() {
+    let mut db = RotDb::ephemeral();
+    for (key, value) in [("rust", 10), ("Rust::Analyzer", 3), ("trusty", 3), ("rustc", 7),
+                         ("crust.pizza", -1), ("ruby", 20), ("r.ust", 5), ("go", 1)] {
+        db.set(key, value);
+    }
+    let found = |db: &RotDb, text| {
+        db.search(text).into_iter().map(|(key, _)| key).collect::<Vec<_>>()
+    };
+    assert_eq!(db.search("RUST"), vec![("rust".to_string(), 10), ("rustc".to_string(), 7),
+                                       ("rust.analyzer".to_string(), 3),
+                                       ("trusty".to_string(), 3),
+                                       ("crust.pizza".to_string(), -1)]);
+    // Found anywhere in the key, unlike keys_with_prefix
+    assert_eq!(db.keys_with_prefix("rust"), vec!["rust", "rust.analyzer", "rustc"]);
+    assert_eq!(found(&db, "st.p"), vec!["crust.pizza"]);
+    assert_eq!(found(&db, "t::a"), vec!["rust.analyzer"]);
+    assert_eq!(found(&db, "java"), Vec::<String>::new());
+    assert_eq!(found(&db, "").len(), 8);
+    db.reset("rustc");
+    assert_eq!(found(&db, "rustc"), Vec::<String>::new());
+}
+
+#[test]
+fn test_adjust_prefix() {
+    let mut db = RotDb::ephemeral();
+    for (key, value) in &[("foo.bar", 1), ("Foo::Baz", 2), ("foobar", 3), ("bar.foo", 4), ("foo.cold", 5)] {
+        db.set(key, *value);
+    }
+    db.freeze("foo.cold");
+    assert_eq!(db.keys_with_prefix("Foo::"), vec!["foo.bar", "foo.baz", "foo.cold"]);
+    assert_eq!(db.adjust_prefix("foo.", 10), 2);
+    assert_eq!(db.get("foo.bar"), Some(11));
+    assert_eq!(db.get("foo.baz"), Some(12));
+    assert_eq!(db.get("foo.cold"), Some(5));
+    assert_eq!(db.get("foobar"), Some(3));
+    assert_eq!(db.get("bar.foo"), Some(4));
+    assert_eq!(db.adjust_prefix("nope", 1), 0);
+    assert_eq!(db.adjust_prefix("", -1), 4);
+    assert_eq!(db.get("bar.foo"), Some(3));
+}
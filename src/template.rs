@@ -0,0 +1,103 @@
+/// Reply formats, with `{name}` placeholders filled in when replying
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Templates {
+    /// Reply to `?key`
+    pub query: String,
+    /// Reply to `key++` and `key--`
+    pub change: String,
+    /// Each entry of the `?top` leaderboard
+    pub top_entry: String,
+    /// Reply to `?key` for a key that has never been changed, with
+    /// `report_absent` set
+    pub absent: String,
+}
+
+/// How much the reply to `?key` says beyond the `query` template, from
+/// least to most
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Verbosity {
+    /// Just the template, which by default is the value
+    #[default]
+    Value,
+    /// The key's rank on the leaderboard as well
+    Rank,
+    /// The rank, and how long ago the key was last changed
+    Full,
+}
+
+/// Placeholders available in each of the `Templates`
+pub const KEY_PLACEHOLDERS: &[&str] = &["key", "value", "rank"];
+
+impl Default for Templates {
+    fn default() -> Templates {
+        Templates {
+            query: "{key} = {value}".to_string(),
+            change: "{key} = {value}".to_string(),
+            top_entry: "{key} = {value}".to_string(),
+            absent: "No data for {key}".to_string(),
+        }
+    }
+}
+
+/// Split a template into literal text and placeholder names
+fn tokens(template: &str) -> Vec<(&str, bool)> {
+    let mut tokens = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let name = rest[start + 1..].find('}')
+                .map(|end| &rest[start + 1..start + 1 + end])
+                .filter(|name| !name.is_empty()
+                               && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+        match name {
+            Some(name) => {
+                tokens.push((&rest[..start], false));
+                tokens.push((name, true));
+                rest = &rest[start + name.len() + 2..];
+            }
+            None => {
+                tokens.push((&rest[..start + 1], false));
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    tokens.push((rest, false));
+    tokens
+}
+
+/// Check that a template only uses the `allowed` placeholders
+pub fn validate(template: &str, allowed: &[&str]) -> Result<(), String> {
+    match tokens(template).iter().find(|(name, placeholder)| *placeholder && !allowed.contains(name)) {
+        Some((name, _)) => Err(format!("Unknown placeholder {{{}}} in \"{}\"", name, template)),
+        None => Ok(()),
+    }
+}
+
+/// Fill in a template's placeholders.  Placeholders without a value are
+/// left as they are.
+pub fn render(template: &str, values: &[(&str, &str)]) -> String {
+    tokens(template).iter().map(|&(text, placeholder)| {
+        if !placeholder {
+            return text.to_string();
+        }
+        match values.iter().find(|(name, _)| *name == text) {
+            Some((_, value)) => value.to_string(),
+            None => format!("{{{}}}", text),
+        }
+    }).collect()
+}
+
+#[test]
+fn test_templates() {
+    let values = [("key", "foo"), ("value", "10"), ("rank", "2")];
+    assert_eq!(render("{key} = {value}", &values), "foo = 10");
+    assert_eq!(render("#{rank}: {key} ({value} points)", &values), "#2: foo (10 points)");
+    assert_eq!(render("{key}{value}{key}", &values), "foo10foo");
+    assert_eq!(render("no placeholders", &values), "no placeholders");
+    assert_eq!(render("{ key } {} {{key}} {key", &values), "{ key } {} {foo} {key");
+    assert_eq!(render("{unknown} {key}", &values), "{unknown} foo");
+
+    assert_eq!(validate("{key} = {value} (rank {rank})", KEY_PLACEHOLDERS), Ok(()));
+    assert_eq!(validate("{key} = {} {", KEY_PLACEHOLDERS), Ok(()));
+    assert!(validate("{key} = {valeu}", KEY_PLACEHOLDERS).is_err());
+    assert!(validate("{top}", KEY_PLACEHOLDERS).is_err());
+}